@@ -36,6 +36,8 @@ pub struct Disk {
 
     /// Pointer back to the corresponding Port Control Registers, found at `BAR5[100h]`-`BAR5[10FFh]`.
     pub(super) px:         &'static mut Px,
+    /// The interrupt raised when a command of the port completes.
+    pub(super) interrupt:  PortInterrupt,
     /// The allocated Received FIS memory zone that the port uses.
     pub(super) rfis:       ZeroBox<ReceivedFis>,
     /// The allocated Command List memory zone that the port uses.
@@ -102,6 +104,7 @@ impl Disk {
                     lba + sector_step,
                     core::cmp::min(sector_count - sector_step, step as u64),
                     self.px,
+                    &self.interrupt,
                     &mut self.cmd_list.slots[command_slot_index],
                     self.cmd_tables[command_slot_index].as_mut().unwrap(),
                     command_slot_index,
@@ -143,6 +146,7 @@ impl Disk {
                 lba,
                 sector_count,
                 self.px,
+                &self.interrupt,
                 &mut self.cmd_list.slots[command_slot_index],
                 self.cmd_tables[command_slot_index].as_mut().unwrap(),
                 command_slot_index,
@@ -6,7 +6,8 @@
 //! [Serial ATA AHCI: Specification, Rev. 1.3.1]: http://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/serial-ata-ahci-spec-rev1-3-1.pdf
 
 use sunrise_libuser::io::{Io, Mmio};
-use sunrise_libuser::syscalls::{sleep_thread, query_physical_address};
+use sunrise_libuser::syscalls::{self, sleep_thread, query_physical_address};
+use sunrise_libuser::types::ReadableEvent;
 use sunrise_libuser::mem::{map_mmio, virt_to_phys};
 use sunrise_libuser::error::{Error, AhciError};
 use sunrise_libuser::zero_box::*;
@@ -15,6 +16,8 @@ use core::mem::size_of;
 use core::cmp::min;
 use core::time::Duration;
 use alloc::vec::Vec;
+use alloc::sync::Arc;
+use spin::Mutex;
 use crate::fis::*;
 use crate::disk::Disk;
 use static_assertions::assert_eq_size;
@@ -191,7 +194,10 @@ impl HbaMemoryRegisters {
     /// * mapping `BAR5` failed.
     /// * GHC.AE is not set (controller is in legacy support mode), because conditions specified in
     ///   section 10.2 are tedious.
-    pub fn init(bar5: usize) -> Vec<Disk> {
+    ///
+    /// The HBA signals command completion on `irq_event`. A `level_triggered` event is
+    /// acknowledged each time the interrupt has been cleared on the HBA.
+    pub fn init(bar5: usize, irq_event: ReadableEvent, level_triggered: bool) -> Vec<Disk> {
         let mapping = match map_mmio::<HbaMemoryRegisters>(bar5 as _) {
             Ok(vaddr) => vaddr,
             Err(e) => {
@@ -210,13 +216,26 @@ impl HbaMemoryRegisters {
             return Vec::new();
         }
 
-        // globally disable interrupts for this controller
+        let command_list_len = ghc_registers.cap.read().ncs() as usize + 1;
+        let pi = ghc_registers.pi.read();
+
+        // Globally enable interrupts for this controller. Each port enables the ones it wants
+        // in its PxIE, before issuing its first command.
+        ghc_registers.is.write(0xFFFF_FFFF);
         let mut ghc = ghc_registers.ghc.read();
-        ghc.set_ie(false);
+        ghc.set_ie(true);
         ghc_registers.ghc.write(ghc);
 
-        let command_list_len = ghc_registers.cap.read().ncs() as usize + 1;
-        let pi = ghc_registers.pi.read();
+        let interrupt = Arc::new(HbaInterrupt {
+            event: irq_event,
+            level_triggered,
+            is: Mutex::new(unsafe {
+                // constructing a reference to the IS register we previously mapped.
+                // safe, it is the only reference to this register from now on, and it is
+                // never unmapped.
+                &mut (*mapping).generic_host_control.is
+            }),
+        });
 
         let port_registers = unsafe {
             // constructing a reference to the port registers we previously mapped.
@@ -231,12 +250,59 @@ impl HbaMemoryRegisters {
             // filter out ports not implemented
             .filter(|(index, _)| (pi & (1u32 << index)) != 0)
             // init each port, keep only successful ones
-            .filter_map(|(_port_index, px)| Px::init(px, command_list_len))
+            .filter_map(|(port_index, px)| Px::init(px, command_list_len, PortInterrupt {
+                hba: Arc::clone(&interrupt),
+                port: port_index,
+            }))
             // put that in a vec
             .collect()
     }
 }
 
+/// The interrupt of an HBA, shared by all its ports.
+///
+/// Only the ports with a command in flight raise interrupts: a port only enables the command
+/// completion and error interrupts, and the driver issues a single command at a time. So a
+/// port waiting for its command clears only its own bit of the HBA's IS.
+#[derive(Debug)]
+pub struct HbaInterrupt {
+    /// The event signaled when the HBA raises its interrupt.
+    event: ReadableEvent,
+    /// Whether `event` must be acknowledged once the interrupt is cleared on the HBA, for the
+    /// kernel to unmask it.
+    level_triggered: bool,
+    /// The HBA's interrupt status register, found at `BAR5[08h]`.
+    is: Mutex<&'static mut Mmio<u32>>,
+}
+
+/// The interrupt of an HBA, as seen by one of its ports.
+#[derive(Debug)]
+pub struct PortInterrupt {
+    /// The interrupt of the port's HBA.
+    hba: Arc<HbaInterrupt>,
+    /// The index of the port in the HBA.
+    port: usize,
+}
+
+impl PortInterrupt {
+    /// Waits for the HBA to raise an interrupt, and clears it for `px`.
+    ///
+    /// Returns the interrupt status of the port, which was cleared.
+    fn wait(&self, px: &mut Px) -> Result<PxIS, Error> {
+        syscalls::wait_synchronization(&[self.hba.event.0.as_ref()], None)?;
+
+        // Clear the port's interrupt status, then the HBA's one. See spec section 5.6.2.
+        let status = px.is.read();
+        px.is.write(status);
+        self.hba.is.lock().write(1u32 << self.port);
+
+        if self.hba.level_triggered {
+            syscalls::acknowledge_interrupt(&self.hba.event)?;
+        }
+        Ok(status)
+    }
+}
+
 // ---------------------------------------------------------------------------------------------- //
 //                                         Port Registers                                         //
 // ---------------------------------------------------------------------------------------------- //
@@ -569,7 +635,7 @@ impl Px {
     ///
     /// If the port is not connected to anything, or initialisation failed,
     /// this function returns `None`.
-    fn init(port_registers: &'static mut Px, command_list_length: usize) -> Option<Disk> {
+    fn init(port_registers: &'static mut Px, command_list_length: usize, interrupt: PortInterrupt) -> Option<Disk> {
         port_registers.stop();
         port_registers.disable_fis_receive();
         if !port_registers.probe() {
@@ -578,7 +644,20 @@ impl Px {
             return None;
         }
 
-        port_registers.ie.write(PxIE(0x00)); // no interrupts
+        // Only get interrupts for command completion and errors.
+        let mut ie = PxIE(0x00);
+        ie.set_dhre(true);
+        ie.set_pse(true);
+        ie.set_dse(true);
+        ie.set_sbde(true);
+        ie.set_tfee(true);
+        ie.set_hbfe(true);
+        ie.set_hbde(true);
+        ie.set_ife(true);
+        ie.set_infe(true);
+        ie.set_ofe(true);
+        port_registers.is.write(PxIS(0xFFFF_FFFF));
+        port_registers.ie.write(ie);
         let mut received_fis = ZeroBox::<ReceivedFis>::new_zeroed();
         unsafe {
             // safe: when the Disk is dropped we make sure to call `clear_addresses`.
@@ -608,7 +687,7 @@ impl Px {
             // safe: - port is started,
             //       - index is 0, which is always implemented (required by spec),
             //       - no command has been issued yet, so CI is clear.
-            match Self::identify(port_registers, &mut cmd_list.slots[0], cmd_tables[0].as_mut().unwrap(), 0) {
+            match Self::identify(port_registers, &interrupt, &mut cmd_list.slots[0], cmd_tables[0].as_mut().unwrap(), 0) {
                 Ok(x) => x,
                 Err(e) => {
                     error!("Initializing port failed: IDENTIFY DEVICE command failed. Error: {:?}. Status: {:?}", e, port_registers);
@@ -622,6 +701,7 @@ impl Px {
 
         Some(Disk {
             px: port_registers,
+            interrupt,
             rfis: received_fis,
             cmd_list,
             cmd_tables,
@@ -630,56 +710,16 @@ impl Px {
         })
     }
 
-    /// Checks if the command issued in `slot` is still running.
+    /// Waits for the command in `slot` to complete, or for an error to occur.
     ///
-    /// This function returns true either if the `slot` bit is cleared in `PxCI`, or if an error occurred.
-    fn command_running(&self, slot: usize) -> bool {
-        (self.ci.readf(1u32 << slot) || self.tfd.read().bsy()) && !self.is.read().is_err()
-    }
-
-    /// Polls the port until the command in `slot` is completed, or an error occurred.
-    // todo: AHCI interrupts - command completion
-    // body: The AHCI driver does not make any use of AHCI interruptions.
-    // body: To check that a command has been completed, it polls the port repeatedly
-    // body: until the PxCI bit cleared.
-    // body:
-    // body: This is bad for performances, uses unnecessary time slices, and is an
-    // body: awful design.
-    // body:
-    // body: We need to figure out how to make AHCI interrupts work for us.
-    // body:
-    // body: The problem that we faced is the following:
-    // body:
-    // body: When enabled, after a command has completed, the HBA sends an irq to the PIC.
-    // body: The irq kernel-side top-half `acknowledges the irq` (sends EOI to the 8259A),
-    // body: and delays the actual handling to the userspace-side bottom-half.
-    // body: It then rets to the interrupted context, and re-enables interrupts by doing so.
-    // body:
-    // body: At this point the HBA, whose state has not been resolved, re-triggers an interrupt.
-    // body:
-    // body: Hence the kernel is spending 100% cpu time servicing an infinite irq
-    // body: and the OS completely freezes.
-    // body:
-    // body: This happens even while the 8359A is in `edge triggered mode`, which means that the
-    // body: PCI irq line is cycling through an HIGH and LOW, which is really odd according to the
-    // body: spec, where the irq are supposed to be "level sensitive".
-    // body:
-    // body: I think a little more digging up would be necessary to find out if we just configured
-    // body: something incorrectly, or if AHCI interrupts truly cannot be handled in the bottom-half.
-    // body:
-    // body: In the few open-source microkernels I reviewed that have an AHCI driver:
-    // body:
-    // body: - Redox does not implement interrupts (well ... just like us),
-    // body: - HelenOS seems to allow userspace drivers to have their own top-half routine.
-    // body:   I don't know if they run in ring 0 or ring 3, how the page-tables switching is handled,
-    // body:   but I'm really curious and will try to find out more about it.
-    // body: - Minix3 seems to be actually using interrupts, through its blockdriver multithreading framework.
-    // body:   I should look this up.
-    fn wait_command_completion(&self, slot: usize) -> Result<(), Error> {
-        while self.command_running(slot) {
-            sleep_thread(0).unwrap();
+    /// The port raises an interrupt when the command completes. Because we clear its interrupt
+    /// status each time, errors are accumulated as we go.
+    fn wait_command_completion(&mut self, slot: usize, interrupt: &PortInterrupt) -> Result<(), Error> {
+        let mut errored = false;
+        while !errored && (self.ci.readf(1u32 << slot) || self.tfd.read().bsy()) {
+            errored = interrupt.wait(self)?.is_err();
         }
-        if self.is.read().is_err() {
+        if errored {
             Err(AhciError::IoError.into())
         } else {
             Ok(())
@@ -699,7 +739,7 @@ impl Px {
     /// * `command_slot_index` must not have its bit set in `PxCI`.
     /// * `command_header` and `command_table` must belong to `command_slot_index`'s command slot.
     #[allow(clippy::cast_lossless)] // trust me, types won't change
-    unsafe fn identify(px: &mut Px, command_header: &mut CmdHeader, command_table: &mut CmdTable, command_slot_index: usize) -> Result<(u64, bool), Error> {
+    unsafe fn identify(px: &mut Px, interrupt: &PortInterrupt, command_header: &mut CmdHeader, command_table: &mut CmdTable, command_slot_index: usize) -> Result<(u64, bool), Error> {
 
         /// The IDENTIFY DEVICE command. See ATA spec.
         const ATA_CMD_IDENTIFY: u8 = 0xEC;
@@ -738,7 +778,7 @@ impl Px {
 
         // set PxCI
         px.ci.write(1u32 << command_slot_index);
-        px.wait_command_completion(command_slot_index)?;
+        px.wait_command_completion(command_slot_index, interrupt)?;
 
         let mut supports_48_bit = true;
 
@@ -770,7 +810,7 @@ impl Px {
     /// * `buffer[0] - buffer[buffer_len - 1]` must fall in a single mapping.
     /// * `command_slot_index` must be free to use, implemented,
     ///    and must point to `command_header` and `command_table`.
-    /// * `px` must be properly initialized.
+    /// * `px` must be properly initialized, and `interrupt` be its interrupt.
     ///
     /// # Error
     ///
@@ -788,6 +828,7 @@ impl Px {
         lba: u64,
         sector_count: u64,
         px: &mut Px,
+        interrupt: &PortInterrupt,
         command_header: &mut CmdHeader,
         command_table: &mut CmdTable,
         command_slot_index: usize,
@@ -851,7 +892,7 @@ impl Px {
 
         // set PxCI
         px.ci.write(1u32 << command_slot_index);
-        px.wait_command_completion(command_slot_index)?;
+        px.wait_command_completion(command_slot_index, interrupt)?;
         Ok(())
     }
 
@@ -870,7 +911,7 @@ impl Px {
     /// * `buffer[0] - buffer[buffer_len - 1]` must fall in a single mapping.
     /// * `command_slot_index` must be free to use, implemented,
     ///    and must point to `command_header` and `command_table`.
-    /// * `px` must be properly initialized.
+    /// * `px` must be properly initialized, and `interrupt` be its interrupt.
     ///
     /// # Error
     ///
@@ -888,6 +929,7 @@ impl Px {
         lba: u64,
         sector_count: u64,
         px: &mut Px,
+        interrupt: &PortInterrupt,
         command_header: &mut CmdHeader,
         command_table: &mut CmdTable,
         command_slot_index: usize,
@@ -951,7 +993,7 @@ impl Px {
 
         // set PxCI
         px.ci.write(1u32 << command_slot_index);
-        px.wait_command_completion(command_slot_index)?;
+        px.wait_command_completion(command_slot_index, interrupt)?;
         Ok(())
     }
 }
@@ -11,7 +11,6 @@
 //! - NCQ support
 //! - hotplug/remove of a device
 //! - hotplug/remove of a controller
-//! - interrupts notifying command has been completed (WIP)
 //! - real error management
 //! - Port Multipliers
 //! - PCI-to-PCI bridges
@@ -36,8 +35,18 @@
 //! before accepting other requests.
 //!
 //! This is highly unsatisfying, since AHCI supports up to 32 commands being issued
-//! simultaneously. Unfortunately we can't take advantage of that until we manage to
-//! make command-completion interrupts work.
+//! simultaneously. We wait for a command-completion interrupt while a command is in flight,
+//! but the whole driver is blocked in the meantime.
+//!
+//! # Interrupts
+//!
+//! The HBA raises its interrupt when a command completes. We ask the kernel for a Message
+//! Signaled Interrupt vector, and program it in the HBA's MSI capability. MSIs are never shared,
+//! and are edge-triggered, so they don't need to be acknowledged.
+//!
+//! If the HBA doesn't support MSI, we fall back to the legacy interrupt line it is routed to,
+//! which is level-triggered: the kernel masks it each time it fires, until we clear the
+//! interrupt on the HBA and acknowledge it.
 
 #![feature(box_syntax, untagged_unions, const_vec_new)]
 #![no_std]
@@ -74,11 +83,12 @@ use crate::disk::{Disk, IDisk};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
-use sunrise_libuser::error::{Error, AhciError};
+use sunrise_libuser::error::{Error, AhciError, KernelError};
 use sunrise_libuser::futures::{WaitableManager, WorkQueue};
 use sunrise_libuser::ipc::server::{port_handler, new_session_wrapper};
 use spin::Mutex;
-use sunrise_libuser::syscalls;
+use sunrise_libuser::syscalls::{self, InterruptType};
+use sunrise_libuser::types::ReadableEvent;
 use sunrise_libuser::ahci::{AhciInterface as IAhciInterface, IDiskProxy, IDisk as _};
 use sunrise_libuser::futures_rs::future::FutureObj;
 
@@ -97,6 +107,7 @@ static DISKS: Mutex<Vec<Arc<Mutex<Disk>>>> = Mutex::new(Vec::new());
 ///
 /// 1. Discover HBAs on the PCI.
 /// 2. For every found HBA:
+///     - Listen to its interrupt, preferably an MSI.
 ///     - Initialize each implemented port if we detect it is connected to a device.
 ///     - Push the created [Disk]s in [DISKS].
 /// 3. Start the event loop.
@@ -104,9 +115,16 @@ fn main() {
     debug!("AHCI driver starting up");
     let ahci_controllers = pci::get_ahci_controllers();
     debug!("AHCI controllers : {:#x?}", ahci_controllers);
-    for (bar5, _) in ahci_controllers {
+    for controller in ahci_controllers {
+        let (irq_event, level_triggered) = match listen_to_interrupt(&controller) {
+            Ok(irq) => irq,
+            Err(err) => {
+                error!("HBA {:#010x}, initialization failed: can't listen to IRQ {}, {:?}.", controller.bar5, controller.interrupt_line, err);
+                continue;
+            }
+        };
         DISKS.lock().extend(
-            HbaMemoryRegisters::init(bar5 as _)
+            HbaMemoryRegisters::init(controller.bar5 as _, irq_event, level_triggered)
                 .drain(..).map(|disk| Arc::new(Mutex::new(disk)))
         );
     }
@@ -119,6 +137,24 @@ fn main() {
     man.run();
 }
 
+/// Creates the event signaled by the interrupt of an HBA.
+///
+/// Tries to allocate an MSI vector first, and falls back to the HBA's legacy interrupt line.
+/// Returns the event, and whether it is level-triggered.
+fn listen_to_interrupt(controller: &pci::AhciController) -> Result<(ReadableEvent, bool), KernelError> {
+    match syscalls::create_msi_event() {
+        Ok((event, address, data)) => {
+            if controller.enable_msi(address, data) {
+                return Ok((event, false));
+            }
+            info!("HBA {:#010x} does not support MSI, using IRQ {}.", controller.bar5, controller.interrupt_line);
+        },
+        Err(err) => info!("Can't allocate an MSI vector, using IRQ {}: {:?}.", controller.interrupt_line, err)
+    }
+    let event = syscalls::create_interrupt_event(controller.interrupt_line as _, InterruptType::Level)?;
+    Ok((event, true))
+}
+
 /// Main interface to the AHCI driver.
 ///
 /// Registered under the name `"ahci:\0"` to the Service Manager, after the discovery stage.
@@ -174,6 +210,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
@@ -183,6 +220,8 @@ capabilities!(CAPABILITIES = Capabilities {
         sunrise_libuser::syscalls::nr::QueryMemory,
         sunrise_libuser::syscalls::nr::ConnectToNamedPort,
         sunrise_libuser::syscalls::nr::CreateInterruptEvent,
+        sunrise_libuser::syscalls::nr::AcknowledgeInterrupt,
+        sunrise_libuser::syscalls::nr::CreateMsiEvent,
         sunrise_libuser::syscalls::nr::QueryPhysicalAddress,
         sunrise_libuser::syscalls::nr::MapMmioRegion,
         sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
@@ -191,19 +230,11 @@ capabilities!(CAPABILITIES = Capabilities {
         sunrise_libuser::syscalls::nr::CreateSession,
     ],
     raw_caps: [
-        // todo: IRQ capabilities at runtime
-        // body: Currently IRQ capabilities are declared at compile-time.
-        // body:
-        // body: However, for PCI, the IRQ line we want to subscribe to
-        // body: can only be determined at runtime by reading the `Interrupt Line` register
-        // body: that has been set-up during POST.
-        // body:
-        // body: What would be the proper way to handle such a case ?
-        // body:
-        // body: - Declaring every IRQ line in our capabilities, but only effectively using one ?
-        // body: - Deporting the PIC management to a userspace module, and allow it to accept
-        // body:   dynamic irq capabilities in yet undefined way.
-        sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 0), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 1), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 2), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 3),
-        sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 0), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 1), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 2), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 3),
+        sunrise_libuser::caps::msi_allowed(),
+        // Fallback for HBAs without MSI. The legacy line is only known at runtime, by reading
+        // the `Interrupt Line` register set-up during POST, so declare the lines the BIOS routes
+        // PCI interrupts to.
+        sunrise_libuser::caps::irq_pair(5, 9), sunrise_libuser::caps::irq_pair(10, 11),
+        sunrise_libuser::caps::ioport_range_start(pci::CONFIG_ADDRESS), sunrise_libuser::caps::ioport_range_end(pci::CONFIG_DATA + 3),
     ]
 });
@@ -1,6 +1,7 @@
 //! PCI discovery
 //!
-//! A minimal PCI implementation, that permits only discovering AHCI devices, and querying their BAR.
+//! A minimal PCI implementation, that permits only discovering AHCI devices, querying their BAR,
+//! and setting up their Message Signaled Interrupts.
 
 use sunrise_libutils::io::{Io, Pio};
 use spin::Mutex;
@@ -48,6 +49,13 @@ const MAX_FUNC: u8 = 15;
 /// The highest addressable register on a function on a slot on a bus.
 const MAX_REGISTER: u8 = 63;
 
+/// The Capabilities List bit in the status register.
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+/// The Interrupt Disable bit in the command register.
+const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+/// The capability ID of the MSI capability.
+const CAPABILITY_ID_MSI: u8 = 0x05;
+
 /// A pci device, addressed by its bus number, slot, and function.
 #[derive(Debug, Copy, Clone)]
 #[allow(clippy::missing_docs_in_private_items)]
@@ -229,6 +237,66 @@ impl PciDevice {
     fn command(&self) -> u16 {
         (self.read_config_register(1) >> 0) as u16
     }
+
+    /// Writes the command register.
+    ///
+    /// The status register shares its config register, but writing 0 to it is a no-op.
+    fn set_command(&self, command: u16) {
+        self.write_config_register(1, u32::from(command))
+    }
+
+    /// Walks the device's capabilities list, looking for the capability `id`.
+    ///
+    /// Returns the config register of the capability's first dword.
+    fn find_capability(&self, id: u8) -> Option<u8> {
+        if self.status() & STATUS_CAPABILITIES_LIST == 0 {
+            return None;
+        }
+        let mut ptr = match self.header {
+            PciHeader::GeneralDevice(header00) => header00.capabilities_ptr,
+            _ => return None
+        };
+        // The list is at most as long as there are dwords in the config space. Bail out
+        // on a broken list rather than looping forever.
+        for _ in 0..=MAX_REGISTER {
+            // the bottom two bits are reserved.
+            let register = (ptr & 0xFC) >> 2;
+            if register == 0 {
+                return None;
+            }
+            let header = self.read_config_register(register);
+            if header as u8 == id {
+                return Some(register);
+            }
+            ptr = (header >> 8) as u8;
+        }
+        None
+    }
+
+    /// Programs the device's MSI capability with the `address` and `data` of an interrupt vector,
+    /// enables MSI, and disables the legacy interrupt line.
+    ///
+    /// A single message is enabled. Returns false if the device has no MSI capability.
+    fn enable_msi(&self, address: u32, data: u32) -> bool {
+        let register = match self.find_capability(CAPABILITY_ID_MSI) {
+            Some(register) => register,
+            None => return false
+        };
+        // Message Control is the upper half of the capability's first dword.
+        let control = self.read_config_register(register);
+        let is_64_bit = control & (1 << 23) != 0;
+        self.write_config_register(register + 1, address);
+        if is_64_bit {
+            self.write_config_register(register + 2, 0);
+            self.write_config_register(register + 3, data & 0xFFFF);
+        } else {
+            self.write_config_register(register + 2, data & 0xFFFF);
+        }
+        // Multiple Message Enable (bits 22:20) = 1 message, MSI Enable (bit 16) set.
+        self.write_config_register(register, (control & !(0b111 << 20)) | (1 << 16));
+        self.set_command(self.command() | COMMAND_INTERRUPT_DISABLE);
+        true
+    }
 }
 
 /// Read one of the 64 32-bit registers of a pci bus>device>func.
@@ -306,19 +374,40 @@ fn discover() -> Vec<PciDevice> {
     devices
 }
 
+/// An AHCI controller found by pci discovery.
+#[derive(Debug, Clone, Copy)]
+pub struct AhciController {
+    /// The physical address of the controller's HBA memory registers, found in BAR5.
+    pub bar5: u32,
+    /// The legacy interrupt line the controller is routed to, as set up during POST.
+    pub interrupt_line: u8,
+    /// The pci function of the controller.
+    device: PciDevice,
+}
+
+impl AhciController {
+    /// Makes the controller signal its interrupts with the Message Signaled Interrupt
+    /// `address` and `data`, instead of its legacy interrupt line.
+    ///
+    /// Returns false if the controller does not support MSI.
+    pub fn enable_msi(&self, address: u32, data: u32) -> bool {
+        self.device.enable_msi(address, data)
+    }
+}
+
 /// Gets the ahci controllers found by pci discovery.
-///
-/// # Returns
-///
-/// Returns the controller's BAR5 address and size, if one was found.
-pub fn get_ahci_controllers() -> Vec<(u32, u32)> {
+pub fn get_ahci_controllers() -> Vec<AhciController> {
     discover().iter()
         .filter(|device| device.class == 0x01 && device.subclass == 0x06 && device.prog_if == 0x01)
         .map(|device| {
             match device.header {
                 PciHeader::GeneralDevice(header00) => {
                     match header00.bar5 {
-                        BAR::Memory(addr, size) => (addr, size),
+                        BAR::Memory(addr, _) => AhciController {
+                            bar5: addr,
+                            interrupt_line: header00.interrupt_line,
+                            device: *device,
+                        },
                         _ => panic!("PCI device with unexpected BAR 5")
                     }
                 },
@@ -0,0 +1,255 @@
+//! Crash reporter
+//!
+//! Started by the loader when a process crashes, with the pid and the title
+//! name of the crashed process as arguments. Attaches to the dead process,
+//! and writes a report containing the exception, the registers, a symbolized
+//! backtrace, and the memory map of the code to `/crash/<title>-<time>.txt`.
+//!
+//! The loader keeps the crashed process alive until we exit.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate alloc;
+
+use core::fmt::Write;
+use core::str;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sunrise_libuser::{kip_header, capabilities};
+use sunrise_libuser::argv;
+use sunrise_libuser::error::Error;
+use sunrise_libuser::fs::{FileSystemPath, IFileSystemServiceProxy};
+use sunrise_libuser::syscalls::{self, CrashReport, ExceptionType};
+use sunrise_libuser::time::RTCManagerProxy;
+use sunrise_libuser::types::DebugSession;
+use sunrise_libkern::{MemoryInfo, MemoryType};
+use log::error;
+
+/// Maximum number of frames walked when building the backtrace.
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+/// Maximum length of the function names displayed in the backtrace. Longer
+/// names are truncated.
+const MAX_SYMBOL_LEN: usize = 256;
+
+/// Gets the argument at `index` as a str.
+fn arg(index: isize) -> Option<&'static str> {
+    if index >= argv::argc() {
+        return None;
+    }
+    unsafe {
+        // Safety: argv has at least argc elements, each of them a
+        // null-terminated string living as long as the process.
+        let arg = *argv::argv().offset(index);
+        let len = (0..).find(|&i| *arg.add(i) == 0)?;
+        str::from_utf8(core::slice::from_raw_parts(arg, len)).ok()
+    }
+}
+
+/// Gets the return addresses of the crashed thread by following the chain of
+/// saved ebp. Only works for code compiled with frame pointers.
+fn backtrace(debug: &DebugSession, report: &CrashReport) -> Vec<usize> {
+    let mut frames = vec![report.exception.context.eip];
+    let mut ebp = report.exception.context.ebp;
+    while ebp != 0 && frames.len() < MAX_BACKTRACE_DEPTH {
+        let mut frame = [0; 8];
+        if syscalls::read_debug_process_memory(debug, ebp, &mut frame).is_err() {
+            break;
+        }
+        let mut saved_ebp = [0; 4];
+        let mut ret_addr = [0; 4];
+        saved_ebp.copy_from_slice(&frame[..4]);
+        ret_addr.copy_from_slice(&frame[4..]);
+        let (saved_ebp, ret_addr) = (u32::from_le_bytes(saved_ebp) as usize, u32::from_le_bytes(ret_addr) as usize);
+        if ret_addr == 0 || saved_ebp <= ebp {
+            break;
+        }
+        frames.push(ret_addr);
+        ebp = saved_ebp;
+    }
+    frames
+}
+
+/// Gets the name of the function containing `addr` in the crashed process,
+/// and the offset of `addr` in it.
+fn symbol(debug: &DebugSession, addr: usize) -> Option<(String, usize)> {
+    let mut name = [0; MAX_SYMBOL_LEN];
+    let (offset, name_len) = syscalls::get_debug_process_symbol(debug, addr, &mut name).ok()?;
+    let name = String::from_utf8_lossy(&name[..core::cmp::min(name_len, name.len())]);
+    Some((name.into_owned(), offset))
+}
+
+/// Gets the mappings containing code in the crashed process.
+fn modules(debug: &DebugSession) -> Vec<MemoryInfo> {
+    let mut modules = Vec::new();
+    let mut addr = 0usize;
+    while let Ok((meminfo, _)) = syscalls::query_debug_process_memory(debug, addr) {
+        match meminfo.memtype.ty() {
+            MemoryType::CodeStatic | MemoryType::CodeMutable | MemoryType::ModuleCodeStatic |
+            MemoryType::ModuleCodeMutable | MemoryType::CodeReadOnly => modules.push(meminfo),
+            _ => ()
+        }
+        addr = match meminfo.baseaddr.checked_add(meminfo.size) {
+            Some(next) if next > addr => next,
+            _ => break
+        };
+    }
+    modules
+}
+
+/// Formats the crash report of the process.
+fn format_report(debug: &DebugSession, title: &str, pid: u64, time: i64, report: &CrashReport) -> String {
+    let mut out = String::new();
+    let info = &report.exception;
+    let context = &info.context;
+
+    let _ = writeln!(out, "Crash report for {} (pid {}), at {}", title, pid, time);
+    let _ = writeln!(out, "Thread {} crashed: {:?}", report.tid, info.exception_type);
+    match info.exception_type {
+        ExceptionType::UserBreak => {
+            let break_info = &report.break_info[..report.break_info_size];
+            let _ = writeln!(out, "Break reason: {:?}, info: {:?}", report.break_reason, String::from_utf8_lossy(break_info));
+        },
+        ExceptionType::PageFault => {
+            let _ = writeln!(out, "Accessing {:#010x}, error code {:#x}", info.fault_address, info.error_code);
+        },
+        ExceptionType::StackOverflow => {
+            let _ = writeln!(out, "Overflowed its stack, accessing the guard page at {:#010x}, error code {:#x}", info.fault_address, info.error_code);
+        },
+        ExceptionType::IoPortNotAllowed => {
+            let _ = writeln!(out, "Accessing IO port {:#06x}, not allowed by the capabilities", info.error_code);
+        },
+        _ => {
+            let _ = writeln!(out, "Error code {:#x}", info.error_code);
+        }
+    }
+
+    let _ = writeln!(out, "\nRegisters:");
+    let _ = writeln!(out, "EIP={:#010x} ESP={:#010x} EBP={:#010x} EFLAGS={:#010x}", context.eip, context.esp, context.ebp, context.eflags);
+    let _ = writeln!(out, "EAX={:#010x} EBX={:#010x} ECX={:#010x} EDX={:#010x}", context.eax, context.ebx, context.ecx, context.edx);
+    let _ = writeln!(out, "ESI={:#010x} EDI={:#010x}", context.esi, context.edi);
+
+    let modules = modules(debug);
+
+    let _ = writeln!(out, "\nBacktrace:");
+    for (idx, addr) in backtrace(debug, report).into_iter().enumerate() {
+        let _ = write!(out, "#{:<2} {:#010x}", idx, addr);
+        if let Some((name, offset)) = symbol(debug, addr) {
+            let _ = write!(out, " {}+{:#x}", name, offset);
+        }
+        let module = modules.iter().find(|module| module.baseaddr <= addr && addr - module.baseaddr < module.size);
+        match module {
+            Some(module) => { let _ = writeln!(out, " ({:#010x}+{:#x})", module.baseaddr, addr - module.baseaddr); },
+            None => { let _ = writeln!(out); },
+        }
+    }
+
+    let _ = writeln!(out, "\nModules:");
+    for module in &modules {
+        let _ = writeln!(out, "{:#010x}-{:#010x} {:?} {:?}", module.baseaddr, module.baseaddr + module.size, module.perms, module.memtype.ty());
+    }
+    out
+}
+
+/// Writes `data` in a new file at `path`.
+fn write_file(path: &str, data: &[u8]) -> Result<(), Error> {
+    let fs_proxy = IFileSystemServiceProxy::raw_new()?;
+    let filesystem = fs_proxy.open_disk_partition(0, 0)?;
+
+    let mut ipc_path: FileSystemPath = [0; 0x300];
+    ipc_path[..b"/crash".len()].copy_from_slice(b"/crash");
+    // The directory usually already exists.
+    let _ = filesystem.create_directory(&ipc_path);
+
+    let mut ipc_path: FileSystemPath = [0; 0x300];
+    ipc_path[..path.len()].copy_from_slice(path.as_bytes());
+    filesystem.create_file(0, data.len() as u64, &ipc_path)?;
+    let file = filesystem.open_file(0b111, &ipc_path)?;
+    file.write(0, 0, data.len() as u64, data)?;
+    Ok(())
+}
+
+fn main() {
+    let (pid, title) = match (arg(1).and_then(|pid| pid.parse::<u64>().ok()), arg(2)) {
+        (Some(pid), Some(title)) => (pid, title),
+        _ => {
+            error!("Usage: creport <pid> <title>");
+            return;
+        }
+    };
+
+    let debug = match syscalls::debug_active_process(pid) {
+        Ok(debug) => debug,
+        Err(err) => {
+            error!("Failed to attach to {} (pid {}): {:?}", title, pid, err);
+            return;
+        }
+    };
+    let report = match syscalls::get_crash_report(&debug) {
+        Ok(report) => report,
+        Err(err) => {
+            error!("{} (pid {}) did not crash: {:?}", title, pid, err);
+            return;
+        }
+    };
+
+    let time = RTCManagerProxy::raw_new()
+        .and_then(|rtc| rtc.get_rtc_time())
+        .unwrap_or(0);
+
+    let text = format_report(&debug, title, pid, time, &report);
+    let path = format!("/crash/{}-{}.txt", title, time);
+    if let Err(err) = write_file(&path, text.as_bytes()) {
+        error!("Failed to write {}: {:?}\n{}", path, err, text);
+    }
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"creport\0\0\0\0\0",
+    title_id: 0x0200000000000036,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+
+        sunrise_libuser::syscalls::nr::DebugActiveProcess,
+        sunrise_libuser::syscalls::nr::QueryDebugProcessMemory,
+        sunrise_libuser::syscalls::nr::ReadDebugProcessMemory,
+        sunrise_libuser::syscalls::nr::GetCrashReport,
+        sunrise_libuser::syscalls::nr::GetDebugProcessSymbol,
+    ],
+    raw_caps: [sunrise_libuser::caps::debug_flags(false, true)]
+});
@@ -81,6 +81,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         nr::SleepThread,
         nr::ExitProcess,
+        nr::Break,
         nr::CreateThread,
         nr::StartThread,
         nr::ExitThread,
@@ -91,6 +92,8 @@ capabilities!(CAPABILITIES = Capabilities {
 
         nr::ConnectToNamedPort,
         nr::SetHeapSize,
+        nr::MapMemory,
+        nr::UnmapMemory,
         nr::SendSyncRequestWithUserBuffer,
         nr::QueryMemory,
         nr::CreateSharedMemory,
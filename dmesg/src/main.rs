@@ -0,0 +1,123 @@
+//! Kernel log viewer
+//!
+//! Prints the records of the kernel log ring buffer.
+//!
+//! Usage: `dmesg [-f] [-l <level>]`
+//!
+//! - `-f`: follow mode. Keeps printing the new records as they get logged.
+//! - `-l <level>`: only prints the records at least as severe as `level`, one
+//!   of error, warn, info, debug or trace.
+
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+use std::env;
+use std::os::sunrise::prelude::*;
+use sunrise_libuser::syscalls::{self, dmesg};
+
+/// Names of the levels, indexed by level - 1.
+const LEVELS: [&str; 5] = ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+/// Gets the level with the given name, case insensitive.
+fn parse_level(name: &str) -> Option<u32> {
+    LEVELS.iter()
+        .position(|level| level.eq_ignore_ascii_case(name))
+        .map(|idx| idx as u32 + 1)
+}
+
+/// Prints the records of a buffer filled by the kernel, skipping the ones less
+/// severe than `max_level`.
+fn print_records(buf: &[u8], max_level: u32) {
+    for record in dmesg::parse_records(buf) {
+        if record.header.level > max_level {
+            continue;
+        }
+        let level = LEVELS.get(record.header.level as usize - 1).unwrap_or(&"?");
+        let timestamp = record.header.timestamp_ns;
+        println!("[{:5}.{:06}] [{}] - {} - {} - {}",
+                 timestamp / 1_000_000_000, timestamp % 1_000_000_000 / 1000, level,
+                 String::from_utf8_lossy(record.target),
+                 String::from_utf8_lossy(record.process),
+                 String::from_utf8_lossy(record.message));
+    }
+}
+
+/// Prints how to use dmesg.
+fn print_usage() {
+    println!("Usage: dmesg [-f] [-l error|warn|info|debug|trace]");
+}
+
+/// The entry point of the program.
+fn main() {
+    let mut follow = false;
+    let mut max_level = LEVELS.len() as u32;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "-f" => follow = true,
+            "-l" => match args.next().and_then(|level| parse_level(&level)) {
+                Some(level) => max_level = level,
+                None => return print_usage()
+            },
+            _ => return print_usage()
+        }
+    }
+
+    // Create the event before reading, so we don't miss the records logged
+    // in between.
+    let event = if follow {
+        Some(syscalls::create_kernel_log_event().expect("Failed to create the kernel log event"))
+    } else {
+        None
+    };
+
+    let mut buf = vec![0; dmesg::MAX_RECORD_SIZE * 4];
+    let mut cursor = 0;
+    loop {
+        let (next_cursor, written) = syscalls::read_kernel_log(cursor, &mut buf).expect("Failed to read the kernel log");
+        cursor = next_cursor;
+        print_records(&buf[..written], max_level);
+
+        if written == 0 {
+            match &event {
+                Some(event) => { let _ = syscalls::wait_synchronization(&[event.0.as_ref()], None); },
+                None => break
+            }
+        }
+    }
+}
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        nr::SleepThread,
+        nr::ExitProcess,
+        nr::Break,
+        nr::CreateThread,
+        nr::StartThread,
+        nr::ExitThread,
+        nr::CloseHandle,
+        nr::WaitSynchronization,
+        nr::OutputDebugString,
+        nr::SetThreadArea,
+
+        nr::ConnectToNamedPort,
+        nr::SetHeapSize,
+        nr::MapMemory,
+        nr::UnmapMemory,
+        nr::SendSyncRequestWithUserBuffer,
+        nr::QueryMemory,
+        nr::CreateSharedMemory,
+        nr::MapSharedMemory,
+        nr::UnmapSharedMemory,
+
+        nr::ReadKernelLog,
+        nr::CreateKernelLogEvent,
+    ]
+});
@@ -96,6 +96,14 @@ impl DriverManager {
         self.drives.len() as u32
     }
 
+    /// Flush the data not yet written on every opened drive.
+    pub fn flush_all(&mut self) -> LibUserResult<()> {
+        for drive in self.drives.values() {
+            drive.lock().flush()?;
+        }
+        Ok(())
+    }
+
     /// Open an instance of a filesystem.
     pub fn construct_filesystem_from_disk_partition(&mut self, disk_id: DiskId, partition_id: PartitionId, mut storage: PartitionStorage) -> LibUserResult<Arc<Mutex<Box<dyn FileSystemOperations>>>> {
         let disk_hashmap_opt  = self.partitions.get_mut(&disk_id);
@@ -324,5 +324,10 @@ impl FileSystemProxy {
         let mut partition_manager = PartitionManager::new(storage.as_mut());
         partition_manager.initialize()
     }
+
+    /// Flush the data not yet written on every disk.
+    pub fn flush_all(&mut self) -> LibUserResult<()> {
+        DRIVER_MANAGER.lock().flush_all()
+    }
 }
 
@@ -0,0 +1,26 @@
+//! Embeds the manifest pinning the hashes of the builtin modules in the kernel.
+//!
+//! The manifest is read from the file `SUNRISE_BUILTINS_MANIFEST` points to,
+//! generated by the `builtins-manifest` task of the Makefile. When it is not
+//! set, an empty manifest is embedded, and the kernel refuses to start the
+//! builtin modules unless it is built with the `insecure` feature.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SUNRISE_BUILTINS_MANIFEST");
+
+    let manifest = match env::var_os("SUNRISE_BUILTINS_MANIFEST") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+            fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("Failed to read the builtins manifest {:?}: {}", path, err))
+        },
+        None => String::new()
+    };
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("builtins.sha256"), manifest).unwrap();
+}
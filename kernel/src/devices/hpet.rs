@@ -13,7 +13,8 @@ use core::fmt;
 use core::fmt::Debug;
 use core::fmt::Formatter;
 
-use crate::timer;
+use crate::sync::Once;
+use crate::timer::{self, DeadlineTimer};
 
 bitfield! {
     /// Represent the lower part of the General Capabilities and ID Register.
@@ -108,8 +109,10 @@ pub struct HpetRegister {
     /// General Interrupt Status Register.
     pub general_interrupt_status: Mmio<u32>, // 0x20
     _reserved3: [u8; 0xCC], // 0x24
-    /// main counter value.
-    pub main_counter_value: Mmio<u64>, // 0xF0
+    /// main counter value low part.
+    pub main_counter_value_low: Mmio<u32>, // 0xF0
+    /// main counter value high part.
+    pub main_counter_value_high: Mmio<u32>, // 0xF4
     _reserved4: u64, // 0xF8
 }
 
@@ -121,7 +124,8 @@ impl Debug for HpetRegister {
             .field("period", &self.period)
             .field("general_configuration", &self.general_configuration)
             .field("general_interrupt_status", &self.general_interrupt_status)
-            .field("main_counter_value", &self.main_counter_value)
+            .field("main_counter_value_low", &self.main_counter_value_low)
+            .field("main_counter_value_high", &self.main_counter_value_high)
             .finish()
     }
 }
@@ -404,17 +408,39 @@ impl Hpet {
     }
 
     /// Set HPET main counter value.
+    ///
+    /// # Note
+    ///
+    /// The HPET must be disabled.
     pub fn set_main_counter_value(&self, value: u64) {
         unsafe {
             (*self.inner)
-                .main_counter_value
-                .write(value)
-        }
+                .main_counter_value_low
+                .write((value & 0xFFFF_FFFF) as u32)
+        };
+        unsafe {
+            (*self.inner)
+                .main_counter_value_high
+                .write((value >> 32) as u32)
+        };
     }
 
     /// Get HPET main counter value.
     pub fn get_main_counter_value(&self) -> u64 {
-        unsafe { (*self.inner).main_counter_value.read() }
+        // We can only read the counter 32 bits at a time. Read the high part
+        // again to detect the low part overflowing in between.
+        loop {
+            let high = unsafe { (*self.inner).main_counter_value_high.read() };
+            let low = unsafe { (*self.inner).main_counter_value_low.read() };
+            if high == unsafe { (*self.inner).main_counter_value_high.read() } {
+                return (u64::from(high) << 32) | u64::from(low);
+            }
+        }
+    }
+
+    /// Return true if the main counter is 64 bits wide.
+    pub fn has_64bit_counter(&self) -> bool {
+        unsafe { (*self.inner).identifier.read().counter_size_capability() }
     }
 
     /// Disable HPET (main timer halted, and timer interrupts disabled).
@@ -448,6 +474,62 @@ impl Hpet {
 
 assert_eq_size!(HpetRegister, [u8; 0x100]);
 
+/// The HPET, used as a tickless [DeadlineTimer]: its main counter gives the
+/// time, and its first timer raises an IRQ at the nearest deadline.
+#[derive(Debug)]
+struct HpetDeadlineTimer {
+    /// The HPET device.
+    hpet: Hpet,
+    /// Its first timer, in one shot mode.
+    timer: HpetTimer,
+    /// Cached value of ``Hpet::get_frequency``.
+    frequency: u64,
+}
+
+// Safety: The registers are only accessed through volatile MMIO. The
+// comparator of the timer is only written with the timer queue locked.
+unsafe impl Send for HpetDeadlineTimer {}
+unsafe impl Sync for HpetDeadlineTimer {}
+
+impl HpetDeadlineTimer {
+    /// Minimum time between now and a deadline, in nanoseconds. Closer
+    /// deadlines might pass before the comparator is written.
+    const MIN_DELTA_NS: u64 = 1_000;
+
+    /// Converts a number of main counter ticks to nanoseconds.
+    fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        timer::ticks_to_ns(ticks, self.frequency)
+    }
+
+    /// Converts nanoseconds to a number of main counter ticks, rounding up.
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        timer::ns_to_ticks(ns, self.frequency)
+    }
+}
+
+impl DeadlineTimer for HpetDeadlineTimer {
+    fn now_ns(&self) -> u64 {
+        self.ticks_to_ns(self.hpet.get_main_counter_value())
+    }
+
+    fn set_deadline_ns(&self, deadline_ns: u64) {
+        let mut deadline = self.ns_to_ticks(deadline_ns);
+        loop {
+            self.timer.set_comparator_value(deadline);
+            // In one shot mode, the IRQ is only raised when the counter
+            // reaches the comparator. Make sure it did not pass it already.
+            let now = self.hpet.get_main_counter_value();
+            if now < deadline {
+                break;
+            }
+            deadline = now + self.ns_to_ticks(Self::MIN_DELTA_NS);
+        }
+    }
+}
+
+/// The instance of the HPET device we are using, when it runs tickless.
+static HPET_DEADLINE_TIMER: Once<HpetDeadlineTimer> = Once::new();
+
 /// The instance of the HPET device we are using.
 static mut HPET_INSTANCE: Option<Hpet> = None;
 
@@ -486,31 +568,10 @@ pub unsafe fn init(hpet: &acpi::Hpet) -> bool {
 
     let main_timer = main_timer_opt.unwrap();
 
-    // The timer must support periodic interrupt otherwise we cannot use it!
-    if !main_timer.support_periodic_interrupt() {
-        paging::kernel_memory::get_kernel_memory().unmap(virtual_address, PAGE_SIZE);
-        return false;
-    }
-
-    // Set the tick rate in femtoseconds
-    // Kernel needs an update frequency of 1 milliseconds.
-    // TODO: Switch to a lower update frequency in HPET
-    // BODY: We will maybe prefer to have a better resolution for kernel time.
-    // BODY: For that to be possible, we need to take care of the sleep_thread logic in userland first (sleep_thread(0) shouldn't be used).
-
-    let irq_period_ns = 1 * 1_000_000;
-    let irq_period_fs = irq_period_ns * 1_000_000;
     info!("HPET frequency: {} Hz", hpet_instance.get_frequency());
-    info!("HPET IRQ period: {} fs", irq_period_fs);
-
-    let irq_period_tick = irq_period_fs / u64::from(hpet_instance.get_period());
 
     // IO-APIC expects edge triggering by default.
     main_timer.set_edge_trigger();
-    main_timer.set_periodic_mode();
-    main_timer.enable_interrupt();
-    main_timer.set_accumulator_value(irq_period_tick);
-    main_timer.set_comparator_value(irq_period_tick);
     // TODO: Use IRQ2 for HPET.
     // BODY: Idealy, HPET should be using IRQ2 (which seems to be generally
     // BODY: wired properly). Unfortunately, qemu has an unfortunate bug where
@@ -525,6 +586,44 @@ pub unsafe fn init(hpet: &acpi::Hpet) -> bool {
     // supported.
     main_timer.set_interrupt_route(16);
 
+    // With 64 bits counters, the HPET won't overflow, and can run tickless:
+    // the main timer only raises an IRQ at the nearest deadline.
+    if hpet_instance.has_64bit_counter() && main_timer.support_64bit() {
+        main_timer.set_one_shot_mode();
+        main_timer.set_comparator_value(u64::max_value());
+        main_timer.enable_interrupt();
+        hpet_instance.enable();
+
+        let frequency = hpet_instance.get_frequency();
+        let deadline_timer = HPET_DEADLINE_TIMER.call_once(|| HpetDeadlineTimer {
+            hpet: hpet_instance,
+            timer: main_timer,
+            frequency,
+        });
+        info!("HPET running tickless");
+        timer::set_tickless_kernel_timer_info(16, frequency, deadline_timer);
+        return true;
+    }
+
+    // Otherwise, the timer must support periodic interrupt or we cannot use it!
+    if !main_timer.support_periodic_interrupt() {
+        paging::kernel_memory::get_kernel_memory().unmap(virtual_address, PAGE_SIZE);
+        return false;
+    }
+
+    // Set the tick rate in femtoseconds
+    // Kernel needs an update frequency of 1 milliseconds.
+    let irq_period_ns = 1 * 1_000_000;
+    let irq_period_fs = irq_period_ns * 1_000_000;
+    info!("HPET IRQ period: {} fs", irq_period_fs);
+
+    let irq_period_tick = irq_period_fs / u64::from(hpet_instance.get_period());
+
+    main_timer.set_periodic_mode();
+    main_timer.enable_interrupt();
+    main_timer.set_accumulator_value(irq_period_tick);
+    main_timer.set_comparator_value(irq_period_tick);
+
     // Clear the interrupt state
     hpet_instance.enable();
 
@@ -14,7 +14,13 @@ use crate::paging::kernel_memory::get_kernel_memory;
 use core::cell::UnsafeCell;
 use core::marker::PhantomData;
 use core::fmt;
+use core::arch::x86::__cpuid;
 use bit_field::BitField;
+use crate::devices::pit;
+use crate::i386::instructions::tsc::rdtsc;
+use crate::i386::interrupt::{self, LAPIC_TIMER_IRQ};
+use crate::sync::Once;
+use crate::timer::{self, DeadlineTimer};
 
 /// Specifies how the APICs listed in the destination field should act upon
 /// reception of this signal. Note that certain Delivery Modes only operate as
@@ -551,6 +557,92 @@ impl fmt::Debug for LocalApic {
     }
 }
 
+/// The IA32_TSC_DEADLINE MSR. In TSC-Deadline mode, the APIC timer fires when
+/// the TSC reaches its value.
+///
+/// See chapter 10.5.4.1: TSC-Deadline Mode.
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Checks whether the APIC timer supports TSC-Deadline mode, and whether the
+/// TSC is invariant, e.g. it runs at a constant rate regardless of the power
+/// state of the CPU.
+pub fn supports_tsc_deadline() -> bool {
+    unsafe {
+        // Safety: cpuid has no side-effects. Every CPU we support has it.
+        let tsc_deadline = __cpuid(1).ecx.get_bit(24);
+        let invariant_tsc = __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx.get_bit(8);
+        tsc_deadline && invariant_tsc
+    }
+}
+
+/// Arms the APIC timer to fire when the TSC reaches `deadline`, replacing the
+/// previous deadline. If it already did, the timer fires immediately. 0
+/// disarms the timer.
+///
+/// The APIC timer must be in TSC-Deadline mode.
+///
+/// # Safety
+///
+/// The CPU must support TSC-Deadline mode. See [supports_tsc_deadline].
+unsafe fn set_tsc_deadline(deadline: u64) {
+    asm!("wrmsr" :: "{ecx}"(IA32_TSC_DEADLINE), "{eax}"(deadline as u32), "{edx}"((deadline >> 32) as u32) :: "volatile");
+}
+
+/// The APIC timer in TSC-Deadline mode, used as a tickless [DeadlineTimer]:
+/// the TSC gives the time, and the APIC timer raises an IRQ at the nearest
+/// deadline.
+#[derive(Debug)]
+struct TscDeadlineTimer {
+    /// Value of the TSC when the timer started.
+    tsc_origin: u64,
+    /// Frequency of the TSC, in Hertz.
+    frequency: u64,
+}
+
+impl DeadlineTimer for TscDeadlineTimer {
+    fn now_ns(&self) -> u64 {
+        timer::ticks_to_ns(rdtsc() - self.tsc_origin, self.frequency)
+    }
+
+    fn set_deadline_ns(&self, deadline_ns: u64) {
+        // Never write 0, which would disarm the timer.
+        let deadline = self.tsc_origin.saturating_add(timer::ns_to_ticks(deadline_ns, self.frequency)).max(1);
+        unsafe {
+            // Safety: checked in init_tsc_deadline_timer.
+            set_tsc_deadline(deadline);
+        }
+    }
+}
+
+/// The APIC timer, when it runs in TSC-Deadline mode.
+static TSC_DEADLINE_TIMER: Once<TscDeadlineTimer> = Once::new();
+
+/// How long the TSC frequency is measured for, in milliseconds.
+const TSC_CALIBRATION_MS: u64 = 10;
+
+/// Tries to run the kernel timer tickless on the APIC timer in TSC-Deadline
+/// mode. Returns false if the CPU does not support it.
+///
+/// The frequency of the TSC is measured with the PIT.
+pub fn init_tsc_deadline_timer() -> bool {
+    if !supports_tsc_deadline() {
+        return false;
+    }
+
+    let start = rdtsc();
+    pit::spin_wait_ms(TSC_CALIBRATION_MS as usize);
+    let frequency = (rdtsc() - start) * (1000 / TSC_CALIBRATION_MS);
+    info!("TSC frequency: {} Hz", frequency);
+
+    let deadline_timer = TSC_DEADLINE_TIMER.call_once(|| TscDeadlineTimer {
+        tsc_origin: rdtsc(),
+        frequency,
+    });
+    interrupt::enable_tsc_deadline_timer();
+    timer::set_tickless_kernel_timer_info(LAPIC_TIMER_IRQ, frequency, deadline_timer);
+    true
+}
+
 // TODO: LocalAPIC should not be Send/Sync.
 // BODY: LocalApic should be stored in a cpu_local, removing the need for Send/
 // BODY: Sync bounds. Problem is, we don't really have a way to create CPU Locals
@@ -709,6 +801,17 @@ impl LocalApic {
         }
     }
 
+    /// Puts the APIC timer in TSC-Deadline mode, raising `vector` at the
+    /// deadlines written in the IA32_TSC_DEADLINE MSR.
+    ///
+    /// See chapter 10.5.4.1: TSC-Deadline Mode.
+    pub fn enable_tsc_deadline_timer(&self, vector: u8) {
+        let mut lvt_timer = LocalVector(0);
+        lvt_timer.set_vector(u32::from(vector));
+        lvt_timer.set_timer_mode(TimerMode::TscDeadline);
+        unsafe { (*self.internal.get()).lvt_timer.write(lvt_timer); }
+    }
+
     /// Sends an IPI.
     ///
     /// See 10.6 Issuing Interprocessor Interrupts
@@ -11,7 +11,17 @@ pub mod ioapic;
 use crate::i386::acpi;
 
 /// Initialize a timer to be used by the OS.
+///
+/// Prefers the APIC timer in TSC-Deadline mode, then the HPET, and falls back
+/// to the PIT.
 pub fn init_timer() {
+    if lapic::init_tsc_deadline_timer() {
+        info!("Initialized the APIC timer in TSC-Deadline mode");
+        unsafe { pit::disable() };
+        info!("Disabled PIT");
+        return;
+    }
+
     let mut use_pit = false;
     if let Some(acpi_info) = acpi::try_get_acpi_information() {
         if let Some(hpet_info) = acpi_info.hpet() {
@@ -23,6 +23,7 @@ use sunrise_libkern::MemoryType;
 use crate::frame_allocator::PhysicalMemRegion;
 use crate::utils::{self, align_up};
 use crate::error::KernelError;
+use crate::process::symbols::SymbolMap;
 use sunrise_libkern::process::KipHeader;
 use plain::Plain;
 
@@ -79,6 +80,16 @@ pub fn map_grub_module(module: &ModuleTag) -> Result<MappedGrubModule<'_>, Kerne
     })
 }
 
+impl<'a> MappedGrubModule<'a> {
+    /// Gets the content of the module.
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            // Safety: The module is mapped until we get dropped.
+            slice::from_raw_parts(self.start.addr() as *const u8, self.len)
+        }
+    }
+}
+
 impl<'a> Drop for MappedGrubModule<'a> {
     /// Unmap the module, but do not deallocate physical memory
     fn drop(&mut self) {
@@ -97,6 +108,16 @@ pub fn get_kacs<'a>(module: &'a MappedGrubModule<'_>) -> Option<&'a [u8]> {
         .map(|section| section.raw_data(&elf))
 }
 
+/// Gets the functions of a kernel built-in loaded at `base`, from the
+/// .symtab and .strtab sections of its elf.
+pub fn get_symbols(module: &MappedGrubModule<'_>, base: usize) -> Option<SymbolMap> {
+    let elf = module.elf.as_ref().expect("Failed parsing multiboot module as elf");
+
+    let symtab = elf.find_section_by_name(".symtab")?.raw_data(&elf);
+    let strtab = elf.find_section_by_name(".strtab")?.raw_data(&elf);
+    Some(SymbolMap::from_elf_sections(symtab, strtab, elf.header.pt1.class(), base))
+}
+
 /// Gets the KIP Header of the provided module, found in the .kip_header
 /// section of the ELF.
 #[allow(clippy::cast_ptr_alignment)]
@@ -136,6 +157,41 @@ pub fn load_builtin(process_memory: &mut ProcessMemory, module: &MappedGrubModul
     entry_point as usize
 }
 
+/// Passes the command line of the module to the built-in as its arguments.
+///
+/// They are put right after its last segment, where `sunrise_libuser::argv`
+/// expects them, laid out the same way the loader does for regular titles.
+/// Built-ins get no environment.
+pub fn load_builtin_args(process_memory: &mut ProcessMemory, module: &MappedGrubModule<'_>, base: usize, cmdline: &str) {
+    let elf = module.elf.as_ref().expect("Failed parsing multiboot module as elf");
+
+    let image_end = elf.program_iter()
+        .filter(|ph| ph.get_type().expect("Failed to get type of elf program header") == Load)
+        .map(|ph| base + ph.virtual_addr() as usize + align_up(ph.mem_size() as usize, PAGE_SIZE))
+        .max()
+        .expect("Built-in has no segment to load");
+
+    // Room for the header, the cmdline, a copy of it to split the arguments,
+    // and a page for the vector of pointers.
+    let args = cmdline.as_bytes();
+    let args_size = align_up(args.len() * 2 + 0x20, core::mem::size_of::<usize>());
+    let args_size = align_up(args_size + 0x1000 / core::mem::size_of::<usize>(), PAGE_SIZE);
+
+    let userspace_addr = VirtualAddress(image_end);
+    process_memory.create_regular_mapping(userspace_addr, args_size, MemoryType::CodeMutable, MappingAccessRights::u_rw())
+        .expect("Cannot map the built-in arguments");
+
+    let mirror = process_memory.mirror_mapping(userspace_addr, args_size)
+        .expect("Cannot mirror the built-in arguments");
+    let dest = unsafe { slice::from_raw_parts_mut(mirror.addr().addr() as *mut u8, args_size) };
+    for byte in dest.iter_mut() {
+        *byte = 0x00;
+    }
+    dest[0..4].copy_from_slice(&(args_size as u32).to_le_bytes());
+    dest[4..8].copy_from_slice(&(args.len() as u32).to_le_bytes());
+    dest[0x20..0x20 + args.len()].copy_from_slice(args);
+}
+
 /// Loads an elf segment by coping file_size bytes to the right address,
 /// and filling remaining with 0s.
 /// This is used by NOBITS sections (.bss), this way we initialize them to 0.
@@ -82,6 +82,10 @@ pub enum KernelError {
     ReservedValue {
         backtrace: Backtrace,
     },
+    #[fail(display = "Handle table full.")]
+    OutOfHandles {
+        backtrace: Backtrace,
+    },
 
 }
 
@@ -102,6 +106,7 @@ impl From<KernelError> for UserspaceError {
             KernelError::NotImplemented { .. } => UserspaceError::NotImplemented,
             KernelError::WrongMappingFramesForTy { .. } => UserspaceError::InvalidCombination,
             KernelError::InvalidMemState { .. } => UserspaceError::InvalidMemState,
+            KernelError::OutOfHandles { .. } => UserspaceError::OutOfHandles,
         }
     }
 }
@@ -90,6 +90,13 @@ pub trait Waitable: Debug + Send + Sync {
     /// #}
     /// ```
     fn register(&self);
+
+    /// Called once the current thread stops waiting on the Waitable, whether
+    /// it got signaled or not.
+    ///
+    /// Waitables that keep track of the threads waiting on them until they're
+    /// signaled can forget about it here. By default, this does nothing.
+    fn unregister(&self) {}
 }
 
 /// Waits for an event to occur on one of the given Waitable objects.
@@ -103,11 +110,11 @@ where
     let waitable = waitable_intoiter.into_iter();
     let interrupt_manager = SpinLockIRQ::new(());
 
-    loop {
+    let signaled = 'wait: loop {
         // Early-check for events that have already been signaled.
         for item in waitable.clone() {
             if item.is_signaled() {
-                return Ok(item);
+                break 'wait Ok(item);
             }
         }
 
@@ -123,8 +130,15 @@ where
         // bug otherwise.
 
         // Schedule
-        scheduler::unschedule(&interrupt_manager, lock)?;
+        if let Err(err) = scheduler::unschedule(&interrupt_manager, lock) {
+            break 'wait Err(err);
+        }
+    };
+
+    for item in waitable {
+        item.unregister();
     }
+    signaled
 }
 
 /// The underlying shared object of a [ReadableEvent]/[WritableEvent].
@@ -222,23 +236,89 @@ impl WritableEvent {
 /// When created, is_signaled is called and the IRQ was triggered, it will
 /// increment the ACK count by 1. This means that if multiple IRQs happened
 /// between wait calls, it will immediately return true.
-// TODO: Allow configuring edge vs level triggering.
+///
+/// A level-triggered IRQEvent instead consumes all the pending IRQs at once.
+/// Its IRQ line gets masked every time the IRQ is triggered, and stays masked
+/// until the driver calls [IRQEvent::acknowledge], after having cleared the
+/// interrupt condition on its device. This prevents a level-triggered device
+/// from storming the CPU while the userspace bottom-half hasn't run yet. When
+/// several drivers share the line, it stays masked until all of them
+/// acknowledged the IRQ.
+///
+/// An MSI IRQEvent owns an interrupt vector allocated with
+/// [allocate_msi_event], which is freed when the event is dropped.
 #[derive(Debug)]
 pub struct IRQEvent {
     /// The global state of the IRQ this event is listening on.
     /// Contains the IRQ trigger count.
     state: &'static IRQState,
+    /// The IRQ line this event is listening on.
+    irq: u8,
     /// Acknowledgement counter for this IRQEvent instance. Each time we get
     /// signaled, this counter is incremented until it matches the counter in
     /// state.
     ack: AtomicUsize,
+    /// Value of the counter in state when the driver last called
+    /// [IRQEvent::acknowledge]. A level-triggered event owes an
+    /// acknowledgement while it is behind.
+    acked: AtomicUsize,
+    /// How the IRQ this event is listening on gets delivered.
+    kind: IRQKind,
+}
+
+/// How the IRQ of an [IRQEvent] gets delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IRQKind {
+    /// Rising-edge triggered IOAPIC line.
+    Edge,
+    /// Level-triggered IOAPIC line, masked until acknowledged.
+    Level,
+    /// Message Signaled Interrupt, on a vector owned by the event.
+    Msi,
+}
+
+impl IRQEvent {
+    /// Tells the IRQ line of a level-triggered event may be unmasked, after
+    /// the driver handled the interrupt. The line is unmasked once all the
+    /// level-triggered events listening on it acknowledged the last IRQ.
+    ///
+    /// Does nothing if the event already acknowledged the last IRQ.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidState`
+    ///   - The event is not level-triggered.
+    pub fn acknowledge(&self) -> Result<(), KernelError> {
+        if self.kind != IRQKind::Level {
+            return Err(KernelError::InvalidState { backtrace: Backtrace::new() })
+        }
+        let mut level = self.state.level.lock();
+        if self.take_pending_ack() {
+            level.pending_acks -= 1;
+            if level.pending_acks == 0 {
+                crate::i386::interrupt::unmask(self.irq);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the last IRQ as acknowledged, returning whether it wasn't yet.
+    ///
+    /// Must be called with the level lock of the IRQ held, so the IRQ isn't
+    /// dispatched meanwhile.
+    fn take_pending_ack(&self) -> bool {
+        let counter = self.state.counter.load(Ordering::SeqCst);
+        self.acked.swap(counter, Ordering::SeqCst) < counter
+    }
 }
 
 impl Waitable for IRQEvent {
     fn is_signaled(&self) -> bool {
         self.ack.fetch_update(|x| {
-            if x < self.state.counter.load(Ordering::SeqCst) {
-                // TODO: If level-triggered, set this to the counter.
+            let counter = self.state.counter.load(Ordering::SeqCst);
+            if x < counter && self.kind == IRQKind::Level {
+                Some(counter)
+            } else if x < counter {
                 Some(x + 1)
             } else {
                 None
@@ -258,27 +338,105 @@ impl Waitable for IRQEvent {
     }
 }
 
+impl Drop for IRQEvent {
+    /// Stops masking the line on IRQ if this was the last level-triggered event
+    /// listening on it. Unmasks the line if this was the last event owing an
+    /// acknowledgement.
+    ///
+    /// Frees the vector of an MSI event.
+    fn drop(&mut self) {
+        match self.kind {
+            IRQKind::Edge => (),
+            IRQKind::Level => {
+                let mut level = self.state.level.lock();
+                level.listeners -= 1;
+                let owed_ack = self.take_pending_ack();
+                if owed_ack {
+                    level.pending_acks -= 1;
+                }
+                if (owed_ack && level.pending_acks == 0) || level.listeners == 0 {
+                    crate::i386::interrupt::unmask(self.irq);
+                }
+            },
+            IRQKind::Msi => crate::i386::interrupt::free_msi(self.irq),
+        }
+    }
+}
+
 /// Signal the scheduler and waiters that an IRQ has been triggered.
 ///
+/// If a level-triggered [IRQEvent] is listening on this IRQ, the line gets
+/// masked until it is acknowledged.
+///
 /// Usually, the IRQ handling code calls this. But it may be used to generate
 /// synthetic IRQs.
 pub fn dispatch_event(irq: usize) {
-    IRQ_STATES[irq].counter.fetch_add(1, Ordering::SeqCst);
+    {
+        let mut level = IRQ_STATES[irq].level.lock();
+        if level.listeners != 0 {
+            crate::i386::interrupt::mask(irq as u8);
+            level.pending_acks = level.listeners;
+        }
+        IRQ_STATES[irq].counter.fetch_add(1, Ordering::SeqCst);
+    }
     let mut processes = IRQ_STATES[irq].waiting_processes.lock();
     while let Some(process) = processes.pop() {
         scheduler::add_to_schedule_queue(process);
     }
 }
 
+/// Gets the number of times the given IRQ was triggered since boot.
+pub fn get_irq_count(irq: u8) -> usize {
+    IRQ_STATES[irq as usize].counter.load(Ordering::SeqCst)
+}
+
 /// Creates an IRQEvent waiting for the given IRQ number.
 pub fn wait_event(irq: u8) -> IRQEvent {
     debug!("Waiting for {}", irq);
     crate::i386::interrupt::unmask(irq);
+    let counter = IRQ_STATES[irq as usize].counter.load(Ordering::SeqCst);
     IRQEvent {
-        state: &IRQ_STATES[irq as usize], ack: AtomicUsize::new(IRQ_STATES[irq as usize].counter.load(Ordering::SeqCst))
+        state: &IRQ_STATES[irq as usize], irq, ack: AtomicUsize::new(counter), acked: AtomicUsize::new(counter),
+        kind: IRQKind::Edge,
     }
 }
 
+/// Creates a level-triggered IRQEvent waiting for the given IRQ number. See
+/// [IRQEvent].
+pub fn wait_level_event(irq: u8) -> IRQEvent {
+    debug!("Waiting for {} (level-triggered)", irq);
+    let mut level = IRQ_STATES[irq as usize].level.lock();
+    level.listeners += 1;
+    // Don't unmask the line while another driver owes an acknowledgement.
+    if level.pending_acks == 0 {
+        crate::i386::interrupt::unmask(irq);
+    }
+    let counter = IRQ_STATES[irq as usize].counter.load(Ordering::SeqCst);
+    IRQEvent {
+        state: &IRQ_STATES[irq as usize], irq, ack: AtomicUsize::new(counter), acked: AtomicUsize::new(counter),
+        kind: IRQKind::Level,
+    }
+}
+
+/// Allocates an interrupt vector for a Message Signaled Interrupt, and creates
+/// an IRQEvent waiting on it.
+///
+/// Returns the event, along with the address and data the device must write
+/// to trigger the interrupt. The vector is freed when the event is dropped.
+///
+/// Returns None if all the MSI vectors are in use.
+pub fn allocate_msi_event() -> Option<(IRQEvent, u32, u32)> {
+    let irq = crate::i386::interrupt::allocate_msi()?;
+    let (address, data) = crate::i386::interrupt::msi_address_data(irq);
+    debug!("Waiting for MSI {} (address {:#010x}, data {:#06x})", irq, address, data);
+    let counter = IRQ_STATES[irq as usize].counter.load(Ordering::SeqCst);
+    let event = IRQEvent {
+        state: &IRQ_STATES[irq as usize], irq, ack: AtomicUsize::new(counter), acked: AtomicUsize::new(counter),
+        kind: IRQKind::Msi,
+    };
+    Some((event, address, data))
+}
+
 /// Global state of an IRQ.
 ///
 /// Counts the number of times this IRQ was triggered from kernel boot.
@@ -290,7 +448,21 @@ struct IRQState {
     counter: AtomicUsize,
     /// List of processes waiting on this IRQ. When this IRQ is triggered, all
     /// those processes will be rescheduled.
-    waiting_processes: SpinLockIRQ<Vec<Arc<ThreadStruct>>>
+    waiting_processes: SpinLockIRQ<Vec<Arc<ThreadStruct>>>,
+    /// Level-triggered IRQEvents listening on this IRQ. Locked while the IRQ
+    /// is dispatched.
+    level: SpinLockIRQ<LevelState>,
+}
+
+/// State of the level-triggered IRQEvents listening on an IRQ.
+#[derive(Debug)]
+struct LevelState {
+    /// Number of level-triggered IRQEvents listening on the IRQ. While it is
+    /// not 0, the line gets masked every time the IRQ is triggered.
+    listeners: usize,
+    /// Number of them that have yet to acknowledge the last IRQ. The line is
+    /// unmasked once it gets back to 0.
+    pending_acks: usize,
 }
 
 impl IRQState {
@@ -300,16 +472,26 @@ impl IRQState {
         IRQState {
             irqnum,
             counter: AtomicUsize::new(0),
-            waiting_processes: SpinLockIRQ::new(Vec::new())
+            waiting_processes: SpinLockIRQ::new(Vec::new()),
+            level: SpinLockIRQ::new(LevelState { listeners: 0, pending_acks: 0 }),
         }
     }
 }
 
-/// Global state for all the IRQ handled by the IOAPIC.
-static IRQ_STATES: [IRQState; 17] = [
+/// Global state for all the IRQ handled by the IOAPIC, followed by the MSI
+/// vectors and the APIC timer.
+static IRQ_STATES: [IRQState; 34] = [
     IRQState::new(0x20), IRQState::new(0x21), IRQState::new(0x22), IRQState::new(0x23),
     IRQState::new(0x24), IRQState::new(0x25), IRQState::new(0x26), IRQState::new(0x27),
     IRQState::new(0x28), IRQState::new(0x29), IRQState::new(0x2A), IRQState::new(0x2B),
     IRQState::new(0x2C), IRQState::new(0x2D), IRQState::new(0x2E), IRQState::new(0x2F),
     IRQState::new(0x30),
+    // MSI vectors
+    IRQState::new(0x31), IRQState::new(0x32), IRQState::new(0x33),
+    IRQState::new(0x34), IRQState::new(0x35), IRQState::new(0x36), IRQState::new(0x37),
+    IRQState::new(0x38), IRQState::new(0x39), IRQState::new(0x3A), IRQState::new(0x3B),
+    IRQState::new(0x3C), IRQState::new(0x3D), IRQState::new(0x3E), IRQState::new(0x3F),
+    IRQState::new(0x40),
+    // APIC timer
+    IRQState::new(0x41),
 ];
@@ -81,6 +81,10 @@ pub struct FrameAllocatori386 {
     /// and it can be put in the bss by the compiler
     memory_bitmap: [u8; FRAMES_BITMAP_SIZE],
 
+    /// The number of frames marked usable by the bootloader, including the ones
+    /// reserved at init.
+    total_frames: usize,
+
     /// All operations have to check that the Allocator has been initialized
     initialized: bool
 }
@@ -101,6 +105,7 @@ impl FrameAllocatori386 {
         FrameAllocatori386 {
             // 0 is allocated/reserved
             memory_bitmap: [0x00; FRAMES_BITMAP_SIZE],
+            total_frames: 0,
             initialized: false
         }
     }
@@ -299,6 +304,28 @@ impl FrameAllocatorTrait for FrameAllocator {
         // collected_regions is dropped, marking them free again
         Err(KernelError::PhysicalMemoryExhaustion { backtrace: Backtrace::new() })
     }
+
+    /// Gets the total amount of usable physical memory, in bytes.
+    ///
+    /// # Panics
+    ///
+    /// * Panics if FRAME_ALLOCATOR was not initialized.
+    fn total_memory() -> usize {
+        let allocator = FRAME_ALLOCATOR.lock();
+        assert!(allocator.initialized, "The frame allocator was not initialized");
+        allocator.total_frames * PAGE_SIZE
+    }
+
+    /// Gets the amount of physical memory that is currently free, in bytes.
+    ///
+    /// # Panics
+    ///
+    /// * Panics if FRAME_ALLOCATOR was not initialized.
+    fn free_memory() -> usize {
+        let allocator = FRAME_ALLOCATOR.lock();
+        assert!(allocator.initialized, "The frame allocator was not initialized");
+        count_free_frames(&allocator.memory_bitmap) * PAGE_SIZE
+    }
 }
 
 /// Initialize the [FrameAllocator] by parsing the multiboot information
@@ -326,6 +353,8 @@ pub fn init(boot_info: &BootInformation) {
 
     }
 
+    allocator.total_frames = count_free_frames(&allocator.memory_bitmap);
+
     // Reserve everything mapped in KernelLand
     drop(allocator); // prevent deadlock
     get_kernel_memory().reserve_kernel_land_frames();
@@ -382,6 +411,11 @@ pub fn init(boot_info: &BootInformation) {
 #[cfg(test)]
 pub use self::test::init;
 
+/// Counts the frames marked free in the bitmap.
+fn count_free_frames(bitmap: &[u8]) -> usize {
+    bitmap.iter().map(|byte| byte.count_ones() as usize).sum()
+}
+
 /// Marks a physical memory area as reserved and will never give it when requesting a frame.
 /// This is used to mark where memory holes are, or where the kernel was mapped
 ///
@@ -449,6 +483,8 @@ mod test {
         // make it all available
         mark_area_free(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
 
+        allocator.total_frames = count_free_frames(&allocator.memory_bitmap);
+
         // reserve one frame, in the middle, just for fun
         mark_area_reserved(&mut allocator.memory_bitmap, PAGE_SIZE * 3, PAGE_SIZE * 3 + 1);
 
@@ -513,6 +549,21 @@ mod test {
         FrameAllocator::allocate_frames_fragmented(0).unwrap_err();
     }
 
+    /// Allocating and freeing frames is reflected in the memory stats.
+    #[test]
+    fn memory_stats() {
+        let _f = crate::frame_allocator::init();
+        assert_eq!(FrameAllocator::total_memory(), ALL_MEMORY);
+        // init reserves one frame.
+        assert_eq!(FrameAllocator::free_memory(), ALL_MEMORY - PAGE_SIZE);
+
+        let frames = FrameAllocator::allocate_frames_fragmented(3 * PAGE_SIZE).unwrap();
+        assert_eq!(FrameAllocator::free_memory(), ALL_MEMORY - 4 * PAGE_SIZE);
+
+        drop(frames);
+        assert_eq!(FrameAllocator::free_memory(), ALL_MEMORY - PAGE_SIZE);
+    }
+
     #[test] #[should_panic] fn no_init_frame() { let _ = FrameAllocator::allocate_frame(); }
     #[test] #[should_panic] fn no_init_region() { let _ = FrameAllocator::allocate_region(PAGE_SIZE); }
     #[test] #[should_panic] fn no_init_fragmented() { let _ = FrameAllocator::allocate_frames_fragmented(PAGE_SIZE); }
@@ -26,6 +26,12 @@ pub trait FrameAllocatorTrait: FrameAllocatorTraitPrivate {
     fn allocate_frame() -> Result<PhysicalMemRegion, KernelError> {
         Self::allocate_region(PAGE_SIZE)
     }
+
+    /// Gets the total amount of usable physical memory, in bytes.
+    fn total_memory() -> usize;
+
+    /// Gets the amount of physical memory that is currently free, in bytes.
+    fn free_memory() -> usize;
 }
 
 use self::private::FrameAllocatorTraitPrivate;
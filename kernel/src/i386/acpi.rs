@@ -1,6 +1,10 @@
 //! ACPI detection
 //!
 //! This module is in charge of detecting the presence of ACPI and provide other part of the kernel with the data about the system.
+//!
+//! Most tables are parsed by the acpi crate. The power management information
+//! of the FADT, and the sleep types of the `\_S5` package of the DSDT, are
+//! parsed here, and used by [power](super::power) to turn off the system.
 
 #![allow(dead_code)]
 
@@ -20,11 +24,73 @@ use crate::frame_allocator::PhysicalMemRegion;
 
 use crate::utils;
 
+use alloc::vec::Vec;
+
 use super::multiboot;
 
 /// Stores the ACPI data
 static ACPI_INFO: Once<Acpi> = Once::new();
 
+/// Stores the power management information of the FADT.
+static FADT_INFO: Once<FadtInfo> = Once::new();
+
+/// Size of the header common to all the ACPI tables.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// Flag of the FADT telling the reset register is supported.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+/// Address space of a [GenericAddress] in the system IO space.
+pub const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+
+/// An ACPI Generic Address Structure, describing the location of a register.
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    /// The address space the register is in, e.g. [ADDRESS_SPACE_SYSTEM_IO].
+    pub address_space: u8,
+    /// The size of the register in bits.
+    pub bit_width: u8,
+    /// The offset of the register at the address, in bits.
+    pub bit_offset: u8,
+    /// The access size, 1 for bytes, 2 for words, etc.
+    pub access_size: u8,
+    /// The address of the register in its address space.
+    pub address: u64,
+}
+
+/// The power management information of the FADT, along with the sleep types
+/// of the soft off state.
+///
+/// IO ports are 0 when the block is not implemented.
+#[derive(Debug)]
+pub struct FadtInfo {
+    /// The IRQ line of the System Control Interrupt.
+    pub sci_irq: u8,
+    /// The port to write [FadtInfo::acpi_enable] to, to enable ACPI mode.
+    pub smi_command_port: u16,
+    /// The value to write to the SMI command port to enable ACPI mode.
+    pub acpi_enable: u8,
+    /// The port of the PM1a event block.
+    pub pm1a_event_block: u16,
+    /// The port of the PM1b event block.
+    pub pm1b_event_block: u16,
+    /// The size of the PM1 event blocks. The first half holds the status
+    /// register, and the second half the enable register.
+    pub pm1_event_length: u8,
+    /// The port of the PM1a control block.
+    pub pm1a_control_block: u16,
+    /// The port of the PM1b control block.
+    pub pm1b_control_block: u16,
+    /// The register to write [FadtInfo::reset_value] to, to reset the system.
+    /// None if the firmware does not support it.
+    pub reset_register: Option<GenericAddress>,
+    /// The value to write to the reset register.
+    pub reset_value: u8,
+    /// The `SLP_TYPa` and `SLP_TYPb` values of the `\_S5` package, used to
+    /// put the system in the soft off state. None if it wasn't found.
+    pub s5_sleep_types: Option<(u8, u8)>,
+}
+
 
 /// Get a reference to the ACPI information.
 ///
@@ -42,6 +108,13 @@ pub fn try_get_acpi_information() -> Option<&'static Acpi> {
     ACPI_INFO.r#try()
 }
 
+/// Tries to get the power management information of the FADT.
+///
+/// Returns `None` if the module hasn't been inited yet, if ACPI isn't availaible, or if the system has no FADT.
+pub fn try_get_fadt_information() -> Option<&'static FadtInfo> {
+    FADT_INFO.r#try()
+}
+
 /// ACPI Memory handler
 struct MemoryHandler;
 
@@ -83,16 +156,189 @@ unsafe fn parse_rsdp_tag(memory_handler: &mut MemoryHandler, rsdp_virtual_addres
         _ => panic!("RSDP VIRTUAL MAPPING DOESN'T MAP TO ANYTHING???")
     };
 
+    parse_rsdp(memory_handler, rsdp_physical_address)
+}
+
+/// Parse the tables pointed by the RSDP at the given physical address.
+unsafe fn parse_rsdp(memory_handler: &mut MemoryHandler, rsdp_physical_address: usize) -> bool {
     if let Ok(acpi) = acpi::parse_rsdp(memory_handler, rsdp_physical_address) {
         ACPI_INFO.call_once(|| {
             acpi
         });
+        if let Some(fadt) = find_table(rsdp_physical_address, b"FACP").map(parse_fadt) {
+            FADT_INFO.call_once(|| {
+                fadt
+            });
+        }
         true
     } else {
         false
     }
 }
 
+/// Reads a little-endian u16 at `offset` in `bytes`.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from(bytes[offset]) | u16::from(bytes[offset + 1]) << 8
+}
+
+/// Reads a little-endian u32 at `offset` in `bytes`.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(bytes, offset)) | u32::from(read_u16(bytes, offset + 2)) << 16
+}
+
+/// Maps `size` bytes of physical memory at `address`, and passes them to `f`.
+fn with_physical_memory<R>(address: usize, size: usize, f: impl FnOnce(&[u8]) -> R) -> R {
+    let mut handler = MemoryHandler;
+    let mapping = handler.map_physical_region::<u8>(address, size);
+    let result = f(unsafe {
+        // Safety: The region was just mapped, and we only read it.
+        core::slice::from_raw_parts(mapping.virtual_start.as_ptr(), size)
+    });
+    handler.unmap_physical_region(mapping);
+    result
+}
+
+/// Maps the ACPI table at `address`, and passes its bytes, header included,
+/// to `f`.
+fn with_table<R>(address: usize, f: impl FnOnce(&[u8]) -> R) -> R {
+    let length = with_physical_memory(address, SDT_HEADER_SIZE, |header| read_u32(header, 4)) as usize;
+    with_physical_memory(address, core::cmp::max(length, SDT_HEADER_SIZE), f)
+}
+
+/// Finds the table with the given signature in the RSDT, and returns its
+/// physical address.
+///
+/// As a 32-bit kernel, we always use the RSDT, whose entries are 32-bit
+/// addresses, even when an XSDT is present.
+fn find_table(rsdp_physical_address: usize, signature: &[u8; 4]) -> Option<usize> {
+    let rsdt_address = with_physical_memory(rsdp_physical_address, 20, |rsdp| read_u32(rsdp, 16)) as usize;
+    let entries: Vec<usize> = with_table(rsdt_address, |rsdt| {
+        rsdt[SDT_HEADER_SIZE..].chunks_exact(4).map(|entry| read_u32(entry, 0) as usize).collect()
+    });
+    entries.into_iter()
+        .find(|&address| with_physical_memory(address, 4, |table_signature| table_signature == signature))
+}
+
+/// Parses the power management information of the FADT at `fadt_address`,
+/// and looks for the `\_S5` package in the DSDT it points to.
+fn parse_fadt(fadt_address: usize) -> FadtInfo {
+    let (fadt, dsdt_address) = with_table(fadt_address, |fadt| {
+        let revision = fadt[8];
+        let flags = read_u32(fadt, 112);
+        let reset_register = if revision >= 2 && fadt.len() >= 129 && flags & FADT_RESET_REG_SUP != 0 {
+            Some(GenericAddress {
+                address_space: fadt[116],
+                bit_width: fadt[117],
+                bit_offset: fadt[118],
+                access_size: fadt[119],
+                address: u64::from(read_u32(fadt, 120)) | u64::from(read_u32(fadt, 124)) << 32,
+            })
+        } else {
+            None
+        };
+
+        let info = FadtInfo {
+            sci_irq: read_u16(fadt, 46) as u8,
+            smi_command_port: read_u32(fadt, 48) as u16,
+            acpi_enable: fadt[52],
+            pm1a_event_block: read_u32(fadt, 56) as u16,
+            pm1b_event_block: read_u32(fadt, 60) as u16,
+            pm1_event_length: fadt[88],
+            pm1a_control_block: read_u32(fadt, 64) as u16,
+            pm1b_control_block: read_u32(fadt, 68) as u16,
+            reset_register,
+            reset_value: if reset_register.is_some() { fadt[128] } else { 0 },
+            s5_sleep_types: None,
+        };
+        (info, read_u32(fadt, 40) as usize)
+    });
+
+    let s5_sleep_types = if dsdt_address != 0 {
+        with_table(dsdt_address, |dsdt| find_s5_sleep_types(&dsdt[SDT_HEADER_SIZE..]))
+    } else {
+        None
+    };
+    if s5_sleep_types.is_none() {
+        info!("No \\_S5 package in the DSDT, powering off will not be supported");
+    }
+
+    FadtInfo { s5_sleep_types, ..fadt }
+}
+
+/// Finds the `\_S5` package in the AML of the DSDT, and returns its `SLP_TYPa`
+/// and `SLP_TYPb` values.
+///
+/// We don't have an AML interpreter. Instead, this looks for the bytes of a
+/// `Name(_S5, Package() { ... })` and decodes its first two integers, which is
+/// how every firmware we know of declares it.
+fn find_s5_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    /// NameOp.
+    const NAME_OP: u8 = 0x08;
+    /// PackageOp.
+    const PACKAGE_OP: u8 = 0x12;
+    /// BytePrefix, followed by a byte integer.
+    const BYTE_PREFIX: u8 = 0x0A;
+    /// ZeroOp.
+    const ZERO_OP: u8 = 0x00;
+    /// OneOp.
+    const ONE_OP: u8 = 0x01;
+
+    let parse_package = |pos: usize| -> Option<(u8, u8)> {
+        // The name is either declared relative to its scope, or from the root.
+        let is_name = (pos >= 1 && aml[pos - 1] == NAME_OP)
+            || (pos >= 2 && aml[pos - 2] == NAME_OP && aml[pos - 1] == b'\\');
+        if !is_name {
+            return None;
+        }
+
+        let mut bytes = aml[pos + 4..].iter().cloned();
+        if bytes.next()? != PACKAGE_OP {
+            return None;
+        }
+        // The two high bits of the PkgLength lead byte tell how many bytes follow.
+        let pkg_length_lead = bytes.next()?;
+        for _ in 0..pkg_length_lead >> 6 {
+            bytes.next()?;
+        }
+        let _num_elements = bytes.next()?;
+
+        let mut integer = || match bytes.next()? {
+            BYTE_PREFIX => bytes.next(),
+            ZERO_OP => Some(0),
+            ONE_OP => Some(1),
+            _ => None
+        };
+        Some((integer()?, integer()?))
+    };
+
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| name == b"_S5_")
+        .find_map(|(pos, _)| parse_package(pos))
+}
+
+/// Looks for the RSDP in the first KiB of the EBDA, and then in the BIOS
+/// read-only memory area. Returns its physical address.
+fn search_for_rsdp_bios() -> Option<usize> {
+    /// Tells if `bytes` starts with a valid RSDP.
+    fn is_rsdp(bytes: &[u8]) -> bool {
+        bytes.len() >= 20 && &bytes[..8] == b"RSD PTR "
+            && bytes[..20].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+    }
+
+    // The BDA holds the segment of the EBDA.
+    let ebda_address = with_physical_memory(0x40E, 2, |bda| read_u16(bda, 0)) as usize * 16;
+    let areas = [(ebda_address, 0x400), (0xE0000, 0x20000)];
+
+    areas.iter()
+        .filter(|(start, _)| *start != 0)
+        .find_map(|&(start, length)| with_physical_memory(start, length, |area| {
+            (0..length).step_by(16)
+                .find(|&offset| is_rsdp(&area[offset..]))
+                .map(|offset| start + offset)
+        }))
+}
+
 /// Parse ACPI tables and store them.
 pub unsafe fn init() {
     let mut handler = MemoryHandler;
@@ -114,14 +360,10 @@ pub unsafe fn init() {
         }
     }
     if !is_init {
-        if let Ok(acpi) = acpi::search_for_rsdp_bios(&mut handler) {
-            info!("Found RSDP inside BIOS memory");
-
-            ACPI_INFO.call_once(|| {
-                acpi
-            });
+        if let Some(rsdp_physical_address) = search_for_rsdp_bios() {
+            info!("Found RSDP inside BIOS memory at address {:x}", rsdp_physical_address);
 
-            is_init = true;
+            is_init = parse_rsdp(&mut handler, rsdp_physical_address);
         }
     }
 
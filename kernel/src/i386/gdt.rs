@@ -77,7 +77,7 @@
 use crate::sync::{SpinLockIRQ, Once};
 use bit_field::BitField;
 use core::mem::size_of;
-use core::ops::{Deref, DerefMut};
+use core::ops::{Deref, DerefMut, Range};
 use core::fmt;
 
 use crate::i386::{PrivilegeLevel, TssStruct};
@@ -86,6 +86,7 @@ use crate::i386::instructions::tables::{lgdt, lldt, ltr, DescriptorTablePointer}
 use crate::i386::instructions::segmentation::*;
 
 use crate::paging::PAGE_SIZE;
+use crate::process::IoBitmap;
 use sunrise_libkern::TLS;
 use crate::sync::SpinLock;
 use bitfield::fmt::Debug;
@@ -380,7 +381,12 @@ pub struct MainTask {
     ///
     /// * `0`: this port is addressable.
     /// * `1`: this port is not addressable.
-    pub iopb: [u8; 0x2001]
+    pub iopb: [u8; 0x2001],
+    /// Pid of the process whose IO bitmap is loaded in the `iopb`.
+    iopb_owner: Option<usize>,
+    /// Bytes of the `iopb` where the IO bitmap of `iopb_owner` was copied. All
+    /// the other bytes are 0xFF.
+    iopb_loaded: Range<usize>,
 }
 
 impl Debug for MainTask {
@@ -388,6 +394,7 @@ impl Debug for MainTask {
         f.debug_struct("MainTask")
             .field("tss", &self.tss)
             .field("iopb", &"*omitted*")
+            .field("iopb_owner", &self.iopb_owner)
             .finish()
     }
 }
@@ -405,7 +412,9 @@ impl MainTask {
     const fn empty() -> MainTask {
         MainTask {
             tss: TssStruct::empty(),
-            iopb: [0u8; 0x2001]
+            iopb: [0u8; 0x2001],
+            iopb_owner: None,
+            iopb_loaded: 0..0,
         }
     }
 
@@ -418,6 +427,24 @@ impl MainTask {
         self.tss.init();
         for v in &mut self.iopb[..] { *v = 0xFF }
     }
+
+    /// Loads the IO bitmap of the process `pid` in the IOPB, revoking the
+    /// ports of the process previously loaded.
+    ///
+    /// Does nothing if it is already loaded, so the IOPB is only touched when
+    /// switching to a thread of another process.
+    pub fn load_io_bitmap(&mut self, pid: usize, bitmap: &IoBitmap) {
+        if self.iopb_owner == Some(pid) {
+            return;
+        }
+
+        for v in &mut self.iopb[self.iopb_loaded.clone()] { *v = 0xFF }
+
+        let loaded = bitmap.offset()..bitmap.offset() + bitmap.bytes().len();
+        self.iopb[loaded.clone()].copy_from_slice(bitmap.bytes());
+        self.iopb_loaded = loaded;
+        self.iopb_owner = Some(pid);
+    }
 }
 
 /// Main TSS
@@ -437,7 +464,8 @@ impl MainTask {
 /// * `1`: this port is not addressable.
 ///
 /// This array is checked by the cpu every time a port is accessed by userspace, and we use it
-/// to enforce io-space policies. This array is updated at every task switch.
+/// to enforce io-space policies. The [IoBitmap] of a process is loaded in it when switching to
+/// one of its threads from a thread of another process, see [MainTask::load_io_bitmap].
 ///
 /// The kernel bypasses this protection by having the `IOPL` set to `0b00` in `EFLAGS`,
 /// making the kernel able to access all ports at all times.
@@ -2,13 +2,13 @@
 //!
 //! This file contains the arch-generic implementation details of interrupt
 //! handling. It contains the interrupt initialization routine, and routines to
-//! unmask and acknowledge interrupts.
+//! mask, unmask and acknowledge interrupts.
 
 use crate::devices::pic;
 use crate::devices::lapic::LocalApic;
 use crate::devices::ioapic::IoApic;
 use acpi::interrupt::{InterruptModel, InterruptSourceOverride};
-use crate::sync::Once;
+use crate::sync::{Once, SpinLock};
 use alloc::vec::Vec;
 use crate::mem::PhysicalAddress;
 
@@ -25,6 +25,21 @@ struct InterruptHandler {
 /// Global state for the interrupt handler.
 static INTERRUPT_HANDLER: Once<InterruptHandler> = Once::new();
 
+/// First IRQ number used for Message Signaled Interrupts. IRQs below are
+/// IOAPIC lines.
+pub const MSI_IRQ_BASE: u8 = 17;
+
+/// Number of IRQs available for Message Signaled Interrupts.
+pub const MSI_IRQ_COUNT: u8 = 16;
+
+/// IRQ raised by the APIC timer of the root CPU, when it is used as the kernel
+/// timer. Comes right after the MSI vectors.
+pub const LAPIC_TIMER_IRQ: u8 = MSI_IRQ_BASE + MSI_IRQ_COUNT;
+
+/// Bitmap of the MSI IRQs currently in use. Bit `n` represents IRQ
+/// `MSI_IRQ_BASE + n`.
+static MSI_ALLOCATED: SpinLock<u16> = SpinLock::new(0);
+
 /// Initialize the interrupt handler.
 pub fn init() {
     // Always initialize the pic to redirect entries (otherwise, we might
@@ -110,24 +125,95 @@ pub fn acknowledge(_irq: u8) {
 ///
 /// Panics if called before calling `init`.
 pub fn unmask(irq: u8) {
+    set_mask(irq, false)
+}
+
+/// Masks the given IRQ. Used to keep a level-triggered IRQ from firing again
+/// until its driver acknowledges it.
+///
+/// # Panic
+///
+/// Panics if called before calling `init`.
+pub fn mask(irq: u8) {
+    set_mask(irq, true)
+}
+
+/// Masks or unmasks the given IRQ in the IOAPIC handling it.
+///
+/// # Panic
+///
+/// Panics if called before calling `init`.
+fn set_mask(irq: u8, masked: bool) {
     let ioapics = &INTERRUPT_HANDLER.r#try().unwrap().ioapics;
 
     // First, find the "real" IRQ number:
     let irqisa = irq;
     let irq = isa_to_ioapic_irq(irq);
 
-    debug!("Unmasking IRQ {} (ISA {})", irq, irqisa);
+    debug!("{} IRQ {} (ISA {})", if masked { "Masking" } else { "Unmasking" }, irq, irqisa);
 
-    // Then, unmask it.
+    // Then, (un)mask it.
     let ioapic = ioapics.iter().find(|ioapic|
                                      ioapic.interrupt_base() <= irq &&
                                      irq < ioapic.interrupt_base() + ioapic.redirection_entry_count()).unwrap();
 
     let mut redirection_entry = ioapic.redirection_entry((irq - ioapic.interrupt_base()) as u8);
-    redirection_entry.set_interrupt_mask(false);
+    redirection_entry.set_interrupt_mask(masked);
     ioapic.set_redirection_entry((irq - ioapic.interrupt_base()) as u8, redirection_entry);
 }
 
+/// Puts the APIC timer of the root CPU in TSC-Deadline mode, raising
+/// [LAPIC_TIMER_IRQ].
+///
+/// # Panic
+///
+/// Panics if called before calling `init`.
+pub fn enable_tsc_deadline_timer() {
+    INTERRUPT_HANDLER.r#try().unwrap().root_lapic.enable_tsc_deadline_timer(0x20 + LAPIC_TIMER_IRQ);
+}
+
+/// Allocates an IRQ for a Message Signaled Interrupt. Returns None if they are
+/// all in use.
+pub fn allocate_msi() -> Option<u8> {
+    let mut allocated = MSI_ALLOCATED.lock();
+    let idx = (!*allocated).trailing_zeros() as u8;
+    if idx >= MSI_IRQ_COUNT {
+        return None;
+    }
+    *allocated |= 1 << idx;
+    Some(MSI_IRQ_BASE + idx)
+}
+
+/// Frees an IRQ allocated with [allocate_msi].
+///
+/// # Panic
+///
+/// Panics if the IRQ is not an allocated MSI.
+pub fn free_msi(irq: u8) {
+    assert!(MSI_IRQ_BASE <= irq && irq < MSI_IRQ_BASE + MSI_IRQ_COUNT, "IRQ {} is not an MSI", irq);
+    let mut allocated = MSI_ALLOCATED.lock();
+    let bit = 1 << (irq - MSI_IRQ_BASE);
+    assert!(*allocated & bit != 0, "MSI {} was not allocated", irq);
+    *allocated &= !bit;
+}
+
+/// Gets the address and data a device must write to trigger the given MSI.
+///
+/// The message targets the root CPU's Local APIC, in fixed delivery mode, and
+/// is edge-triggered.
+///
+/// # Panic
+///
+/// Panics if called before calling `init`.
+pub fn msi_address_data(irq: u8) -> (u32, u32) {
+    let lapic = &INTERRUPT_HANDLER.r#try().unwrap().root_lapic;
+    // The APIC ID lives in the top byte of the register.
+    let apic_id = lapic.local_apic_id() >> 24;
+    let address = 0xFEE0_0000 | (apic_id << 12);
+    let data = 0x20 + u32::from(irq);
+    (address, data)
+}
+
 /// Gets the IOAPIC pin associated with an ISA (i8259) IRQ.
 ///
 /// # Panic
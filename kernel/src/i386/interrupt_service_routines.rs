@@ -10,7 +10,9 @@
 //!
 //! # Exceptions
 //!
-//! All exceptions are considered unrecoverable errors, and kill the process that issued it.
+//! Exceptions caused by userspace are forwarded to the exception handler the process registered
+//! with [set_exception_handler], if any. Otherwise, they are considered unrecoverable errors, and
+//! crash the process that issued it. See [user_exception].
 //!
 //! Feature `panic-on-exception` makes the kernel stop and panic when a thread generates
 //! an exception. This is useful for debugging.
@@ -33,7 +35,7 @@ use crate::mem::VirtualAddress;
 use crate::paging::kernel_memory::get_kernel_memory;
 use crate::i386::PrivilegeLevel;
 use crate::scheduler::{get_current_thread, get_current_process};
-use crate::process::{ProcessStruct, ThreadState};
+use crate::process::{ProcessStruct, ThreadStruct, ThreadState};
 use crate::sync::{SpinLock, SpinLockIRQ};
 use core::sync::atomic::{AtomicU8, Ordering};
 
@@ -47,7 +49,10 @@ use crate::mem::{UserSpacePtr, UserSpacePtrMut};
 use crate::error::UserspaceError;
 use crate::syscalls::*;
 use bit_field::BitArray;
-use sunrise_libkern::{nr, SYSCALL_NAMES};
+use sunrise_libkern::{nr, SYSCALL_NAMES, MemoryState, MemoryPermissions, MemoryAttributes};
+use sunrise_libkern::process::{ExceptionType, ExceptionInfo, CrashReport, ThreadContext};
+use sunrise_libkern::trace::TraceEventKind;
+use core::mem::size_of;
 
 /// Contains the number of interrupts we are currently inside.
 ///
@@ -63,6 +68,7 @@ pub static INSIDE_INTERRUPT_COUNT: AtomicU8 = AtomicU8::new(0);
 /// caller must make sure all of its scope variables are ok to be leaked.
 pub fn check_thread_killed() {
     if scheduler::get_current_thread().state.load(Ordering::SeqCst) == ThreadState::TerminationPending {
+        crate::trace::record(TraceEventKind::ThreadExit, 0, 0);
         let lock = SpinLockIRQ::new(());
         loop { // in case of spurious wakeups
             let _ = scheduler::unschedule(&lock, lock.lock());
@@ -70,6 +76,18 @@ pub fn check_thread_killed() {
     }
 }
 
+/// Checks if our thread was suspended by userspace, in which case park
+/// ourselves until we get resumed.
+///
+/// Must be called before [check_thread_killed], as a suspended thread can still
+/// get killed.
+pub fn check_thread_suspended() {
+    let thread = scheduler::get_current_thread();
+    if thread.is_suspended() {
+        ThreadStruct::wait_while_suspended(&thread);
+    }
+}
+
 /// Represents a register backup.
 ///
 /// The exception wrapper constructs this structure before calling the exception handler,
@@ -115,6 +133,67 @@ impl core::fmt::Display for UserspaceHardwareContext {
     }
 }
 
+impl UserspaceHardwareContext {
+    /// Gets the registers userspace can see, as exposed to it by the
+    /// debugging and exception SVCs.
+    pub fn thread_context(&self) -> ThreadContext {
+        ThreadContext {
+            eip: self.eip,
+            esp: self.esp,
+            ebp: self.ebp,
+            eax: self.eax,
+            ebx: self.ebx,
+            ecx: self.ecx,
+            edx: self.edx,
+            esi: self.esi,
+            edi: self.edi,
+            eflags: self.eflags,
+        }
+    }
+
+    /// Overwrites the registers with the ones of `context`, which userspace
+    /// could have modified.
+    ///
+    /// Only the arithmetic flags and the direction flag are taken from
+    /// `context.eflags`, userspace is not allowed to change the others.
+    ///
+    /// # Safety
+    ///
+    /// `self` must be the context pushed by [trap_gate_asm] for an interrupt
+    /// that came from userspace, see [UserspaceHardwareContext::set_userspace_esp].
+    pub unsafe fn set_thread_context(&mut self, context: &ThreadContext) {
+        let user_flags = EFlags::CARRY_FLAG | EFlags::PARITY_FLAG | EFlags::AUXILIARY_CARRY_FLAG
+            | EFlags::ZERO_FLAG | EFlags::SIGN_FLAG | EFlags::DIRECTION_FLAG | EFlags::OVERFLOW_FLAG;
+        let user_flags = user_flags.bits() as usize;
+
+        self.eip = context.eip;
+        self.ebp = context.ebp;
+        self.eax = context.eax;
+        self.ebx = context.ebx;
+        self.ecx = context.ecx;
+        self.edx = context.edx;
+        self.esi = context.esi;
+        self.edi = context.edi;
+        self.eflags = (self.eflags & !user_flags) | (context.eflags & user_flags);
+        self.set_userspace_esp(context.esp);
+    }
+
+    /// Changes the esp userspace will resume with.
+    ///
+    /// `.esp` is not reloaded by [trap_gate_asm], so this writes the esp that
+    /// was pushed by the cpu, right after `.eflags`.
+    ///
+    /// # Safety
+    ///
+    /// `self` must be the context pushed by [trap_gate_asm] for an interrupt
+    /// that came from userspace. Only in this case did the cpu push an esp.
+    pub unsafe fn set_userspace_esp(&mut self, esp: usize) {
+        self.esp = esp;
+        let cpu_pushed_esp = (&mut self.eflags as *mut usize).offset(1);
+        *cpu_pushed_esp = esp;
+    }
+}
+
 // gonna write constants in the code, cause not enough registers.
 // just check we aren't hard-coding the wrong values.
 const_assert_eq!((GdtIndex::KTls as u16) << 3 | 0b00, 0x18);
@@ -407,8 +486,9 @@ macro_rules! trap_gate_asm {
 ///         ProcessStruct::kill_current_process();                                   //
 ///     }
 ///
-///     // if we're returning to userspace, check we haven't been killed
+///     // if we're returning to userspace, check we haven't been suspended or killed
 ///     if comming from Ring == 3 {
+///         check_thread_suspended();
 ///         check_thread_killed();
 ///     }
 /// }
@@ -594,8 +674,9 @@ macro_rules! generate_trap_gate_handler {
                 let _ = INSIDE_INTERRUPT_COUNT.fetch_sub(1, Ordering::SeqCst);
             }
 
-            // if we're returning to userspace, check we haven't been killed
+            // if we're returning to userspace, check we haven't been suspended or killed
             if let PrivilegeLevel::Ring3 = SegmentSelector(userspace_context.cs as u16).rpl() {
+                check_thread_suspended();
                 check_thread_killed();
             }
         }
@@ -612,7 +693,7 @@ generate_trap_gate_handler!(name: "Divide Error Exception",
                 wrapper_rust_fnname: divide_by_zero_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: divide_error_handler
 );
 
 generate_trap_gate_handler!(name: "Debug Exception",
@@ -648,7 +729,7 @@ generate_trap_gate_handler!(name: "Overflow Exception",
                 wrapper_rust_fnname: overflow_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: overflow_handler
 );
 
 generate_trap_gate_handler!(name: "BOUND Range Exceeded Exception",
@@ -657,7 +738,7 @@ generate_trap_gate_handler!(name: "BOUND Range Exceeded Exception",
                 wrapper_rust_fnname: bound_range_exceeded_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: bound_range_exceeded_handler
 );
 
 generate_trap_gate_handler!(name: "Invalid opcode Exception",
@@ -666,7 +747,7 @@ generate_trap_gate_handler!(name: "Invalid opcode Exception",
                 wrapper_rust_fnname: invalid_opcode_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: invalid_opcode_handler
 );
 
 generate_trap_gate_handler!(name: "Device Not Available Exception",
@@ -675,7 +756,7 @@ generate_trap_gate_handler!(name: "Device Not Available Exception",
                 wrapper_rust_fnname: device_not_available_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: device_not_available_handler
 );
 
 /// Double fault handler. Panics the kernel unconditionally.
@@ -700,7 +781,7 @@ generate_trap_gate_handler!(name: "Segment Not Present Exception",
                 wrapper_rust_fnname: segment_not_present_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: segment_not_present_handler
 );
 
 generate_trap_gate_handler!(name: "Stack Fault Exception",
@@ -709,7 +790,7 @@ generate_trap_gate_handler!(name: "Stack Fault Exception",
                 wrapper_rust_fnname: stack_fault_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: stack_fault_handler
 );
 
 generate_trap_gate_handler!(name: "General Protection Fault Exception",
@@ -718,7 +799,7 @@ generate_trap_gate_handler!(name: "General Protection Fault Exception",
                 wrapper_rust_fnname: general_protection_fault_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: general_protection_fault_handler
 );
 
 generate_trap_gate_handler!(name: "Page Fault Exception",
@@ -756,14 +837,183 @@ fn user_page_fault_panic(_exception_name: &'static str, hwcontext: &mut Userspac
     });
 }
 
-/// Overriding the default kill strategy so we can display cr2
-fn user_page_fault_handler(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+/// Overriding the default user exception strategy so we can report cr2
+fn user_page_fault_handler(exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
     let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
     let cause_address = crate::paging::read_cr2();
 
+    let is_stack_overflow = {
+        let thread = get_current_thread();
+        let is_stack_overflow = thread.process.pmemory.lock().is_stack_guard(cause_address);
+        if is_stack_overflow {
+            error!("Thread {} of process {} overflowed its stack (accessed {:?})", thread.tid, thread.process.name, cause_address);
+        }
+        is_stack_overflow
+    };
+    info!("Page Fault accessing {:?}, exception errcode: {:?}", cause_address, errcode);
+    let exception_type = if is_stack_overflow { ExceptionType::StackOverflow } else { ExceptionType::PageFault };
+    user_exception(exception_name, exception_type, hwcontext.errcode, cause_address.addr(), hwcontext);
+}
+
+/// Overriding the default user exception strategy so we can report accesses to IO ports the
+/// capabilities don't allow as such.
+fn general_protection_fault_handler(exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+    // IOPB violations don't have an error code.
+    if hwcontext.errcode == 0 {
+        if let Some(port) = faulting_io_port(hwcontext) {
+            let process = get_current_process();
+            if !process.capabilities.ioports.is_allowed(port) {
+                error!("Process {} accessed IO port {:#06x}, which its capabilities don't allow", process.name, port);
+                drop(process);
+                user_exception(exception_name, ExceptionType::IoPortNotAllowed, usize::from(port), 0, hwcontext);
+                return;
+            }
+        }
+    }
+    user_exception(exception_name, ExceptionType::GeneralProtectionFault, hwcontext.errcode, 0, hwcontext);
+}
+
+/// Decodes the IO port accessed by the userspace instruction at eip, if it is an `in`, `out`,
+/// `ins` or `outs`.
+///
+/// Returns None for any other instruction, or if it cannot be read.
+fn faulting_io_port(hwcontext: &UserspaceHardwareContext) -> Option<u16> {
+    /// Maximum length of an x86 instruction.
+    const MAX_INSTRUCTION_LEN: usize = 15;
+
+    let process = get_current_process();
+    let read = |offset: usize| -> Option<u8> {
+        let addr = VirtualAddress(hwcontext.eip.checked_add(offset)?);
+        process.pmemory.lock().check_range(addr, 1,
+            MemoryState::empty(), MemoryState::empty(),
+            MemoryPermissions::READABLE, MemoryPermissions::READABLE,
+            MemoryAttributes::empty(), MemoryAttributes::empty(),
+            MemoryAttributes::empty()).ok()?;
+        // The byte was checked to be mapped readable in the current process.
+        Some(*UserSpacePtr(addr.addr() as *const u8))
+    };
+
+    // Skip the operand size, address size and rep prefixes.
+    let mut offset = 0;
+    let opcode = loop {
+        match read(offset)? {
+            0x66 | 0x67 | 0xF2 | 0xF3 if offset < MAX_INSTRUCTION_LEN => offset += 1,
+            opcode => break opcode
+        }
+    };
+
+    match opcode {
+        // in/out imm8
+        0xE4..=0xE7 => read(offset + 1).map(u16::from),
+        // ins/outs, in/out dx
+        0x6C..=0x6F | 0xEC..=0xEF => Some(hwcontext.edx as u16),
+        _ => None
+    }
+}
+
+/// Generates handler strategies forwarding an exception to [user_exception].
+macro_rules! user_exception_handlers {
+    ($($fnname:ident => $exception_type:ident,)*) => {
+        $(
+            /// Auto generated function. Forwards the exception to [user_exception].
+            fn $fnname(exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+                user_exception(exception_name, ExceptionType::$exception_type, hwcontext.errcode, 0, hwcontext);
+            }
+        )*
+    };
+}
+
+user_exception_handlers! {
+    divide_error_handler => DivideError,
+    overflow_handler => Overflow,
+    bound_range_exceeded_handler => BoundRangeExceeded,
+    invalid_opcode_handler => InvalidOpcode,
+    device_not_available_handler => DeviceNotAvailable,
+    segment_not_present_handler => SegmentNotPresent,
+    stack_fault_handler => StackFault,
+    x87_floating_point_handler => X87FloatingPoint,
+    alignment_check_handler => AlignmentCheck,
+    simd_floating_point_handler => SimdFloatingPoint,
+    virtualization_handler => Virtualization,
+}
+
+/// Handles an exception caused by userspace.
+///
+/// If the process registered an exception handler, the thread is redirected
+/// to it, see [enter_exception_handler]. Otherwise, the process crashes, and
+/// the exception is kept in its crash report.
+fn user_exception(exception_name: &'static str, exception_type: ExceptionType, error_code: usize, fault_address: usize, hwcontext: &mut UserspaceHardwareContext) {
+    let info = ExceptionInfo {
+        exception_type,
+        error_code,
+        fault_address,
+        context: hwcontext.thread_context(),
+    };
+
     let thread = get_current_thread();
-    error!("Page Fault accessing {:?}, exception errcode: {:?} in {:#?}", cause_address, errcode, thread);
-    ProcessStruct::kill_current_process();
+    if enter_exception_handler(&thread, &info, hwcontext) {
+        return;
+    }
+
+    error!("{}, errorcode: {}, in {:#?}", exception_name, error_code, thread);
+    let report = CrashReport {
+        tid: thread.tid,
+        exception: info,
+        ..CrashReport::default()
+    };
+    drop(thread);
+    ProcessStruct::crash_current_process(report);
+}
+
+/// Makes the current thread jump to the exception handler of its process.
+///
+/// Writes `info` on the handler stack, followed by a cdecl call frame with a
+/// pointer to it, and points eip and esp to the handler.
+///
+/// Returns false if the exception could not be forwarded: the process has no
+/// handler, a thread is already running it, or its stack is not mapped RW.
+fn enter_exception_handler(thread: &ThreadStruct, info: &ExceptionInfo, hwcontext: &mut UserspaceHardwareContext) -> bool {
+    // Claim the handler first, so another thread can't use its stack under us.
+    let (entrypoint, stack_top) = match &mut *thread.process.exception_handler.lock() {
+        Some(handler) if handler.current.is_none() => {
+            handler.current = Some((thread.tid, handler.stack_top, *info));
+            (handler.entrypoint, handler.stack_top)
+        },
+        _ => return false
+    };
+
+    let info_addr = VirtualAddress((stack_top.addr() - size_of::<ExceptionInfo>()) & !0xF);
+    // A cdecl frame: a null return address, and the argument. The argument is
+    // 16-byte aligned, as the System V i386 ABI expects at function entry.
+    let frame_addr = info_addr - 16 - size_of::<usize>();
+
+    let is_stack_mapped = thread.process.pmemory.lock().check_range(frame_addr, stack_top - frame_addr,
+        MemoryState::empty(), MemoryState::empty(),
+        MemoryPermissions::RW, MemoryPermissions::RW,
+        MemoryAttributes::empty(), MemoryAttributes::empty(),
+        MemoryAttributes::empty()).is_ok();
+
+    let mut handler_lock = thread.process.exception_handler.lock();
+    let handler = handler_lock.as_mut().expect("Exception handler unregistered while handling an exception");
+    if !is_stack_mapped {
+        warn!("Exception handler stack of process {} is not mapped RW", thread.process.name);
+        handler.current = None;
+        return false;
+    }
+
+    // The range was checked to be mapped RW in the current process.
+    *UserSpacePtrMut(info_addr.addr() as *mut ExceptionInfo) = *info;
+    let mut frame = UserSpacePtrMut::from_raw_parts_mut(frame_addr.addr() as *mut usize, 2);
+    frame[0] = 0;
+    frame[1] = info_addr.addr();
+
+    handler.current = Some((thread.tid, info_addr, *info));
+    hwcontext.eip = entrypoint.addr();
+    unsafe {
+        // Safety: we're handling an exception that came from userspace.
+        hwcontext.set_userspace_esp(frame_addr.addr());
+    }
+    true
 }
 
 generate_trap_gate_handler!(name: "x87 FPU floating-point error",
@@ -772,7 +1022,7 @@ generate_trap_gate_handler!(name: "x87 FPU floating-point error",
                 wrapper_rust_fnname: x87_floating_point_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: x87_floating_point_handler
 );
 
 generate_trap_gate_handler!(name: "Alignment Check Exception",
@@ -781,7 +1031,7 @@ generate_trap_gate_handler!(name: "Alignment Check Exception",
                 wrapper_rust_fnname: alignment_check_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: alignment_check_handler
 );
 
 generate_trap_gate_handler!(name: "Machine-Check Exception",
@@ -799,7 +1049,7 @@ generate_trap_gate_handler!(name: "SIMD Floating-Point Exception",
                 wrapper_rust_fnname: simd_floating_point_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: simd_floating_point_handler
 );
 
 generate_trap_gate_handler!(name: "Virtualization Exception",
@@ -808,7 +1058,7 @@ generate_trap_gate_handler!(name: "Virtualization Exception",
                 wrapper_rust_fnname: virtualization_exception_rust_wrapper,
                 kernel_fault_strategy: panic,
                 user_fault_strategy: panic,
-                handler_strategy: kill
+                handler_strategy: virtualization_handler
 );
 
 generate_trap_gate_handler!(name: "Security Exception",
@@ -950,9 +1200,13 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
 
     let allowed = cfg!(feature = "no-security-check") || allowed;
 
+    crate::trace::record(TraceEventKind::SyscallEnter, syscall_nr as u32, 0);
+
     match (allowed, syscall_nr) {
         // Horizon-inspired syscalls!
         (true, nr::SetHeapSize) => hwcontext.apply1(set_heap_size(x0)),
+        (true, nr::MapMemory) => hwcontext.apply0(map_memory(x0, x1, x2)),
+        (true, nr::UnmapMemory) => hwcontext.apply0(unmap_memory(x0, x1, x2)),
         (true, nr::QueryMemory) => hwcontext.apply1(query_memory(UserSpacePtrMut(x0 as _), x1, x2)),
         (true, nr::ExitProcess) => hwcontext.apply0(exit_process()),
         (true, nr::CreateThread) => hwcontext.apply1(create_thread(x0, x1, x2, x3 as _, x4 as _)),
@@ -969,14 +1223,36 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
         (true, nr::ConnectToNamedPort) => hwcontext.apply1(connect_to_named_port(UserSpacePtr(x0 as _))),
         (true, nr::SendSyncRequestWithUserBuffer) => hwcontext.apply0(send_sync_request_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2 as _)),
         (true, nr::GetProcessId) => hwcontext.apply1(get_process_id(x0 as _)),
+        (true, nr::GetThreadId) => hwcontext.apply1(get_thread_id(x0 as _)),
+        (true, nr::Break) => hwcontext.apply0(break_(x0 as _, UserSpacePtr::from_raw_parts(x1 as _, x2))),
         (true, nr::OutputDebugString) => hwcontext.apply0(output_debug_string(UserSpacePtr::from_raw_parts(x0 as _, x1), x2, UserSpacePtr::from_raw_parts(x3 as _, x4))),
+        (true, nr::ReturnFromException) => {
+            // On success, the registers were restored to the ones of the
+            // exception. Don't overwrite them with a return value.
+            if let Err(err) = return_from_exception(x0 as _, hwcontext) {
+                hwcontext.apply0(Err(err))
+            }
+        },
+        (true, nr::SetThreadActivity) => hwcontext.apply0(set_thread_activity(x0 as _, x1 as _)),
+        (true, nr::GetThreadContext3) => hwcontext.apply0(get_thread_context3(UserSpacePtrMut(x0 as _), x1 as _)),
         (true, nr::CreateSession) => hwcontext.apply2(create_session(x0 != 0, x1 as _)),
         (true, nr::AcceptSession) => hwcontext.apply1(accept_session(x0 as _)),
         (true, nr::ReplyAndReceiveWithUserBuffer) => hwcontext.apply1(reply_and_receive_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), UserSpacePtr::from_raw_parts(x2 as _, x3), x4 as _, x5)),
         (true, nr::CreateEvent) => hwcontext.apply2(create_event()),
         (true, nr::CreateSharedMemory) => hwcontext.apply1(create_shared_memory(x0 as _, x1 as _, x2 as _)),
+        (true, nr::CreateCodeMemory) => hwcontext.apply1(create_code_memory(x0, x1)),
+        (true, nr::ControlCodeMemory) => hwcontext.apply0(control_code_memory(x0 as _, x1 as _, x2, x3, x4 as _)),
+        (true, nr::SleepSystem) => hwcontext.apply0(sleep_system(x0 != 0)),
         (true, nr::CreateInterruptEvent) => hwcontext.apply1(create_interrupt_event(x0, x1 as u32)),
         (true, nr::QueryPhysicalAddress) => hwcontext.apply3(query_physical_address(x0 as _)),
+        (true, nr::SetProcessActivity) => hwcontext.apply0(set_process_activity(x0 as _, x1 as _)),
+        (true, nr::GetSystemInfo) => hwcontext.apply2(get_system_info(x0 as _, x1 as _, x2).map(|info| (info as usize, (info >> 32) as usize))),
+        (true, nr::DebugActiveProcess) => hwcontext.apply1(debug_active_process(x0)),
+        (true, nr::GetThreadList) => hwcontext.apply1(get_thread_list(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2 as _)),
+        (true, nr::GetDebugThreadContext) => hwcontext.apply0(get_debug_thread_context(UserSpacePtrMut(x0 as _), x1 as _, x2, x3 as _)),
+        (true, nr::QueryDebugProcessMemory) => hwcontext.apply1(query_debug_process_memory(UserSpacePtrMut(x0 as _), x1, x2 as _, x3)),
+        (true, nr::ReadDebugProcessMemory) => hwcontext.apply0(read_debug_process_memory(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x3), x1 as _, x2)),
+        (true, nr::GetDebugThreadParam) => hwcontext.apply2(get_debug_thread_param(x0 as _, x1, x2 as _)),
         (true, nr::GetProcessList) => hwcontext.apply1(get_process_list(x0 as _, x1 as _)),
         (true, nr::CreatePort) => hwcontext.apply2(create_port(x0 as _, x1 != 0, UserSpacePtr(x2 as _))),
         (true, nr::ManageNamedPort) => hwcontext.apply1(manage_named_port(UserSpacePtr(x0 as _), x1 as _)),
@@ -984,6 +1260,9 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
         (true, nr::SetProcessMemoryPermission) => hwcontext.apply0(set_process_memory_permission(x0 as _, x1 as _, x2 as _, x3 as _)),
         (true, nr::MapProcessMemory) => hwcontext.apply0(map_process_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
         (true, nr::UnmapProcessMemory) => hwcontext.apply0(unmap_process_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
+        (true, nr::QueryProcessMemory) => hwcontext.apply1(query_process_memory(UserSpacePtrMut(x0 as _), x1, x2 as _, x3)),
+        (true, nr::MapProcessCodeMemory) => hwcontext.apply0(map_process_code_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
+        (true, nr::UnmapProcessCodeMemory) => hwcontext.apply0(unmap_process_code_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
         (true, nr::CreateProcess) => hwcontext.apply1(create_process(UserSpacePtr(x0 as _), UserSpacePtr::from_raw_parts(x1 as _, x2 * 4))),
         (true, nr::StartProcess) => hwcontext.apply0(start_process(x0 as _, x1 as _, x2 as _, x3 as _)),
         (true, nr::TerminateProcess) => hwcontext.apply0(terminate_process(x0 as _)),
@@ -993,32 +1272,72 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
         (true, nr::MapFramebuffer) => hwcontext.apply4(map_framebuffer()),
         (true, nr::MapMmioRegion) => hwcontext.apply0(map_mmio_region(x0, x1, x2, x3 != 0)),
         (true, nr::SetThreadArea) => hwcontext.apply0(set_thread_area(x0)),
+        (true, nr::GetProcessMemoryUsage) => hwcontext.apply0(get_process_memory_usage(UserSpacePtrMut(x0 as _), x1)),
+        (true, nr::AcknowledgeInterrupt) => hwcontext.apply0(acknowledge_interrupt(x0 as _)),
+        (true, nr::CreateMsiEvent) => hwcontext.apply3(create_msi_event()),
+        (true, nr::SetExceptionHandler) => hwcontext.apply0(set_exception_handler(x0, x1)),
+        (true, nr::GetCrashReport) => hwcontext.apply0(get_crash_report(UserSpacePtrMut(x0 as _), x1 as _)),
+        (true, nr::SetProcessSymbols) => hwcontext.apply0(set_process_symbols(x0 as _, UserSpacePtr::from_raw_parts(x1 as _, x2), UserSpacePtr::from_raw_parts(x3 as _, x4), x5)),
+        (true, nr::GetDebugProcessSymbol) => hwcontext.apply2(get_debug_process_symbol(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2 as _, x3)),
+        (true, nr::ReadKernelLog) => hwcontext.apply2(read_kernel_log(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2)),
+        (true, nr::CreateKernelLogEvent) => hwcontext.apply1(create_kernel_log_event()),
+        (true, nr::SetLogFilter) => hwcontext.apply0(set_log_filter(UserSpacePtr::from_raw_parts(x0 as _, x1), UserSpacePtr::from_raw_parts(x2 as _, x3))),
+        (true, nr::ReadTraceBuffer) => hwcontext.apply2(read_trace_buffer(x0, UserSpacePtrMut::from_raw_parts_mut(x1 as _, x2), x3)),
+        (true, nr::CreateTimer) => hwcontext.apply1(create_timer()),
+        (true, nr::SetTimer) => hwcontext.apply0(set_timer(x0 as _, (x1 as u64) | ((x2 as u64) << 32), (x3 as u64) | ((x4 as u64) << 32))),
+        (true, nr::CancelTimer) => hwcontext.apply0(cancel_timer(x0 as _)),
+        (true, nr::CreatePowerButtonEvent) => hwcontext.apply1(create_power_button_event()),
+        (true, nr::GetProcessHandles) => hwcontext.apply1(get_process_handles(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2)),
 
         // Unknown/unauthorized syscall.
         (false, _) => {
             // Attempted to call unauthorized SVC. Horizon invokes usermode
-            // exception handling in some cases. Let's just kill the process for
-            // now.
+            // exception handling in some cases. Let's just crash the process
+            // for now.
             let curproc = get_current_process();
             error!("Process {} attempted to use unauthorized syscall {} ({:#04x}), killing",
                    curproc.name, syscall_name, syscall_nr);
-            ProcessStruct::kill_current_process();
+            drop(curproc);
+            invalid_svc_crash(syscall_nr, hwcontext);
         },
         _ => {
             let curproc = get_current_process();
             error!("Process {} attempted to use unknown syscall {} ({:#04x}), killing",
                    curproc.name, syscall_name, syscall_nr);
-            ProcessStruct::kill_current_process();
+            drop(curproc);
+            invalid_svc_crash(syscall_nr, hwcontext);
         }
     }
+
+    crate::trace::record(TraceEventKind::SyscallExit, syscall_nr as u32, hwcontext.eax as u32);
+}
+
+/// Crashes the current process after it used an unknown or unauthorized SVC.
+fn invalid_svc_crash(syscall_nr: usize, hwcontext: &UserspaceHardwareContext) {
+    let report = CrashReport {
+        tid: get_current_thread().tid,
+        exception: ExceptionInfo {
+            exception_type: ExceptionType::InvalidSvc,
+            error_code: syscall_nr,
+            fault_address: 0,
+            context: hwcontext.thread_context(),
+        },
+        ..CrashReport::default()
+    };
+    ProcessStruct::crash_current_process(report);
 }
 
 /// Generates irq handlers.
 ///
 /// For each irq number it is given, this macro will generate an irq handler that:
 ///
-/// 1. acknowledges the irq
+/// 1. lets the kernel handle the ACPI SCI, if this irq line is the SCI's
 /// 2. dispatches the event for this irq line
+/// 3. wakes up the threads waiting on a timer, if this irq line is the timer's
+/// 4. acknowledges the irq
+///
+/// The event is dispatched first so level-triggered lines get masked before the
+/// EOI, and don't immediately fire again.
 ///
 /// It uses [`generate_trap_gate_handler`] internally to generate the asm and low-level rust wrappers.
 /// You must give it an ident for both of those functions that will be passed on to `generate_trap_gate_handler`,
@@ -1033,8 +1352,12 @@ macro_rules! irq_handler {
         $(
             /// Auto generated irq handler. See [`irq_handler`].
             fn $handler_name(_exception_name: &'static str, _hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
-                crate::i386::interrupt::acknowledge($irq_nbr);
+                crate::trace::record(TraceEventKind::IrqEnter, $irq_nbr, 0);
+                crate::i386::power::handle_irq($irq_nbr);
                 crate::event::dispatch_event($irq_nbr);
+                crate::timer::handle_irq($irq_nbr);
+                crate::i386::interrupt::acknowledge($irq_nbr);
+                crate::trace::record(TraceEventKind::IrqExit, $irq_nbr, 0);
             }
 
             generate_trap_gate_handler!(name: "Irq handler",
@@ -1051,7 +1374,7 @@ macro_rules! irq_handler {
         /// Array of interrupt handlers.
         ///
         /// The position in the array defines the IRQ this handler is targeting. See [`irq_handler`].
-        static IRQ_HANDLERS : [extern "C" fn(); 17] = [
+        static IRQ_HANDLERS : [extern "C" fn(); 34] = [
             $(
                 $asm_wrapper_name,
             )*
@@ -1077,6 +1400,23 @@ irq_handler!(
     14, primary_ata_handler,   primary_ata_handler_asm_wrapper,   primary_ata_handler_rust_wrapper;
     15, secondary_ata_handler, secondary_ata_handler_asm_wrapper, secondary_ata_handler_rust_wrapper;
     16, hpet_handler,          hpet_handler_asm_wrapper,          hpet_handler_rust_wrapper;
+    17, msi0_handler,          msi0_handler_asm_wrapper,          msi0_handler_rust_wrapper;
+    18, msi1_handler,          msi1_handler_asm_wrapper,          msi1_handler_rust_wrapper;
+    19, msi2_handler,          msi2_handler_asm_wrapper,          msi2_handler_rust_wrapper;
+    20, msi3_handler,          msi3_handler_asm_wrapper,          msi3_handler_rust_wrapper;
+    21, msi4_handler,          msi4_handler_asm_wrapper,          msi4_handler_rust_wrapper;
+    22, msi5_handler,          msi5_handler_asm_wrapper,          msi5_handler_rust_wrapper;
+    23, msi6_handler,          msi6_handler_asm_wrapper,          msi6_handler_rust_wrapper;
+    24, msi7_handler,          msi7_handler_asm_wrapper,          msi7_handler_rust_wrapper;
+    25, msi8_handler,          msi8_handler_asm_wrapper,          msi8_handler_rust_wrapper;
+    26, msi9_handler,          msi9_handler_asm_wrapper,          msi9_handler_rust_wrapper;
+    27, msi10_handler,         msi10_handler_asm_wrapper,         msi10_handler_rust_wrapper;
+    28, msi11_handler,         msi11_handler_asm_wrapper,         msi11_handler_rust_wrapper;
+    29, msi12_handler,         msi12_handler_asm_wrapper,         msi12_handler_rust_wrapper;
+    30, msi13_handler,         msi13_handler_asm_wrapper,         msi13_handler_rust_wrapper;
+    31, msi14_handler,         msi14_handler_asm_wrapper,         msi14_handler_rust_wrapper;
+    32, msi15_handler,         msi15_handler_asm_wrapper,         msi15_handler_rust_wrapper;
+    33, lapic_timer_handler,   lapic_timer_handler_asm_wrapper,   lapic_timer_handler_rust_wrapper;
 );
 
 lazy_static! {
@@ -15,6 +15,7 @@ pub mod process_switch;
 pub mod gdt;
 pub mod interrupt;
 pub mod interrupt_service_routines;
+pub mod power;
 
 pub mod pio {
     //! Port IO
@@ -163,6 +164,17 @@ pub mod instructions {
             SegmentSelector(segment)
         }
     }
+    pub mod tsc {
+        //! Time Stamp Counter.
+
+        /// Reads the Time Stamp Counter.
+        pub fn rdtsc() -> u64 {
+            unsafe {
+                // Safety: rdtsc has no side-effects. Every CPU we support has it.
+                core::arch::x86::_rdtsc()
+            }
+        }
+    }
     pub mod interrupts {
         //! Interrupt disabling functionality.
 
@@ -0,0 +1,273 @@
+//! ACPI power management
+//!
+//! Turns the system off by putting it in the ACPI soft off state (S5), resets
+//! it, and handles the power button.
+//!
+//! Pressing the power button raises the System Control Interrupt. The kernel
+//! clears the button status on the PM1 event registers, so the SCI line goes
+//! back down, and counts the presses. Userspace waits for them with a
+//! [PowerButtonEvent], and decides what to do: usually ask the services to
+//! save their state, and then call [sleep_system].
+//!
+//! [sleep_system]: crate::syscalls::sleep_system
+
+use core::iter;
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+use crate::error::UserspaceError;
+use crate::event::{self, IRQEvent, Waitable};
+use crate::i386::instructions::interrupts;
+use crate::i386::instructions::tables::{lidt, DescriptorTablePointer};
+use crate::i386::pio::Pio;
+use crate::io::Io;
+
+use super::acpi::{self, FadtInfo, ADDRESS_SPACE_SYSTEM_IO};
+
+/// SCI_EN bit of the PM1 control registers, set by the firmware once the
+/// system is in ACPI mode.
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+/// Offset of the SLP_TYP field of the PM1 control registers.
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+/// SLP_TYP field of the PM1 control registers, the sleep state to enter.
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+/// SLP_EN bit of the PM1 control registers. Writing it enters the sleep state.
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+/// PWRBTN_STS bit of the PM1 status registers, and PWRBTN_EN bit of the PM1
+/// enable registers.
+const PM1_PWRBTN: u16 = 1 << 8;
+
+/// Status register of the PS/2 keyboard controller.
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+/// Command register of the PS/2 keyboard controller.
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+/// Bit of the keyboard controller status, set while it has not read its input
+/// buffer yet.
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+/// Keyboard controller command pulsing the CPU reset line.
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+
+/// Number of iterations we spin for while waiting on the firmware or the
+/// hardware.
+///
+/// This does not rely on the kernel timer, since interrupts are disabled when
+/// we wait for the system to go down.
+const SPIN_ITERATIONS: usize = 10_000_000;
+
+/// Number of times the power button was pressed since boot.
+static POWER_BUTTON_PRESSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Spins until `condition` returns true, or [SPIN_ITERATIONS] elapse. Returns
+/// the last result of `condition`.
+fn spin_until(mut condition: impl FnMut() -> bool) -> bool {
+    for _ in 0..SPIN_ITERATIONS {
+        if condition() {
+            return true;
+        }
+        spin_loop_hint();
+    }
+    condition()
+}
+
+/// Gets the FADT if it describes the PM1 event registers the power button is
+/// reported on.
+fn power_button_fadt() -> Option<&'static FadtInfo> {
+    acpi::try_get_fadt_information()
+        .filter(|fadt| fadt.pm1a_event_block != 0 && fadt.pm1_event_length >= 4)
+}
+
+/// Gets the ports of the PM1 event blocks that are implemented.
+fn pm1_event_blocks(fadt: &FadtInfo) -> impl Iterator<Item = u16> {
+    iter::once(fadt.pm1a_event_block)
+        .chain(iter::once(fadt.pm1b_event_block))
+        .filter(|block| *block != 0)
+}
+
+/// Switches the firmware to ACPI mode if it is not already in it, so it
+/// stops handling the power management events itself.
+fn enable_acpi(fadt: &FadtInfo) {
+    let pm1a_control = Pio::<u16>::new(fadt.pm1a_control_block);
+    if pm1a_control.read() & PM1_CNT_SCI_EN != 0 || fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+
+    info!("Switching the firmware to ACPI mode");
+    Pio::<u8>::new(fadt.smi_command_port).write(fadt.acpi_enable);
+    if !spin_until(|| pm1a_control.read() & PM1_CNT_SCI_EN != 0) {
+        warn!("The firmware did not switch to ACPI mode");
+    }
+}
+
+/// Switches to ACPI mode, and enables the power button.
+///
+/// Must be called after the interrupts are initialized. Does nothing if the
+/// system has no FADT.
+pub fn init() {
+    let fadt = match acpi::try_get_fadt_information() {
+        Some(fadt) if fadt.pm1a_control_block != 0 => fadt,
+        _ => return info!("No FADT, ACPI power management is not available")
+    };
+    enable_acpi(fadt);
+
+    if let Some(fadt) = power_button_fadt() {
+        let enable_offset = u16::from(fadt.pm1_event_length / 2);
+        for block in pm1_event_blocks(fadt) {
+            // Forget about the presses that happened before we booted.
+            Pio::<u16>::new(block).write(PM1_PWRBTN);
+            let mut enable = Pio::<u16>::new(block + enable_offset);
+            enable.write(enable.read() | PM1_PWRBTN);
+        }
+        crate::i386::interrupt::unmask(fadt.sci_irq);
+        info!("Power button enabled on SCI {}", fadt.sci_irq);
+    }
+}
+
+/// Counts the power button presses, and clears their status so the SCI line
+/// goes back down.
+///
+/// Called on every IRQ. Does nothing if `irq` is not the SCI.
+pub fn handle_irq(irq: u8) {
+    let fadt = match power_button_fadt() {
+        Some(fadt) if fadt.sci_irq == irq => fadt,
+        _ => return
+    };
+
+    for block in pm1_event_blocks(fadt) {
+        let mut status = Pio::<u16>::new(block);
+        if status.read() & PM1_PWRBTN != 0 {
+            // Status bits are cleared by writing 1 to them.
+            status.write(PM1_PWRBTN);
+            POWER_BUTTON_PRESSES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Turns the system off, by putting it in the ACPI soft off state.
+///
+/// Only returns if it failed.
+///
+/// # Errors
+///
+/// - `NotImplemented`
+///    - The system has no FADT, or its DSDT has no `\_S5` package.
+/// - `InvalidState`
+///    - The system did not turn off.
+pub fn power_off() -> Result<(), UserspaceError> {
+    let fadt = acpi::try_get_fadt_information()
+        .filter(|fadt| fadt.pm1a_control_block != 0)
+        .ok_or(UserspaceError::NotImplemented)?;
+    let (slp_typa, slp_typb) = fadt.s5_sleep_types.ok_or(UserspaceError::NotImplemented)?;
+
+    info!("Powering off");
+    enable_acpi(fadt);
+
+    let control_blocks = [(fadt.pm1a_control_block, slp_typa), (fadt.pm1b_control_block, slp_typb)];
+    unsafe {
+        // Safety: We are going down, nothing else should run anymore.
+        interrupts::cli();
+    }
+
+    // Program the sleep type of both blocks before entering the sleep state.
+    for &(port, slp_typ) in control_blocks.iter().filter(|(port, _)| *port != 0) {
+        let mut control = Pio::<u16>::new(port);
+        let value = (control.read() & !PM1_CNT_SLP_TYP_MASK) | ((u16::from(slp_typ) << PM1_CNT_SLP_TYP_SHIFT) & PM1_CNT_SLP_TYP_MASK);
+        control.write(value);
+    }
+    for &(port, _) in control_blocks.iter().filter(|(port, _)| *port != 0) {
+        let mut control = Pio::<u16>::new(port);
+        let value = control.read();
+        control.write(value | PM1_CNT_SLP_EN);
+    }
+
+    // The system should be off by now.
+    spin_until(|| false);
+    unsafe {
+        // Safety: Interrupts were enabled when we got called.
+        interrupts::sti();
+    }
+    error!("The system did not power off");
+    Err(UserspaceError::InvalidState)
+}
+
+/// Resets the system.
+///
+/// Tries the ACPI reset register, then the keyboard controller, and finally
+/// triple faults.
+pub fn reboot() -> ! {
+    info!("Rebooting");
+    unsafe {
+        // Safety: We are going down, nothing else should run anymore.
+        interrupts::cli();
+    }
+
+    let reset_register = acpi::try_get_fadt_information()
+        .and_then(|fadt| fadt.reset_register.map(|register| (register, fadt.reset_value)));
+    match reset_register {
+        Some((register, value)) if register.address_space == ADDRESS_SPACE_SYSTEM_IO => {
+            Pio::<u8>::new(register.address as u16).write(value);
+            spin_until(|| false);
+            warn!("The ACPI reset register did not reset the system");
+        }
+        Some((register, _)) => info!("Unsupported ACPI reset register address space {}", register.address_space),
+        None => ()
+    }
+
+    // Pulse the CPU reset line through the keyboard controller.
+    let status = Pio::<u8>::new(KEYBOARD_CONTROLLER_STATUS);
+    spin_until(|| status.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0);
+    Pio::<u8>::new(KEYBOARD_CONTROLLER_COMMAND).write(KEYBOARD_CONTROLLER_RESET);
+    spin_until(|| false);
+    warn!("The keyboard controller did not reset the system, triple faulting");
+
+    unsafe {
+        // Safety: Without an IDT, the breakpoint exception triple faults,
+        // which resets the CPU.
+        lidt(DescriptorTablePointer { limit: 0, base: 0 });
+        asm!("int3" :::: "volatile");
+    }
+    loop {
+        spin_loop_hint();
+    }
+}
+
+/// An event signaled when the power button is pressed.
+///
+/// It stays signaled until it is cleared with [PowerButtonEvent::clear].
+#[derive(Debug)]
+pub struct PowerButtonEvent {
+    /// Number of presses the last time this event was cleared.
+    seen: AtomicUsize,
+    /// Event waking up the waiting threads on every SCI.
+    sci: IRQEvent,
+}
+
+impl PowerButtonEvent {
+    /// Creates an event that will get signaled by the next presses of the
+    /// power button.
+    ///
+    /// # Errors
+    ///
+    /// - `NotImplemented`
+    ///    - The system has no FADT, or it does not describe the power button.
+    pub fn new() -> Result<PowerButtonEvent, UserspaceError> {
+        let fadt = power_button_fadt().ok_or(UserspaceError::NotImplemented)?;
+        Ok(PowerButtonEvent {
+            seen: AtomicUsize::new(POWER_BUTTON_PRESSES.load(Ordering::SeqCst)),
+            sci: event::wait_event(fadt.sci_irq),
+        })
+    }
+
+    /// Clears the signaled state, until the power button is pressed again.
+    pub fn clear(&self) {
+        self.seen.store(POWER_BUTTON_PRESSES.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+impl Waitable for PowerButtonEvent {
+    fn is_signaled(&self) -> bool {
+        POWER_BUTTON_PRESSES.load(Ordering::SeqCst) != self.seen.load(Ordering::SeqCst)
+    }
+
+    fn register(&self) {
+        self.sci.register()
+    }
+}
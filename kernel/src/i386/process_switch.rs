@@ -130,19 +130,6 @@ pub unsafe extern "C" fn process_switch(thread_b: Arc<ThreadStruct>, thread_curr
         esp_to_load
     };
 
-    // Set IOPB back to "nothing allowed" state
-    // todo do not change iopb if thread_b belongs to the same process.
-
-    // MAIN_TSS should otherwise only be locked during DOUBLE_FAULTING,
-    // in which case we really shouldn't be context-switching.
-    let mut main_tss = MAIN_TASK.try_lock()
-        .expect("Cannot lock main tss");
-    for ioport in &thread_current.process.capabilities.ioports {
-        let ioport = *ioport as usize;
-        main_tss.iopb[ioport / 8] = 0xFF;
-    }
-    drop(main_tss);
-
     // current is still stored in scheduler's global CURRENT_PROCESS, so it's not dropped yet.
     drop(thread_current);
 
@@ -195,11 +182,8 @@ pub unsafe extern "C" fn process_switch(thread_b: Arc<ThreadStruct>, thread_curr
     // Set the ESP0
     main_tss.tss.esp0 = me.kstack.get_stack_start() as u32;
 
-    // Set IOPB
-    for ioport in &me.process.capabilities.ioports {
-        let ioport = *ioport as usize;
-        main_tss.iopb[ioport / 8] &= !(1 << (ioport % 8));
-    }
+    // Set IOPB, if we come from a thread of another process.
+    main_tss.load_io_bitmap(me.process.pid, &me.process.capabilities.ioports);
 
     me
 }
@@ -214,8 +198,30 @@ pub unsafe extern "C" fn process_switch(thread_b: Arc<ThreadStruct>, thread_curr
 ///
 /// This function will definitely fuck up your stack, so make sure you're calling it on a
 /// never-scheduled thread's empty-stack.
-#[allow(clippy::fn_to_numeric_cast)]
 pub unsafe fn prepare_for_first_schedule(t: &ThreadStruct, entrypoint: usize, userspace_args: (usize, usize), userspace_stack: usize) {
+    prepare_stack_for_first_schedule(t, first_schedule, entrypoint, userspace_args, userspace_stack)
+}
+
+/// Prepares a kernel thread for its first schedule. Like [prepare_for_first_schedule], except
+/// the thread will call `entrypoint(arg)` in ring 0 instead of jumping to userspace, and exit
+/// when it returns.
+///
+/// # Safety
+///
+/// This function will definitely fuck up your stack, so make sure you're calling it on a
+/// never-scheduled thread's empty-stack.
+#[allow(clippy::fn_to_numeric_cast)]
+pub unsafe fn prepare_for_first_schedule_kernel(t: &ThreadStruct, entrypoint: fn(usize), arg: usize) {
+    prepare_stack_for_first_schedule(t, first_schedule_kernel, entrypoint as usize, (arg, 0), 0)
+}
+
+/// Writes the registers popped on the first schedule-in of a thread, which will `ret` to `callback`.
+///
+/// # Safety
+///
+/// Same as [prepare_for_first_schedule].
+#[allow(clippy::fn_to_numeric_cast)]
+unsafe fn prepare_stack_for_first_schedule(t: &ThreadStruct, callback: unsafe fn(), entrypoint: usize, userspace_args: (usize, usize), userspace_stack: usize) {
     #[repr(packed)]
     #[allow(clippy::missing_docs_in_private_items)]
     struct RegistersOnStack {
@@ -256,7 +262,7 @@ pub unsafe fn prepare_for_first_schedule(t: &ThreadStruct, entrypoint: usize, us
         edx: userspace_args.1 as u32,             //  |
         ecx: userspace_args.0 as u32,             //  |
         eax: entrypoint as u32,                   //  |
-        callback_eip: first_schedule as u32       //  |
+        callback_eip: callback as u32             //  |
         // --------------                             |
         // poison ebp        <------------------------+    * 'stack_start' *
         // poison eip
@@ -301,32 +307,82 @@ unsafe fn first_schedule() {
         // reconstruct an Arc to our ProcessStruct from the leaked pointer
         let current = unsafe { Arc::from_raw(whoami) };
 
-        // MAIN_TSS must have been unlocked by now.
-        let mut main_tss = MAIN_TASK.try_lock()
-            .expect("Cannot lock main tss");
-
-        // Set the ESP0
-        main_tss.tss.esp0 = current.kstack.get_stack_start() as u32;
+        first_schedule_tss(&current);
 
-        // todo do not touch iopb if we come from a thread of the same process.
-        // Set IOPB
-        for ioport in &current.process.capabilities.ioports {
-            let ioport = *ioport as usize;
-            main_tss.iopb[ioport / 8] &= !(1 << (ioport % 8));
+        // call the scheduler to finish the high-level process switch mechanics
+        unsafe {
+            // safe: interrupts are off
+            crate::scheduler::scheduler_first_schedule(current, || jump_to_entrypoint(entrypoint, userspace_stack, arg1, arg2));
         }
 
-        drop(main_tss); // unlock it
+        unreachable!()
+    }
+}
+
+/// The function ret'd on, on a kernel thread's first schedule - as setup by the
+/// [`prepare_for_first_schedule_kernel`].
+///
+/// Same as [`first_schedule`], but calls the entrypoint in ring 0 instead of jumping to userspace.
+/// When the entrypoint returns, the thread exits.
+///
+/// # Safety:
+///
+/// * Interrupts must be disabled.
+/// * Arguments must respect the [`prepare_for_first_schedule`] ABI, and be popped into registers.
+#[naked]
+unsafe fn first_schedule_kernel() {
+    // just get the ProcessStruct pointer in $edi, the entrypoint in $eax, and call a rust function
+    unsafe {
+        asm!("
+        push ebx
+        push edx
+        push ecx
+        push eax
+        push edi
+        call $0
+        " : : "i"(first_schedule_kernel_inner as *const u8) : : "volatile", "intel");
+    }
+
+    /// Stack is set-up, now we can run rust code.
+    extern "C" fn first_schedule_kernel_inner(whoami: *const ThreadStruct, entrypoint: usize, arg1: usize, _arg2: usize, _stack: usize) -> ! {
+        // reconstruct an Arc to our ProcessStruct from the leaked pointer
+        let current = unsafe { Arc::from_raw(whoami) };
+
+        first_schedule_tss(&current);
+
+        let entrypoint = unsafe {
+            // safe: prepare_for_first_schedule_kernel was passed a fn(usize).
+            core::mem::transmute::<usize, fn(usize)>(entrypoint)
+        };
 
         // call the scheduler to finish the high-level process switch mechanics
         unsafe {
             // safe: interrupts are off
-            crate::scheduler::scheduler_first_schedule(current, || jump_to_entrypoint(entrypoint, userspace_stack, arg1, arg2));
+            crate::scheduler::scheduler_first_schedule(current, || {
+                entrypoint(arg1);
+
+                ThreadStruct::exit(crate::scheduler::get_current_thread());
+                crate::i386::interrupt_service_routines::check_thread_killed();
+            });
         }
 
         unreachable!()
     }
 }
 
+/// Sets up the ESP0 and IOPB of the TSS for a thread being scheduled for the first time.
+fn first_schedule_tss(current: &ThreadStruct) {
+    // MAIN_TSS must have been unlocked by now.
+    let mut main_tss = MAIN_TASK.try_lock()
+        .expect("Cannot lock main tss");
+
+    // Set the ESP0
+    main_tss.tss.esp0 = current.kstack.get_stack_start() as u32;
+
+    // Set IOPB, if we come from a thread of another process.
+    main_tss.load_io_bitmap(current.process.pid, &current.process.capabilities.ioports);
+}
+
 /// Jumps to Userspace, and run a userspace program.
 ///
 /// This function is called on the first schedule of a process or thread,
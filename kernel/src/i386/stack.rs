@@ -364,3 +364,58 @@ fn dump_stack_from_slice<'a>(stack: &[u8], orig_address: usize, mut esp: usize,
         frame_nb += 1;
     }
 }
+
+/// Maximum number of frames displayed by [dump_user_backtrace].
+const MAX_USER_BACKTRACE_DEPTH: usize = 32;
+
+/// Dumps the backtrace of a thread of the current process, displaying each
+/// frame as `function+offset` when the process has symbols.
+///
+/// Unlike [dump_stack], this does not hexdump the frames, and only follows the
+/// chain of saved ebp/eip, checking that each one is readable. It is used to
+/// log the backtrace of a crashing process. See
+/// [crate::process::ProcessStruct::crash_current_process].
+#[allow(unused_must_use)]
+pub fn dump_user_backtrace(mut ebp: usize, mut eip: usize) {
+    use crate::devices::rs232::SerialLogger;
+    use core::fmt::Write;
+    use sunrise_libkern::{MemoryState, MemoryPermissions, MemoryAttributes};
+
+    let process = scheduler::get_current_process();
+    let symbols = process.symbols.lock().clone();
+
+    writeln!(SerialLogger, "---------- Backtrace of {} ---------", process.name);
+    let pmemory = process.pmemory.lock();
+    for frame_nb in 0..MAX_USER_BACKTRACE_DEPTH {
+        match symbols.as_ref().and_then(|symbols| symbols.lookup(eip)) {
+            Some((name, offset)) => writeln!(SerialLogger, "> Frame #{} - {}+{:#x}, eip: {:#010x}", frame_nb, name, offset, eip),
+            None => writeln!(SerialLogger, "> Frame #{} - unknown, eip: {:#010x}", frame_nb, eip),
+        };
+
+        if ebp == 0 || !UserLand::contains_region(VirtualAddress(ebp), 2 * size_of::<usize>()) {
+            break;
+        }
+        let is_frame_readable = pmemory.check_range(VirtualAddress(ebp), 2 * size_of::<usize>(),
+            MemoryState::empty(), MemoryState::empty(),
+            MemoryPermissions::READABLE, MemoryPermissions::READABLE,
+            MemoryAttributes::empty(), MemoryAttributes::empty(),
+            MemoryAttributes::empty()).is_ok();
+        if !is_frame_readable {
+            writeln!(SerialLogger, "Cannot access saved ebp/eip at {:#010x}", ebp);
+            break;
+        }
+
+        // fetch saved ebp/eip at [ebp]
+        let frame = unsafe {
+            // safe: we checked the frame is mapped readable in the current
+            // process, and we hold its memory lock.
+            ::core::slice::from_raw_parts(ebp as *const usize, 2)
+        };
+        ebp = frame[0];
+        eip = frame[1];
+        if eip == 0 {
+            break;
+        }
+    }
+    writeln!(SerialLogger, "-------- End of backtrace --------");
+}
@@ -15,6 +15,9 @@
 //! used with the `event::wait` function. This will wait until the associated
 //! ClientPort had its connect operation called.
 //!
+//! A ClientPort implements it too, and gets signaled once all the ServerPorts
+//! are gone, usually because the process hosting the port died.
+//!
 //! ```rust
 //! let (server, client) = Port::new();
 //! let client_sess = client.connect();
@@ -42,11 +45,14 @@ struct Port {
     /// Number of active ServerPort. When it drops to 0, future connection
     /// attempts will faill with [UserspaceError::PortRemoteDead].
     servercount: AtomicUsize,
+    /// List of threads waiting for all the ServerPorts to be dropped.
+    death_waiters: SpinLock<Vec<Weak<ThreadStruct>>>,
 }
 
 /// The client side of a Port.
 ///
-/// This side can call connect().
+/// This side can call connect(). It implements Waitable, which waits until all
+/// the ServerPorts are dropped.
 #[derive(Debug, Clone)]
 pub struct ClientPort(Arc<Port>);
 
@@ -78,7 +84,8 @@ pub fn new(_max_sessions: u32) -> (ServerPort, ClientPort) {
     let port = Arc::new(Port {
         servercount: AtomicUsize::new(0),
         incoming_connections: SpinLock::new(Vec::new()),
-        accepters: SpinLock::new(Vec::new())
+        accepters: SpinLock::new(Vec::new()),
+        death_waiters: SpinLock::new(Vec::new()),
     });
     (Port::server(port.clone()), Port::client(port.clone()))
 }
@@ -99,6 +106,22 @@ impl Waitable for ServerPort {
     }
 }
 
+// Wait for the server side to die.
+impl Waitable for ClientPort {
+    fn is_signaled(&self) -> bool {
+        self.0.servercount.load(Ordering::SeqCst) == 0
+    }
+
+    fn register(&self) {
+        let mut death_waiters = self.0.death_waiters.lock();
+        let curproc = scheduler::get_current_thread();
+
+        if !death_waiters.iter().filter_map(|v| v.upgrade()).any(|v| Arc::ptr_eq(&curproc, &v)) {
+            death_waiters.push(Arc::downgrade(&curproc));
+        }
+    }
+}
+
 impl Clone for ServerPort {
     fn clone(&self) -> Self {
         assert!(self.0.servercount.fetch_add(1, Ordering::SeqCst) != usize::max_value(), "Overflow when incrementing servercount");
@@ -118,6 +141,12 @@ impl Drop for ServerPort {
             for request in internal.drain(..) {
                 scheduler::add_to_schedule_queue(request.creator.clone());
             }
+
+            for waiter in self.0.death_waiters.lock().drain(..) {
+                if let Some(thread) = waiter.upgrade() {
+                    scheduler::add_to_schedule_queue(thread);
+                }
+            }
         }
     }
 }
@@ -163,6 +192,11 @@ impl ServerPort {
 }
 
 impl ClientPort {
+    /// Checks whether `server` is the other side of this port.
+    pub fn is_client_of(&self, server: &ServerPort) -> bool {
+        Arc::ptr_eq(&self.0, &server.0)
+    }
+
     /// Connects to this port.
     pub fn connect(&self) -> Result<ClientSession, UserspaceError> {
         let incoming = Arc::new(IncomingConnection {
@@ -39,6 +39,7 @@ use bit_field::BitField;
 use crate::error::KernelError;
 use crate::checks::check_lower_than_usize;
 use sunrise_libkern::MemoryType;
+use sunrise_libkern::trace::TraceEventKind;
 use sunrise_libutils::align_up;
 
 use failure::Backtrace;
@@ -467,6 +468,11 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
 }
 
 impl ClientSession {
+    /// Checks whether `server` is the other side of this session.
+    pub fn is_client_of(&self, server: &ServerSession) -> bool {
+        Arc::ptr_eq(&self.0, &server.0)
+    }
+
     /// Send an IPC request through the client pipe. Takes a userspace buffer
     /// containing the packed IPC request. When returning, the buffer will
     /// contain the IPC answer (unless an error occured).
@@ -490,6 +496,7 @@ impl ClientSession {
                 return Err(UserspaceError::PortRemoteDead);
             }
 
+            crate::trace::record(TraceEventKind::IpcRequest, &*self.0 as *const _ as usize as u32, 0);
             internal.incoming_requests.push(Request {
                 sender_buf: VirtualAddress(buf.as_ptr() as usize),
                 sender_bufsize: buf.len(),
@@ -637,6 +644,8 @@ impl ServerSession {
 
         *active.answered.lock() = Some(Ok(()));
 
+        crate::trace::record(TraceEventKind::IpcReply, &*self.0 as *const _ as usize as u32, active.sender.tid as u32);
+
         scheduler::add_to_schedule_queue(active.sender.clone());
 
         Ok(())
@@ -703,18 +712,19 @@ fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8]
     if descriptor.num_copy_handles() != 0 || descriptor.num_move_handles() != 0 {
         let mut from_handle_table = from_proc.process.phandles.lock();
         let mut to_handle_table = to_proc.process.phandles.lock();
+        to_handle_table.check_free_handles(usize::from(descriptor.num_copy_handles()) + usize::from(descriptor.num_move_handles()))?;
 
         for i in 0..descriptor.num_copy_handles() {
             let handle = u32::from_le_bytes(from_buf[curoff..curoff + 4].try_into().unwrap());
             let handle = from_handle_table.get_handle(handle)?;
-            let handle = to_handle_table.add_handle(handle);
+            let handle = to_handle_table.add_handle(handle)?;
             (&mut to_buf[curoff..curoff + 4]).copy_from_slice(&handle.to_le_bytes()[..]);
             curoff += 4;
         }
         for i in 0..descriptor.num_move_handles() {
             let handle = u32::from_le_bytes(from_buf[curoff..curoff + 4].try_into().unwrap());
             let handle = from_handle_table.delete_handle(handle)?;
-            let handle = to_handle_table.add_handle(handle);
+            let handle = to_handle_table.add_handle(handle)?;
             (&mut to_buf[curoff..curoff + 4]).copy_from_slice(&handle.to_le_bytes()[..]);
             curoff += 4;
         }
@@ -0,0 +1,401 @@
+//! Kernel log ring buffer
+//!
+//! Every record printed by the [Logger](super) is also kept in a ring buffer,
+//! along with its level, its target, the name of the process that logged it,
+//! and a timestamp. Userspace reads it incrementally with [read_kernel_log],
+//! and waits for new records with a [KernelLogEvent]. When the buffer is full,
+//! the oldest records are dropped.
+//!
+//! Records are stored in the format described in [sunrise_libkern::dmesg], so
+//! reading them is a plain copy.
+//!
+//! Positions in the ring buffer are offsets that grow (and wrap around) for
+//! the whole lifetime of the kernel. A reader remembers the offset it stopped
+//! at, and gets the records logged after it on its next read.
+//!
+//! [read_kernel_log]: crate::syscalls::read_kernel_log
+
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::Level;
+use plain::Plain;
+use sunrise_libkern::dmesg::{DmesgRecordHeader, MAX_NAME_LEN, MAX_MESSAGE_LEN};
+use crate::event::Waitable;
+use crate::process::ThreadStruct;
+use crate::scheduler;
+use crate::sync::SpinLockIRQ;
+use crate::timer;
+
+/// Size of the ring buffer. Must be a power of two, so offsets keep pointing
+/// to the same index when they wrap around.
+const DMESG_SIZE: usize = 0x10000;
+
+/// Size of the header of a record.
+const HEADER_SIZE: usize = size_of::<DmesgRecordHeader>();
+
+/// The kernel log ring buffer.
+struct Dmesg {
+    /// The records.
+    buf: [u8; DMESG_SIZE],
+    /// Offset at which the next record will be written.
+    head: usize,
+    /// Offset of the oldest record.
+    tail: usize,
+}
+
+/// The kernel log ring buffer.
+static DMESG: SpinLockIRQ<Dmesg> = SpinLockIRQ::new(Dmesg {
+    buf: [0; DMESG_SIZE],
+    head: 0,
+    tail: 0,
+});
+
+/// Copy of the head of [DMESG], so [KernelLogEvent] can check for new records
+/// without locking.
+static DMESG_HEAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Threads waiting on a [KernelLogEvent], woken up by the next record.
+static DMESG_WAITERS: SpinLockIRQ<Vec<Arc<ThreadStruct>>> = SpinLockIRQ::new(Vec::new());
+
+impl Dmesg {
+    /// Writes `data` at `offset` in the ring buffer.
+    fn write_at(&mut self, offset: usize, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.buf[offset.wrapping_add(i) % DMESG_SIZE] = *byte;
+        }
+    }
+
+    /// Reads `data.len()` bytes at `offset` in the ring buffer.
+    fn read_at(&self, offset: usize, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.buf[offset.wrapping_add(i) % DMESG_SIZE];
+        }
+    }
+
+    /// Reads the header of the record at `offset`.
+    fn header_at(&self, offset: usize) -> DmesgRecordHeader {
+        let mut bytes = [0; HEADER_SIZE];
+        self.read_at(offset, &mut bytes);
+        let mut header = DmesgRecordHeader::default();
+        header.copy_from_bytes(&bytes).expect("Header buffer is large enough");
+        header
+    }
+
+    /// Drops the oldest records until `size` bytes are free after the head.
+    fn reserve(&mut self, size: usize) {
+        while self.head.wrapping_sub(self.tail) + size > DMESG_SIZE {
+            let header = self.header_at(self.tail);
+            self.tail = self.tail.wrapping_add(header.size as usize);
+        }
+    }
+
+    /// Appends a record, dropping the oldest records if the ring buffer is
+    /// full. Returns the new head.
+    fn push(&mut self, level: Level, target: &str, process_name: &str, args: &fmt::Arguments<'_>) -> usize {
+        let start = self.head;
+        let mut writer = RecordWriter {
+            dmesg: self,
+            pos: start.wrapping_add(HEADER_SIZE),
+            written: 0,
+            limit: MAX_NAME_LEN,
+        };
+        let _ = writer.write_str(target);
+        let target_len = writer.next_field(MAX_NAME_LEN);
+        let _ = writer.write_str(process_name);
+        let process_len = writer.next_field(MAX_MESSAGE_LEN);
+        let _ = writer.write_fmt(*args);
+        let message_len = writer.next_field(0);
+        let end = writer.pos;
+
+        let header = DmesgRecordHeader {
+            size: end.wrapping_sub(start) as u32,
+            level: level as u32,
+            timestamp_ns: timer::get_time_since_boot_ns(),
+            target_len: target_len as u32,
+            process_len: process_len as u32,
+            message_len: message_len as u32,
+            reserved: 0,
+        };
+        let header_bytes = unsafe {
+            // Safety: DmesgRecordHeader is repr(C) and has no implicit padding.
+            plain::as_bytes(&header)
+        };
+        self.write_at(start, header_bytes);
+        self.head = end;
+        end
+    }
+
+    /// Copies the records logged after `cursor` to `out`. See [read].
+    fn read(&self, cursor: usize, out: &mut [u8]) -> (usize, usize) {
+        let mut cursor = if self.head.wrapping_sub(cursor) > self.head.wrapping_sub(self.tail) {
+            self.tail
+        } else {
+            cursor
+        };
+
+        let mut written = 0;
+        while cursor != self.head {
+            let size = self.header_at(cursor).size as usize;
+            if size < HEADER_SIZE || size > self.head.wrapping_sub(cursor) {
+                // The cursor does not point to a record. Skip to the newest ones.
+                cursor = self.head;
+                break;
+            }
+            if written + size > out.len() {
+                break;
+            }
+            self.read_at(cursor, &mut out[written..written + size]);
+            written += size;
+            cursor = cursor.wrapping_add(size);
+        }
+        (cursor, written)
+    }
+}
+
+/// Writes the body of a record after the head of the ring buffer, truncating
+/// it when it reaches `limit` bytes.
+struct RecordWriter<'a> {
+    /// The ring buffer.
+    dmesg: &'a mut Dmesg,
+    /// Offset of the end of the body written so far.
+    pos: usize,
+    /// Number of bytes written in the current field.
+    written: usize,
+    /// Maximum length of the current field.
+    limit: usize,
+}
+
+impl<'a> RecordWriter<'a> {
+    /// Starts a new field of the record, of at most `limit` bytes. Returns the
+    /// length of the previous field.
+    fn next_field(&mut self, limit: usize) -> usize {
+        let written = self.written;
+        self.written = 0;
+        self.limit = limit;
+        written
+    }
+}
+
+impl<'a> Write for RecordWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = core::cmp::min(s.len(), self.limit - self.written);
+        let size = self.pos.wrapping_sub(self.dmesg.head) + len;
+        self.dmesg.reserve(size);
+        self.dmesg.write_at(self.pos, &s.as_bytes()[..len]);
+        self.pos = self.pos.wrapping_add(len);
+        self.written += len;
+        Ok(())
+    }
+}
+
+/// Appends a record to the ring buffer, dropping the oldest records if it is
+/// full, and wakes up the threads waiting on a [KernelLogEvent].
+///
+/// The record is silently dropped if the ring buffer is already locked, which
+/// happens if we panic while logging.
+pub fn push(level: Level, target: &str, process_name: &str, args: &fmt::Arguments<'_>) {
+    let head = match DMESG.try_lock() {
+        Some(mut dmesg) => dmesg.push(level, target, process_name, args),
+        None => return
+    };
+    DMESG_HEAD.store(head, Ordering::SeqCst);
+    wake_waiters();
+}
+
+/// Wakes up the threads waiting on a [KernelLogEvent].
+///
+/// We log from the scheduler too, while it holds the schedule queue. So this
+/// doesn't wait for the queue: the threads it can't wake up now are left in
+/// [DMESG_WAITERS], for the next record.
+///
+/// The current thread is left there too. It can only be logging between its
+/// registration and its unscheduling, and must still be woken up afterwards.
+fn wake_waiters() {
+    let mut waiters = match DMESG_WAITERS.try_lock() {
+        Some(waiters) => waiters,
+        None => return
+    };
+    let current = scheduler::try_get_current_thread();
+    let mut queue_locked = false;
+    waiters.retain(|thread| {
+        if queue_locked || current.as_ref().map_or(false, |current| Arc::ptr_eq(current, thread)) {
+            return true;
+        }
+        queue_locked = !scheduler::try_add_to_schedule_queue(thread);
+        queue_locked
+    });
+}
+
+/// Copies the records logged after `cursor` to `out`, stopping at the first
+/// one that does not fit. Returns the cursor to use for the next read, and the
+/// number of bytes written to `out`.
+///
+/// If the records after `cursor` were already dropped, starts from the oldest
+/// record still in the ring buffer. In particular, a reader can pass 0 on its
+/// first read to get all the records.
+pub fn read(cursor: usize, out: &mut [u8]) -> (usize, usize) {
+    DMESG.lock().read(cursor, out)
+}
+
+/// An event signaled when new records were added to the kernel log.
+///
+/// [push] wakes up the threads waiting on it, which then check whether the log
+/// changed since the last time this event was signaled.
+#[derive(Debug)]
+pub struct KernelLogEvent {
+    /// Head of the kernel log the last time this event was signaled.
+    seen: AtomicUsize,
+}
+
+impl Default for KernelLogEvent {
+    /// Creates an event that will get signaled by the next records.
+    fn default() -> KernelLogEvent {
+        KernelLogEvent {
+            seen: AtomicUsize::new(DMESG_HEAD.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+impl Waitable for KernelLogEvent {
+    fn is_signaled(&self) -> bool {
+        let head = DMESG_HEAD.load(Ordering::SeqCst);
+        self.seen.swap(head, Ordering::SeqCst) != head
+    }
+
+    fn register(&self) {
+        let curthread = scheduler::get_current_thread();
+        let mut waiters = DMESG_WAITERS.lock();
+        if !waiters.iter().any(|thread| Arc::ptr_eq(&curthread, thread)) {
+            waiters.push(curthread);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use log::Level;
+    use sunrise_libkern::dmesg::{parse_records, MAX_RECORD_SIZE};
+
+    use super::{Dmesg, DMESG_SIZE, HEADER_SIZE};
+
+    /// An empty ring buffer, whose offsets start at `start`.
+    fn dmesg(start: usize) -> Box<Dmesg> {
+        Box::new(Dmesg {
+            buf: [0; DMESG_SIZE],
+            head: start,
+            tail: start,
+        })
+    }
+
+    /// Reads all the records after `cursor`. Returns the new cursor, and the
+    /// messages of the records.
+    fn read_messages(dmesg: &Dmesg, cursor: usize) -> (usize, Vec<Vec<u8>>) {
+        let mut buf = vec![0; DMESG_SIZE];
+        let (cursor, len) = dmesg.read(cursor, &mut buf);
+        let messages = parse_records(&buf[..len]).map(|record| record.message.to_vec()).collect();
+        (cursor, messages)
+    }
+
+    #[test]
+    fn push_read() {
+        let mut dmesg = dmesg(0);
+        let head = dmesg.push(Level::Warn, "kernel", "sm", &format_args!("hello {}", 42));
+        assert_eq!(head, dmesg.head);
+
+        let mut buf = vec![0; MAX_RECORD_SIZE];
+        let (cursor, len) = dmesg.read(0, &mut buf);
+        assert_eq!((cursor, len), (head, head));
+
+        let records = parse_records(&buf[..len]).collect::<Vec<_>>();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].header.level, Level::Warn as u32);
+        assert_eq!(records[0].target, b"kernel");
+        assert_eq!(records[0].process, b"sm");
+        assert_eq!(records[0].message, b"hello 42");
+
+        // Nothing new since.
+        assert_eq!(dmesg.read(cursor, &mut buf), (cursor, 0));
+    }
+
+    #[test]
+    fn read_stops_at_full_buffer() {
+        let mut dmesg = dmesg(0);
+        let first = dmesg.push(Level::Info, "kernel", "", &format_args!("first"));
+        dmesg.push(Level::Info, "kernel", "", &format_args!("second"));
+
+        // Only the first record fits.
+        let mut buf = vec![0; first + 1];
+        assert_eq!(dmesg.read(0, &mut buf), (first, first));
+        let (_, messages) = read_messages(&dmesg, first);
+        assert_eq!(messages, vec![b"second".to_vec()]);
+
+        // Not even the header fits.
+        assert_eq!(dmesg.read(0, &mut buf[..HEADER_SIZE]), (0, 0));
+    }
+
+    #[test]
+    fn truncates_fields() {
+        let mut dmesg = dmesg(0);
+        let long = "a".repeat(0x1000);
+        dmesg.push(Level::Info, &long, &long, &format_args!("{}", long));
+
+        let mut buf = vec![0; MAX_RECORD_SIZE];
+        let (_, len) = dmesg.read(0, &mut buf);
+        assert_eq!(len, MAX_RECORD_SIZE);
+    }
+
+    #[test]
+    fn reserve_drops_oldest() {
+        let mut dmesg = dmesg(0);
+        let message = "m".repeat(0x3F0);
+        for i in 0..200 {
+            dmesg.push(Level::Info, "kernel", "", &format_args!("{:03}{}", i, message));
+            assert!(dmesg.head.wrapping_sub(dmesg.tail) <= DMESG_SIZE);
+        }
+        assert!(dmesg.head > DMESG_SIZE, "the ring buffer should have wrapped around");
+
+        let (cursor, messages) = read_messages(&dmesg, dmesg.tail);
+        assert_eq!(cursor, dmesg.head);
+        // The records that are left are the newest ones, in order, and intact
+        // even if they straddle the end of the buffer.
+        let first = 200 - messages.len();
+        for (i, msg) in messages.iter().enumerate() {
+            assert_eq!(msg, format!("{:03}{}", first + i, message).as_bytes());
+        }
+    }
+
+    #[test]
+    fn lagging_cursor_restarts_from_tail() {
+        let mut dmesg = dmesg(0);
+        let message = "m".repeat(0x3F0);
+        let stale = dmesg.push(Level::Info, "kernel", "", &format_args!("{}", message));
+        for _ in 0..100 {
+            dmesg.push(Level::Info, "kernel", "", &format_args!("{}", message));
+        }
+        assert!(dmesg.tail > stale, "the record after the cursor should have been dropped");
+
+        let mut buf = vec![0; MAX_RECORD_SIZE];
+        let (cursor, len) = dmesg.read(stale, &mut buf);
+        let (expected_cursor, expected_len) = dmesg.read(dmesg.tail, &mut buf);
+        assert_eq!((cursor, len), (expected_cursor, expected_len));
+        assert_ne!(len, 0);
+    }
+
+    #[test]
+    fn offsets_wrap_around() {
+        let start = usize::max_value() - 0x10;
+        let mut dmesg = dmesg(start);
+        dmesg.push(Level::Info, "kernel", "", &format_args!("first"));
+        let head = dmesg.push(Level::Info, "kernel", "", &format_args!("second"));
+        assert!(head < start, "the offsets should have wrapped around");
+
+        let (cursor, messages) = read_messages(&dmesg, start);
+        assert_eq!(cursor, head);
+        assert_eq!(messages, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+}
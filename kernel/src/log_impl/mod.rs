@@ -1,22 +1,53 @@
 //! A simple log implementation based on env_logger
+//!
+//! Records are printed on the serial port, and kept in the [dmesg] ring
+//! buffer.
+//!
+//! Records are filtered by a global filter, parsed from the kernel cmdline.
+//! It can be replaced at runtime, and processes can be given their own filter,
+//! which then applies to the records logged in their context, both by
+//! themselves and by the kernel. See [set_filter].
 #![allow(clippy::missing_docs_in_private_items)]
 mod filter;
+pub mod dmesg;
+
+pub use self::dmesg::KernelLogEvent;
 
 use log::{self, Log, Metadata, Record, LevelFilter};
 use crate::devices::rs232::SerialLogger;
 use core::fmt::Write;
+use alloc::string::String;
+use alloc::vec::Vec;
 use crate::i386::multiboot::get_boot_information;
-use crate::sync::{SpinRwLock, Once};
+use crate::sync::{SpinLockIRQ, Once};
 use crate::scheduler;
 
+/// The filters are behind IRQ-safe locks: interrupt handlers log too, and
+/// would deadlock if they interrupted [set_filter] while it held a lock.
 struct Logger {
-    filter: SpinRwLock<filter::Filter>
+    filter: SpinLockIRQ<filter::Filter>,
+    /// Filters of the processes that have their own, by process name.
+    process_filters: SpinLockIRQ<Vec<(String, filter::Filter)>>,
+}
+
+impl Logger {
+    /// Checks a record against the filter of the current process, or the
+    /// global filter if it doesn't have one.
+    fn check_filter(&self, check: impl Fn(&filter::Filter) -> bool) -> bool {
+        if let Some(thread) = scheduler::try_get_current_thread() {
+            let process_filters = self.process_filters.lock();
+            if let Some((_, filter)) = process_filters.iter().find(|(name, _)| *name == thread.process.name) {
+                return check(filter);
+            }
+        }
+        check(&self.filter.lock())
+    }
 }
 
 #[allow(unused_must_use)]
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata<'_>) -> bool {
-        self.filter.read().enabled(metadata)
+        self.check_filter(|filter| filter.enabled(metadata))
     }
 
     fn log(&self, record: &Record<'_>) {
@@ -28,11 +59,13 @@ impl Log for Logger {
             log::Level::Debug => SerialColor::Cyan,
             log::Level::Trace => SerialColor::White,
         });
-        if self.filter.read().matches(record) {
+        if self.check_filter(|filter| filter.matches(record)) {
             if let Some(thread) = scheduler::try_get_current_thread() {
                 writeln!(SerialLogger, "[{}{}{}] - {} - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), thread.process.name, record.args());
+                dmesg::push(record.level(), record.target(), &thread.process.name, record.args());
             } else {
                 writeln!(SerialLogger, "[{}{}{}] - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), record.args());
+                dmesg::push(record.level(), record.target(), "", record.args());
             }
         }
     }
@@ -47,7 +80,7 @@ pub fn early_init() {
     let filter = filter::Builder::new()
         .filter(None, LevelFilter::Info)
         .build();
-    log::set_logger(LOGGER.call_once(|| Logger { filter: SpinRwLock::new(filter) } ))
+    log::set_logger(LOGGER.call_once(|| Logger { filter: SpinLockIRQ::new(filter), process_filters: SpinLockIRQ::new(Vec::new()) } ))
         .expect("log_impl::init to be called before logger is initialized");
     log::set_max_level(LevelFilter::Trace);
     info!("Logging enabled");
@@ -58,5 +91,34 @@ pub fn init() {
     let logger = LOGGER.r#try().expect("early_init to be called before init");
     let cmdline = get_boot_information().command_line_tag().unwrap().command_line();
     let newfilter = filter::Builder::new().parse(cmdline).build();
-    *logger.filter.write() = newfilter;
+    let _oldfilter = core::mem::replace(&mut *logger.filter.lock(), newfilter);
+}
+
+/// Replaces the filter directives of the processes named `process_name`, or
+/// the global filter if it is None. The directives use the same syntax as the
+/// kernel cmdline, e.g. `info,sunrise_libuser::ipc=trace`.
+///
+/// Empty directives remove the filter of the processes, making them use the
+/// global filter again.
+pub fn set_filter(process_name: Option<&str>, directives: &str) {
+    let logger = LOGGER.r#try().expect("early_init to be called before set_filter");
+    // Build the filter before taking the locks, and drop the old one after
+    // releasing them: logging while holding them would deadlock.
+    let filter = filter::Builder::new().parse(directives).build();
+    match process_name {
+        None => {
+            let _oldfilter = core::mem::replace(&mut *logger.filter.lock(), filter);
+        },
+        Some(process_name) => {
+            let process_name = String::from(process_name);
+            let mut process_filters = logger.process_filters.lock();
+            let old = process_filters.iter().position(|(name, _)| *name == process_name)
+                .map(|idx| process_filters.swap_remove(idx));
+            if !directives.is_empty() {
+                process_filters.push((process_name, filter));
+            }
+            drop(process_filters);
+            drop(old);
+        }
+    }
 }
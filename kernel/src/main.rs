@@ -66,6 +66,7 @@ pub mod heap_allocator;
 pub mod devices;
 pub mod sync;
 pub mod timer;
+pub mod trace;
 pub mod process;
 pub mod scheduler;
 pub mod mem;
@@ -75,6 +76,8 @@ pub mod utils;
 pub mod checks;
 pub mod cpu_locals;
 pub mod panic;
+#[cfg(feature = "kernel-selftest")]
+pub mod selftest;
 
 #[cfg(target_os = "none")]
 // Make rust happy about rust_oom being no_mangle...
@@ -92,9 +95,19 @@ use crate::i386::stack;
 use crate::paging::PAGE_SIZE;
 use crate::mem::VirtualAddress;
 use crate::process::ProcessStruct;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::cpu_locals::init_cpu_locals;
 use sunrise_libkern::process::*;
 
+/// Manifest pinning the hashes of the builtin modules, by module name. See
+/// [utils::manifest].
+///
+/// It is embedded at build time from the file `SUNRISE_BUILTINS_MANIFEST`
+/// points to. When it is empty, the kernel refuses to boot, unless it is built
+/// with the `insecure` feature, in which case builtin modules are not verified.
+static BUILTINS_MANIFEST: &str = include_str!(concat!(env!("OUT_DIR"), "/builtins.sha256"));
+
 /// Forces a double fault by stack overflowing.
 ///
 /// Can be used to manually check the double fault task gate is configured correctly.
@@ -127,6 +140,11 @@ unsafe fn force_double_fault() {
 /// which either provide necessary services for loading a process, or may define the list of other processes to launch (`boot`).
 ///
 /// We load their elf with a minimal [elf_loader], add them to the schedule queue, and run them as regular userspace processes.
+/// Their arguments are the command line of their module, e.g. `shell --serial`.
+///
+/// Modules that are not listed in the [BUILTINS_MANIFEST], or whose hash does not match it, are refused and not started.
+///
+/// When built with the `kernel-selftest` feature, the [selftest]s are run first.
 ///
 /// # Afterwards
 ///
@@ -135,14 +153,42 @@ unsafe fn force_double_fault() {
 ///
 /// From now on, the kernel's only job will be to respond to IRQs and serve syscalls.
 fn main() {
+    #[cfg(feature = "kernel-selftest")]
+    {
+        info!("Running the kernel self-tests");
+        selftest::run();
+    }
+
+    let manifest = utils::manifest::Manifest::new(BUILTINS_MANIFEST);
+    if manifest.is_empty() {
+        if cfg!(feature = "insecure") {
+            warn!("No builtins manifest, builtin modules are not verified");
+        } else {
+            panic!("No builtins manifest, refusing to start the builtin modules. Build the kernel with the builtins-manifest task, or with the insecure feature.");
+        }
+    }
+
     info!("Loading all the init processes");
+    // Built-ins are only started once they are all created, so their PIDs are
+    // contiguous, and known before any of them runs.
+    let mut builtins = Vec::new();
     for module in i386::multiboot::get_boot_information().module_tags().skip(1) {
-        info!("Loading {}", module.name());
+        // The command line of a module is its name, followed by the arguments
+        // of the built-in.
+        let name = module.name().split(' ').next().unwrap_or("");
+        info!("Loading {}", name);
         let mapped_module = elf_loader::map_grub_module(module)
-            .unwrap_or_else(|_| panic!("Unable to find available memory for module {}", module.name()));
+            .unwrap_or_else(|_| panic!("Unable to find available memory for module {}", name));
+
+        if !manifest.is_empty() {
+            if let Err(err) = manifest.verify(name, mapped_module.data()) {
+                error!("Refusing to start module {}: {}", name, err);
+                continue;
+            }
+        }
 
         let kip_header = elf_loader::get_kip_header(&mapped_module)
-            .unwrap_or_else(|| panic!("Unable to find KIP header for module {}", module.name()));
+            .unwrap_or_else(|| panic!("Unable to find KIP header for module {}", name));
 
         let mut flags = ProcInfoFlags(0);
         flags.set_address_space_type(ProcInfoAddrSpace::AS32Bit);
@@ -170,8 +216,21 @@ fn main() {
         {
                 let mut pmemlock = proc.pmemory.lock();
                 elf_loader::load_builtin(&mut pmemlock, &mapped_module, aslr_base);
+                elf_loader::load_builtin_args(&mut pmemlock, &mapped_module, aslr_base, module.name());
         };
 
+        if let Some(symbols) = elf_loader::get_symbols(&mapped_module, aslr_base) {
+            *proc.symbols.lock() = Some(Arc::new(symbols));
+        }
+
+        builtins.push((proc, kip_header));
+    }
+
+    if let (Some((first, _)), Some((last, _))) = (builtins.first(), builtins.last()) {
+        process::INITIAL_PROCESS_ID_RANGE.call_once(|| (first.pid, last.pid));
+    }
+
+    for (proc, kip_header) in builtins {
         ProcessStruct::start(&proc, u32::from(kip_header.main_thread_priority), kip_header.stack_page_count as usize * PAGE_SIZE)
             .expect("failed creating process");
     }
@@ -256,6 +315,10 @@ pub extern "C" fn common_start(multiboot_info_addr: usize) -> ! {
 
     devices::init_timer();
 
+    i386::power::init();
+
+    trace::init();
+
     //info!("Disable timer interrupt");
     //devices::pic::get().mask(0);
 
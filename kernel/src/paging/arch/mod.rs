@@ -1,4 +1,21 @@
 //! Arch-specific implementations of paging
+//!
+//! Only i386 is supported for now.
+
+// TODO: x86_64 (long mode) support
+// BODY: Running 64-bit titles needs a whole x86_64 arch backend, not only
+// BODY: 4-level paging here:
+// BODY:
+// BODY: - a long mode `bootstrap`, setting up the initial page tables and the
+// BODY:   64-bit GDT,
+// BODY: - a `syscall`/`sysret` entry point, replacing the `int 0x80` one,
+// BODY: - 64-bit versions of the interrupt handlers, the context switch and the
+// BODY:   TLS segment handling of `ThreadStruct`,
+// BODY: - `x86_64-unknown-none` and `x86_64-unknown-sunrise-user` targets,
+// BODY: - loader support for ELF64 titles, started with `ProcInfoAddrSpace::AS39Bit`.
+// BODY:
+// BODY: Until then, the loader refuses ELF64 titles and svcCreateProcess refuses
+// BODY: 64-bit processes.
 
 mod i386;
 
@@ -60,6 +60,11 @@ impl UserspaceBookkeeping {
         UserspaceBookkeeping { mappings }
     }
 
+    /// Iterates over all the occupied mappings, in ascending address order.
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.values()
+    }
+
     /// Returns the mapping `address` falls into, or if it is available,
     /// the first following mapping.
     ///
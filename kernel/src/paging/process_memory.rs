@@ -324,6 +324,128 @@ impl ProcessMemory {
         self.userspace_bookkeping.mapping_at(address)
     }
 
+    /// Checks whether `address` falls in the guard page of a stack, i.e. the
+    /// page right below a Stack mapping, if it is either a frameless Reserved
+    /// mapping or Unmapped.
+    ///
+    /// The kernel reserves a guard page below the stack of the main thread.
+    /// The other threads' stacks are mapped by userspace with `svcMapMemory`,
+    /// which leaves the page below them unmapped.
+    ///
+    /// Used by the page fault handler to tell stack overflows apart from other
+    /// invalid accesses.
+    pub fn is_stack_guard(&self, address: VirtualAddress) -> bool {
+        let stack_addr = match address.floor().checked_add(PAGE_SIZE) {
+            Some(stack_addr) => stack_addr,
+            None => return false
+        };
+        if self.query_memory(stack_addr).mapping().state().ty() != MemoryType::Stack {
+            return false
+        }
+        let guard = self.query_memory(address);
+        let guard = guard.mapping();
+        match (guard.frames(), guard.state().ty()) {
+            (MappingFrames::None, MemoryType::Reserved) |
+            (MappingFrames::None, MemoryType::Unmapped) => true,
+            _ => false
+        }
+    }
+
+    /// Unmaps the `address..address + length` range, splitting the mappings
+    /// straddling its edges: their parts outside of the range are mapped back
+    /// as they were.
+    ///
+    /// Returns the mappings that covered the range, cut to it, in order. They
+    /// are no longer mapped, and can be mapped back with
+    /// [map_partial_shared_mapping](ProcessMemory::map_partial_shared_mapping)
+    /// from their frames, phys_offset and length.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is not fully mapped with shared frames. The caller
+    /// should check the state of the range beforehand.
+    pub fn unmap_shared(&mut self, address: VirtualAddress, length: usize) -> Vec<Mapping> {
+        let mut removed = Vec::new();
+        let end_addr = address + length;
+        let mut addr = address;
+
+        while addr < end_addr {
+            let (mapping_addr, mapping_length) = {
+                let meminfo = self.query_memory(addr);
+                (meminfo.mapping().address(), meminfo.mapping().length())
+            };
+            let mapping = self.unmap(mapping_addr, mapping_length).expect("Unmap can't fail.");
+
+            let frames = if let MappingFrames::Shared(frames) = mapping.frames() {
+                frames
+            } else {
+                panic!("Non-shared frames in mapping {:?}", mapping);
+            };
+            let mapping_end = mapping.address() + mapping.length();
+
+            // Remap the parts outside of the range.
+            if mapping.address() < addr {
+                self.map_partial_shared_mapping(frames.clone(), mapping.address(), mapping.phys_offset(), addr - mapping.address(), mapping.state().ty(), mapping.flags()).expect("Can't fail");
+            }
+            if mapping_end > end_addr {
+                let phys_offset = mapping.phys_offset() + (end_addr - mapping.address());
+                self.map_partial_shared_mapping(frames.clone(), end_addr, phys_offset, mapping_end - end_addr, mapping.state().ty(), mapping.flags()).expect("Can't fail");
+            }
+
+            let offset = mapping.phys_offset() + (addr - mapping.address());
+            let curlen = core::cmp::min(end_addr - addr, mapping_end - addr);
+            removed.push(Mapping::new(addr, MappingFrames::Shared(frames.clone()), offset, curlen, mapping.state().ty(), mapping.flags())
+                .expect("Can't fail"));
+
+            addr += curlen;
+        }
+
+        removed
+    }
+
+    /// Changes the permissions of the `address..address + length` range to
+    /// `flags`, splitting the mappings as necessary, and returns the frames
+    /// backing that range as a list of `(frames, phys_offset, length)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is not fully mapped with shared frames. The caller
+    /// should check the state of the range beforehand.
+    pub fn reprotect_shared(&mut self, address: VirtualAddress, length: usize, flags: MappingAccessRights) -> Vec<(Arc<SpinRwLock<Vec<PhysicalMemRegion>>>, usize, usize)> {
+        let mut chunks = Vec::new();
+        for mapping in self.unmap_shared(address, length) {
+            if let MappingFrames::Shared(frames) = mapping.frames() {
+                self.map_partial_shared_mapping(frames.clone(), mapping.address(), mapping.phys_offset(), mapping.length(), mapping.state().ty(), flags).expect("Can't fail");
+                chunks.push((frames.clone(), mapping.phys_offset(), mapping.length()));
+            }
+        }
+
+        chunks
+    }
+
+    /// Builds the list of physical pages backing the given range, akin to the
+    /// PageList of HOS/NX. Pages that are not backed by any frame (unmapped
+    /// memory, guard pages...) are not part of the list.
+    ///
+    /// Used to check two ranges map the exact same frames, e.g. when undoing a
+    /// `svcMapProcessMemory`.
+    pub fn page_list(&self, address: VirtualAddress, length: usize) -> Vec<PhysicalAddress> {
+        let mut pages = Vec::new();
+        let mut cur_addr = address;
+        let end_addr = address + length;
+        while cur_addr < end_addr {
+            let mem = self.query_memory(cur_addr);
+            let mapping = mem.mapping();
+            let offset_in_mapping = cur_addr - mapping.address();
+            let curlen = core::cmp::min(end_addr - cur_addr, mapping.length() - offset_in_mapping);
+            pages.extend(mapping.frames_it()
+                .skip(offset_in_mapping / PAGE_SIZE)
+                .take(curlen / PAGE_SIZE));
+            cur_addr = cur_addr + curlen;
+        }
+        pages
+    }
+
     /*/// Shrink the mapping at `address` to `new_size`.
     ///
     /// If `new_size` == 0, the mapping is unmapped entirely.
@@ -513,6 +635,50 @@ impl ProcessMemory {
         Ok(self.heap_base_address)
     }
 
+    /// Gets the size of the heap, in bytes.
+    ///
+    /// The heap may have been split by remapping parts of it elsewhere, so we
+    /// sum the size of every Heap mapping.
+    pub fn heap_size(&self) -> usize {
+        self.userspace_bookkeping.mappings()
+            .filter(|mapping| mapping.state().ty() == MemoryType::Heap)
+            .map(|mapping| mapping.length())
+            .sum()
+    }
+
+    /// Gets the amount of physical memory mapped in this address space, in
+    /// bytes.
+    ///
+    /// Frames aliased multiple times (by svcMapMemory, code memory...) are only
+    /// counted once. Device memory (Io and Normal mappings, used for MMIO and
+    /// the framebuffer) is not counted. Frames shared with other processes are
+    /// counted in every process mapping them.
+    pub fn resident_size(&self) -> usize {
+        let mut frames: Vec<PhysicalAddress> = self.userspace_bookkeping.mappings()
+            .filter(|mapping| match mapping.state().ty() {
+                MemoryType::Io | MemoryType::Normal => false,
+                _ => true
+            })
+            .flat_map(|mapping| mapping.frames_it())
+            .collect();
+        frames.sort_unstable();
+        frames.dedup();
+        frames.len() * PAGE_SIZE
+    }
+
+    /// Gets the amount of memory shared with other processes through shared
+    /// memory or IPC buffers, in bytes.
+    pub fn shared_size(&self) -> usize {
+        self.userspace_bookkeping.mappings()
+            .filter(|mapping| match mapping.state().ty() {
+                MemoryType::SharedMemory | MemoryType::Ipc |
+                MemoryType::NonSecureIpc | MemoryType::NonDeviceIpc => true,
+                _ => false
+            })
+            .map(|mapping| mapping.length())
+            .sum()
+    }
+
     /// Switches to this process memory
     pub fn switch_to(&mut self) {
         self.table_hierarchy.switch_to();
@@ -8,7 +8,10 @@ use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use crate::event::{IRQEvent, ReadableEvent, WritableEvent, Waitable};
-use crate::sync::{SpinLockIRQ, SpinLock, Mutex};
+use crate::log_impl::KernelLogEvent;
+use crate::timer::{self, Timer};
+use crate::i386::power::PowerButtonEvent;
+use crate::sync::{SpinLockIRQ, SpinLock, Mutex, Once};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::scheduler;
 use crate::error::{KernelError, UserspaceError};
@@ -21,12 +24,16 @@ use crate::sync::SpinRwLock;
 use atomic::Atomic;
 
 pub mod thread_local_storage;
+pub mod code_memory;
+pub mod symbols;
 mod capabilities;
-pub use self::capabilities::ProcessCapabilities;
+pub use self::capabilities::{ProcessCapabilities, IoBitmap, MAX_HANDLE_TABLE_SIZE};
 use crate::paging::{InactiveHierarchy, InactiveHierarchyTrait, PAGE_SIZE, MappingAccessRights};
 use self::thread_local_storage::TLSManager;
+use self::code_memory::CodeMemory;
+use self::symbols::SymbolMap;
 use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
-use sunrise_libkern::process::{ProcessState, ProcInfo};
+use sunrise_libkern::process::{ProcessState, ProcInfo, ProcessMemoryUsage, ExceptionInfo, CrashReport, HandleKind, HandleInfo};
 use sunrise_libkern::MemoryType;
 
 /// List of processes currently running on the system.
@@ -109,6 +116,8 @@ pub struct ProcessStruct {
     pub pid:                  usize,
     /// A name for this process.
     pub name:                 String,
+    /// The title id of this process, given at its creation.
+    pub title_id:             u64,
     /// The memory view of this process. Shared among the threads.
     pub pmemory:              Mutex<ProcessMemory>,
     /// The handles of this process. Shared among the threads.
@@ -129,6 +138,40 @@ pub struct ProcessStruct {
 
     /// Tracks used and free allocated Thread Local Storage regions of this process.
     pub tls_manager: Mutex<TLSManager>,
+
+    /// The userspace exception handler registered with
+    /// [crate::syscalls::set_exception_handler], if any.
+    pub exception_handler: SpinLock<Option<ExceptionHandler>>,
+
+    /// Why this process crashed, if it did. Set by
+    /// [ProcessStruct::crash_current_process].
+    pub crash_report: SpinLock<Option<CrashReport>>,
+
+    /// The functions of this process, used to symbolize its backtraces. See
+    /// the [symbols] module.
+    pub symbols: SpinLock<Option<Arc<SymbolMap>>>,
+}
+
+/// A userspace exception handler.
+///
+/// When a thread causes an exception, the kernel writes an [ExceptionInfo] on
+/// the handler's stack, and makes the thread jump to the handler's entrypoint.
+/// The handler then calls [crate::syscalls::return_from_exception] to resume
+/// the thread or let the process crash.
+///
+/// There is a single handler stack per process, so only one thread may run
+/// the handler at a time. An exception in another thread while the handler
+/// runs crashes the process.
+#[derive(Debug)]
+pub struct ExceptionHandler {
+    /// Address of the handler. Called with a pointer to the [ExceptionInfo] as
+    /// its sole cdecl argument.
+    pub entrypoint: VirtualAddress,
+    /// Top of the stack the handler runs on.
+    pub stack_top: VirtualAddress,
+    /// The exception currently being handled: the id of the thread running
+    /// the handler, where its [ExceptionInfo] was written, and a copy of it.
+    pub current: Option<(usize, VirtualAddress, ExceptionInfo)>,
 }
 
 /// Next available PID.
@@ -136,9 +179,21 @@ pub struct ProcessStruct {
 /// PIDs are just allocated sequentially in ascending order, and reaching usize::max_value() causes a panic.
 static NEXT_PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
 
+/// Smallest and biggest PIDs of the kernel built-ins, set once they are all
+/// created, before any of them is started.
+pub static INITIAL_PROCESS_ID_RANGE: Once<(usize, usize)> = Once::new();
+
+/// Next available thread ID.
+///
+/// TIDs are allocated sequentially in ascending order, and are unique across all processes.
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(0);
+
 /// The struct representing a thread. A process may own multiple threads.
 #[derive(Debug)]
 pub struct ThreadStruct {
+    /// The unique id of this thread.
+    pub tid: usize,
+
     /// The state of this thread.
     pub state: Atomic<ThreadState>,
 
@@ -180,7 +235,34 @@ pub struct ThreadStruct {
     /// Thread state event
     ///
     /// This is used when signaling that this thread as exited.
-    state_event: ThreadStateEvent
+    state_event: ThreadStateEvent,
+
+    /// Whether userspace asked for this thread to be suspended. See
+    /// [ThreadStruct::set_suspended].
+    suspension: SpinLockIRQ<ThreadSuspension>,
+}
+
+/// Suspension state of a thread, set through SetThreadActivity or
+/// SetProcessActivity.
+///
+/// A suspended thread keeps running until it attempts to return to userspace,
+/// at which point it parks itself in [ThreadStruct::wait_while_suspended].
+struct ThreadSuspension {
+    /// Whether the thread should stop before returning to userspace.
+    suspended: bool,
+    /// The thread itself, if it is currently parked. Taken and rescheduled when
+    /// the thread is resumed.
+    parked: Option<Arc<ThreadStruct>>,
+}
+
+impl core::fmt::Debug for ThreadSuspension {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Don't print the parked thread, it would recurse into ourselves.
+        f.debug_struct("ThreadSuspension")
+            .field("suspended", &self.suspended)
+            .field("parked", &self.parked.is_some())
+            .finish()
+    }
 }
 
 /// A handle to a userspace-accessible resource.
@@ -225,6 +307,20 @@ pub enum Handle {
     /// memory, which means the memory will only get freed once all handles to
     /// it are dropped.
     SharedMemory(Arc<SpinRwLock<Vec<PhysicalMemRegion>>>),
+    /// A memory region that can be aliased as both RW- and R-X, used to
+    /// generate code at runtime. See [code_memory] for more information.
+    CodeMemory(Arc<CodeMemory>),
+    /// A debug session on a process, created with DebugActiveProcess. Allows
+    /// inspecting the threads of the process.
+    Debug(Arc<ProcessStruct>),
+    /// An event signaled when records are added to the kernel log. See
+    /// [crate::log_impl::dmesg].
+    KernelLogEvent(KernelLogEvent),
+    /// A timer, signaled at a deadline, and optionally periodically after it.
+    Timer(Timer),
+    /// An event signaled when the power button is pressed. See
+    /// [crate::i386::power].
+    PowerButtonEvent(PowerButtonEvent),
 }
 
 /// The underlying shared object of a [Weak<ThreadStrct>].
@@ -269,7 +365,11 @@ impl Handle {
         match *self {
             Handle::ReadableEvent(ref waitable) => Ok(waitable),
             Handle::InterruptEvent(ref waitable) => Ok(waitable),
+            Handle::KernelLogEvent(ref waitable) => Ok(waitable),
+            Handle::Timer(ref waitable) => Ok(waitable),
+            Handle::PowerButtonEvent(ref waitable) => Ok(waitable),
             Handle::ServerPort(ref serverport) => Ok(serverport),
+            Handle::ClientPort(ref clientport) => Ok(clientport),
             Handle::ServerSession(ref serversession) => Ok(serversession),
             Handle::Thread(ref thread) => Ok(thread),
             Handle::Process(ref process) => Ok(process),
@@ -348,6 +448,67 @@ impl Handle {
             Err(UserspaceError::InvalidHandle)
         }
     }
+
+    /// Casts the handle as an Arc<[CodeMemory]>, or returns a `UserspaceError`.
+    pub fn as_code_memory(&self) -> Result<Arc<CodeMemory>, UserspaceError> {
+        if let Handle::CodeMemory(ref s) = *self {
+            Ok((*s).clone())
+        } else {
+            Err(UserspaceError::InvalidHandle)
+        }
+    }
+
+    /// Casts the handle as the Arc<[ProcessStruct]> being debugged, or returns
+    /// a `UserspaceError`.
+    pub fn as_debug(&self) -> Result<Arc<ProcessStruct>, UserspaceError> {
+        if let Handle::Debug(ref s) = *self {
+            Ok((*s).clone())
+        } else {
+            Err(UserspaceError::InvalidHandle)
+        }
+    }
+
+    /// Casts the handle as a [Timer], or returns a `UserspaceError`.
+    pub fn as_timer(&self) -> Result<&Timer, UserspaceError> {
+        if let Handle::Timer(ref s) = *self {
+            Ok(s)
+        } else {
+            Err(UserspaceError::InvalidHandle)
+        }
+    }
+
+    /// Gets the kind of Kernel Object this handle points to.
+    pub fn kind(&self) -> HandleKind {
+        match self {
+            Handle::InterruptEvent(_) => HandleKind::InterruptEvent,
+            Handle::ReadableEvent(_) => HandleKind::ReadableEvent,
+            Handle::WritableEvent(_) => HandleKind::WritableEvent,
+            Handle::ServerPort(_) => HandleKind::ServerPort,
+            Handle::ClientPort(_) => HandleKind::ClientPort,
+            Handle::ServerSession(_) => HandleKind::ServerSession,
+            Handle::ClientSession(_) => HandleKind::ClientSession,
+            Handle::Thread(_) => HandleKind::Thread,
+            Handle::Process(_) => HandleKind::Process,
+            Handle::SharedMemory(_) => HandleKind::SharedMemory,
+            Handle::CodeMemory(_) => HandleKind::CodeMemory,
+            Handle::Debug(_) => HandleKind::Debug,
+            Handle::KernelLogEvent(_) => HandleKind::KernelLogEvent,
+            Handle::Timer(_) => HandleKind::Timer,
+            Handle::PowerButtonEvent(_) => HandleKind::PowerButtonEvent,
+        }
+    }
+
+    /// Checks whether `other` is the other side of this handle, e.g. the
+    /// [ServerSession] of a [ClientSession].
+    pub fn is_peer_of(&self, other: &Handle) -> bool {
+        match (self, other) {
+            (Handle::ClientSession(client), Handle::ServerSession(server)) |
+            (Handle::ServerSession(server), Handle::ClientSession(client)) => client.is_client_of(server),
+            (Handle::ClientPort(client), Handle::ServerPort(server)) |
+            (Handle::ServerPort(server), Handle::ClientPort(client)) => client.is_client_of(server),
+            _ => false
+        }
+    }
 }
 
 /// Holds the table associating userspace handle numbers to a kernel [Handle].
@@ -368,6 +529,10 @@ impl Handle {
 /// In Sunrise, we do not yet have randomness, so the counter just starts from 1 and
 /// goes up.
 ///
+/// A process can only have as many handles open as its HANDLE_TABLE_SIZE kernel
+/// capability allows, [MAX_HANDLE_TABLE_SIZE] if it doesn't declare one. Adding
+/// more fails with `OutOfHandles`.
+///
 /// There exists two "meta-handles": 0xFFFF8000 and 0xFFFF8001, which always
 /// point to the current process and thread, respectively. Those handles are not
 /// *actually* stored in the handle table to avoid creating a reference cycle.
@@ -376,44 +541,84 @@ impl Handle {
 #[derive(Debug)]
 pub struct HandleTable {
     /// Internal mapping from a handle number to a Kernel Object.
-    table: BTreeMap<u32, Arc<Handle>>,
+    table: BTreeMap<u32, HandleTableEntry>,
     /// The next handle's ID.
-    counter: u32
+    counter: u32,
+    /// The maximum number of handles in the table, from the HANDLE_TABLE_SIZE
+    /// kernel capability.
+    max_handles: usize,
+}
+
+/// An entry of the [HandleTable].
+#[derive(Debug)]
+struct HandleTableEntry {
+    /// The Kernel Object.
+    handle: Arc<Handle>,
+    /// When the handle was added to the table, in nanoseconds since boot.
+    created_ns: u64,
 }
 
 impl Default for HandleTable {
-    /// Creates an empty handle table. Note that an empty handle table still
-    /// implicitly contains the meta-handles 0xFFFF8000 and 0xFFFF8001.
+    /// Creates an empty handle table, that can hold [MAX_HANDLE_TABLE_SIZE]
+    /// handles. Note that an empty handle table still implicitly contains the
+    /// meta-handles 0xFFFF8000 and 0xFFFF8001.
     fn default() -> Self {
+        HandleTable::new(MAX_HANDLE_TABLE_SIZE)
+    }
+}
+
+impl HandleTable {
+    /// Creates an empty handle table, that can hold `max_handles` handles, not
+    /// counting the meta-handles.
+    pub fn new(max_handles: usize) -> Self {
         HandleTable {
             table: BTreeMap::new(),
-            counter: 1
+            counter: 1,
+            max_handles,
         }
     }
-}
 
-impl HandleTable {
-    // TODO: HandleTable::add_handle should error if the table is full.
-    // BODY: HandleTable::add_handle currently assumes that insertion will always
-    // BODY: succeed. It does not implement any handle count limitations present
-    // BODY: in Horizon/NX. Furthermore, if the handle table is completely filled
-    // BODY: (e.g. there are 2^32 handles), the function will infinite loop.
-    // BODY: And as if that wasn't enough: it doesn't technically guarantee a
-    // BODY: handle will not get reused.
+    // TODO: HandleTable::add_handle doesn't guarantee handles are not reused.
+    // BODY: The handle counter only goes up, and overflows once 2^32 handles
+    // BODY: have been created. Horizon/NX avoids this with the randomized top
+    // BODY: 16 bits of the handle.
     /// Add a handle to the handle table, returning the userspace handle number
     /// associated to the given handle.
+    ///
+    /// # Errors
+    ///
+    /// - `OutOfHandles`
+    ///    - The table already holds as many handles as the process is allowed
+    ///      to have.
     #[allow(clippy::map_entry)]
-    pub fn add_handle(&mut self, handle: Arc<Handle>) -> u32 {
+    pub fn add_handle(&mut self, handle: Arc<Handle>) -> Result<u32, UserspaceError> {
+        self.check_free_handles(1)?;
+        let created_ns = timer::get_time_since_boot_ns();
         loop {
             let handlenum = self.counter;
             self.counter += 1;
             if !self.table.contains_key(&handlenum) {
-                self.table.insert(handlenum, handle);
-                break handlenum;
+                self.table.insert(handlenum, HandleTableEntry { handle, created_ns });
+                break Ok(handlenum);
             }
         }
     }
 
+    /// Checks that `count` more handles can be added to the table, so
+    /// operations adding multiple handles can fail before adding any.
+    ///
+    /// # Errors
+    ///
+    /// - `OutOfHandles`
+    ///    - The table cannot hold `count` more handles.
+    pub fn check_free_handles(&self, count: usize) -> Result<(), UserspaceError> {
+        if self.table.len() + count > self.max_handles {
+            Err(UserspaceError::OutOfHandles)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Gets the Kernel Handle associated with the given userspace handle number.
     ///
     /// # Errors
@@ -424,7 +629,7 @@ impl HandleTable {
         match handle {
             0xFFFF8000 => Ok(Arc::new(Handle::Thread(Arc::downgrade(&scheduler::get_current_thread())))),
             0xFFFF8001 => Ok(Arc::new(Handle::Process(scheduler::get_current_process()))),
-            handle => self.table.get(&handle).cloned().ok_or(UserspaceError::InvalidHandle)
+            handle => self.get_handle_no_alias(handle)
         }
     }
 
@@ -437,7 +642,7 @@ impl HandleTable {
     /// - `InvalidHandle`
     ///    - The provided handle does not exist in the handle table.
     pub fn get_handle_no_alias(&self, handle: u32) -> Result<Arc<Handle>, UserspaceError> {
-        self.table.get(&handle).cloned().ok_or(UserspaceError::InvalidHandle)
+        self.table.get(&handle).map(|entry| entry.handle.clone()).ok_or(UserspaceError::InvalidHandle)
     }
 
     /// Deletes the mapping from the given userspace handle number. Returns the
@@ -445,7 +650,19 @@ impl HandleTable {
     /// to another process in an IPC move).
     pub fn delete_handle(&mut self, handle: u32) -> Result<Arc<Handle>, UserspaceError> {
         // TODO: Handle 0xFFFF8000 and 0xFFFF8001 ?
-        self.table.remove(&handle).ok_or(UserspaceError::InvalidHandle)
+        self.table.remove(&handle).map(|entry| entry.handle).ok_or(UserspaceError::InvalidHandle)
+    }
+
+    /// Gets the number of handles in the table, not counting the meta-handles.
+    pub fn handle_count(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Iterates over the handles in the table, sorted by handle number. Yields
+    /// the handle number, the Kernel Handle, and when it was added to the
+    /// table in nanoseconds since boot.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Arc<Handle>, u64)> {
+        self.table.iter().map(|(handlenum, entry)| (*handlenum, &entry.handle, entry.created_ns))
     }
 }
 
@@ -513,6 +730,7 @@ impl ProcessStruct {
             ProcessStruct {
                 pid,
                 name: String::from_utf8_lossy(&procinfo.name).into_owned(),
+                title_id: procinfo.title_id,
                 entrypoint: VirtualAddress(procinfo.code_addr as usize),
                 pmemory,
                 state: Mutex::new(ProcessStateData {
@@ -522,8 +740,11 @@ impl ProcessStruct {
                     thread_maternity: Vec::new(),
                 }),
                 threads: SpinLockIRQ::new(Vec::new()),
-                phandles: SpinLockIRQ::new(HandleTable::default()),
+                phandles: SpinLockIRQ::new(HandleTable::new(capabilities.handle_table_size)),
                 tls_manager: Mutex::new(TLSManager::default()),
+                exception_handler: SpinLock::new(None),
+                crash_report: SpinLock::new(None),
+                symbols: SpinLock::new(None),
                 capabilities
             }
         );
@@ -560,8 +781,15 @@ impl ProcessStruct {
         // Allocate stack within new map region.
         let stack_size = sunrise_libutils::align_up(stack_size, PAGE_SIZE);
         let mut pmem = this.pmemory.lock();
-        let stack_addr = pmem.find_available_space(stack_size)?;
-        pmem.create_regular_mapping(stack_addr, stack_size, MemoryType::Stack, MappingAccessRights::u_rw())?;
+        // Put a guard page right below the stack, so overflowing it faults
+        // instead of silently corrupting whatever is mapped there.
+        let guard_addr = pmem.find_available_space(stack_size + PAGE_SIZE)?;
+        let stack_addr = guard_addr + PAGE_SIZE;
+        pmem.guard(guard_addr, PAGE_SIZE, MemoryType::Reserved)?;
+        if let Err(err) = pmem.create_regular_mapping(stack_addr, stack_size, MemoryType::Stack, MappingAccessRights::u_rw()) {
+            pmem.unmap(guard_addr, PAGE_SIZE).expect("Failed to remove the stack guard we just created");
+            return Err(err.into());
+        }
         core::mem::drop(pmem);
 
         // Set self.mainThreadStackSize = stack_size.
@@ -602,6 +830,63 @@ impl ProcessStruct {
         self.state.lock().state
     }
 
+    /// Gets a report of the memory and handles this process currently uses.
+    pub fn memory_usage(&self) -> ProcessMemoryUsage {
+        let (heap_size, resident_size, shared_size) = {
+            let pmemory = self.pmemory.lock();
+            (pmemory.heap_size(), pmemory.resident_size(), pmemory.shared_size())
+        };
+        ProcessMemoryUsage {
+            heap_size,
+            resident_size,
+            shared_size,
+            handle_count: self.phandles.lock().handle_count(),
+        }
+    }
+
+    /// Lists the open handles of this process, to chase handle leaks. See
+    /// [HandleInfo] for the details of each field.
+    ///
+    /// Finding the peer of sessions and ports goes through the handle table of
+    /// every process, so this is slow.
+    pub fn handle_infos(&self) -> Vec<HandleInfo> {
+        let now = timer::get_time_since_boot_ns();
+        let handles = self.phandles.lock().iter()
+            .map(|(handlenum, handle, created_ns)| (handlenum, handle.clone(), created_ns))
+            .collect::<Vec<_>>();
+        // Don't hold the lock while upgrading: dropping the last reference to a
+        // process takes it.
+        let process_list = PROCESS_LIST.lock().clone();
+        let processes = process_list.iter()
+            .filter_map(|process| process.upgrade())
+            .collect::<Vec<_>>();
+
+        handles.iter().map(|(handlenum, handle, created_ns)| {
+            let peer_pid = match &**handle {
+                Handle::Process(process) | Handle::Debug(process) => Some(process.pid),
+                Handle::Thread(thread) => thread.upgrade().map(|thread| thread.process.pid),
+                Handle::ServerPort(_) | Handle::ClientPort(_) |
+                Handle::ServerSession(_) | Handle::ClientSession(_) => processes.iter()
+                    .find(|process| process.phandles.lock().iter().any(|(_, other, _)| handle.is_peer_of(other)))
+                    .map(|process| process.pid),
+                _ => None
+            };
+            HandleInfo {
+                handle: *handlenum,
+                kind: handle.kind(),
+                peer_pid: peer_pid.map(|pid| pid as u64).unwrap_or(HandleInfo::NO_PEER),
+                age_ns: now.saturating_sub(*created_ns),
+            }
+        }).collect()
+    }
+
+    /// Gets the living threads of this process.
+    pub fn living_threads(&self) -> Vec<Arc<ThreadStruct>> {
+        self.threads.lock().iter()
+            .filter_map(|thread| thread.upgrade())
+            .collect()
+    }
+
     /// Clears the signaled state of this process.
     ///
     /// If the state is Exited, this function will return an error and the
@@ -657,6 +942,7 @@ impl ProcessStruct {
         ProcessStruct {
                 pid,
                 name: String::from("init"),
+                title_id: 0,
                 entrypoint: VirtualAddress(0),
                 pmemory: Mutex::new(pmemory),
                 threads: SpinLockIRQ::new(Vec::new()),
@@ -668,6 +954,9 @@ impl ProcessStruct {
                     thread_maternity: Vec::new(),
                 }),
                 tls_manager: Mutex::new(TLSManager::default()),
+                exception_handler: SpinLock::new(None),
+                crash_report: SpinLock::new(None),
+                symbols: SpinLock::new(None),
                 capabilities: ProcessCapabilities::default(),
         }
     }
@@ -717,6 +1006,27 @@ impl ProcessStruct {
         this.kill_subthreads(statelock);
     }
 
+    /// Records why the current process crashed, and kills it. See
+    /// [ProcessStruct::kill_current_process].
+    ///
+    /// Only the first crash is recorded, so another thread crashing while the
+    /// process dies does not hide the original cause.
+    pub fn crash_current_process(report: CrashReport) {
+        let is_first_crash = {
+            let this = scheduler::get_current_process();
+            let mut crash_report = this.crash_report.lock();
+            let is_first_crash = crash_report.is_none();
+            if is_first_crash {
+                *crash_report = Some(report);
+            }
+            is_first_crash
+        };
+        if is_first_crash {
+            crate::stack::dump_user_backtrace(report.exception.context.ebp, report.exception.context.eip);
+        }
+        Self::kill_current_process();
+    }
+
     /// Kill all the subthreads of this process, and set the state to exited.
     fn kill_subthreads(&self, mut statelock: crate::sync::mutex::MutexGuard<ProcessStateData>) {
         // We're going to make things a **lot** simpler. We're just
@@ -818,6 +1128,26 @@ impl ThreadStruct {
         Self::new_locked(belonging_process, &mut *belonging_process.state.lock(), ep, stack, arg)
     }
 
+    /// Creates a new kernel thread, which will run `entrypoint(arg)` in ring 0.
+    ///
+    /// The thread belongs to `belonging_process`, whose page tables it uses, and exits when
+    /// `entrypoint` returns. Kernel threads are not preempted, they must yield with
+    /// [scheduler::schedule] or by blocking.
+    ///
+    /// The returned thread will be in `Stopped` state, see [ThreadStruct::new].
+    pub fn new_kernel(belonging_process: &Arc<ProcessStruct>, entrypoint: fn(usize), arg: usize) -> Result<Weak<Self>, KernelError> {
+        let weak = Self::new(belonging_process, VirtualAddress(0), VirtualAddress(0), Some(arg))?;
+        let thread = weak.upgrade().ok_or(KernelError::ProcessKilled { backtrace: Backtrace::new() })?;
+
+        // overwrite the userspace jump prepared by new with a kernel one.
+        unsafe {
+            // Safety: The thread is still in the maternity, it has never been scheduled.
+            prepare_for_first_schedule_kernel(&thread, entrypoint, arg);
+        }
+
+        Ok(weak)
+    }
+
     /// See [ThreadStruct::new]. Takes the ProcessStruct.data pre-locked to
     /// avoid deadlocks in [ProcessStruct::start()].
     fn new_locked(belonging_process: &Arc<ProcessStruct>, belonging_process_data: &mut ProcessStateData, ep: VirtualAddress, stack: VirtualAddress, arg: Option<usize>) -> Result<Weak<Self>, KernelError> {
@@ -838,6 +1168,7 @@ impl ThreadStruct {
 
         let t = Arc::new(
             ThreadStruct {
+                tid: NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst),
                 state,
                 kstack,
                 hwcontext : empty_hwcontext,
@@ -848,6 +1179,10 @@ impl ThreadStruct {
                 state_event: ThreadStateEvent {
                     waiting_threads: SpinLock::new(Vec::new())
                 },
+                suspension: SpinLockIRQ::new(ThreadSuspension {
+                    suspended: false,
+                    parked: None,
+                }),
             }
         );
 
@@ -858,7 +1193,8 @@ impl ThreadStruct {
             None => {
                 debug_assert!(belonging_process.threads.lock().is_empty() &&
                               belonging_process_data.thread_maternity.is_empty(), "Argument shouldn't be None");
-                let handle = belonging_process.phandles.lock().add_handle(Arc::new(Handle::Thread(Arc::downgrade(&t))));
+                let handle = belonging_process.phandles.lock().add_handle(Arc::new(Handle::Thread(Arc::downgrade(&t))))
+                    .map_err(|_| KernelError::OutOfHandles { backtrace: Backtrace::new() })?;
 
                 (0, handle as usize)
             }
@@ -936,6 +1272,7 @@ impl ThreadStruct {
 
         let t = Arc::new(
             ThreadStruct {
+                tid: NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst),
                 state,
                 kstack,
                 hwcontext,
@@ -946,6 +1283,10 @@ impl ThreadStruct {
                 state_event: ThreadStateEvent {
                     waiting_threads: SpinLock::new(Vec::new())
                 },
+                suspension: SpinLockIRQ::new(ThreadSuspension {
+                    suspended: false,
+                    parked: None,
+                }),
             }
         );
 
@@ -1017,6 +1358,60 @@ impl ThreadStruct {
 
         scheduler::add_to_schedule_queue(this);
     }
+
+    /// Checks whether userspace asked for this thread to be suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.suspension.lock().suspended
+    }
+
+    /// Suspends or resumes this thread.
+    ///
+    /// A thread being suspended will stop the next time it attempts to return
+    /// to userspace. Resuming a thread that is parked puts it back in the
+    /// schedule queue.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidState`
+    ///    - Suspending an already suspended thread.
+    ///    - Resuming a thread that is not suspended.
+    pub fn set_suspended(&self, suspended: bool) -> Result<(), UserspaceError> {
+        let mut suspension = self.suspension.lock();
+        if suspension.suspended == suspended {
+            return Err(UserspaceError::InvalidState);
+        }
+        suspension.suspended = suspended;
+        let parked = suspension.parked.take();
+        drop(suspension);
+
+        if let Some(thread) = parked {
+            scheduler::add_to_schedule_queue(thread);
+        }
+        Ok(())
+    }
+
+    /// Parks the current thread for as long as it is suspended.
+    ///
+    /// Returns early if the thread gets killed while parked.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `this` is not the current thread.
+    pub fn wait_while_suspended(this: &Arc<Self>) {
+        assert!(Arc::ptr_eq(this, &scheduler::get_current_thread()), "Only the current thread may park itself");
+        let mut suspension = this.suspension.lock();
+        while suspension.suspended {
+            suspension.parked = Some(Arc::clone(this));
+            match scheduler::unschedule(&this.suspension, suspension) {
+                Ok(guard) => suspension = guard,
+                Err(_) => {
+                    // We got killed. Don't keep a reference to ourselves around.
+                    this.suspension.lock().parked = None;
+                    return;
+                }
+            }
+        }
+    }
 }
 
 impl Drop for ThreadStruct {
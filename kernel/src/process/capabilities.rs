@@ -12,6 +12,7 @@
 //! architecture to architecture. Arch-specific methods will be marked as so
 //! in their documentation.
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use crate::error::KernelError;
 use failure::Backtrace;
@@ -19,6 +20,7 @@ use bit_field::BitField;
 use bit_field::BitArray;
 use core::fmt;
 use core::convert::TryInto;
+use core::ops::RangeInclusive;
 
 /// Capabilities of a process.
 ///
@@ -42,12 +44,123 @@ pub struct ProcessCapabilities {
     /// Present on every architecture.
     pub irq_access_mask: [u8; 128],
 
-    /// A vector of readable IO ports.
+    /// The IO ports the process is allowed to access.
     ///
-    /// When task switching, the IOPB will be changed to take this into account.
+    /// When switching to a thread of another process, it is loaded in the
+    /// IOPB of the TSS.
     ///
     /// Present on x86 platforms.
-    pub ioports:         Vec<u16>,
+    pub ioports:         IoBitmap,
+
+    /// Whether other processes may debug this process, e.g. inspect its
+    /// threads and memory with a debug handle.
+    ///
+    /// Present on every architecture.
+    pub can_be_debugged: bool,
+
+    /// Whether the process may debug any other process, even those that
+    /// aren't [can_be_debugged](ProcessCapabilities::can_be_debugged).
+    ///
+    /// Present on every architecture.
+    pub can_debug_others: bool,
+
+    /// Whether the process is allowed to create a CodeMemory, allowing it to
+    /// generate code at runtime.
+    ///
+    /// Present on every architecture.
+    pub code_memory_allowed: bool,
+
+    /// Whether the process is allowed to allocate Message Signaled Interrupt
+    /// vectors.
+    ///
+    /// Present on x86 platforms.
+    pub msi_allowed: bool,
+
+    /// The maximum number of handles the process can have open at once. See
+    /// [HandleTable](crate::process::HandleTable).
+    ///
+    /// Present on every architecture.
+    pub handle_table_size: usize,
+}
+
+/// Bitmap of the IO ports a process is allowed to access, in the format of the
+/// IOPB of the TSS: a bit set to 0 means the port is addressable, and 1 that
+/// it is not.
+///
+/// Only the bytes covering the addressable ports are stored, all the ports
+/// outside of them are not addressable.
+///
+/// Present on x86 platforms.
+#[derive(Default)]
+pub struct IoBitmap {
+    /// Index in the IOPB of the first byte of `bits`.
+    offset: usize,
+    /// The bytes of the IOPB starting at `offset`.
+    bits: Box<[u8]>,
+}
+
+impl IoBitmap {
+    /// Creates a bitmap where all the ports in `ranges` are addressable.
+    pub fn new(ranges: &[RangeInclusive<u16>]) -> IoBitmap {
+        let first = ranges.iter().map(|range| usize::from(*range.start()) / 8).min();
+        let last = ranges.iter().map(|range| usize::from(*range.end()) / 8).max();
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return IoBitmap::default()
+        };
+
+        let mut bits = vec![0xFF; last - first + 1].into_boxed_slice();
+        for port in ranges.iter().cloned().flatten() {
+            bits.set_bit(usize::from(port) - first * 8, false);
+        }
+        IoBitmap { offset: first, bits }
+    }
+
+    /// Checks whether `port` is addressable.
+    pub fn is_allowed(&self, port: u16) -> bool {
+        match (usize::from(port) / 8).checked_sub(self.offset) {
+            Some(idx) if idx < self.bits.len() => !self.bits[idx].get_bit(usize::from(port) % 8),
+            _ => false
+        }
+    }
+
+    /// Index in the IOPB of the first byte returned by [bytes].
+    ///
+    /// [bytes]: IoBitmap::bytes
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The bytes of the IOPB covering all the addressable ports, starting at
+    /// [offset]. The bytes outside of them must be 0xFF.
+    ///
+    /// [offset]: IoBitmap::offset
+    pub fn bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+impl fmt::Debug for IoBitmap {
+    /// Prints the ranges of addressable ports.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        let mut start = None;
+        let ports = self.offset * 8..(self.offset + self.bits.len()) * 8;
+        for port in ports.clone() {
+            match (start, self.is_allowed(port as u16)) {
+                (None, true) => start = Some(port),
+                (Some(first), false) => {
+                    list.entry(&format_args!("{:#06x}..={:#06x}", first, port - 1));
+                    start = None;
+                },
+                _ => ()
+            }
+        }
+        if let Some(first) = start {
+            list.entry(&format_args!("{:#06x}..={:#06x}", first, ports.end - 1));
+        }
+        list.finish()
+    }
 }
 
 /// Wrapper around a bitfield that only prints the indices of set bits.
@@ -71,6 +184,11 @@ impl fmt::Debug for ProcessCapabilities {
             .field("syscall_mask", &MaskPrinter(&self.syscall_mask))
             .field("irq_access_mask", &MaskPrinter(&self.irq_access_mask))
             .field("ioports", &self.ioports)
+            .field("can_be_debugged", &self.can_be_debugged)
+            .field("can_debug_others", &self.can_debug_others)
+            .field("code_memory_allowed", &self.code_memory_allowed)
+            .field("msi_allowed", &self.msi_allowed)
+            .field("handle_table_size", &self.handle_table_size)
             .finish()
     }
 }
@@ -97,6 +215,20 @@ const DEBUG_FLAGS: u32 = 16;
 // Sunrise extension
 /// IOPorts the process is allowed to talk to
 const IO_PORTS_ALLOWED: u32 = 10;
+/// Bit of an IO_PORTS_ALLOWED marking the first port of a range. It must be
+/// followed by an IO_PORTS_ALLOWED with [IO_PORT_RANGE_END] set.
+const IO_PORT_RANGE_START: usize = 27;
+/// Bit of an IO_PORTS_ALLOWED marking the last port of a range.
+const IO_PORT_RANGE_END: usize = 28;
+/// Allow creating CodeMemory, to generate code at runtime.
+const CODE_MEMORY_ALLOWED: u32 = 17;
+/// Allow allocating MSI vectors, for PCI devices.
+const MSI_ALLOWED: u32 = 18;
+
+/// Size of the handle table of processes that don't declare a
+/// HANDLE_TABLE_SIZE, or declare a size of 0. Also the biggest size a process
+/// may declare.
+pub const MAX_HANDLE_TABLE_SIZE: usize = 1024;
 
 /// The highest defined svc.
 const MAX_SVC: usize = ::sunrise_libkern::nr::MaxSvc;
@@ -107,14 +239,21 @@ const KACS_NO_DUPLICATES: u32 = 0
     | 1 << APPLICATION_TYPE
     | 1 << KERNEL_RELEASE_VERSION
     | 1 << HANDLE_TABLE_SIZE
-    | 1 << DEBUG_FLAGS;
+    | 1 << DEBUG_FLAGS
+    | 1 << CODE_MEMORY_ALLOWED
+    | 1 << MSI_ALLOWED;
 
 impl Default for ProcessCapabilities {
     fn default() -> Self {
         ProcessCapabilities {
             syscall_mask: [0; 256 / (8 * 4)],
             irq_access_mask: [0; 128],
-            ioports: Vec::new(),
+            ioports: IoBitmap::default(),
+            can_be_debugged: false,
+            can_debug_others: false,
+            code_memory_allowed: false,
+            msi_allowed: false,
+            handle_table_size: MAX_HANDLE_TABLE_SIZE,
         }
     }
 }
@@ -130,10 +269,12 @@ impl ProcessCapabilities {
     /// - KernelReleaseVersion < 0x80000
     ///
     /// INVALID_COMBINATION:
-    /// - Tried to send a duplicate kernel capability that doesn't allow duplicates (bit3, bit13, bit14, bit15, bit16)
+    /// - Tried to send a duplicate kernel capability that doesn't allow duplicates (bit3, bit13, bit14, bit15, bit16, bit17, bit18)
     /// - Tried to send two svc masks with the same index
     /// - Lowest CpuId > Highest CpuId in KernelFlags
     /// - LowestPrio > Highest Prio in KernelFlags
+    /// - IoPortsAllowed range start not followed by a range end, range end
+    ///   without a range start, or range end < range start
     ///
     /// EXCEEDING_MAXIMUM:
     /// - IrqPair with Irq > 0xFF and != 0x3FF
@@ -146,15 +287,25 @@ impl ProcessCapabilities {
     /// - HandleTableSize: bit set in the 31..26 range
     /// - DebugFlags: bits set in the 31..19 range
     /// - ApplicationType: bits set in the 31..17 range
+    /// - CodeMemoryAllowed: bits set in the 31..18 range
+    /// - MsiAllowed: bits set in the 31..19 range
+    /// - IoPortsAllowed: bits set in the 31..29 range
     ///
     /// [switchbrew]: http://switchbrew.org/index.php?title=NPDM#Kernel_Access_Control
     pub fn parse_kcaps(kacs: &[u8]) -> Result<ProcessCapabilities, KernelError> {
         let mut capabilities = ProcessCapabilities {
             syscall_mask: [0; 256 / (8 * 4)],
             irq_access_mask: [0; 128],
-            ioports: Vec::new(),
+            ioports: IoBitmap::default(),
+            can_be_debugged: false,
+            can_debug_others: false,
+            code_memory_allowed: false,
+            msi_allowed: false,
+            handle_table_size: MAX_HANDLE_TABLE_SIZE,
         };
 
+        let mut ioport_ranges = Vec::new();
+
         let mut kac_iter = kacs.chunks(4);
 
         // A bitmask of KACs already found.
@@ -264,30 +415,68 @@ impl ProcessCapabilities {
                     let _version = kac.get_bits(15..32);
                 }
                 HANDLE_TABLE_SIZE => {
-                    let _handle_table_size = kac.get_bits(16..26);
+                    let handle_table_size = kac.get_bits(16..26) as usize;
                     if kac.get_bits(26..32) != 0 {
                         return Err(KernelError::ReservedValue {
                             backtrace: Backtrace::new()
                         })
                     }
+                    if handle_table_size != 0 {
+                        capabilities.handle_table_size = handle_table_size;
+                    }
                 }
                 DEBUG_FLAGS => {
-                    let _can_be_debugged = kac.get_bit(17);
-                    let _can_debug_others = kac.get_bit(18);
                     if kac.get_bits(19..32) != 0 {
                         return Err(KernelError::ReservedValue {
                             backtrace: Backtrace::new()
                         })
                     }
+                    capabilities.can_be_debugged = kac.get_bit(17);
+                    capabilities.can_debug_others = kac.get_bit(18);
                 }
                 IO_PORTS_ALLOWED => {
                     let ioport = kac.get_bits(11..27) as u16;
-                    if kac.get_bits(27..32) != 0 {
+                    if kac.get_bits(29..32) != 0 {
+                        return Err(KernelError::ReservedValue {
+                            backtrace: Backtrace::new()
+                        })
+                    }
+                    match (kac.get_bit(IO_PORT_RANGE_START), kac.get_bit(IO_PORT_RANGE_END)) {
+                        (false, false) => ioport_ranges.push(ioport..=ioport),
+                        (true, false) => {
+                            let end = kac_iter.next()
+                                .map(|kac| u32::from_le_bytes(kac.try_into().expect("Unexpectted kac size")))
+                                .filter(|kac| (!*kac).trailing_zeros() == IO_PORTS_ALLOWED
+                                    && !kac.get_bit(IO_PORT_RANGE_START) && kac.get_bit(IO_PORT_RANGE_END)
+                                    && kac.get_bits(29..32) == 0)
+                                .map(|kac| kac.get_bits(11..27) as u16);
+                            match end {
+                                Some(end) if end >= ioport => ioport_ranges.push(ioport..=end),
+                                _ => return Err(KernelError::InvalidCombination {
+                                    backtrace: Backtrace::new()
+                                })
+                            }
+                        },
+                        _ => return Err(KernelError::InvalidCombination {
+                            backtrace: Backtrace::new()
+                        })
+                    }
+                }
+                CODE_MEMORY_ALLOWED => {
+                    if kac.get_bits(18..32) != 0 {
                         return Err(KernelError::ReservedValue {
                             backtrace: Backtrace::new()
                         })
                     }
-                    capabilities.ioports.push(ioport);
+                    capabilities.code_memory_allowed = true;
+                }
+                MSI_ALLOWED => {
+                    if kac.get_bits(19..32) != 0 {
+                        return Err(KernelError::ReservedValue {
+                            backtrace: Backtrace::new()
+                        })
+                    }
+                    capabilities.msi_allowed = true;
                 }
                 _ => {
                     return Err(KernelError::InvalidKernelCaps {
@@ -298,6 +487,123 @@ impl ProcessCapabilities {
             }
         }
 
+        capabilities.ioports = IoBitmap::new(&ioport_ranges);
+
         Ok(capabilities)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{ProcessCapabilities, IoBitmap, MAX_HANDLE_TABLE_SIZE};
+    use crate::error::KernelError;
+
+    /// Packs kernel capabilities, as found in a .kernel_caps section.
+    fn kacs(kacs: &[u32]) -> Vec<u8> {
+        kacs.iter().flat_map(|kac| kac.to_le_bytes().to_vec()).collect()
+    }
+
+    /// An IO_PORTS_ALLOWED capability for a single port.
+    fn ioport(port: u16) -> u32 {
+        0b11_1111_1111 | u32::from(port) << 11
+    }
+
+    #[test]
+    fn io_bitmap_empty() {
+        let bitmap = IoBitmap::new(&[]);
+        assert!(bitmap.bytes().is_empty());
+        assert!(!bitmap.is_allowed(0));
+        assert!(!bitmap.is_allowed(0xFFFF));
+    }
+
+    #[test]
+    fn io_bitmap_ranges() {
+        let bitmap = IoBitmap::new(&[0x60..=0x60, 0x3F8..=0x3FD, 0x64..=0x64]);
+        assert_eq!(bitmap.offset(), 0x60 / 8);
+        assert_eq!(bitmap.bytes().len(), 0x3FD / 8 - 0x60 / 8 + 1);
+        for port in 0..=0xFFFF {
+            let allowed = port == 0x60 || port == 0x64 || (0x3F8..=0x3FD).contains(&port);
+            assert_eq!(bitmap.is_allowed(port), allowed, "port {:#x}", port);
+        }
+        // Bytes are in the IOPB format: 0 means addressable.
+        assert_eq!(bitmap.bytes()[0], !(1 << 0 | 1 << 4));
+    }
+
+    #[test]
+    fn parse_single_ports() {
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[ioport(0x60), ioport(0x64)])).unwrap();
+        assert!(caps.ioports.is_allowed(0x60));
+        assert!(!caps.ioports.is_allowed(0x61));
+        assert!(caps.ioports.is_allowed(0x64));
+    }
+
+    #[test]
+    fn parse_port_range() {
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[ioport(0xCF8) | 1 << 27, ioport(0xCFF) | 1 << 28])).unwrap();
+        assert!(!caps.ioports.is_allowed(0xCF7));
+        assert!((0xCF8..=0xCFF).all(|port| caps.ioports.is_allowed(port)));
+        assert!(!caps.ioports.is_allowed(0xD00));
+    }
+
+    #[test]
+    fn parse_handle_table_size() {
+        let handle_table_size = |size: u32| 0b111_1111_1111_1111 | size << 16;
+
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[])).unwrap();
+        assert_eq!(caps.handle_table_size, MAX_HANDLE_TABLE_SIZE);
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[handle_table_size(0)])).unwrap();
+        assert_eq!(caps.handle_table_size, MAX_HANDLE_TABLE_SIZE);
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[handle_table_size(64)])).unwrap();
+        assert_eq!(caps.handle_table_size, 64);
+
+        match ProcessCapabilities::parse_kcaps(&kacs(&[handle_table_size(64), handle_table_size(128)])) {
+            Err(KernelError::InvalidCombination { .. }) => (),
+            res => panic!("Duplicate handle table size parsed to {:?}", res)
+        }
+    }
+
+    #[test]
+    fn parse_debug_flags() {
+        let debug_flags = |can_be_debugged: u32, can_debug_others: u32| 0xFFFF | can_be_debugged << 17 | can_debug_others << 18;
+
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[])).unwrap();
+        assert!(!caps.can_be_debugged && !caps.can_debug_others);
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[debug_flags(1, 0)])).unwrap();
+        assert!(caps.can_be_debugged && !caps.can_debug_others);
+        let caps = ProcessCapabilities::parse_kcaps(&kacs(&[debug_flags(0, 1)])).unwrap();
+        assert!(!caps.can_be_debugged && caps.can_debug_others);
+
+        match ProcessCapabilities::parse_kcaps(&kacs(&[debug_flags(1, 1) | 1 << 19])) {
+            Err(KernelError::ReservedValue { .. }) => (),
+            res => panic!("Reserved bit parsed to {:?}", res)
+        }
+    }
+
+    #[test]
+    fn parse_invalid_port_ranges() {
+        let invalid: &[&[u32]] = &[
+            // start without an end.
+            &[ioport(0xCF8) | 1 << 27],
+            &[ioport(0xCF8) | 1 << 27, ioport(0xCFF)],
+            // end without a start.
+            &[ioport(0xCFF) | 1 << 28],
+            // end before start.
+            &[ioport(0xCFF) | 1 << 27, ioport(0xCF8) | 1 << 28],
+            // both at once.
+            &[ioport(0xCF8) | 1 << 27 | 1 << 28],
+        ];
+        for kac in invalid {
+            match ProcessCapabilities::parse_kcaps(&kacs(kac)) {
+                Err(KernelError::InvalidCombination { .. }) => (),
+                res => panic!("{:x?} parsed to {:?}", kac, res.map(|caps| caps.ioports))
+            }
+        }
+
+        match ProcessCapabilities::parse_kcaps(&kacs(&[ioport(0x60) | 1 << 29])) {
+            Err(KernelError::ReservedValue { .. }) => (),
+            res => panic!("Reserved bit parsed to {:?}", res.map(|caps| caps.ioports))
+        }
+    }
+}
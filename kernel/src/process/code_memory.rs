@@ -0,0 +1,221 @@
+//! Code Memory
+//!
+//! Sunrise enforces W^X: a userspace page may never be writable and executable
+//! at the same time. Programs generating code at runtime (such as a JIT) get
+//! around this by creating a CodeMemory from a region of their heap. The
+//! CodeMemory can then be mapped twice:
+//!
+//! - a RW- alias in the owner process (`CodeWritable`), where code gets
+//!   written,
+//! - a R-X alias (`CodeReadOnly`), where the code gets executed.
+//!
+//! Both aliases point to the same frames. While the CodeMemory is alive, the
+//! source heap region is locked: it stays mapped, but loses all its user
+//! permissions. When the CodeMemory is dropped, the aliases still mapped are
+//! unmapped, and then the source region is unlocked: the frames are never left
+//! both writable and executable.
+//!
+//! Creating a CodeMemory requires the process to have the
+//! [code_memory_allowed] capability.
+//!
+//! [code_memory_allowed]: super::ProcessCapabilities::code_memory_allowed
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use crate::frame_allocator::PhysicalMemRegion;
+use crate::mem::{VirtualAddress, PhysicalAddress};
+use crate::paging::{MappingAccessRights, PAGE_SIZE};
+use crate::paging::process_memory::ProcessMemory;
+use crate::process::ProcessStruct;
+use crate::error::UserspaceError;
+use crate::sync::{SpinLock, SpinRwLock};
+use sunrise_libkern::{MemoryAttributes, MemoryPermissions, MemoryType, MemoryState};
+
+/// A chunk of physical memory backing a CodeMemory.
+///
+/// The source region may span multiple mappings, so we keep a list of the
+/// frames, the offset in those frames and the length of each part.
+type CodeMemoryChunk = (Arc<SpinRwLock<Vec<PhysicalMemRegion>>>, usize, usize);
+
+/// Memory region which can be aliased as both RW- and R-X. See the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct CodeMemory {
+    /// The process that created this CodeMemory, and in which the source
+    /// region lives.
+    owner: Weak<ProcessStruct>,
+    /// Address of the source region in the owner's address space.
+    address: VirtualAddress,
+    /// Length of the CodeMemory.
+    length: usize,
+    /// The frames backing this CodeMemory.
+    chunks: Vec<CodeMemoryChunk>,
+    /// The aliases currently mapped: the process they're mapped in, their
+    /// address and their memory type.
+    aliases: SpinLock<Vec<(Weak<ProcessStruct>, VirtualAddress, MemoryType)>>,
+}
+
+impl CodeMemory {
+    /// Creates a CodeMemory from the given region of `owner`'s address space,
+    /// locking the region.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidMemState`
+    ///    - The region does not have the CODE_MEMORY_ALLOWED state.
+    ///    - The region is not RW-.
+    pub fn new(owner: &Arc<ProcessStruct>, address: VirtualAddress, length: usize) -> Result<CodeMemory, UserspaceError> {
+        let mut mem = owner.pmemory.lock();
+
+        mem.check_range(address, length,
+            MemoryState::CODE_MEMORY_ALLOWED, MemoryState::CODE_MEMORY_ALLOWED,
+            MemoryPermissions::all(), MemoryPermissions::RW,
+            MemoryAttributes::all(), MemoryAttributes::empty(),
+            MemoryAttributes::empty())?;
+
+        let chunks = mem.reprotect_shared(address, length, MappingAccessRights::empty());
+
+        Ok(CodeMemory {
+            owner: Arc::downgrade(owner),
+            address,
+            length,
+            chunks,
+            aliases: SpinLock::new(Vec::new()),
+        })
+    }
+
+    /// Gets the process owning this CodeMemory, if it is still alive.
+    pub fn owner(&self) -> Option<Arc<ProcessStruct>> {
+        self.owner.upgrade()
+    }
+
+    /// Gets the length of this CodeMemory.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Lists the physical pages backing this CodeMemory.
+    fn page_list(&self) -> Vec<PhysicalAddress> {
+        let mut pages = Vec::new();
+        for (frames, offset, length) in &self.chunks {
+            pages.extend(frames.read().iter().flatten()
+                .skip(offset / PAGE_SIZE)
+                .take(length / PAGE_SIZE));
+        }
+        pages
+    }
+
+    /// Maps the whole CodeMemory at the given address of `process`, whose
+    /// memory is `mem`.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidMemState`
+    ///    - The destination is not fully unmapped.
+    pub fn map(&self, process: &Arc<ProcessStruct>, mem: &mut ProcessMemory, address: VirtualAddress, ty: MemoryType, flags: MappingAccessRights) -> Result<(), UserspaceError> {
+        mem.check_range(address, self.length,
+            MemoryState::all(), MemoryType::Unmapped.get_memory_state(),
+            MemoryPermissions::empty(), MemoryPermissions::empty(),
+            MemoryAttributes::empty(), MemoryAttributes::empty(),
+            MemoryAttributes::empty())?;
+
+        let mut addr = address;
+        for (frames, offset, length) in &self.chunks {
+            mem.map_partial_shared_mapping(frames.clone(), addr, *offset, *length, ty, flags)
+                .expect("We checked everything, but map_partial_shared_mapping failed");
+            addr += *length;
+        }
+        self.aliases.lock().push((Arc::downgrade(process), address, ty));
+        Ok(())
+    }
+
+    /// Unmaps an alias of the CodeMemory previously created with [map].
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidMemState`
+    ///    - The range is not entirely of the given memory type.
+    /// - `InvalidMemRange`
+    ///    - The range does not map this CodeMemory.
+    ///
+    /// [map]: CodeMemory::map
+    pub fn unmap(&self, process: &Arc<ProcessStruct>, mem: &mut ProcessMemory, address: VirtualAddress, ty: MemoryType) -> Result<(), UserspaceError> {
+        self.unmap_alias(mem, address, ty)?;
+        self.aliases.lock().retain(|(alias_process, alias_address, _)| {
+            let same_process = alias_process.upgrade().map_or(false, |alias_process| Arc::ptr_eq(&alias_process, process));
+            !(same_process && *alias_address == address)
+        });
+        Ok(())
+    }
+
+    /// Checks the source region in the owner's memory `mem` is still the one
+    /// we locked.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidMemState`
+    ///    - The region is not a locked CODE_MEMORY_ALLOWED region anymore.
+    /// - `InvalidMemRange`
+    ///    - The region is not backed by our frames anymore.
+    fn check_source(&self, mem: &ProcessMemory) -> Result<(), UserspaceError> {
+        mem.check_range(self.address, self.length,
+            MemoryState::CODE_MEMORY_ALLOWED, MemoryState::CODE_MEMORY_ALLOWED,
+            MemoryPermissions::all(), MemoryPermissions::empty(),
+            MemoryAttributes::all(), MemoryAttributes::empty(),
+            MemoryAttributes::empty())?;
+
+        if mem.page_list(self.address, self.length) != self.page_list() {
+            return Err(UserspaceError::InvalidMemRange);
+        }
+        Ok(())
+    }
+
+    /// Unmaps the alias at `address` of `mem`, without forgetting about it.
+    fn unmap_alias(&self, mem: &mut ProcessMemory, address: VirtualAddress, ty: MemoryType) -> Result<(), UserspaceError> {
+        mem.check_range(address, self.length,
+            MemoryState::all(), ty.get_memory_state(),
+            MemoryPermissions::empty(), MemoryPermissions::empty(),
+            MemoryAttributes::all(), MemoryAttributes::empty(),
+            MemoryAttributes::empty())?;
+
+        if mem.page_list(address, self.length) != self.page_list() {
+            return Err(UserspaceError::InvalidMemRange);
+        }
+
+        // Our aliases are always created in one go with the same permissions,
+        // so they're made of exactly one mapping per chunk.
+        let mut addr = address;
+        for (_, _, length) in &self.chunks {
+            mem.unmap(addr, *length).expect("Alias of a CodeMemory was split");
+            addr += *length;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CodeMemory {
+    /// Unmaps the remaining aliases, and then unlocks the source region, giving
+    /// it its RW- permissions back.
+    ///
+    /// If the source region was changed behind our back, it is left alone.
+    fn drop(&mut self) {
+        let aliases = core::mem::replace(&mut *self.aliases.lock(), Vec::new());
+        for (process, address, ty) in aliases {
+            // A dead process doesn't have an address space anymore.
+            if let Some(process) = process.upgrade() {
+                let mut mem = process.pmemory.lock();
+                if let Err(err) = self.unmap_alias(&mut mem, address, ty) {
+                    error!("Failed to unmap the {:?} alias of a CodeMemory at {}: {:?}", ty, address, err);
+                }
+            }
+        }
+
+        if let Some(owner) = self.owner.upgrade() {
+            let mut mem = owner.pmemory.lock();
+            match self.check_source(&mem) {
+                Ok(()) => { mem.reprotect_shared(self.address, self.length, MappingAccessRights::u_rw()); },
+                Err(err) => error!("Not unlocking the source of a CodeMemory at {}: {:?}", self.address, err)
+            }
+        }
+    }
+}
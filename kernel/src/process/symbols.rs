@@ -0,0 +1,216 @@
+//! Process symbols
+//!
+//! When a process crashes, we want its backtrace to show function names rather
+//! than raw addresses. To that end, the kernel keeps a compact map of the
+//! functions of each process: their address, their size, and their demangled
+//! name.
+//!
+//! The map is built from the `.symtab` and `.strtab` sections of the ELF. For
+//! the kernel built-ins, the kernel reads them from the multiboot module. For
+//! the titles it loads, the loader hands them over with
+//! [set_process_symbols].
+//!
+//! [set_process_symbols]: crate::syscalls::set_process_symbols
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str;
+use rustc_demangle::demangle;
+use xmas_elf::header::Class;
+
+/// Size of an entry of an ELF32 symbol table.
+const ELF32_SYMBOL_SIZE: usize = 16;
+
+/// Size of an entry of an ELF64 symbol table.
+const ELF64_SYMBOL_SIZE: usize = 24;
+
+/// The `STT_FUNC` symbol type, found in the low nibble of `st_info`.
+const STT_FUNC: u8 = 2;
+
+/// A function of a process.
+#[derive(Debug)]
+struct Symbol {
+    /// Address of the first instruction of the function.
+    start: usize,
+    /// Size of the function, in bytes.
+    size: usize,
+    /// Demangled name of the function, without its hash.
+    name: String,
+}
+
+/// The functions of a process, sorted by address.
+#[derive(Debug, Default)]
+pub struct SymbolMap {
+    /// The functions, sorted by their start address.
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolMap {
+    /// Builds the map of functions from the raw content of the `.symtab` and
+    /// `.strtab` sections of a binary of the given ELF `class`, loaded at
+    /// `base`.
+    ///
+    /// Symbols that are not functions, or whose name is not valid, are
+    /// skipped. A binary of an unknown class has no symbols.
+    pub fn from_elf_sections(symtab: &[u8], strtab: &[u8], class: Class, base: usize) -> SymbolMap {
+        let read_u32 = |entry: &[u8], offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&entry[offset..offset + 4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+        let read_u64 = |entry: &[u8], offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&entry[offset..offset + 8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+
+        // Elf32_Sym and Elf64_Sym order their fields differently.
+        let symbol_size = match class {
+            Class::ThirtyTwo => ELF32_SYMBOL_SIZE,
+            Class::SixtyFour => ELF64_SYMBOL_SIZE,
+            _ => return SymbolMap::default()
+        };
+
+        let mut symbols = Vec::new();
+        for entry in symtab.chunks_exact(symbol_size) {
+            let (name_offset, value, size, info) = match class {
+                Class::SixtyFour => (read_u32(entry, 0), read_u64(entry, 8), read_u64(entry, 16), entry[4]),
+                _ => (read_u32(entry, 0), read_u32(entry, 4), read_u32(entry, 8), entry[12]),
+            };
+            if info & 0xF != STT_FUNC || value == 0 || size == 0 {
+                continue;
+            }
+
+            let name = strtab.get(name_offset..)
+                .and_then(|name| name.split(|&c| c == 0).next())
+                .and_then(|name| str::from_utf8(name).ok());
+            let name = match name {
+                Some(name) => name,
+                None => continue
+            };
+
+            let mut demangled = String::new();
+            let _ = write!(demangled, "{:#}", demangle(name));
+            symbols.push(Symbol {
+                start: base.wrapping_add(value),
+                size,
+                name: demangled,
+            });
+        }
+        symbols.sort_unstable_by_key(|symbol| symbol.start);
+
+        SymbolMap { symbols }
+    }
+
+    /// Finds the function containing `addr`. Returns its name, and the offset
+    /// of `addr` in it.
+    pub fn lookup(&self, addr: usize) -> Option<(&str, usize)> {
+        let idx = match self.symbols.binary_search_by_key(&addr, |symbol| symbol.start) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let symbol = &self.symbols[idx];
+        let offset = addr - symbol.start;
+        if offset < symbol.size {
+            Some((&symbol.name, offset))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use xmas_elf::header::Class;
+
+    use super::{SymbolMap, STT_FUNC};
+
+    /// The string table of the tests. Starts with the empty name, like every
+    /// `.strtab`.
+    const STRTAB: &[u8] = b"\0first\0second\0data\0";
+
+    /// Packs ELF32 symbols `(name offset, value, size, info)`, as found in a
+    /// .symtab section.
+    fn symtab32(symbols: &[(u32, u32, u32, u8)]) -> Vec<u8> {
+        let mut symtab = Vec::new();
+        for &(name, value, size, info) in symbols {
+            symtab.extend_from_slice(&name.to_le_bytes());
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&size.to_le_bytes());
+            symtab.extend_from_slice(&[info, 0, 0, 0]);
+        }
+        symtab
+    }
+
+    /// Packs ELF64 symbols `(name offset, value, size, info)`, as found in a
+    /// .symtab section.
+    fn symtab64(symbols: &[(u32, u64, u64, u8)]) -> Vec<u8> {
+        let mut symtab = Vec::new();
+        for &(name, value, size, info) in symbols {
+            symtab.extend_from_slice(&name.to_le_bytes());
+            symtab.extend_from_slice(&[info, 0, 0, 0]);
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&size.to_le_bytes());
+        }
+        symtab
+    }
+
+    #[test]
+    fn lookup_elf32() {
+        // Unsorted, with a gap between the two functions, and a data symbol.
+        let symtab = symtab32(&[
+            (7, 0x2000, 0x10, STT_FUNC),
+            (1, 0x1000, 0x100, STT_FUNC),
+            (14, 0x3000, 0x10, 1),
+        ]);
+        let map = SymbolMap::from_elf_sections(&symtab, STRTAB, Class::ThirtyTwo, 0x40_0000);
+
+        assert_eq!(map.lookup(0x40_0fff), None);
+        assert_eq!(map.lookup(0x40_1000), Some(("first", 0)));
+        assert_eq!(map.lookup(0x40_10ff), Some(("first", 0xff)));
+        assert_eq!(map.lookup(0x40_1100), None);
+        assert_eq!(map.lookup(0x40_2008), Some(("second", 8)));
+        assert_eq!(map.lookup(0x40_3000), None);
+    }
+
+    #[test]
+    fn lookup_elf64() {
+        let symtab = symtab64(&[
+            (1, 0x1000, 0x100, STT_FUNC),
+            (7, 0x2000, 0x10, STT_FUNC),
+        ]);
+        let map = SymbolMap::from_elf_sections(&symtab, STRTAB, Class::SixtyFour, 0x40_0000);
+
+        assert_eq!(map.lookup(0x40_1010), Some(("first", 0x10)));
+        assert_eq!(map.lookup(0x40_200f), Some(("second", 0xf)));
+        assert_eq!(map.lookup(0x40_2010), None);
+    }
+
+    #[test]
+    fn skips_invalid_symbols() {
+        let symtab = symtab32(&[
+            // undefined
+            (1, 0, 0x10, STT_FUNC),
+            // zero-sized
+            (1, 0x1000, 0, STT_FUNC),
+            // name out of the string table
+            (0x100, 0x2000, 0x10, STT_FUNC),
+        ]);
+        let map = SymbolMap::from_elf_sections(&symtab, STRTAB, Class::ThirtyTwo, 0);
+
+        assert_eq!(map.lookup(0), None);
+        assert_eq!(map.lookup(0x1000), None);
+        assert_eq!(map.lookup(0x2000), None);
+    }
+
+    #[test]
+    fn unknown_class_has_no_symbols() {
+        let symtab = symtab32(&[(1, 0x1000, 0x100, STT_FUNC)]);
+        let map = SymbolMap::from_elf_sections(&symtab, STRTAB, Class::None, 0);
+
+        assert_eq!(map.lookup(0x1000), None);
+    }
+}
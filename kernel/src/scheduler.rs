@@ -10,6 +10,7 @@ use crate::sync::{Lock, SpinLockIRQ, SpinLockIRQGuard};
 use core::sync::atomic::Ordering;
 use crate::error::{UserspaceError};
 use sunrise_libkern::TLS;
+use sunrise_libkern::trace::TraceEventKind;
 use core::cell::RefCell;
 use crate::cpu_locals::ARE_CPU_LOCALS_INITIALIZED_YET;
 
@@ -109,10 +110,27 @@ static SCHEDULE_QUEUE: SpinLockIRQ<Vec<Arc<ThreadStruct>>> = SpinLockIRQ::new(Ve
 ///
 /// Panics if the thread's state was already "Scheduled"
 pub fn add_to_schedule_queue(thread: Arc<ThreadStruct>) {
-
     let mut queue_lock = SCHEDULE_QUEUE.lock();
+    push_to_schedule_queue(&mut queue_lock, thread)
+}
+
+/// Adds a thread at the end of the schedule queue like [add_to_schedule_queue], unless the queue
+/// is already locked. Returns false if it was.
+///
+/// Meant for code that may run while the scheduler holds the queue, such as the logger.
+pub fn try_add_to_schedule_queue(thread: &Arc<ThreadStruct>) -> bool {
+    match SCHEDULE_QUEUE.try_lock() {
+        Some(mut queue_lock) => {
+            push_to_schedule_queue(&mut queue_lock, thread.clone());
+            true
+        },
+        None => false
+    }
+}
 
-    if is_in_schedule_queue(&queue_lock, &thread) {
+/// Pushes a thread at the end of the locked schedule queue. See [add_to_schedule_queue].
+fn push_to_schedule_queue(queue_lock: &mut SpinLockIRQGuard<'_, Vec<Arc<ThreadStruct>>>, thread: Arc<ThreadStruct>) {
+    if is_in_schedule_queue(queue_lock, &thread) {
         return;
     }
 
@@ -299,6 +317,7 @@ where
                 drop(queue);
 
                 let whoami = if !Arc::ptr_eq(&process_b, &proc) {
+                    crate::trace::record(TraceEventKind::ContextSwitch, process_b.process.pid as u32, process_b.tid as u32);
                     unsafe {
                         // safety: interrupts are disabled by the interrupt_lock.
                         process_switch(process_b, proc)
@@ -0,0 +1,422 @@
+//! Kernel self-tests
+//!
+//! When built with the `kernel-selftest` feature, the kernel runs this test
+//! suite right after boot, before loading the Kernel Internal Processes. The
+//! tests exercise the core structures on the real hardware paths: the page
+//! tables, the frame allocator, locks shared between kernel threads, IPC
+//! sessions, and the lifetime of code memories.
+//!
+//! The results are written to the serial port with [SerialLogger], bypassing
+//! the log filters, in the same [TAP] format as the userspace test harness:
+//!
+//! ```text
+//! 1..5
+//! # paging::map_unmap
+//! ok 1 - paging::map_unmap
+//! # frame_allocator::fragmentation
+//! ...
+//! ```
+//!
+//! A failing test panics the kernel, so the last test announced is the one
+//! that failed.
+//!
+//! Kernel threads are not preempted. The tests running on several threads
+//! yield explicitly to interleave them.
+//!
+//! [TAP]: https://testanything.org/tap-specification.html
+
+use core::fmt::Write;
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use sunrise_libkern::MemoryType;
+use sunrise_libkern::process::{ProcInfo, ProcInfoAddrSpace, ProcInfoFlags, ProcessCategory};
+
+use crate::devices::rs232::SerialLogger;
+use crate::event::{self, Waitable};
+use crate::frame_allocator::{FrameAllocator, FrameAllocatorTrait, PhysicalMemRegion};
+use crate::ipc::{session, ServerSession};
+use crate::ipc::session::MsgPackedHdr;
+use crate::mem::{UserSpacePtr, UserSpacePtrMut, VirtualAddress};
+use crate::paging::{PAGE_SIZE, MappingAccessRights, PageState};
+use crate::paging::kernel_memory::get_kernel_memory;
+use crate::process::{ProcessStruct, ThreadStruct};
+use crate::process::code_memory::CodeMemory;
+use crate::scheduler;
+use crate::sync::{Mutex, SpinLock};
+use crate::utils::Splittable;
+
+/// A kernel self-test.
+#[derive(Debug)]
+struct SelfTest {
+    /// Name of the test, as reported.
+    name: &'static str,
+    /// The test itself. Fails by panicking.
+    test: fn(),
+}
+
+/// The tests, in the order they run.
+static TESTS: &[SelfTest] = &[
+    SelfTest { name: "paging::map_unmap", test: paging_map_unmap },
+    SelfTest { name: "frame_allocator::fragmentation", test: frame_allocator_fragmentation },
+    SelfTest { name: "sync::mutex_contention", test: mutex_contention },
+    SelfTest { name: "sync::spin_lock_contention", test: spin_lock_contention },
+    SelfTest { name: "ipc::ping_pong", test: ipc_ping_pong },
+    SelfTest { name: "process::code_memory_outlives_owner", test: code_memory_outlives_owner },
+];
+
+/// Runs the self-tests, and reports their results on the serial port.
+///
+/// Must be called from a thread of the first process, after the scheduler
+/// was initialized.
+///
+/// # Panics
+///
+/// Panics if a test fails.
+pub fn run() {
+    let _ = writeln!(SerialLogger, "1..{}", TESTS.len());
+    for (idx, test) in TESTS.iter().enumerate() {
+        let _ = writeln!(SerialLogger, "# {}", test.name);
+        (test.test)();
+        let _ = writeln!(SerialLogger, "ok {} - {}", idx + 1, test.name);
+    }
+    info!("All {} kernel self-tests passed", TESTS.len());
+}
+
+/// Starts `count` kernel threads running `entrypoint`, each given its index
+/// as argument, and waits for all of them to exit.
+fn spawn_and_join(entrypoint: fn(usize), count: usize) {
+    let process = scheduler::get_current_process();
+    let threads: Vec<Weak<ThreadStruct>> = (0..count).map(|idx| {
+        let thread = ThreadStruct::new_kernel(&process, entrypoint, idx)
+            .expect("Failed to create a kernel thread");
+        ThreadStruct::start(thread.clone())
+            .expect("Failed to start a kernel thread");
+        thread
+    }).collect();
+
+    for thread in &threads {
+        event::wait(Some(thread as &dyn Waitable))
+            .expect("Failed to wait for a kernel thread");
+    }
+}
+
+/// Maps and unmaps KernelLand regions of various sizes, keeping several of
+/// them alive at once, and checks each page keeps the pattern written to it.
+///
+/// Two live mappings sharing a frame, or a mapping left behind, would break
+/// the patterns or the page states.
+fn paging_map_unmap() {
+    /// Number of mappings alive at once.
+    const LIVE: usize = 8;
+    /// Number of mappings made.
+    const ROUNDS: usize = 128;
+
+    /// Fills each page of a mapping with a pattern depending on its address.
+    fn fill(address: VirtualAddress, length: usize) {
+        for page in (address.addr()..address.addr() + length).step_by(PAGE_SIZE) {
+            let words = unsafe {
+                // Safety: We mapped it, and own it.
+                core::slice::from_raw_parts_mut(page as *mut usize, PAGE_SIZE / core::mem::size_of::<usize>())
+            };
+            for (idx, word) in words.iter_mut().enumerate() {
+                *word = page ^ idx;
+            }
+        }
+    }
+
+    /// Checks the pattern written by `fill`, and unmaps the region.
+    fn check_and_unmap(address: VirtualAddress, length: usize) {
+        for page in (address.addr()..address.addr() + length).step_by(PAGE_SIZE) {
+            let words = unsafe {
+                // Safety: We mapped it, and own it.
+                core::slice::from_raw_parts(page as *const usize, PAGE_SIZE / core::mem::size_of::<usize>())
+            };
+            for (idx, word) in words.iter().enumerate() {
+                assert_eq!(*word, page ^ idx, "Page {:#010x} was corrupted", page);
+            }
+        }
+
+        let mut memory = get_kernel_memory();
+        memory.unmap(address, length);
+        for page in (address.addr()..address.addr() + length).step_by(PAGE_SIZE) {
+            match memory.mapping_state(VirtualAddress(page)) {
+                PageState::Available => (),
+                state => panic!("Page {:#010x} is still {:?} after unmap", page, state)
+            }
+        }
+    }
+
+    let mut live: [Option<(VirtualAddress, usize)>; LIVE] = Default::default();
+    for round in 0..ROUNDS {
+        let slot = &mut live[round % LIVE];
+        if let Some((address, length)) = slot.take() {
+            check_and_unmap(address, length);
+        }
+
+        let length = (round % 13 + 1) * PAGE_SIZE;
+        let address = get_kernel_memory().get_pages(length);
+        {
+            let mut memory = get_kernel_memory();
+            for page in (address.addr()..address.addr() + length).step_by(PAGE_SIZE) {
+                match memory.mapping_state(VirtualAddress(page)) {
+                    PageState::Present(_) => (),
+                    state => panic!("Page {:#010x} is {:?} after map", page, state)
+                }
+            }
+        }
+        fill(address, length);
+        *slot = Some((address, length));
+    }
+
+    for (address, length) in live.iter_mut().filter_map(Option::take) {
+        check_and_unmap(address, length);
+    }
+}
+
+/// Fragments physical memory by freeing every other frame of a batch, fills
+/// the holes with a fragmented allocation, and checks no frame is given twice
+/// and every frame is freed in the end.
+fn frame_allocator_fragmentation() {
+    /// Number of frames allocated one by one.
+    const FRAMES: usize = 256;
+
+    // Allocate the vecs first, the heap growing would take frames. Also make
+    // room for the one built by allocate_frames_fragmented.
+    drop(Vec::<PhysicalMemRegion>::with_capacity(FRAMES));
+    let mut frames = Vec::with_capacity(FRAMES);
+    let mut addresses = Vec::with_capacity(FRAMES);
+
+    let free_before = FrameAllocator::free_memory();
+
+    for _ in 0..FRAMES {
+        frames.push(FrameAllocator::allocate_frame().expect("Failed to allocate a frame"));
+    }
+    assert_eq!(FrameAllocator::free_memory(), free_before - FRAMES * PAGE_SIZE);
+
+    // Punch single frame holes.
+    let mut idx = 0;
+    frames.retain(|_| { idx += 1; idx % 2 == 0 });
+    assert_eq!(FrameAllocator::free_memory(), free_before - FRAMES / 2 * PAGE_SIZE);
+
+    let fragmented = FrameAllocator::allocate_frames_fragmented(FRAMES / 2 * PAGE_SIZE)
+        .expect("Failed to allocate fragmented frames");
+    assert_eq!(fragmented.iter().map(|region| region.size()).sum::<usize>(), FRAMES / 2 * PAGE_SIZE);
+
+    addresses.extend(frames.iter().chain(fragmented.iter()).flatten().map(|frame| frame.addr()));
+    addresses.sort_unstable();
+    let count = addresses.len();
+    addresses.dedup();
+    assert_eq!(addresses.len(), count, "A frame was allocated twice");
+
+    // Splitting a region keeps its frames contiguous, and frees both parts.
+    let mut left = FrameAllocator::allocate_region(4 * PAGE_SIZE).expect("Failed to allocate a region");
+    let right = left.split_at(PAGE_SIZE).unwrap().expect("Region was not split");
+    assert_eq!(left.size(), PAGE_SIZE);
+    assert_eq!(right.size(), 3 * PAGE_SIZE);
+    assert_eq!(right.address().addr(), left.address().addr() + PAGE_SIZE);
+
+    drop((left, right, frames, fragmented));
+    assert_eq!(FrameAllocator::free_memory(), free_before, "Frames were leaked");
+}
+
+/// Number of threads of the lock contention tests.
+const LOCK_THREADS: usize = 4;
+/// Number of increments each thread of the lock contention tests does.
+const LOCK_ROUNDS: usize = 64;
+
+/// The counter of [mutex_contention].
+static MUTEX_COUNTER: Mutex<usize> = Mutex::new(0);
+
+/// Increments a counter from several threads, yielding while holding the
+/// mutex so the other threads block on it.
+fn mutex_contention() {
+    fn worker(_idx: usize) {
+        for _ in 0..LOCK_ROUNDS {
+            let mut counter = MUTEX_COUNTER.lock();
+            let value = *counter;
+            scheduler::schedule();
+            *counter = value + 1;
+            drop(counter);
+            scheduler::schedule();
+        }
+    }
+
+    spawn_and_join(worker, LOCK_THREADS);
+    assert_eq!(*MUTEX_COUNTER.lock(), LOCK_THREADS * LOCK_ROUNDS);
+}
+
+/// The counter of [spin_lock_contention].
+static SPIN_LOCK_COUNTER: SpinLock<usize> = SpinLock::new(0);
+
+/// Increments a counter from several threads, yielding while holding the
+/// spin lock so the other threads find it taken.
+fn spin_lock_contention() {
+    fn worker(_idx: usize) {
+        for _ in 0..LOCK_ROUNDS {
+            // Spinning while the owner is scheduled out would never end, as
+            // nothing preempts us. Yield until the lock is free instead.
+            let mut counter = loop {
+                match SPIN_LOCK_COUNTER.try_lock() {
+                    Some(counter) => break counter,
+                    None => scheduler::schedule()
+                }
+            };
+            let value = *counter;
+            scheduler::schedule();
+            *counter = value + 1;
+            drop(counter);
+            scheduler::schedule();
+        }
+    }
+
+    spawn_and_join(worker, LOCK_THREADS);
+    assert_eq!(*SPIN_LOCK_COUNTER.lock(), LOCK_THREADS * LOCK_ROUNDS);
+}
+
+/// Number of requests sent by [ipc_ping_pong].
+const IPC_ROUNDS: u32 = 64;
+
+/// What the server thread of [ipc_ping_pong] needs.
+#[derive(Debug)]
+struct PingPongServer {
+    /// The session to receive the requests on.
+    session: ServerSession,
+    /// The server's IPC buffer.
+    buf: VirtualAddress,
+}
+
+/// Writes an IPC message holding a single word of raw data.
+fn write_ping_pong_message(buf: &mut [u8], value: u32) {
+    let mut hdr = MsgPackedHdr(0);
+    hdr.set_raw_section_size(1);
+    buf[0..8].copy_from_slice(&hdr.0.to_le_bytes());
+    buf[8..12].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Reads the word written by [write_ping_pong_message].
+fn read_ping_pong_message(buf: &[u8]) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&buf[8..12]);
+    u32::from_le_bytes(value)
+}
+
+/// Sends requests to a server thread, which replies with the value it got
+/// plus one.
+///
+/// Both threads belong to the first process, so the messages only carry raw
+/// data: passing handles or buffers within a process is not supported.
+fn ipc_ping_pong() {
+    fn server(arg: usize) {
+        let server = unsafe {
+            // Safety: ipc_ping_pong leaked the box for us.
+            Box::from_raw(arg as *mut PingPongServer)
+        };
+        let mut buf = UserSpacePtrMut::from_raw_parts_mut(server.buf.addr() as *mut u8, PAGE_SIZE);
+
+        for _ in 0..IPC_ROUNDS {
+            event::wait(Some(&server.session as &dyn Waitable)).expect("Failed to wait for a request");
+            server.session.receive(buf, false).expect("Failed to receive a request");
+            let value = read_ping_pong_message(&*buf);
+            write_ping_pong_message(&mut *buf, value + 1);
+            server.session.reply(UserSpacePtr(buf.0)).expect("Failed to reply");
+        }
+    }
+
+    let (server_session, client_session) = session::new();
+
+    // The messages are copied from the IPC buffers in userland.
+    let process = scheduler::get_current_process();
+    let client_buf = {
+        let mut pmemory = process.pmemory.lock();
+        let address = pmemory.find_available_space(2 * PAGE_SIZE)
+            .expect("Failed to find space for the IPC buffers");
+        pmemory.create_regular_mapping(address, 2 * PAGE_SIZE, MemoryType::Heap, MappingAccessRights::u_rw())
+            .expect("Failed to map the IPC buffers");
+        address
+    };
+    let server_buf = client_buf + PAGE_SIZE;
+
+    let server_arg = Box::into_raw(Box::new(PingPongServer { session: server_session, buf: server_buf }));
+    let server_thread = ThreadStruct::new_kernel(&process, server, server_arg as usize)
+        .expect("Failed to create the server thread");
+    ThreadStruct::start(server_thread.clone()).expect("Failed to start the server thread");
+
+    let mut buf = UserSpacePtrMut::from_raw_parts_mut(client_buf.addr() as *mut u8, PAGE_SIZE);
+    for round in 0..IPC_ROUNDS {
+        write_ping_pong_message(&mut *buf, round);
+        client_session.send_request(buf).expect("Failed to send a request");
+        assert_eq!(read_ping_pong_message(&*buf), round + 1, "Wrong reply to request {}", round);
+    }
+
+    event::wait(Some(&server_thread as &dyn Waitable)).expect("Failed to wait for the server thread");
+    process.pmemory.lock().unmap(client_buf, 2 * PAGE_SIZE)
+        .expect("Failed to unmap the IPC buffers");
+}
+
+/// Drops CodeMemories after their source region went away: once after the
+/// owner process was torn down, and once after the source was unmapped from
+/// under it. Neither may panic, and the unmapped source must stay unmapped.
+fn code_memory_outlives_owner() {
+    /// Length of the CodeMemories.
+    const LENGTH: usize = 2 * PAGE_SIZE;
+
+    /// Creates a process without threads.
+    fn create_process() -> Arc<ProcessStruct> {
+        let mut flags = ProcInfoFlags(0);
+        flags.set_address_space_type(ProcInfoAddrSpace::AS32Bit);
+        let procinfo = ProcInfo {
+            name: *b"selftest\0\0\0\0",
+            process_category: ProcessCategory::RegularTitle,
+            title_id: 0,
+            code_addr: 0x400000,
+            code_num_pages: 0,
+            flags,
+            resource_limit_handle: None,
+            system_resource_num_pages: 0
+        };
+        ProcessStruct::new(&procinfo, None).expect("Failed to create a process")
+    }
+
+    /// Creates a CodeMemory from a new heap region of `process`, and maps
+    /// both its aliases. Returns the CodeMemory and its source address.
+    fn create_code_memory(process: &Arc<ProcessStruct>) -> (CodeMemory, VirtualAddress) {
+        let source = {
+            let mut pmemory = process.pmemory.lock();
+            let address = pmemory.find_available_space(LENGTH)
+                .expect("Failed to find space for the source");
+            pmemory.create_regular_mapping(address, LENGTH, MemoryType::Heap, MappingAccessRights::u_rw())
+                .expect("Failed to map the source");
+            address
+        };
+        let code_memory = CodeMemory::new(process, source, LENGTH)
+            .expect("Failed to create a CodeMemory");
+
+        let mut pmemory = process.pmemory.lock();
+        for &(ty, flags) in &[(MemoryType::CodeWritable, MappingAccessRights::u_rw()),
+                              (MemoryType::CodeReadOnly, MappingAccessRights::u_rx())] {
+            let alias = pmemory.find_available_space(LENGTH)
+                .expect("Failed to find space for an alias");
+            code_memory.map(process, &mut pmemory, alias, ty, flags)
+                .expect("Failed to map an alias");
+        }
+        (code_memory, source)
+    }
+
+    // The owner dies first, taking its address space with it.
+    let process = create_process();
+    let (code_memory, _) = create_code_memory(&process);
+    drop(process);
+    assert!(code_memory.owner().is_none(), "The owner was kept alive");
+    drop(code_memory);
+
+    // The owner is alive, but the source was unmapped.
+    let process = create_process();
+    let (code_memory, source) = create_code_memory(&process);
+    process.pmemory.lock().unmap(source, LENGTH)
+        .expect("Failed to unmap the source");
+    drop(code_memory);
+    let pmemory = process.pmemory.lock();
+    assert_eq!(pmemory.query_memory(source).mapping().state().ty(), MemoryType::Unmapped,
+        "The unmapped source was remapped");
+}
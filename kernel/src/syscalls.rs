@@ -11,7 +11,12 @@ use crate::paging::{MappingAccessRights, PAGE_SIZE};
 use crate::paging::lands::{UserLand, VirtualSpaceLand};
 use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTrait};
 use crate::paging::mapping::MappingFrames;
-use crate::process::{Handle, ThreadStruct, ProcessStruct};
+use crate::process::{Handle, ThreadStruct, ProcessStruct, ExceptionHandler};
+use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
+use crate::process::code_memory::CodeMemory;
+use crate::process::symbols::SymbolMap;
+use xmas_elf::header::Class;
+use crate::log_impl::KernelLogEvent;
 use crate::event::{self, Waitable};
 use crate::scheduler::{self, get_current_thread, get_current_process};
 use alloc::string::String;
@@ -20,13 +25,16 @@ use alloc::vec::Vec;
 use crate::ipc;
 use crate::error::{UserspaceError, KernelError};
 use crate::sync::SpinRwLock;
-use crate::timer;
+use crate::timer::{self, Timer};
+use crate::i386::power::{self, PowerButtonEvent};
 use failure::Backtrace;
-use sunrise_libkern::{MemoryInfo, MemoryAttributes, MemoryPermissions, MemoryType, MemoryState};
+use sunrise_libkern::{MemoryInfo, MemoryAttributes, MemoryPermissions, MemoryType, MemoryState, CodeMemoryOperation, InterruptType};
 use sunrise_libkern::process::*;
 use bit_field::BitArray;
 use crate::i386::gdt::{GDT, GdtIndex};
 use core::convert::{TryFrom, TryInto};
+use core::sync::atomic::Ordering;
+use core::mem::size_of;
 
 /// Resize the heap of a process, just like a brk.
 /// It can both expand, and shrink the heap.
@@ -49,6 +57,179 @@ pub fn set_heap_size(new_size: usize) -> Result<usize, UserspaceError> {
     Ok(heap_addr.addr())
 }
 
+/// Maps the given src memory range of the current process to the dst memory
+/// range as Stack memory. This is used to allocate thread stacks from the heap.
+///
+/// The src region gets locked (its user permissions are removed) until it is
+/// unmapped with [unmap_memory()]. The dst region gets the Stack state, with
+/// RW- permissions.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - The src region is outside of the UserLand address space.
+///    - The src memory pages does not have the MAP_ALLOWED state, or are not
+///      RW-.
+///    - The dst memory pages are not all Unmapped.
+/// - `InvalidMemRange`
+///    - `dst_addr + size` overflows
+///    - The dst region is outside of the UserLand address space.
+pub fn map_memory(dst_addr: usize, src_addr: usize, size: usize) -> Result<(), UserspaceError> {
+    let src_addr = VirtualAddress(src_addr);
+    let dst_addr = VirtualAddress(dst_addr);
+
+    src_addr.check_aligned_to(PAGE_SIZE)?;
+    dst_addr.check_aligned_to(PAGE_SIZE)?;
+
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if src_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+    if dst_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+
+    if !UserLand::contains_region(src_addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    if !UserLand::contains_region(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemRange)
+    }
+
+    let process = get_current_process();
+    let mut mem = process.pmemory.lock();
+
+    // Check the src is RW- memory we're allowed to alias.
+    mem.check_range(src_addr, size,
+        MemoryState::MAP_ALLOWED, MemoryState::MAP_ALLOWED,
+        MemoryPermissions::all(), MemoryPermissions::RW,
+        MemoryAttributes::all(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    // Check the destination is fully unmapped.
+    mem.check_range(dst_addr, size,
+        MemoryState::all(), MemoryType::Unmapped.get_memory_state(),
+        MemoryPermissions::empty(), MemoryPermissions::empty(),
+        MemoryAttributes::empty(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    // Lock the src, and alias it in the dst.
+    let chunks = mem.reprotect_shared(src_addr, size, MappingAccessRights::empty());
+    let mut addr = dst_addr;
+    for (frames, offset, length) in chunks {
+        mem.map_partial_shared_mapping(frames, addr, offset, length, MemoryType::Stack, MappingAccessRights::u_rw())
+            .unwrap_or_else(|err| panic!("Failed to map in dst mem: {:?}", err));
+        addr += length;
+    }
+
+    Ok(())
+}
+
+/// Unmaps a memory range mapped with [map_memory()], and gives its original
+/// permissions back to the src memory range.
+///
+/// The whole range mapped by [map_memory()] must be unmapped at once.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - The src region is outside of the UserLand address space.
+///    - The src memory pages does not have the MAP_ALLOWED state, or are not
+///      locked.
+///    - The dst memory pages are not all Stack memory.
+/// - `InvalidMemRange`
+///    - `dst_addr + size` overflows
+///    - The dst region is outside of the UserLand address space.
+///    - The given source range does not map the same pages as the given dst
+///      range.
+pub fn unmap_memory(dst_addr: usize, src_addr: usize, size: usize) -> Result<(), UserspaceError> {
+    let src_addr = VirtualAddress(src_addr);
+    let dst_addr = VirtualAddress(dst_addr);
+
+    src_addr.check_aligned_to(PAGE_SIZE)?;
+    dst_addr.check_aligned_to(PAGE_SIZE)?;
+
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if src_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+    if dst_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+
+    if !UserLand::contains_region(src_addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    if !UserLand::contains_region(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemRange)
+    }
+
+    let process = get_current_process();
+    let mut mem = process.pmemory.lock();
+
+    // Check the src is locked.
+    mem.check_range(src_addr, size,
+        MemoryState::MAP_ALLOWED, MemoryState::MAP_ALLOWED,
+        MemoryPermissions::all(), MemoryPermissions::empty(),
+        MemoryAttributes::all(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    mem.check_range(dst_addr, size,
+        MemoryState::all(), MemoryType::Stack.get_memory_state(),
+        MemoryPermissions::empty(), MemoryPermissions::empty(),
+        MemoryAttributes::all(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    // Check the dst really maps the src frames.
+    if mem.page_list(src_addr, size) != mem.page_list(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+
+    // The dst was mapped in one go by map_memory, so it should be made of
+    // whole mappings. Anything else means we're asked to unmap only part of
+    // it.
+    let mut curaddr = dst_addr;
+    while curaddr < dst_addr + size {
+        let mapping = mem.query_memory(curaddr);
+        curaddr = mapping.mapping().address() + mapping.mapping().length();
+    }
+    if curaddr != dst_addr + size {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+
+    let mut curaddr = dst_addr;
+    while curaddr < dst_addr + size {
+        let mapping_length = mem.query_memory(curaddr).mapping().length();
+        mem.unmap(curaddr, mapping_length).expect("Unmap can't fail.");
+        curaddr += mapping_length;
+    }
+
+    // Unlock the src.
+    mem.reprotect_shared(src_addr, size, MappingAccessRights::u_rw());
+
+    Ok(())
+}
+
 /// Maps the vga frame buffer mmio in userspace memory
 pub fn map_framebuffer() -> Result<(usize, usize, usize, usize), UserspaceError> {
     let tag = i386::multiboot::get_boot_information().framebuffer_tag()
@@ -76,9 +257,9 @@ pub fn map_framebuffer() -> Result<(usize, usize, usize, usize), UserspaceError>
 }
 
 /// Create an event handle for the given IRQ number. Waiting on this handle will
-/// wait until the IRQ is triggered. The flags argument configures the
-/// triggering. If it is false, the IRQ is active HIGH level sensitive, if it is
-/// true it is rising-edge sensitive.
+/// wait until the IRQ is triggered. The flag argument configures the
+/// triggering, see [InterruptType]. A level-triggered event masks the IRQ
+/// each time it fires, until the driver calls [acknowledge_interrupt].
 ///
 /// # Return
 ///
@@ -86,12 +267,10 @@ pub fn map_framebuffer() -> Result<(usize, usize, usize, usize), UserspaceError>
 ///
 /// # Error
 ///
-/// NoSuchEntry: IRQ above 0x3FF or outside the IRQ access mask was given.
-pub fn create_interrupt_event(irq_num: usize, _flag: u32) -> Result<usize, UserspaceError> {
-    // TODO: Properly handle flags in create_interrupt_event.
-    // BODY: The flags in create_interrupt_event configure the triggering of the
-    // BODY: event. If it is false, the IRQ is active HIGH level sensitive. If it
-    // BODY: is true, it is rising-edge sensitive.
+/// NoSuchEntry: IRQ above 0x3FF, outside the IRQ access mask, or not handled by
+/// the IOAPIC was given.
+/// InvalidEnum: The flag is not a valid [InterruptType].
+pub fn create_interrupt_event(irq_num: usize, flag: u32) -> Result<usize, UserspaceError> {
     // TODO: Fully correct error handling in create_interrupt_event.
     // BODY: https://switchbrew.org/w/index.php?title=SVC#svcCreateInterruptEvent
     // BODY: contains complete error code information. Notably, we're missing the
@@ -105,10 +284,70 @@ pub fn create_interrupt_event(irq_num: usize, _flag: u32) -> Result<usize, Users
             return Err(UserspaceError::NoSuchEntry);
         }
     }
-    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::InterruptEvent(event::wait_event(irq_num as u8))));
+    if irq_num >= usize::from(i386::interrupt::MSI_IRQ_BASE) {
+        return Err(UserspaceError::NoSuchEntry);
+    }
+    let event = match InterruptType(flag) {
+        InterruptType::Level => event::wait_level_event(irq_num as u8),
+        InterruptType::Edge => event::wait_event(irq_num as u8),
+        _ => return Err(UserspaceError::InvalidEnum)
+    };
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::InterruptEvent(event)))?;
     Ok(hnd as _)
 }
 
+/// Acknowledges a level-triggered interrupt event, unmasking its IRQ. Drivers
+/// should call this once they cleared the interrupt condition on their device,
+/// after the event got signaled.
+///
+/// This is a Sunrise extension.
+///
+/// # Error
+///
+/// InvalidHandle: The handle is invalid or not an interrupt event.
+/// InvalidState: The interrupt event is edge-triggered.
+pub fn acknowledge_interrupt(handle: u32) -> Result<(), UserspaceError> {
+    let hnd = get_current_process().phandles.lock().get_handle(handle)?;
+    match &*hnd {
+        Handle::InterruptEvent(event) => event.acknowledge().map_err(|err| err.into()),
+        _ => Err(UserspaceError::InvalidHandle)
+    }
+}
+
+/// Allocates an interrupt vector for a Message Signaled Interrupt, and creates
+/// an event handle signaled each time the interrupt is triggered. MSIs are
+/// edge-triggered and never shared, so they don't need to be acknowledged.
+///
+/// This is a Sunrise extension. The process needs the msi_allowed capability
+/// to use this syscall.
+///
+/// # Return
+///
+/// 0. A handle to the readable event associated with the interrupt.
+/// 1. The address to program in the device's MSI capability.
+/// 2. The data to program in the device's MSI capability.
+///
+/// # Error
+///
+/// - `InvalidKernelCaps`
+///    - The process does not have the msi_allowed capability.
+/// - `OutOfResource`
+///    - All the MSI vectors are in use.
+pub fn create_msi_event() -> Result<(usize, usize, usize), UserspaceError> {
+    let curproc = scheduler::get_current_process();
+    if !curproc.capabilities.msi_allowed {
+        if cfg!(feature = "no-security-check") {
+            error!("Process {} attempted to create an MSI event without the capability", curproc.name);
+        } else {
+            return Err(UserspaceError::InvalidKernelCaps);
+        }
+    }
+
+    let (event, address, data) = event::allocate_msi_event().ok_or(UserspaceError::OutOfResource)?;
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::InterruptEvent(event)))?;
+    Ok((hnd as _, address as _, data as _))
+}
+
 /// Gets the physical region a given virtual address maps.
 ///
 /// This syscall is mostly used for DMAs, where the physical address of a buffer needs to be known
@@ -270,7 +509,7 @@ pub fn connect_to_port(handle: u32) -> Result<usize, UserspaceError> {
     let curproc = scheduler::get_current_process();
     let clientport = curproc.phandles.lock().get_handle(handle)?.as_client_port()?;
     let clientsess = clientport.connect()?;
-    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ClientSession(clientsess)));
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ClientSession(clientsess)))?;
     Ok(hnd as _)
 }
 
@@ -299,7 +538,7 @@ pub fn create_thread(ip: usize, arg: usize, sp: usize, _priority: u32, _processo
     let thread = ThreadStruct::new(&cur_proc, VirtualAddress(ip), VirtualAddress(sp), Some(arg))?;
     let handle = Handle::Thread(thread);
     let mut handles_table = cur_proc.phandles.lock();
-    Ok(handles_table.add_handle(Arc::new(handle)) as usize)
+    Ok(handles_table.add_handle(Arc::new(handle))? as usize)
 }
 
 /// Starts a previously created thread.
@@ -331,7 +570,7 @@ pub fn start_thread(thread_handle: u32) -> Result<(), UserspaceError> {
 pub fn connect_to_named_port(name: UserSpacePtr<[u8; 12]>) -> Result<usize, UserspaceError> {
     let session = ipc::connect_to_named_port(*name)?;
     let curproc = scheduler::get_current_process();
-    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ClientSession(session)));
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ClientSession(session)))?;
     Ok(hnd as _)
 }
 
@@ -349,7 +588,7 @@ pub fn connect_to_named_port(name: UserSpacePtr<[u8; 12]>) -> Result<usize, User
 pub fn manage_named_port(name_ptr: UserSpacePtr<[u8; 12]>, max_sessions: u32) -> Result<usize, UserspaceError> {
     let server = ipc::create_named_port(*name_ptr, max_sessions)?;
     let curproc = scheduler::get_current_process();
-    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ServerPort(server)));
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ServerPort(server)))?;
     Ok(hnd as _)
 }
 
@@ -372,7 +611,7 @@ pub fn accept_session(porthandle: u32) -> Result<usize, UserspaceError> {
     };
 
     let server_session = port.accept()?;
-    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ServerSession(server_session)));
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ServerSession(server_session)))?;
     Ok(hnd as _)
 }
 
@@ -425,7 +664,10 @@ pub fn reply_and_receive_with_user_buffer(buf: UserSpacePtrMut<[u8]>, handles: U
 /// Does not accept 0xFFFF8001 or 0xFFFF8000 as handles.
 pub fn close_handle(handle: u32) -> Result<(), UserspaceError> {
     let proc = scheduler::get_current_process();
-    proc.phandles.lock().delete_handle(handle)?;
+    let handle = proc.phandles.lock().delete_handle(handle)?;
+    // Make sure the handle gets dropped after the handle table lock is
+    // released: dropping some handles (e.g. CodeMemory) locks the pmemory.
+    drop(handle);
     Ok(())
 }
 
@@ -465,8 +707,9 @@ pub fn signal_event(handle: u32) -> Result<(), UserspaceError> {
 ///
 /// Calling this on a non-signaled event is a noop.
 ///
-/// Takes either a [crate::event::ReadableEvent] or a
-/// [crate::event::WritableEvent].
+/// Takes either a [crate::event::ReadableEvent], a
+/// [crate::event::WritableEvent], a [PowerButtonEvent], or a [Timer], which
+/// then waits for its next period.
 ///
 /// # Errors
 ///
@@ -478,6 +721,8 @@ pub fn clear_event(handle: u32) -> Result<(), UserspaceError> {
     match &*handle {
         Handle::ReadableEvent(event) => event.clear_signal().map_err(|err| err.into()),
         Handle::WritableEvent(event) => event.clear_signal().map_err(|err| err.into()),
+        Handle::Timer(timer) => timer.clear().map_err(|err| err.into()),
+        Handle::PowerButtonEvent(event) => { event.clear(); Ok(()) },
         _ => Err(UserspaceError::InvalidHandle)?
     }
 }
@@ -487,8 +732,10 @@ pub fn clear_event(handle: u32) -> Result<(), UserspaceError> {
 pub fn create_port(max_sessions: u32, _is_light: bool, _name_ptr: UserSpacePtr<[u8; 12]>) -> Result<(usize, usize), UserspaceError>{
     let (server, client) = ipc::port::new(max_sessions);
     let curproc = scheduler::get_current_process();
-    let serverhnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ServerPort(server)));
-    let clienthnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ClientPort(client)));
+    let mut phandles = curproc.phandles.lock();
+    phandles.check_free_handles(2)?;
+    let serverhnd = phandles.add_handle(Arc::new(Handle::ServerPort(server)))?;
+    let clienthnd = phandles.add_handle(Arc::new(Handle::ClientPort(client)))?;
     Ok((clienthnd as _, serverhnd as _))
 }
 
@@ -502,7 +749,7 @@ pub fn create_shared_memory(size: u32, _myperm: u32, _otherperm: u32) -> Result<
     let frames = FrameAllocator::allocate_frames_fragmented(size as usize)?;
     let handle = Arc::new(Handle::SharedMemory(Arc::new(SpinRwLock::new(frames))));
     let curproc = get_current_process();
-    let hnd = curproc.phandles.lock().add_handle(handle);
+    let hnd = curproc.phandles.lock().add_handle(handle)?;
     Ok(hnd as _)
 }
 
@@ -565,14 +812,166 @@ pub fn unmap_shared_memory(handle: u32, addr: usize, size: usize) -> Result<(),
     Ok(())
 }
 
+/// Creates a CodeMemory from the given region of the current process' heap.
+/// The region gets locked until the CodeMemory is closed. Use
+/// [control_code_memory()] to map the CodeMemory as RW- and R-X.
+///
+/// The process needs the code_memory_allowed capability to use this syscall.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `addr + size` overflows
+///    - The region is outside of the UserLand address space.
+///    - The region does not have the CODE_MEMORY_ALLOWED state, or is not RW-.
+/// - `InvalidKernelCaps`
+///    - The process does not have the code_memory_allowed capability.
+pub fn create_code_memory(addr: usize, size: usize) -> Result<usize, UserspaceError> {
+    let addr = VirtualAddress(addr);
+
+    addr.check_aligned_to(PAGE_SIZE)?;
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    if !UserLand::contains_region(addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    let curproc = scheduler::get_current_process();
+    if !curproc.capabilities.code_memory_allowed {
+        if cfg!(feature = "no-security-check") {
+            error!("Process {} attempted to create a CodeMemory without the capability", curproc.name);
+        } else {
+            return Err(UserspaceError::InvalidKernelCaps);
+        }
+    }
+
+    let code_memory = CodeMemory::new(&curproc, addr, size)?;
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::CodeMemory(Arc::new(code_memory))))?;
+    Ok(hnd as _)
+}
+
+/// Maps or unmaps an alias of a CodeMemory.
+///
+/// - `MapOwner` maps the CodeMemory at `addr` as `CodeWritable` in the process
+///   that created it. `perm` must be RW-.
+/// - `MapSlave` maps the CodeMemory at `addr` as `CodeReadOnly` in the current
+///   process. `perm` must be R-- or R-X.
+/// - `UnmapOwner` and `UnmapSlave` unmap those aliases. `perm` is ignored.
+///
+/// The size must be equal to the size of the CodeMemory.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is not the size of the CodeMemory.
+/// - `InvalidMemState`
+///    - `addr + size` overflows
+///    - The region is outside of the UserLand address space.
+///    - When mapping, the region is not fully Unmapped.
+///    - When unmapping, the region is not of the expected type.
+/// - `InvalidMemRange`
+///    - When unmapping, the region does not map the CodeMemory.
+/// - `InvalidMemPerms`
+///    - `perm` is not allowed for this operation.
+/// - `InvalidEnum`
+///    - `op` is not a valid [CodeMemoryOperation].
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a CodeMemory
+///      handle.
+///    - The owner of the CodeMemory is dead.
+pub fn control_code_memory(handle: u32, op: u32, addr: usize, size: usize, perm: u32) -> Result<(), UserspaceError> {
+    let addr = VirtualAddress(addr);
+
+    addr.check_aligned_to(PAGE_SIZE)?;
+
+    let curproc = scheduler::get_current_process();
+    let code_memory = curproc.phandles.lock().get_handle(handle)?.as_code_memory()?;
+
+    if size != code_memory.length() {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    if !UserLand::contains_region(addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    let perm = MemoryPermissions::from_bits(perm).ok_or(UserspaceError::InvalidMemPerms)?;
+
+    match CodeMemoryOperation(op) {
+        CodeMemoryOperation::MapOwner => {
+            if perm != MemoryPermissions::RW {
+                return Err(UserspaceError::InvalidMemPerms);
+            }
+            let owner = code_memory.owner().ok_or(UserspaceError::InvalidHandle)?;
+            let mut mem = owner.pmemory.lock();
+            code_memory.map(&owner, &mut mem, addr, MemoryType::CodeWritable, MappingAccessRights::u_rw())?;
+        },
+        CodeMemoryOperation::MapSlave => {
+            if perm != MemoryPermissions::RX && perm != MemoryPermissions::RO {
+                return Err(UserspaceError::InvalidMemPerms);
+            }
+            let mut mem = curproc.pmemory.lock();
+            code_memory.map(&curproc, &mut mem, addr, MemoryType::CodeReadOnly, perm.into())?;
+        },
+        CodeMemoryOperation::UnmapOwner => {
+            let owner = code_memory.owner().ok_or(UserspaceError::InvalidHandle)?;
+            let mut mem = owner.pmemory.lock();
+            code_memory.unmap(&owner, &mut mem, addr, MemoryType::CodeWritable)?;
+        },
+        CodeMemoryOperation::UnmapSlave => {
+            let mut mem = curproc.pmemory.lock();
+            code_memory.unmap(&curproc, &mut mem, addr, MemoryType::CodeReadOnly)?;
+        },
+        _ => return Err(UserspaceError::InvalidEnum),
+    }
+
+    Ok(())
+}
 
 /// Query information about an address. Will always fetch the lowest page-aligned
 /// mapping that contains the provided address. Writes the output to the
 /// given userspace pointer to a MemoryInfo structure.
 #[inline(never)]
-pub fn query_memory(mut meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, addr: usize) -> Result<usize, UserspaceError> {
-    let curproc = scheduler::get_current_process();
-    let memlock = curproc.pmemory.lock();
+pub fn query_memory(meminfo: UserSpacePtrMut<MemoryInfo>, unk: usize, addr: usize) -> Result<usize, UserspaceError> {
+    // 0xFFFF8001 is the meta-handle to the current process.
+    query_process_memory(meminfo, unk, 0xFFFF8001, addr)
+}
+
+/// Query information about an address in the address space of the given
+/// process. Behaves exactly like [query_memory()] otherwise.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a Process
+///      handle.
+pub fn query_process_memory(meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, proc_hnd: u32, addr: usize) -> Result<usize, UserspaceError> {
+    let process = scheduler::get_current_process().phandles.lock().get_handle(proc_hnd)?.as_process()?;
+    write_memory_info(meminfo, &process, addr)
+}
+
+/// Writes information about the mapping containing `addr` in the address
+/// space of `process`. Shared by [query_process_memory()] and
+/// [query_debug_process_memory()].
+fn write_memory_info(mut meminfo: UserSpacePtrMut<MemoryInfo>, process: &ProcessStruct, addr: usize) -> Result<usize, UserspaceError> {
+    let memlock = process.pmemory.lock();
     let qmem = memlock.query_memory(VirtualAddress(addr));
     let mapping = qmem.mapping();
     *meminfo = MemoryInfo {
@@ -604,8 +1003,10 @@ pub fn query_memory(mut meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, addr:
 pub fn create_session(_is_light: bool, _unk: usize) -> Result<(usize, usize), UserspaceError> {
     let (server, client) = ipc::session::new();
     let curproc = scheduler::get_current_process();
-    let serverhnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ServerSession(server)));
-    let clienthnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ClientSession(client)));
+    let mut phandles = curproc.phandles.lock();
+    phandles.check_free_handles(2)?;
+    let serverhnd = phandles.add_handle(Arc::new(Handle::ServerSession(server)))?;
+    let clienthnd = phandles.add_handle(Arc::new(Handle::ClientSession(client)))?;
     Ok((serverhnd as _, clienthnd as _))
 }
 
@@ -619,8 +1020,9 @@ pub fn create_event() -> Result<(usize, usize), UserspaceError> {
     let (writable, readable) = crate::event::new_pair();
     let curproc = scheduler::get_current_process();
     let mut phandles = curproc.phandles.lock();
-    let readable = phandles.add_handle(Arc::new(Handle::ReadableEvent(readable)));
-    let writable = phandles.add_handle(Arc::new(Handle::WritableEvent(writable)));
+    phandles.check_free_handles(2)?;
+    let readable = phandles.add_handle(Arc::new(Handle::ReadableEvent(readable)))?;
+    let writable = phandles.add_handle(Arc::new(Handle::WritableEvent(writable)))?;
     Ok((usize::try_from(writable).unwrap(), usize::try_from(readable).unwrap()))
 }
 
@@ -728,9 +1130,6 @@ pub fn set_process_memory_permission(proc_hnd: u32, addr: usize, size: usize, pe
 
     // # KMemoryManager::SetProcessMemoryPermission
 
-    let mut size = size;
-    let mut addr = addr;
-
     let mut dstmem = dstproc.pmemory.lock();
 
     dstmem.check_range(addr, size,
@@ -739,44 +1138,16 @@ pub fn set_process_memory_permission(proc_hnd: u32, addr: usize, size: usize, pe
         MemoryAttributes::all(), MemoryAttributes::empty(),
         MemoryAttributes::IPC_MAPPED | MemoryAttributes::DEVICE_MAPPED)?;
 
-    while size != 0 {
-        let meminfo = dstmem.query_memory(addr);
-
-        let mapping_addr = meminfo.mapping().address();
-        let mapping_length = meminfo.mapping().length();
-        core::mem::drop(meminfo);
-        let meminfo = dstmem.unmap(mapping_addr, mapping_length).expect("Unmap can't fail.");
-
-        let frames = if let MappingFrames::Shared(frames) = meminfo.frames() {
-            frames
-        } else {
-            panic!("Non-shared frames in mapping {:?}", meminfo);
-        };
-
-        // Split mapping
-        if meminfo.address() < addr {
-            dstmem.map_partial_shared_mapping(frames.clone(), meminfo.address(), meminfo.phys_offset(), addr - meminfo.address(), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
-        }
-        if meminfo.address() + meminfo.length() > addr + size {
-            let phys_offset = meminfo.phys_offset() + addr + size - meminfo.address();
-            dstmem.map_partial_shared_mapping(frames.clone(), addr + size, phys_offset, (meminfo.address() + meminfo.length()) - (addr + size), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
-        }
-
-        // Handle middle mapping.
-        let offset_in_mapping = addr - meminfo.address();
-        let offset = offset_in_mapping + meminfo.phys_offset();
-        let curlen = core::cmp::min(size, meminfo.length() - offset_in_mapping);
-
-        let out_type = match meminfo.state().ty() {
+    for mapping in dstmem.unmap_shared(addr, size) {
+        let out_type = match mapping.state().ty() {
             MemoryType::CodeStatic => if perms.contains(MemoryPermissions::WRITABLE) { MemoryType::CodeMutable } else { MemoryType::CodeStatic },
             MemoryType::ModuleCodeStatic => if perms.contains(MemoryPermissions::WRITABLE) { MemoryType::ModuleCodeMutable } else { MemoryType::ModuleCodeStatic },
-            _ => unreachable!("Got a state PROCESS_PERMISSION_CHANGE_ALLOWED that wasn't CodeStatic or ModuleCodeStatic, but a {:?}", meminfo.state().ty())
+            _ => unreachable!("Got a state PROCESS_PERMISSION_CHANGE_ALLOWED that wasn't CodeStatic or ModuleCodeStatic, but a {:?}", mapping.state().ty())
         };
 
-        dstmem.map_partial_shared_mapping(frames.clone(), addr, offset, curlen, out_type, perms.into())?;
-
-        size -= curlen;
-        addr += curlen;
+        if let MappingFrames::Shared(frames) = mapping.frames() {
+            dstmem.map_partial_shared_mapping(frames.clone(), mapping.address(), mapping.phys_offset(), mapping.length(), out_type, perms.into())?;
+        }
     }
 
     Ok(())
@@ -938,10 +1309,6 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
         return Err(UserspaceError::InvalidMemRange)
     }
 
-    let mut size = size;
-    let mut src_addr = src_addr;
-    let mut dst_addr = dst_addr;
-
     let srcmem = srcproc.pmemory.lock();
     let mut dstmem = curproc.pmemory.lock();
 
@@ -959,56 +1326,188 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
         MemoryAttributes::all(), MemoryAttributes::empty(),
         MemoryAttributes::empty())?;
 
-    // TODO: UnmapProcessMemory: Verify that the src page list == dst page list.
-    // BODY: In UnmapProcessMemory, we don't ensure that src_address is correct,
-    // BODY: that is, we don't check that the frames in the dst match the frame
-    // BODY: in the src. HOS/NX does this by building a PageList (essentially
-    // BODY: a vector of frames) and comparing them.
-    // BODY:
-    // BODY: We could do something similar by iterating over the Mappings and
-    // BODY: checking if their Frames + PhysOffset are equals.
+    // Check the dst really maps the src frames.
+    if srcmem.page_list(src_addr, size) != dstmem.page_list(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemRange);
+    }
 
     // Unmap.
-    while size != 0 {
-        let mapping_address;
-        let mapping_length;
-
-        {
-            let meminfo = dstmem.query_memory(dst_addr);
-            mapping_address = meminfo.mapping().address();
-            mapping_length = meminfo.mapping().length();
-        }
-
-        let mapping = dstmem.unmap(mapping_address, mapping_length).unwrap();
-        let offset_in_mapping = dst_addr - mapping.address();
-        let curlen = core::cmp::min(size, mapping.length() - offset_in_mapping);
-
-        if let MappingFrames::Shared(frames) = mapping.frames() {
+    dstmem.unmap_shared(dst_addr, size);
 
-            // Remap left bit
-            if offset_in_mapping != 0 {
-                dstmem.map_partial_shared_mapping(frames.clone(), mapping.address(),
-                    mapping.phys_offset(), offset_in_mapping, mapping.state().ty(),
-                    mapping.flags()).unwrap();
-            }
+    Ok(())
+}
 
-            // Remap right bit
-            if curlen != mapping.length() - offset_in_mapping {
-                dstmem.map_partial_shared_mapping(frames.clone(),
-                    mapping.address() + offset_in_mapping + size,
-                    mapping.phys_offset() + offset_in_mapping + size,
-                    mapping.length() - (offset_in_mapping + size),
-                    mapping.state().ty(),
-                    mapping.flags()).unwrap();
-            }
-        } else {
-            panic!("Got a broken meminfo with non-arc'd frames: {:?}", mapping);
+/// Maps the given src memory range of a process as code in the same process.
+/// This is used to load additional modules (such as NROs) in a process from
+/// memory it allocated on its heap.
+///
+/// The src region gets locked (its user permissions are removed) until it is
+/// unmapped with [unmap_process_code_memory()]. The dst region gets the
+/// ModuleCodeStatic state, with no user permissions. The caller is expected to
+/// call [set_process_memory_permission()] on it to make it usable.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - `dst_addr + size` overflows
+///    - The src region is outside of the UserLand address space.
+///    - The src memory pages does not have the MAP_ALLOWED state, or are not
+///      RW-.
+///    - The dst memory pages are not all Unmapped.
+/// - `InvalidMemRange`
+///    - The dst region is outside of the UserLand address space.
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a Process
+///      handle.
+pub fn map_process_code_memory(proc_hnd: u32, dst_addr: usize, src_addr: usize, size: usize) -> Result<(), UserspaceError> {
+    let src_addr = VirtualAddress(src_addr);
+    let dst_addr = VirtualAddress(dst_addr);
+
+    src_addr.check_aligned_to(PAGE_SIZE)?;
+    dst_addr.check_aligned_to(PAGE_SIZE)?;
+
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if src_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+    if dst_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    let process = scheduler::get_current_process().phandles.lock().get_handle(proc_hnd)?.as_process()?;
+
+    if !UserLand::contains_region(src_addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    if !UserLand::contains_region(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemRange)
+    }
+
+    let mut mem = process.pmemory.lock();
+
+    // Check the src is RW- memory we're allowed to alias.
+    mem.check_range(src_addr, size,
+        MemoryState::MAP_ALLOWED, MemoryState::MAP_ALLOWED,
+        MemoryPermissions::all(), MemoryPermissions::RW,
+        MemoryAttributes::all(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    // Check the destination is fully unmapped.
+    mem.check_range(dst_addr, size,
+        MemoryState::all(), MemoryType::Unmapped.get_memory_state(),
+        MemoryPermissions::empty(), MemoryPermissions::empty(),
+        MemoryAttributes::empty(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    // Lock the src, and alias it in the dst.
+    let chunks = mem.reprotect_shared(src_addr, size, MappingAccessRights::empty());
+    let mut addr = dst_addr;
+    for (frames, offset, length) in chunks {
+        mem.map_partial_shared_mapping(frames, addr, offset, length, MemoryType::ModuleCodeStatic, MappingAccessRights::k_r())
+            .unwrap_or_else(|err| panic!("Failed to map in dst mem: {:?}", err));
+        addr += length;
+    }
+
+    Ok(())
+}
+
+/// Unmaps a memory range mapped with [map_process_code_memory()], and gives
+/// its original permissions back to the src memory range.
+///
+/// It is possible to partially unmap a ProcessCodeMemory.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - `dst_addr + size` overflows
+///    - The src region is outside of the UserLand address space.
+///    - The src memory pages does not have the MAP_ALLOWED state, or are not
+///      locked.
+///    - The dst memory pages does not have the UNMAP_PROCESS_CODE_MEMORY_ALLOWED
+///      state.
+/// - `InvalidMemRange`
+///    - The dst region is outside of the UserLand address space.
+///    - The given source range does not map the same pages as the given dst
+///      range.
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a Process
+///      handle.
+pub fn unmap_process_code_memory(proc_hnd: u32, dst_addr: usize, src_addr: usize, size: usize) -> Result<(), UserspaceError> {
+    let src_addr = VirtualAddress(src_addr);
+    let dst_addr = VirtualAddress(dst_addr);
+
+    src_addr.check_aligned_to(PAGE_SIZE)?;
+    dst_addr.check_aligned_to(PAGE_SIZE)?;
+
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if src_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+    if dst_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    let process = scheduler::get_current_process().phandles.lock().get_handle(proc_hnd)?.as_process()?;
+
+    if !UserLand::contains_region(src_addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    if !UserLand::contains_region(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemRange)
+    }
+
+    let mut mem = process.pmemory.lock();
+
+    // Check the src is locked.
+    mem.check_range(src_addr, size,
+        MemoryState::MAP_ALLOWED, MemoryState::MAP_ALLOWED,
+        MemoryPermissions::all(), MemoryPermissions::empty(),
+        MemoryAttributes::all(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    // The dst might have been split in mappings with different permissions
+    // by set_process_memory_permission, so check_range's coherence checks
+    // would reject it. Check every mapping individually instead.
+    let mut cur_addr = dst_addr;
+    while cur_addr < dst_addr + size {
+        let meminfo = mem.query_memory(cur_addr);
+        if !meminfo.mapping().state().contains(MemoryState::UNMAP_PROCESS_CODE_MEMORY_ALLOWED) {
+            return Err(UserspaceError::InvalidMemState);
         }
-        size -= curlen;
-        src_addr += curlen;
-        dst_addr += curlen;
+        cur_addr = meminfo.mapping().address() + meminfo.mapping().length();
     }
 
+    // Check the dst really maps the src frames.
+    if mem.page_list(src_addr, size) != mem.page_list(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+
+    // Unmap the dst.
+    mem.unmap_shared(dst_addr, size);
+
+    // Unlock the src.
+    mem.reprotect_shared(src_addr, size, MappingAccessRights::u_rw());
+
     Ok(())
 }
 
@@ -1033,6 +1532,8 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
 ///
 /// * `InvalidEnum`
 ///    * ProcInfo contains invalid bitfields
+/// * `InvalidCombination`
+///    * ProcInfo asks for a 64-bit process. Sunrise only runs 32-bit code.
 /// * `InvalidAddress`
 ///    * ProcInfo's `code_addr` is not 21-bit aligned.
 /// * `InvalidMemRange`
@@ -1042,6 +1543,10 @@ pub fn create_process(procinfo: UserSpacePtr<ProcInfo>, caps: UserSpacePtr<[u8]>
     // Ensure the procinfo structure is well-formed.
     procinfo.flags.check()?;
 
+    if procinfo.flags.is_64bit() {
+        return Err(UserspaceError::InvalidCombination);
+    }
+
     let code_allowed_region = match procinfo.flags.address_space_type() {
         ProcInfoAddrSpace::AS32BitNoMap |
         ProcInfoAddrSpace::AS32Bit => 0x00200000..=0x003FFFFFFF,
@@ -1084,7 +1589,7 @@ pub fn create_process(procinfo: UserSpacePtr<ProcInfo>, caps: UserSpacePtr<[u8]>
     newproc.pmemory.lock().create_regular_mapping(VirtualAddress(procinfo.code_addr as usize), procinfo.code_num_pages as usize * PAGE_SIZE, MemoryType::CodeStatic, MappingAccessRights::k_r())?;
 
     let curproc = scheduler::get_current_process();
-    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::Process(newproc)));
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::Process(newproc)))?;
     Ok(hnd as _)
 }
 
@@ -1244,4 +1749,757 @@ pub fn get_process_list(out_pids: u64, max_pids: u64) -> Result<usize, Userspace
         }
     }
     Ok(out_len)
-}
\ No newline at end of file
+}
+
+/// Gets the memory and handle usage of the process with the given pid. See
+/// [ProcessMemoryUsage] for the details of each field.
+///
+/// This is a Sunrise extension, used by tools like `top`.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has this pid.
+pub fn get_process_memory_usage(mut out: UserSpacePtrMut<ProcessMemoryUsage>, pid: usize) -> Result<(), UserspaceError> {
+    // Don't hold the lock while upgrading: dropping the last reference to a
+    // process takes it.
+    let process_list = crate::process::PROCESS_LIST.lock().clone();
+    let process = process_list.iter()
+        .filter_map(|process| process.upgrade())
+        .find(|process| process.pid == pid)
+        .ok_or(UserspaceError::NoSuchEntry)?;
+
+    *out = process.memory_usage();
+    Ok(())
+}
+
+/// Gets information about the system.
+///
+/// Info Type                   | Description
+/// ----------------------------|--------------------------
+/// TotalPhysicalMemorySize = 0 | Total amount of usable physical memory.
+/// UsedPhysicalMemorySize = 1  | Amount of physical memory currently allocated.
+/// InitialProcessIdRange = 2   | Smallest (sub_id 0) or biggest (sub_id 1) PID of the kernel built-ins.
+/// InitialProcessTitleId = 3   | Title id of the kernel built-in whose PID is sub_id.
+///
+/// For the memory sizes, `sub_id` selects the memory pool. We only have a
+/// single pool, so every pool reports the whole physical memory.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - handle is not 0.
+/// - `InvalidCombination`
+///   - sub_id is not a valid pool, or not 0 or 1 for InitialProcessIdRange.
+/// - `InvalidState`
+///   - The kernel started no built-in.
+/// - `NoSuchEntry`
+///   - sub_id is not the PID of a living built-in, for InitialProcessTitleId.
+/// - `InvalidEnum`
+///   - The passed info_type is unknown.
+pub fn get_system_info(info_type: u32, handle: u32, sub_id: usize) -> Result<u64, UserspaceError> {
+    if handle != 0 {
+        return Err(UserspaceError::InvalidHandle);
+    }
+
+    match SystemInfoType(info_type) {
+        SystemInfoType::TotalPhysicalMemorySize | SystemInfoType::UsedPhysicalMemorySize if sub_id > 3 =>
+            Err(UserspaceError::InvalidCombination),
+        SystemInfoType::TotalPhysicalMemorySize => Ok(FrameAllocator::total_memory() as u64),
+        SystemInfoType::UsedPhysicalMemorySize => Ok((FrameAllocator::total_memory() - FrameAllocator::free_memory()) as u64),
+        SystemInfoType::InitialProcessIdRange => {
+            let (min, max) = *crate::process::INITIAL_PROCESS_ID_RANGE.r#try().ok_or(UserspaceError::InvalidState)?;
+            match sub_id {
+                0 => Ok(min as u64),
+                1 => Ok(max as u64),
+                _ => Err(UserspaceError::InvalidCombination)
+            }
+        },
+        SystemInfoType::InitialProcessTitleId => {
+            let (min, max) = *crate::process::INITIAL_PROCESS_ID_RANGE.r#try().ok_or(UserspaceError::InvalidState)?;
+            if sub_id < min || sub_id > max {
+                return Err(UserspaceError::NoSuchEntry);
+            }
+            // Don't hold the lock while upgrading: dropping the last reference
+            // to a process takes it.
+            let process_list = crate::process::PROCESS_LIST.lock().clone();
+            process_list.iter()
+                .filter_map(|process| process.upgrade())
+                .find(|process| process.pid == sub_id)
+                .map(|process| process.title_id)
+                .ok_or(UserspaceError::NoSuchEntry)
+        },
+        _ => Err(UserspaceError::InvalidEnum)
+    }
+}
+
+/// Builds the [ThreadContext] of a thread from the registers saved on its last
+/// kernel entry.
+fn thread_context(thread: &ThreadStruct) -> ThreadContext {
+    thread.userspace_hwcontext.lock().thread_context()
+}
+
+/// Gets a thread of the current process, other than the current thread, from
+/// its handle.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a thread.
+///    - The thread is the current thread, or belongs to another process.
+/// - `InvalidState`
+///    - The thread is already dead.
+fn get_other_thread(handle: u32) -> Result<Arc<ThreadStruct>, UserspaceError> {
+    let thread = get_current_process().phandles.lock()
+        .get_handle(handle)?.as_thread_handle()?
+        .upgrade().ok_or(UserspaceError::InvalidState)?;
+
+    if Arc::ptr_eq(&thread, &get_current_thread()) || !Arc::ptr_eq(&thread.process, &get_current_process()) {
+        return Err(UserspaceError::InvalidHandle);
+    }
+    Ok(thread)
+}
+
+/// Pauses or resumes a thread of the current process.
+///
+/// A paused thread stops the next time it would return to userspace, and
+/// stays stopped until it is set back to Runnable.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a thread.
+///    - The thread is the current thread, or belongs to another process.
+/// - `InvalidEnum`
+///    - The activity is unknown.
+/// - `InvalidState`
+///    - Pausing an already paused thread, or resuming a runnable thread.
+///    - The thread is already dead.
+pub fn set_thread_activity(handle: u32, activity: u32) -> Result<(), UserspaceError> {
+    let suspended = match ThreadActivity(activity) {
+        ThreadActivity::Runnable => false,
+        ThreadActivity::Paused => true,
+        _ => return Err(UserspaceError::InvalidEnum)
+    };
+    get_other_thread(handle)?.set_suspended(suspended)
+}
+
+/// Pauses or resumes every thread of a process.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a process.
+///    - The process is the current process.
+/// - `InvalidEnum`
+///    - The activity is unknown.
+/// - `InvalidState`
+///    - A thread of the process was already in the requested activity. The
+///      other threads are still updated.
+pub fn set_process_activity(handle: u32, activity: u32) -> Result<(), UserspaceError> {
+    let suspended = match ProcessActivity(activity) {
+        ProcessActivity::Runnable => false,
+        ProcessActivity::Paused => true,
+        _ => return Err(UserspaceError::InvalidEnum)
+    };
+    let process = get_current_process().phandles.lock().get_handle(handle)?.as_process()?;
+    if Arc::ptr_eq(&process, &get_current_process()) {
+        return Err(UserspaceError::InvalidHandle);
+    }
+
+    let mut ret = Ok(());
+    for thread in process.living_threads() {
+        if let Err(err) = thread.set_suspended(suspended) {
+            ret = Err(err);
+        }
+    }
+    ret
+}
+
+/// Gets the id of the given thread. Thread ids are unique across the whole
+/// system, and are never reused.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a thread.
+/// - `InvalidState`
+///    - The thread is already dead.
+pub fn get_thread_id(handle: u32) -> Result<usize, UserspaceError> {
+    let thread = get_current_process().phandles.lock()
+        .get_handle(handle)?.as_thread_handle()?
+        .upgrade().ok_or(UserspaceError::InvalidState)?;
+    Ok(thread.tid)
+}
+
+/// Gets the userspace registers of a paused thread of the current process.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a thread.
+///    - The thread is the current thread, or belongs to another process.
+/// - `InvalidState`
+///    - The thread is not paused.
+///    - The thread is already dead.
+pub fn get_thread_context3(mut out: UserSpacePtrMut<ThreadContext>, handle: u32) -> Result<(), UserspaceError> {
+    let thread = get_other_thread(handle)?;
+    if !thread.is_suspended() {
+        return Err(UserspaceError::InvalidState);
+    }
+    *out = thread_context(&thread);
+    Ok(())
+}
+
+/// Fills the provided array with the ids of the living threads of a process.
+///
+/// If `handle` is 0, lists the threads of the current process. Otherwise, it
+/// must be a debug handle obtained through [debug_active_process].
+///
+/// Returns the total number of living threads. If this number is bigger than
+/// the size of the array, the user won't have all the ids.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is not 0 and is not a debug handle.
+pub fn get_thread_list(mut out_tids: UserSpacePtrMut<[u64]>, handle: u32) -> Result<usize, UserspaceError> {
+    let process = if handle == 0 {
+        get_current_process()
+    } else {
+        get_current_process().phandles.lock().get_handle(handle)?.as_debug()?
+    };
+
+    let threads = process.living_threads();
+    for (out, thread) in out_tids.iter_mut().zip(&threads) {
+        *out = thread.tid as u64;
+    }
+    Ok(threads.len())
+}
+
+/// Creates a debug handle on the process with the given pid, allowing to
+/// inspect its threads through [get_thread_list], [get_debug_thread_context]
+/// and [get_debug_thread_param].
+///
+/// The target process must be [can_be_debugged], unless the current process
+/// [can_debug_others].
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has this pid.
+/// - `InvalidState`
+///   - The target process can't be debugged, and the current process is not
+///     allowed to debug other processes.
+///
+/// [can_be_debugged]: crate::process::ProcessCapabilities::can_be_debugged
+/// [can_debug_others]: crate::process::ProcessCapabilities::can_debug_others
+// TODO: Debug events
+// BODY: A debug handle should be waitable, and receive events when the process
+// BODY: creates threads, crashes, or hits a breakpoint. We only implement the
+// BODY: thread inspection part for now.
+pub fn debug_active_process(pid: usize) -> Result<usize, UserspaceError> {
+    // Don't hold the lock while upgrading: dropping the last reference to a
+    // process takes it.
+    let process_list = crate::process::PROCESS_LIST.lock().clone();
+    let process = process_list.iter()
+        .filter_map(|process| process.upgrade())
+        .find(|process| process.pid == pid)
+        .ok_or(UserspaceError::NoSuchEntry)?;
+
+    let curproc = get_current_process();
+    if !process.capabilities.can_be_debugged && !curproc.capabilities.can_debug_others {
+        return Err(UserspaceError::InvalidState);
+    }
+
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::Debug(process)))?;
+    Ok(hnd as usize)
+}
+
+/// Gets a thread of a debugged process from its id.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a debug handle.
+/// - `NoSuchEntry`
+///    - The process has no living thread with this id.
+fn get_debug_thread(handle: u32, tid: usize) -> Result<Arc<ThreadStruct>, UserspaceError> {
+    let process = get_current_process().phandles.lock().get_handle(handle)?.as_debug()?;
+    process.living_threads().into_iter()
+        .find(|thread| thread.tid == tid)
+        .ok_or(UserspaceError::NoSuchEntry)
+}
+
+/// Gets the userspace registers of a thread of a debugged process.
+///
+/// The thread does not need to be paused. If it isn't, the registers are a
+/// snapshot of its last kernel entry.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a debug handle.
+/// - `NoSuchEntry`
+///    - The process has no living thread with this id.
+pub fn get_debug_thread_context(mut out: UserSpacePtrMut<ThreadContext>, handle: u32, tid: usize, _flags: u32) -> Result<(), UserspaceError> {
+    let thread = get_debug_thread(handle, tid)?;
+    *out = thread_context(&thread);
+    Ok(())
+}
+
+/// Gets information about a thread of a debugged process.
+///
+/// Param     | Description
+/// ----------|--------------------------
+/// State = 1 | Returns 1 in the 64-bit output if the thread is paused, 0
+///           | otherwise, and its [ThreadState] in the 32-bit output.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a debug handle.
+/// - `NoSuchEntry`
+///    - The process has no living thread with this id.
+/// - `InvalidEnum`
+///    - The param is unknown.
+pub fn get_debug_thread_param(handle: u32, tid: usize, param: u32) -> Result<(usize, usize), UserspaceError> {
+    let thread = get_debug_thread(handle, tid)?;
+
+    match DebugThreadParam(param) {
+        DebugThreadParam::State => {
+            let state = match thread.state.load(Ordering::SeqCst) {
+                crate::process::ThreadState::Paused => ThreadState::Waiting,
+                crate::process::ThreadState::Running => ThreadState::Running,
+                crate::process::ThreadState::TerminationPending => ThreadState::TerminationPending,
+                crate::process::ThreadState::Scheduled => ThreadState::Scheduled,
+            };
+            Ok((thread.is_suspended() as usize, state.0 as usize))
+        },
+        _ => Err(UserspaceError::InvalidEnum)
+    }
+}
+
+/// Gets information about an address in the address space of a debugged
+/// process. Behaves exactly like [query_memory()] otherwise.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a debug handle.
+pub fn query_debug_process_memory(meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, debug_hnd: u32, addr: usize) -> Result<usize, UserspaceError> {
+    let process = get_current_process().phandles.lock().get_handle(debug_hnd)?.as_debug()?;
+    write_memory_info(meminfo, &process, addr)
+}
+
+/// Copies memory of a debugged process, starting at `addr`, into the given
+/// buffer.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a debug handle.
+/// - `InvalidMemRange`
+///    - The range does not fit in the userspace address space.
+/// - `InvalidMemState`
+///    - Part of the range is not readable.
+///    - Part of the range is not backed by regular memory, such as an MMIO
+///      region.
+pub fn read_debug_process_memory(mut buffer: UserSpacePtrMut<[u8]>, debug_hnd: u32, addr: usize) -> Result<(), UserspaceError> {
+    let process = get_current_process().phandles.lock().get_handle(debug_hnd)?.as_debug()?;
+    let size = buffer.len();
+    if size == 0 {
+        return Ok(());
+    }
+    if !UserLand::contains_region(VirtualAddress(addr), size) {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+
+    let mem = process.pmemory.lock();
+    let mut copied = 0;
+    while copied < size {
+        let cur_addr = VirtualAddress(addr + copied);
+        let qmem = mem.query_memory(cur_addr);
+        let mapping = qmem.mapping();
+        let perms: MemoryPermissions = mapping.flags().into();
+        if !perms.contains(MemoryPermissions::READABLE) {
+            return Err(UserspaceError::InvalidMemState);
+        }
+
+        let curlen = core::cmp::min(size - copied, (mapping.address() + mapping.length()) - cur_addr);
+        let mirror = mem.mirror_mapping(cur_addr, curlen)?;
+        let from = UserSpacePtr::from_raw_parts(mirror.addr().addr() as *const u8, mirror.len());
+        buffer[copied..copied + curlen].copy_from_slice(&from);
+        copied += curlen;
+    }
+    Ok(())
+}
+
+/// Registers the exception handler of the current process. See
+/// [ExceptionHandler].
+///
+/// When a thread causes an exception, it jumps to `entrypoint` on the stack
+/// ending at `stack_top`, with a pointer to an [ExceptionInfo] as its sole
+/// cdecl argument. The handler must then call [return_from_exception].
+///
+/// A null `entrypoint` unregisters the handler, making exceptions crash the
+/// process again.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - `entrypoint` or the page below `stack_top` is outside of the
+///      userspace address space.
+/// - `InvalidState`
+///    - A thread is currently running the exception handler.
+pub fn set_exception_handler(entrypoint: usize, stack_top: usize) -> Result<(), UserspaceError> {
+    let process = get_current_process();
+    let mut handler = process.exception_handler.lock();
+    if handler.as_ref().map_or(false, |handler| handler.current.is_some()) {
+        return Err(UserspaceError::InvalidState);
+    }
+
+    if entrypoint == 0 {
+        *handler = None;
+        return Ok(());
+    }
+
+    if !UserLand::contains_address(VirtualAddress(entrypoint)) || stack_top < PAGE_SIZE
+        || !UserLand::contains_region(VirtualAddress(stack_top - PAGE_SIZE), PAGE_SIZE)
+    {
+        return Err(UserspaceError::InvalidAddress);
+    }
+
+    *handler = Some(ExceptionHandler {
+        entrypoint: VirtualAddress(entrypoint),
+        stack_top: VirtualAddress(stack_top),
+        current: None,
+    });
+    Ok(())
+}
+
+/// Finishes handling an exception in the exception handler of the process.
+///
+/// If `result` is 0, the thread that caused the exception resumes with the
+/// registers found in the [ExceptionInfo] given to the handler, which it may
+/// have modified. Otherwise, the process crashes, with the exception in its
+/// crash report.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///    - The current thread is not running the exception handler.
+pub fn return_from_exception(result: u32, hwcontext: &mut UserspaceHardwareContext) -> Result<(), UserspaceError> {
+    let thread = get_current_thread();
+    let (info_addr, info) = {
+        let mut handler = thread.process.exception_handler.lock();
+        let handler = handler.as_mut().ok_or(UserspaceError::InvalidState)?;
+        match handler.current {
+            Some((tid, info_addr, info)) if tid == thread.tid => {
+                handler.current = None;
+                (info_addr, info)
+            },
+            _ => return Err(UserspaceError::InvalidState)
+        }
+    };
+
+    let is_info_readable = result == 0 && thread.process.pmemory.lock().check_range(info_addr, size_of::<ExceptionInfo>(),
+        MemoryState::empty(), MemoryState::empty(),
+        MemoryPermissions::READABLE, MemoryPermissions::READABLE,
+        MemoryAttributes::empty(), MemoryAttributes::empty(),
+        MemoryAttributes::empty()).is_ok();
+
+    if is_info_readable {
+        let context = UserSpacePtr(info_addr.addr() as *const ExceptionInfo).context;
+        unsafe {
+            // Safety: we're handling a syscall, which came from userspace.
+            hwcontext.set_thread_context(&context);
+        }
+    } else {
+        error!("Exception handler of process {} did not handle {:?} (result {:#x})",
+               thread.process.name, info.exception_type, result);
+        let report = CrashReport {
+            tid: thread.tid,
+            exception: info,
+            ..CrashReport::default()
+        };
+        drop(thread);
+        ProcessStruct::crash_current_process(report);
+    }
+    Ok(())
+}
+
+/// Reports a fatal error, and crashes the current process.
+///
+/// The crash report of the process gets `reason`, the registers of the
+/// calling thread, and the start of `info`. If `info` is not readable, the
+/// report is kept without it.
+pub fn break_(reason: u32, info: UserSpacePtr<[u8]>) -> Result<(), UserspaceError> {
+    let thread = get_current_thread();
+    let mut report = CrashReport {
+        tid: thread.tid,
+        exception: ExceptionInfo {
+            exception_type: ExceptionType::UserBreak,
+            context: thread_context(&thread),
+            ..ExceptionInfo::default()
+        },
+        break_reason: BreakReason(reason),
+        ..CrashReport::default()
+    };
+
+    let info_addr = VirtualAddress(info.0 as *const u8 as usize);
+    let info_size = core::cmp::min(info.len(), report.break_info.len());
+    let is_info_readable = info_size != 0 && UserLand::contains_region(info_addr, info_size)
+        && thread.process.pmemory.lock().check_range(info_addr, info_size,
+            MemoryState::empty(), MemoryState::empty(),
+            MemoryPermissions::READABLE, MemoryPermissions::READABLE,
+            MemoryAttributes::empty(), MemoryAttributes::empty(),
+            MemoryAttributes::empty()).is_ok();
+    if is_info_readable {
+        report.break_info[..info_size].copy_from_slice(&info[..info_size]);
+        report.break_info_size = info_size;
+    }
+
+    error!("Process {} called Break with reason {:?}", thread.process.name, report.break_reason);
+    drop(thread);
+    ProcessStruct::crash_current_process(report);
+    Ok(())
+}
+
+/// Gets why a debugged process crashed.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a debug handle.
+/// - `NoSuchEntry`
+///    - The process did not crash. It is still running, or exited normally.
+pub fn get_crash_report(mut out: UserSpacePtrMut<CrashReport>, debug_hnd: u32) -> Result<(), UserspaceError> {
+    let process = get_current_process().phandles.lock().get_handle(debug_hnd)?.as_debug()?;
+    let report = (*process.crash_report.lock()).ok_or(UserspaceError::NoSuchEntry)?;
+    *out = report;
+    Ok(())
+}
+
+/// Sets the functions of a process, used to symbolize its backtraces. See
+/// [SymbolMap].
+///
+/// `symtab` and `strtab` are the raw `.symtab` and `.strtab` sections of the
+/// ELF binary of the process, loaded at `base`. They replace the symbols
+/// the process previously had.
+///
+/// The binary has the same ELF class as the kernel: [create_process] refuses
+/// processes of another pointer width.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a process handle.
+/// - `InvalidState`
+///    - The process was already started.
+pub fn set_process_symbols(proc_hnd: u32, symtab: UserSpacePtr<[u8]>, strtab: UserSpacePtr<[u8]>, base: usize) -> Result<(), UserspaceError> {
+    let process = get_current_process().phandles.lock().get_handle(proc_hnd)?.as_process()?;
+    if process.state() != ProcessState::Created {
+        return Err(UserspaceError::InvalidState);
+    }
+
+    let class = if cfg!(target_pointer_width = "64") { Class::SixtyFour } else { Class::ThirtyTwo };
+    let symbols = SymbolMap::from_elf_sections(&symtab, &strtab, class, base);
+    *process.symbols.lock() = Some(Arc::new(symbols));
+    Ok(())
+}
+
+/// Finds the function containing `addr` in a debugged process.
+///
+/// Copies as much of its name as fits in `name`, and returns the offset of
+/// `addr` in the function, and the length of its whole name.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a debug handle.
+/// - `NoSuchEntry`
+///    - The process has no symbols, or `addr` is not in any of its functions.
+pub fn get_debug_process_symbol(mut name: UserSpacePtrMut<[u8]>, debug_hnd: u32, addr: usize) -> Result<(usize, usize), UserspaceError> {
+    let process = get_current_process().phandles.lock().get_handle(debug_hnd)?.as_debug()?;
+    let symbols = process.symbols.lock().clone().ok_or(UserspaceError::NoSuchEntry)?;
+    let (symbol_name, offset) = symbols.lookup(addr).ok_or(UserspaceError::NoSuchEntry)?;
+
+    let copy_len = core::cmp::min(name.len(), symbol_name.len());
+    name[..copy_len].copy_from_slice(&symbol_name.as_bytes()[..copy_len]);
+    Ok((offset, symbol_name.len()))
+}
+
+/// Reads the kernel log, starting after `cursor`. See [crate::log_impl::dmesg].
+///
+/// Fills `buf` with as many whole records as fit, in the format described in
+/// [sunrise_libkern::dmesg]. Pass 0 as `cursor` to start from the oldest record
+/// still in the log, and the returned cursor to get the following records.
+///
+/// # Returns
+///
+/// 0. The cursor to pass to the next read.
+/// 1. The number of bytes written to `buf`.
+pub fn read_kernel_log(mut buf: UserSpacePtrMut<[u8]>, cursor: usize) -> Result<(usize, usize), UserspaceError> {
+    Ok(crate::log_impl::dmesg::read(cursor, &mut buf))
+}
+
+/// Creates an event signaled when records are added to the kernel log. It is
+/// signaled at most once for every wait, no matter how many records were
+/// added.
+///
+/// # Returns
+///
+/// A handle to the event.
+pub fn create_kernel_log_event() -> Result<usize, UserspaceError> {
+    let hnd = get_current_process().phandles.lock().add_handle(Arc::new(Handle::KernelLogEvent(KernelLogEvent::default())))?;
+    Ok(hnd as _)
+}
+
+/// Replaces the kernel log filter directives of the processes named
+/// `process_name`, or the global filter if `process_name` is empty. See
+/// [crate::log_impl::set_filter].
+///
+/// The directives use the same syntax as the kernel cmdline, e.g.
+/// `info,sunrise_libuser::ipc=trace`. Empty directives remove the filter of
+/// the processes, making them use the global filter again.
+pub fn set_log_filter(directives: UserSpacePtr<[u8]>, process_name: UserSpacePtr<[u8]>) -> Result<(), UserspaceError> {
+    let directives = String::from_utf8_lossy(&*directives);
+    let process_name = String::from_utf8_lossy(&*process_name);
+    let process_name = if process_name.is_empty() { None } else { Some(&*process_name) };
+    info!("Setting log filter of {} to {:?}", process_name.unwrap_or("the kernel"), directives);
+    crate::log_impl::set_filter(process_name, &directives);
+    Ok(())
+}
+
+/// Reads the kernel trace buffer of CPU `cpu`. Only available when the kernel
+/// is built with the `tracing` feature.
+///
+/// Fills `buf` with as many events as fit, as an array of
+/// [TraceEvent](sunrise_libkern::trace::TraceEvent). Pass 0 as `cursor` to
+/// start from the oldest event still in the buffer, and the returned cursor to
+/// get the following events.
+///
+/// # Returns
+///
+/// 0. The cursor to pass to the next read.
+/// 1. The number of events written to `buf`.
+///
+/// # Errors
+///
+/// - `InvalidProcessorId`
+///    - `cpu` does not exist.
+/// - `NotImplemented`
+///    - The kernel was built without the `tracing` feature.
+pub fn read_trace_buffer(cpu: usize, mut buf: UserSpacePtrMut<[u8]>, cursor: usize) -> Result<(usize, usize), UserspaceError> {
+    crate::trace::read(cpu, cursor, &mut buf)
+}
+
+/// Creates a disarmed timer. Arm it with [set_timer].
+///
+/// A timer gets signaled when its deadline passes, and stays signaled until it
+/// is cleared with [clear_event]. A periodic timer then gets signaled again at
+/// its next period.
+///
+/// # Returns
+///
+/// A handle to the timer.
+pub fn create_timer() -> Result<usize, UserspaceError> {
+    let hnd = get_current_process().phandles.lock().add_handle(Arc::new(Handle::Timer(Timer::default())))?;
+    Ok(hnd as _)
+}
+
+/// Arms a timer to get signaled in `delay_ns` nanoseconds, and then every
+/// `period_ns` nanoseconds if it is not 0. Clears its signaled state.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a timer.
+pub fn set_timer(timer_hnd: u32, delay_ns: u64, period_ns: u64) -> Result<(), UserspaceError> {
+    let timer = get_current_process().phandles.lock().get_handle(timer_hnd)?;
+    timer.as_timer()?.set(delay_ns, period_ns);
+    Ok(())
+}
+
+/// Disarms a timer, and clears its signaled state.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle is invalid or not a timer.
+pub fn cancel_timer(timer_hnd: u32) -> Result<(), UserspaceError> {
+    let timer = get_current_process().phandles.lock().get_handle(timer_hnd)?;
+    timer.as_timer()?.cancel();
+    Ok(())
+}
+
+/// Turns the system off, or resets it if `reboot` is true.
+///
+/// Horizon puts the console to sleep instead, and takes no argument. Sunrise
+/// has no sleep state to go to.
+///
+/// The kernel does not save anything before going down: the caller should
+/// first ask the services to save their state, the filesystem in particular.
+///
+/// Only returns if it failed to turn the system off. Resetting always
+/// succeeds, falling back to a triple fault.
+///
+/// # Errors
+///
+/// - `NotImplemented`
+///    - The system does not support ACPI soft off.
+/// - `InvalidState`
+///    - The system did not turn off.
+pub fn sleep_system(reboot: bool) -> Result<(), UserspaceError> {
+    if reboot {
+        power::reboot()
+    } else {
+        power::power_off()
+    }
+}
+
+/// Creates an event signaled when the power button is pressed. It stays
+/// signaled until it is cleared with [clear_event].
+///
+/// # Returns
+///
+/// A handle to the event.
+///
+/// # Errors
+///
+/// - `NotImplemented`
+///    - The system does not describe a power button in its ACPI tables.
+pub fn create_power_button_event() -> Result<usize, UserspaceError> {
+    let event = PowerButtonEvent::new()?;
+    let hnd = get_current_process().phandles.lock().add_handle(Arc::new(Handle::PowerButtonEvent(event)))?;
+    Ok(hnd as _)
+}
+
+/// Lists the open handles of the process with the given pid, to chase handle
+/// leaks. See [HandleInfo] for the details of each field.
+///
+/// Fills `out` with as many handles as fit, sorted by handle number, and
+/// returns the number of handles the process has open.
+///
+/// This is a Sunrise extension, used by the `handles` command of the shell.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///    - No living process has this pid.
+pub fn get_process_handles(mut out: UserSpacePtrMut<[HandleInfo]>, pid: usize) -> Result<usize, UserspaceError> {
+    // Don't hold the lock while upgrading: dropping the last reference to a
+    // process takes it.
+    let process_list = crate::process::PROCESS_LIST.lock().clone();
+    let process = process_list.iter()
+        .filter_map(|process| process.upgrade())
+        .find(|process| process.pid == pid)
+        .ok_or(UserspaceError::NoSuchEntry)?;
+
+    let handles = process.handle_infos();
+    for (out, handle) in out.iter_mut().zip(handles.iter()) {
+        *out = *handle;
+    }
+    Ok(handles.len())
+}
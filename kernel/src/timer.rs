@@ -1,21 +1,64 @@
 //! The core timing of Sunrise.
+//!
+//! The kernel keeps track of time with a hardware timer. When possible, the
+//! timer is used tickless: it keeps a high resolution counter, and only raises
+//! an interrupt at the nearest deadline a thread is waiting for. Otherwise, it
+//! raises an IRQ periodically, and the time is derived by counting them.
+//!
+//! Threads waiting on a [Timer] are kept in a queue, sorted by deadline. When
+//! the timer IRQ is triggered, [handle_irq] only has to look at the front of
+//! the queue to wake up the threads whose deadline passed, and to program the
+//! next deadline.
 
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Debug;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use super::event;
-use super::event::{IRQEvent, Waitable};
-use super::sync::Once;
+use failure::Backtrace;
+
+use super::error::KernelError;
+use super::event::{self, Waitable};
+use super::process::ThreadStruct;
+use super::scheduler;
+use super::sync::{Once, SpinLockIRQ};
 use super::utils::div_ceil;
 
+/// A hardware timer able to raise its IRQ at an arbitrary time, used to run
+/// the kernel tickless.
+pub trait DeadlineTimer: Debug + Send + Sync {
+    /// Gets the time elapsed since the timer started, in nanoseconds.
+    fn now_ns(&self) -> u64;
+
+    /// Programs the timer to raise its IRQ at `deadline_ns`, replacing the
+    /// previous deadline. If `deadline_ns` already passed, the IRQ is raised
+    /// as soon as possible.
+    ///
+    /// This is always called with the timer queue locked.
+    fn set_deadline_ns(&self, deadline_ns: u64);
+}
+
+/// How the kernel timer keeps track of time.
+#[derive(Debug)]
+enum TimerMode {
+    /// The timer raises an IRQ periodically.
+    Periodic {
+        /// The IRQ period in nanoseconds.
+        irq_period_ns: u64,
+    },
+    /// The timer raises an IRQ at the nearest deadline.
+    Tickless(&'static dyn DeadlineTimer),
+}
+
 /// This represent the information to derive all internal timing in Sunrise.
 struct KernelTimerInfo {
     /// The frequency of the oscillator used as primary source of this timer, when not divided, in Hertz.
     ///
-    /// The value here is only informative, you should use `.irq_periode_ns`.
+    /// The value here is only informative.
     oscillator_frequency: u64,
 
-    /// The IRQ period used on this timer in nanoseconds.
-    pub irq_period_ns: u64,
+    /// How the timer keeps track of time.
+    mode: TimerMode,
 
     /// The IRQ number that the timer use.
     pub irq_number: u8,
@@ -24,80 +67,427 @@ struct KernelTimerInfo {
 /// Stores the information needed for Sunrise's internal timing.
 static KERNEL_TIMER_INFO: Once<KernelTimerInfo> = Once::new();
 
-/// Set the information required for Sunrise timer to work.
-/// 
+/// Sets the timer used by Sunrise.
+///
 /// # Panics
 ///
 /// Panics if the timer info has already been initialized.
-pub fn set_kernel_timer_info(irq_number: u8, oscillator_frequency: u64, irq_period_ns: u64) {
+fn init_kernel_timer_info(timer_info: KernelTimerInfo) {
     assert!(KERNEL_TIMER_INFO.r#try().is_none(), "Kernel Timer Info is already initialized!");
-    KERNEL_TIMER_INFO.call_once(|| {
-        KernelTimerInfo {
-            irq_number,
-            oscillator_frequency,
-            irq_period_ns
-        }
+    KERNEL_TIMER_INFO.call_once(|| timer_info);
+}
+
+/// Set the information required for Sunrise timer to work, from a timer
+/// raising `irq_number` every `irq_period_ns` nanoseconds.
+///
+/// # Panics
+///
+/// Panics if the timer info has already been initialized.
+pub fn set_kernel_timer_info(irq_number: u8, oscillator_frequency: u64, irq_period_ns: u64) {
+    init_kernel_timer_info(KernelTimerInfo {
+        irq_number,
+        oscillator_frequency,
+        mode: TimerMode::Periodic { irq_period_ns },
     });
 }
 
-/// Returns a stream of event that trigger every `ns` amount of nanoseconds.
-/// 
-/// # Note
-/// 
-/// - If the timer resolution cannot handle it, this is not going to be accurate.
-/// - Minimal resolution for HPET (10Mhz) / HPET QEMU (100Mhz): 100ns / 10ns
-/// - Minimal resolution for PIC (~1Mhz): 10ms
-pub fn wait_ns(ns: usize) -> impl Waitable {
-    let timer_info = KERNEL_TIMER_INFO.r#try().expect("Kernel Timer Info is not initialized!");
-    IRQTimer::new(ns, timer_info.irq_number, timer_info.irq_period_ns)
+/// Set the information required for Sunrise timer to work, from a tickless
+/// timer raising `irq_number` at the deadlines it is given.
+///
+/// # Panics
+///
+/// Panics if the timer info has already been initialized.
+pub fn set_tickless_kernel_timer_info(irq_number: u8, oscillator_frequency: u64, timer: &'static dyn DeadlineTimer) {
+    init_kernel_timer_info(KernelTimerInfo {
+        irq_number,
+        oscillator_frequency,
+        mode: TimerMode::Tickless(timer),
+    });
+}
+
+/// Converts a number of ticks of a counter running at `frequency` Hz to
+/// nanoseconds, rounding down.
+pub fn ticks_to_ns(ticks: u64, frequency: u64) -> u64 {
+    ticks / frequency * 1_000_000_000 + ticks % frequency * 1_000_000_000 / frequency
+}
+
+/// Converts nanoseconds to a number of ticks of a counter running at
+/// `frequency` Hz, rounding up. Saturates instead of overflowing.
+pub fn ns_to_ticks(ns: u64, frequency: u64) -> u64 {
+    (ns / 1_000_000_000).saturating_mul(frequency)
+        .saturating_add(div_ceil(ns % 1_000_000_000 * frequency, 1_000_000_000))
+}
+
+/// Gets the time elapsed since the kernel timer started, in nanoseconds.
+/// Returns 0 while the timer is not initialized.
+///
+/// With a periodic timer, this counts its interruptions, and is only as
+/// precise as its period.
+pub fn get_time_since_boot_ns() -> u64 {
+    match KERNEL_TIMER_INFO.r#try() {
+        Some(KernelTimerInfo { mode: TimerMode::Periodic { irq_period_ns }, irq_number, .. }) =>
+            event::get_irq_count(*irq_number) as u64 * irq_period_ns,
+        Some(KernelTimerInfo { mode: TimerMode::Tickless(timer), .. }) => timer.now_ns(),
+        None => 0
+    }
+}
+
+/// A waiter on a [Timer], usually a thread.
+#[derive(Debug)]
+struct TimerQueueEntry<T> {
+    /// When the waiter should be woken up, in nanoseconds since boot.
+    deadline_ns: u64,
+    /// Id of the [Timer] the waiter is waiting on.
+    timer_id: usize,
+    /// The waiter.
+    waiter: T,
 }
 
+/// The waiters on the [Timer]s, sorted by decreasing deadline, so the nearest
+/// one is at the end.
 #[derive(Debug)]
-/// A stream of event that trigger every `ns` amount of nanoseconds, by counting interruptions.
-pub struct IRQTimer {
-    /// Approximation of number of ns spent between triggers.
-    every_ns: usize,
-    /// IRQ event period in nanoseconds.
-    irq_period_ns: u64,
-    /// The IRQ that we wait on.
-    parent_event: IRQEvent,
-    /// The reset value of ``.countdown_value``.
-    reset_value: usize,
-    /// Number of IRQ triggers to wait for. Derived from `.every_ns`. This is the exact time amout that is used.
-    countdown_value: AtomicUsize
-}
-
-impl IRQTimer {
-    /// Create a new IRQ timer instance from the time to wait (in ns), the irq number and irq event period (in ns).
-    pub fn new(ns: usize, irq: u8, irq_period_ns: u64) -> Self {
-        let mut reset_value = div_ceil(ns as u64, irq_period_ns) as usize;
-        if reset_value == 0 {
-            reset_value = 1;
+struct TimerQueue<T> {
+    /// The waiters, sorted by decreasing deadline.
+    entries: Vec<TimerQueueEntry<T>>,
+}
+
+impl<T> TimerQueue<T> {
+    /// Creates an empty queue.
+    const fn new() -> TimerQueue<T> {
+        TimerQueue { entries: Vec::new() }
+    }
+
+    /// Adds a waiter on the timer `timer_id` to the queue.
+    fn insert(&mut self, deadline_ns: u64, timer_id: usize, waiter: T) {
+        let idx = match self.entries.binary_search_by(|probe| deadline_ns.cmp(&probe.deadline_ns)) {
+            Ok(idx) | Err(idx) => idx
+        };
+        self.entries.insert(idx, TimerQueueEntry { deadline_ns, timer_id, waiter });
+    }
+
+    /// Checks whether a waiter on the timer `timer_id` matching `matches` is in
+    /// the queue.
+    fn contains(&self, timer_id: usize, matches: impl Fn(&T) -> bool) -> bool {
+        self.entries.iter().any(|entry| entry.timer_id == timer_id && matches(&entry.waiter))
+    }
+
+    /// Removes the waiters on the timer `timer_id` matching `matches` from the
+    /// queue, and returns them.
+    fn remove(&mut self, timer_id: usize, matches: impl Fn(&T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        let mut idx = 0;
+        while idx < self.entries.len() {
+            if self.entries[idx].timer_id == timer_id && matches(&self.entries[idx].waiter) {
+                removed.push(self.entries.remove(idx).waiter);
+            } else {
+                idx += 1;
+            }
         }
+        removed
+    }
+
+    /// Moves the waiters on the timer `timer_id` to a new deadline.
+    fn requeue(&mut self, timer_id: usize, deadline_ns: u64) {
+        for waiter in self.remove(timer_id, |_| true) {
+            self.insert(deadline_ns, timer_id, waiter);
+        }
+    }
 
-        IRQTimer {
-            every_ns: ns,
-            irq_period_ns,
-            parent_event: event::wait_event(irq),
-            reset_value,
-            countdown_value: AtomicUsize::new(0)
+    /// Removes the waiter with the nearest deadline if it passed at `now_ns`,
+    /// and returns it.
+    fn pop_expired(&mut self, now_ns: u64) -> Option<T> {
+        if self.next_deadline()? <= now_ns {
+            self.entries.pop().map(|entry| entry.waiter)
+        } else {
+            None
         }
     }
+
+    /// Gets the nearest deadline in the queue.
+    fn next_deadline(&self) -> Option<u64> {
+        self.entries.last().map(|entry| entry.deadline_ns)
+    }
 }
 
-impl Waitable for IRQTimer {
-    fn register(&self) {
-        self.parent_event.register()
+/// The threads waiting on a [Timer].
+///
+/// Dropping the last reference to a thread frees it, which must not happen
+/// while the queue is locked. Threads removed from the queue are dropped
+/// after releasing it.
+static TIMER_QUEUE: SpinLockIRQ<TimerQueue<Arc<ThreadStruct>>> = SpinLockIRQ::new(TimerQueue::new());
+
+/// Programs the timer for the nearest deadline of `queue`, when it runs
+/// tickless.
+fn program_next_deadline(queue: &TimerQueue<Arc<ThreadStruct>>) {
+    if let (Some(KernelTimerInfo { mode: TimerMode::Tickless(timer), .. }), Some(next)) = (KERNEL_TIMER_INFO.r#try(), queue.next_deadline()) {
+        timer.set_deadline_ns(next);
+    }
+}
+
+/// Wakes up the threads whose deadline passed, and programs the timer for the
+/// next deadline.
+///
+/// Called on every IRQ. Does nothing if `irq` is not the kernel timer IRQ.
+pub fn handle_irq(irq: u8) {
+    match KERNEL_TIMER_INFO.r#try() {
+        Some(timer_info) if timer_info.irq_number == irq => (),
+        _ => return
+    };
+
+    let now = get_time_since_boot_ns();
+    loop {
+        let mut queue = TIMER_QUEUE.lock();
+        match queue.pop_expired(now) {
+            Some(thread) => {
+                drop(queue);
+                scheduler::add_to_schedule_queue(thread);
+            },
+            None => {
+                program_next_deadline(&queue);
+                break;
+            }
+        }
     }
+}
+
+/// Deadline of a disarmed [Timer].
+const DISARMED: u64 = u64::max_value();
+
+/// Source of the ids of the [Timer]s.
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The deadline and period of a [Timer].
+#[derive(Debug, Clone, Copy)]
+struct TimerState {
+    /// When the timer gets signaled, in nanoseconds since boot. [DISARMED]
+    /// if it is disarmed.
+    deadline_ns: u64,
+    /// The period of the timer in nanoseconds, or 0 if it is one-shot.
+    period_ns: u64,
+}
 
+/// A waitable signaled once its deadline passes, and then periodically if it
+/// has a period.
+///
+/// A timer stays signaled until it is cleared with [Timer::clear], which moves
+/// a periodic timer to its next period, and disarms a one-shot timer.
+#[derive(Debug)]
+pub struct Timer {
+    /// Identifies the threads waiting on this timer in the timer queue.
+    id: usize,
+    /// The deadline and period of the timer.
+    state: SpinLockIRQ<TimerState>,
+}
+
+impl Default for Timer {
+    /// Creates a disarmed timer.
+    fn default() -> Timer {
+        Timer {
+            id: NEXT_TIMER_ID.fetch_add(1, Ordering::SeqCst),
+            state: SpinLockIRQ::new(TimerState { deadline_ns: DISARMED, period_ns: 0 }),
+        }
+    }
+}
+
+impl Timer {
+    /// Creates a timer signaled in `delay_ns` nanoseconds.
+    pub fn oneshot(delay_ns: u64) -> Timer {
+        let timer = Timer::default();
+        timer.set(delay_ns, 0);
+        timer
+    }
+
+    /// Creates a timer signaled every `period_ns` nanoseconds.
+    pub fn periodic(period_ns: u64) -> Timer {
+        let timer = Timer::default();
+        timer.set(period_ns, period_ns);
+        timer
+    }
+
+    /// Arms the timer to get signaled in `delay_ns` nanoseconds, and then
+    /// every `period_ns` nanoseconds if it is not 0. Clears its signaled
+    /// state.
+    ///
+    /// The threads already waiting on the timer are woken up at the new
+    /// deadline.
+    pub fn set(&self, delay_ns: u64, period_ns: u64) {
+        let deadline_ns = get_time_since_boot_ns().saturating_add(delay_ns);
+        *self.state.lock() = TimerState { deadline_ns, period_ns };
+        self.requeue(deadline_ns);
+    }
+
+    /// Disarms the timer, and clears its signaled state.
+    pub fn cancel(&self) {
+        *self.state.lock() = TimerState { deadline_ns: DISARMED, period_ns: 0 };
+        let waiters = TIMER_QUEUE.lock().remove(self.id, |_| true);
+        drop(waiters);
+    }
+
+    /// Clears the signaled state. A periodic timer gets signaled again at its
+    /// next period, skipping the ones that already passed. A one-shot timer
+    /// gets disarmed.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidState`
+    ///   - The timer wasn't signaled.
+    pub fn clear(&self) -> Result<(), KernelError> {
+        let now = get_time_since_boot_ns();
+        let mut state = self.state.lock();
+        if now < state.deadline_ns {
+            return Err(KernelError::InvalidState { backtrace: Backtrace::new() })
+        }
+        if state.period_ns == 0 {
+            state.deadline_ns = DISARMED;
+        } else {
+            let missed_periods = (now - state.deadline_ns) / state.period_ns;
+            state.deadline_ns += (missed_periods + 1) * state.period_ns;
+        }
+        Ok(())
+    }
+
+    /// Moves the threads waiting on this timer to a new deadline.
+    fn requeue(&self, deadline_ns: u64) {
+        let mut queue = TIMER_QUEUE.lock();
+        queue.requeue(self.id, deadline_ns);
+        program_next_deadline(&queue);
+    }
+}
+
+impl Waitable for Timer {
     fn is_signaled(&self) -> bool {
-        // First, reset the spins if necessary
-        self.countdown_value.compare_and_swap(0, self.reset_value, Ordering::SeqCst);
+        get_time_since_boot_ns() >= self.state.lock().deadline_ns
+    }
+
+    fn register(&self) {
+        let deadline_ns = self.state.lock().deadline_ns;
+        if deadline_ns == DISARMED {
+            return;
+        }
+
+        let thread = scheduler::get_current_thread();
+        let mut queue = TIMER_QUEUE.lock();
+        if !queue.contains(self.id, |waiter| Arc::ptr_eq(waiter, &thread)) {
+            queue.insert(deadline_ns, self.id, thread);
+            program_next_deadline(&queue);
+        }
+    }
 
-        // Then, check if it's us.
-        self.parent_event.is_signaled()
-            // Then, check if we need more spins.
-            && self.countdown_value.fetch_sub(1, Ordering::SeqCst) == 1
+    fn unregister(&self) {
+        let thread = scheduler::get_current_thread();
+        let waiters = TIMER_QUEUE.lock().remove(self.id, |waiter| Arc::ptr_eq(waiter, &thread));
+        drop(waiters);
     }
 }
 
+impl Drop for Timer {
+    /// Removes the threads waiting on this timer from the timer queue.
+    fn drop(&mut self) {
+        let waiters = TIMER_QUEUE.lock().remove(self.id, |_| true);
+        drop(waiters);
+    }
+}
+
+/// Returns a timer signaled once `ns` nanoseconds have elapsed.
+///
+/// # Note
+///
+/// - If the timer resolution cannot handle it, this is not going to be accurate.
+/// - Minimal resolution for HPET (10Mhz) / HPET QEMU (100Mhz): 100ns / 10ns
+/// - Minimal resolution for PIC (~1Mhz): 10ms
+pub fn wait_ns(ns: usize) -> Timer {
+    Timer::oneshot(ns as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{TimerQueue, ticks_to_ns, ns_to_ticks};
+
+    #[test]
+    fn queue_pops_nearest_first() {
+        let mut queue = TimerQueue::new();
+        queue.insert(300, 0, "c");
+        queue.insert(100, 1, "a");
+        queue.insert(200, 2, "b");
+        assert_eq!(queue.next_deadline(), Some(100));
+
+        assert_eq!(queue.pop_expired(50), None);
+        assert_eq!(queue.pop_expired(250), Some("a"));
+        assert_eq!(queue.pop_expired(250), Some("b"));
+        assert_eq!(queue.pop_expired(250), None);
+        assert_eq!(queue.next_deadline(), Some(300));
+        assert_eq!(queue.pop_expired(300), Some("c"));
+        assert_eq!(queue.next_deadline(), None);
+    }
+
+    #[test]
+    fn queue_remove() {
+        let mut queue = TimerQueue::new();
+        queue.insert(100, 0, 1);
+        queue.insert(200, 0, 2);
+        queue.insert(150, 1, 3);
+
+        assert!(queue.contains(0, |&waiter| waiter == 2));
+        assert!(!queue.contains(1, |&waiter| waiter == 2));
+
+        assert_eq!(queue.remove(0, |&waiter| waiter == 2), vec![2]);
+        assert!(!queue.contains(0, |&waiter| waiter == 2));
+        assert_eq!(queue.remove(0, |_| true), vec![1]);
+        assert_eq!(queue.remove(0, |_| true), Vec::<u32>::new());
+
+        assert_eq!(queue.next_deadline(), Some(150));
+        assert_eq!(queue.pop_expired(150), Some(3));
+    }
+
+    #[test]
+    fn queue_requeue() {
+        let mut queue = TimerQueue::new();
+        queue.insert(100, 0, 1);
+        queue.insert(200, 1, 2);
+        queue.insert(100, 0, 3);
+
+        queue.requeue(0, 300);
+        assert_eq!(queue.next_deadline(), Some(200));
+        assert_eq!(queue.pop_expired(299), Some(2));
+        assert_eq!(queue.pop_expired(299), None);
+
+        let mut woken = Vec::new();
+        while let Some(waiter) = queue.pop_expired(300) {
+            woken.push(waiter);
+        }
+        woken.sort();
+        assert_eq!(woken, vec![1, 3]);
+    }
+
+    #[test]
+    fn ticks_ns_conversions() {
+        // 10MHz, a tick is 100ns.
+        assert_eq!(ticks_to_ns(0, 10_000_000), 0);
+        assert_eq!(ticks_to_ns(1, 10_000_000), 100);
+        assert_eq!(ns_to_ticks(100, 10_000_000), 1);
+        // Rounds up to a tick, so deadlines are never early.
+        assert_eq!(ns_to_ticks(1, 10_000_000), 1);
+        assert_eq!(ns_to_ticks(101, 10_000_000), 2);
+
+        // A frequency that doesn't divide a second.
+        let frequency = 14_318_180;
+        assert_eq!(ticks_to_ns(frequency, frequency), 1_000_000_000);
+        assert_eq!(ns_to_ticks(1_000_000_000, frequency), frequency);
+        for ns in &[1, 999, 1_000_000, 123_456_789_012] {
+            let ticks = ns_to_ticks(*ns, frequency);
+            assert!(ticks_to_ns(ticks, frequency) >= *ns);
+            assert!(ticks_to_ns(ticks - 1, frequency) < *ns);
+        }
+    }
+
+    #[test]
+    fn ticks_ns_conversions_dont_overflow() {
+        // A 3GHz TSC after 100 years.
+        let frequency = 3_000_000_000;
+        let ticks = 100 * 365 * 24 * 3600 * frequency;
+        assert_eq!(ticks_to_ns(ticks, frequency), 100 * 365 * 24 * 3600 * 1_000_000_000);
+        assert_eq!(ns_to_ticks(u64::max_value(), frequency), u64::max_value());
+    }
+}
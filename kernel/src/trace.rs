@@ -0,0 +1,180 @@
+//! Kernel tracing
+//!
+//! When built with the `tracing` feature, the kernel records timestamped
+//! events in a ring buffer for each CPU: syscalls, context switches, IRQs, and
+//! IPC requests and replies. Userspace reads them with [read_trace_buffer], in
+//! the format described in [sunrise_libkern::trace]. When a buffer is full,
+//! the oldest events are overwritten.
+//!
+//! Without the feature, [record] does nothing, and reading the buffers fails
+//! with `NotImplemented`.
+//!
+//! Recording an event must be cheap, as it happens on every syscall and IRQ.
+//! Events are timestamped with the TSC, and only converted to nanoseconds
+//! since boot when they are read, by comparing the TSC with the kernel timer.
+//!
+//! [read_trace_buffer]: crate::syscalls::read_trace_buffer
+
+use sunrise_libkern::trace::TraceEventKind;
+use crate::error::UserspaceError;
+
+#[cfg(feature = "tracing")]
+use core::cell::Cell;
+#[cfg(feature = "tracing")]
+use alloc::boxed::Box;
+#[cfg(feature = "tracing")]
+use alloc::vec::Vec;
+#[cfg(feature = "tracing")]
+use sunrise_libkern::trace::TraceEvent;
+#[cfg(feature = "tracing")]
+use crate::sync::{Once, SpinLock, SpinLockIRQ};
+#[cfg(feature = "tracing")]
+use crate::i386::instructions::tsc::rdtsc;
+
+/// Number of events kept in the ring buffer of each CPU.
+#[cfg(feature = "tracing")]
+const TRACE_BUFFER_LEN: usize = 0x2000;
+
+/// The ring buffer of a CPU.
+#[cfg(feature = "tracing")]
+struct TraceBuffer {
+    /// The events. Their `timestamp_ns` holds the raw TSC value.
+    events: Box<[TraceEvent]>,
+    /// Number of events recorded since boot. The next event is written at
+    /// `head % TRACE_BUFFER_LEN`.
+    head: usize,
+}
+
+/// The ring buffer of the current CPU. None until [init] is called.
+#[cfg(feature = "tracing")]
+#[thread_local]
+static TRACE_BUFFER: Cell<Option<&'static SpinLockIRQ<TraceBuffer>>> = Cell::new(None);
+
+/// The ring buffers of all the CPUs, indexed by CPU number.
+#[cfg(feature = "tracing")]
+static TRACE_BUFFERS: SpinLock<Vec<&'static SpinLockIRQ<TraceBuffer>>> = SpinLock::new(Vec::new());
+
+/// TSC value and time since boot in nanoseconds when [init] was called, used
+/// to convert timestamps.
+#[cfg(feature = "tracing")]
+static TSC_ORIGIN: Once<(u64, u64)> = Once::new();
+
+/// Allocates the ring buffer of the current CPU, and starts recording events.
+///
+/// Must be called after the cpu-locals and the kernel timer are initialized.
+/// Does nothing without the `tracing` feature.
+pub fn init() {
+    #[cfg(feature = "tracing")]
+    {
+        TSC_ORIGIN.call_once(|| (rdtsc(), crate::timer::get_time_since_boot_ns()));
+        let buffer: &'static _ = Box::leak(Box::new(SpinLockIRQ::new(TraceBuffer {
+            events: vec![TraceEvent::default(); TRACE_BUFFER_LEN].into_boxed_slice(),
+            head: 0,
+        })));
+        TRACE_BUFFERS.lock().push(buffer);
+        TRACE_BUFFER.set(Some(buffer));
+        info!("Kernel tracing enabled, {} events per CPU", TRACE_BUFFER_LEN);
+    }
+}
+
+/// Records an event in the ring buffer of the current CPU, along with the
+/// current process and thread. See [TraceEventKind] for the meaning of the
+/// arguments.
+///
+/// Does nothing without the `tracing` feature, or before [init] is called.
+#[inline]
+pub fn record(kind: TraceEventKind, arg0: u32, arg1: u32) {
+    #[cfg(feature = "tracing")]
+    {
+        if !crate::cpu_locals::ARE_CPU_LOCALS_INITIALIZED_YET.load(core::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        let buffer = match TRACE_BUFFER.get() {
+            Some(buffer) => buffer,
+            None => return
+        };
+        let (pid, tid) = match crate::scheduler::try_get_current_thread() {
+            Some(thread) => (thread.process.pid as u32, thread.tid as u32),
+            None => (0, 0)
+        };
+        let event = TraceEvent {
+            timestamp_ns: rdtsc(),
+            kind,
+            pid,
+            tid,
+            arg0,
+            arg1,
+            reserved: 0,
+        };
+
+        // We might be recording an IRQ that interrupted a read of the buffer.
+        // Drop the event rather than deadlocking.
+        if let Some(mut buffer) = buffer.try_lock() {
+            let idx = buffer.head % TRACE_BUFFER_LEN;
+            buffer.events[idx] = event;
+            buffer.head = buffer.head.wrapping_add(1);
+        }
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (kind, arg0, arg1);
+}
+
+/// Copies the events of CPU `cpu` recorded after `cursor` to `out`, as an
+/// array of [TraceEvent](sunrise_libkern::trace::TraceEvent) with their
+/// timestamps converted to nanoseconds since boot. Returns the cursor to use
+/// for the next read, and the number of events written to `out`.
+///
+/// If the events after `cursor` were already overwritten, starts from the
+/// oldest event still in the ring buffer. In particular, a reader can pass 0
+/// on its first read to get all the events.
+///
+/// # Errors
+///
+/// - `InvalidProcessorId`
+///    - `cpu` does not exist.
+/// - `NotImplemented`
+///    - The kernel was built without the `tracing` feature.
+pub fn read(cpu: usize, cursor: usize, out: &mut [u8]) -> Result<(usize, usize), UserspaceError> {
+    #[cfg(feature = "tracing")]
+    {
+        let buffer = *TRACE_BUFFERS.lock().get(cpu).ok_or(UserspaceError::InvalidProcessorId)?;
+
+        let (tsc_origin, ns_origin) = *TSC_ORIGIN.r#try().expect("Trace buffers exist before init");
+        let elapsed_us = (crate::timer::get_time_since_boot_ns() - ns_origin) / 1000;
+        let cycles_per_us = match (rdtsc() - tsc_origin).checked_div(elapsed_us) {
+            Some(0) | None => 1,
+            Some(cycles_per_us) => cycles_per_us
+        };
+
+        let buffer = buffer.lock();
+        let len = core::cmp::min(buffer.head, TRACE_BUFFER_LEN);
+        let mut cursor = if buffer.head.wrapping_sub(cursor) > len {
+            buffer.head.wrapping_sub(len)
+        } else {
+            cursor
+        };
+
+        let mut written = 0;
+        for out_event in out.chunks_exact_mut(core::mem::size_of::<TraceEvent>()) {
+            if cursor == buffer.head {
+                break;
+            }
+            let mut event = buffer.events[cursor % TRACE_BUFFER_LEN];
+            event.timestamp_ns = ns_origin + event.timestamp_ns.saturating_sub(tsc_origin) * 1000 / cycles_per_us;
+            let event_bytes = unsafe {
+                // Safety: TraceEvent is repr(C) and has no implicit padding.
+                plain::as_bytes(&event)
+            };
+            out_event.copy_from_slice(event_bytes);
+            written += 1;
+            cursor = cursor.wrapping_add(1);
+        }
+        Ok((cursor, written))
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (cpu, cursor, out);
+        Err(UserspaceError::NotImplemented)
+    }
+}
+
@@ -482,7 +482,7 @@ lazy_static! {
     static ref PRIMARY_PS2 : PS2 = PS2 {
         status_port: Pio::<u8>::new(0x64),
         data_port: Pio::<u8>::new(0x60),
-        event: syscalls::create_interrupt_event(1, 0).unwrap(),
+        event: syscalls::create_interrupt_event(1, syscalls::InterruptType::Edge).unwrap(),
         is_capslocked: AtomicBool::new(false),
         is_left_shift: AtomicBool::new(false),
         is_right_shift: AtomicBool::new(false),
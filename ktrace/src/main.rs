@@ -0,0 +1,147 @@
+//! Kernel trace dumper
+//!
+//! Reads the trace buffers of the kernel, and writes them to a file in the
+//! Chrome trace event format, which can be opened in `chrome://tracing` or
+//! Perfetto. The kernel must be built with the `tracing` feature.
+//!
+//! Usage: `ktrace <output.json>`
+//!
+//! Syscalls and IRQs show up as slices on the thread they happened in, and
+//! context switches, IPC and thread exits as instant events.
+
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::os::sunrise::prelude::*;
+use sunrise_libuser::error::KernelError;
+use sunrise_libuser::syscalls::{self, SYSCALL_NAMES};
+use sunrise_libuser::syscalls::trace::{TraceEvent, TraceEventKind};
+
+/// Number of events read from the kernel at once.
+const EVENTS_PER_READ: usize = 0x100;
+
+/// Reads all the events of the trace buffer of CPU `cpu`.
+fn read_events(cpu: usize) -> Result<Vec<TraceEvent>, KernelError> {
+    let mut events = Vec::new();
+    let mut buf = [TraceEvent::default(); EVENTS_PER_READ];
+    let mut cursor = 0;
+    loop {
+        let (next_cursor, count) = syscalls::read_trace_buffer(cpu, cursor, &mut buf)?;
+        cursor = next_cursor;
+        events.extend_from_slice(&buf[..count]);
+        if count < buf.len() {
+            return Ok(events);
+        }
+    }
+}
+
+/// Writes an event of the Chrome trace event format, followed by a comma
+/// unless it is the first one.
+fn write_event(out: &mut impl Write, first: &mut bool, event: &TraceEvent, cpu: usize, name: &str, phase: char, args: &str) -> io::Result<()> {
+    if !*first {
+        writeln!(out, ",")?;
+    }
+    *first = false;
+    let scope = if phase == 'i' { r#","s":"t""# } else { "" };
+    write!(out, r#"{{"name":"{}","ph":"{}","ts":{}.{:03},"pid":{},"tid":{}{},"args":{{"cpu":{}{}}}}}"#,
+           name, phase, event.timestamp_ns / 1000, event.timestamp_ns % 1000,
+           event.pid, event.tid, scope, cpu, args)
+}
+
+/// Writes the events of CPU `cpu` to `out`.
+fn write_events(out: &mut impl Write, first: &mut bool, cpu: usize, events: &[TraceEvent]) -> io::Result<()> {
+    for event in events {
+        let syscall_name = || SYSCALL_NAMES.get(event.arg0 as usize).cloned().unwrap_or("Unknown");
+        match event.kind {
+            TraceEventKind::SyscallEnter => write_event(out, first, event, cpu, syscall_name(), 'B', "")?,
+            TraceEventKind::SyscallExit => write_event(out, first, event, cpu, syscall_name(), 'E',
+                &format!(r#","result":{}"#, event.arg1))?,
+            TraceEventKind::ContextSwitch => write_event(out, first, event, cpu, "Context switch", 'i',
+                &format!(r#","next_pid":{},"next_tid":{}"#, event.arg0, event.arg1))?,
+            TraceEventKind::IrqEnter => write_event(out, first, event, cpu, &format!("IRQ {}", event.arg0), 'B', "")?,
+            TraceEventKind::IrqExit => write_event(out, first, event, cpu, &format!("IRQ {}", event.arg0), 'E', "")?,
+            TraceEventKind::IpcRequest => write_event(out, first, event, cpu, "IPC request", 'i',
+                &format!(r#","session":"{:#x}""#, event.arg0))?,
+            TraceEventKind::IpcReply => write_event(out, first, event, cpu, "IPC reply", 'i',
+                &format!(r#","session":"{:#x}","sender_tid":{}"#, event.arg0, event.arg1))?,
+            TraceEventKind::ThreadExit => write_event(out, first, event, cpu, "Thread exit", 'i', "")?,
+            _ => ()
+        }
+    }
+    Ok(())
+}
+
+/// Writes the trace file, with the events of every CPU.
+fn write_trace(out: &mut impl Write, cpus: &[Vec<TraceEvent>]) -> io::Result<()> {
+    let mut first = true;
+    writeln!(out, r#"{{"traceEvents":["#)?;
+    for (cpu, events) in cpus.iter().enumerate() {
+        write_events(out, &mut first, cpu, events)?;
+    }
+    writeln!(out, "\n]}}")?;
+    out.flush()
+}
+
+/// The entry point of the program.
+fn main() {
+    let path = match (env::args().nth(1), env::args().nth(2)) {
+        (Some(path), None) => path,
+        _ => return println!("Usage: ktrace <output.json>")
+    };
+
+    let mut cpus = Vec::new();
+    loop {
+        match read_events(cpus.len()) {
+            Ok(events) => cpus.push(events),
+            Err(KernelError::InvalidProcessorId) => break,
+            Err(KernelError::NotImplemented) => return println!("ktrace: the kernel was built without the tracing feature"),
+            Err(err) => return println!("ktrace: failed to read the trace buffer: {:?}", err)
+        }
+    }
+
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(err) => return println!("ktrace: {}: {}", path, err)
+    };
+    match write_trace(&mut BufWriter::new(file), &cpus) {
+        Ok(()) => println!("Wrote {} events to {}", cpus.iter().map(Vec::len).sum::<usize>(), path),
+        Err(err) => println!("ktrace: {}: {}", path, err)
+    }
+}
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        nr::SleepThread,
+        nr::ExitProcess,
+        nr::Break,
+        nr::CreateThread,
+        nr::StartThread,
+        nr::ExitThread,
+        nr::CloseHandle,
+        nr::WaitSynchronization,
+        nr::OutputDebugString,
+        nr::SetThreadArea,
+
+        nr::ConnectToNamedPort,
+        nr::SetHeapSize,
+        nr::MapMemory,
+        nr::UnmapMemory,
+        nr::SendSyncRequestWithUserBuffer,
+        nr::QueryMemory,
+        nr::CreateSharedMemory,
+        nr::MapSharedMemory,
+        nr::UnmapSharedMemory,
+
+        nr::ReadTraceBuffer,
+    ]
+});
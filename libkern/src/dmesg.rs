@@ -0,0 +1,105 @@
+//! Kernel log records
+//!
+//! The kernel keeps the records it logs in a ring buffer, which userspace reads
+//! with `svcReadKernelLog`. The buffer it fills is a sequence of records, each
+//! made of a [DmesgRecordHeader], followed by the target, the name of the
+//! process that logged it, and the message. Use [parse_records] to iterate
+//! over them.
+
+use core::mem::size_of;
+use plain::Plain;
+use static_assertions::assert_eq_size;
+
+/// Maximum length of the target and the process name of a record. Longer ones
+/// are truncated.
+pub const MAX_NAME_LEN: usize = 0x40;
+
+/// Maximum length of the message of a record. Longer ones are truncated.
+pub const MAX_MESSAGE_LEN: usize = 0x400;
+
+/// Maximum size of a record, header included. Buffers given to
+/// `svcReadKernelLog` should be at least this big, or they might not fit the
+/// next record.
+pub const MAX_RECORD_SIZE: usize = size_of::<DmesgRecordHeader>() + 2 * MAX_NAME_LEN + MAX_MESSAGE_LEN;
+
+/// The header of a kernel log record.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DmesgRecordHeader {
+    /// Size of the whole record, header included.
+    pub size: u32,
+    /// Level of the record, from 1 (Error) to 5 (Trace), the same way as the
+    /// `log` crate.
+    pub level: u32,
+    /// Time the record was logged at, in nanoseconds since boot.
+    pub timestamp_ns: u64,
+    /// Length of the target of the record.
+    pub target_len: u32,
+    /// Length of the name of the process that logged the record. 0 if it was
+    /// logged outside of any process, during boot.
+    pub process_len: u32,
+    /// Length of the message of the record.
+    pub message_len: u32,
+    /// Reserved, always 0.
+    pub reserved: u32,
+}
+
+assert_eq_size!(DmesgRecordHeader, [u8; 32]);
+
+unsafe impl Plain for DmesgRecordHeader {}
+
+/// A kernel log record, borrowed from the buffer filled by
+/// `svcReadKernelLog`.
+#[derive(Debug, Clone, Copy)]
+pub struct DmesgRecord<'a> {
+    /// The header of the record.
+    pub header: DmesgRecordHeader,
+    /// The target of the record, usually the module that logged it.
+    pub target: &'a [u8],
+    /// The name of the process that logged the record.
+    pub process: &'a [u8],
+    /// The message of the record. May have been truncated in the middle of a
+    /// UTF-8 character.
+    pub message: &'a [u8],
+}
+
+/// Iterates over the records of a buffer filled by `svcReadKernelLog`. See
+/// [parse_records].
+#[derive(Debug, Clone)]
+pub struct DmesgRecords<'a> {
+    /// The records that were not yet iterated over.
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for DmesgRecords<'a> {
+    type Item = DmesgRecord<'a>;
+
+    fn next(&mut self) -> Option<DmesgRecord<'a>> {
+        let mut header = DmesgRecordHeader::default();
+        header.copy_from_bytes(self.buf).ok()?;
+
+        let size = header.size as usize;
+        let (target_len, process_len, message_len) = (header.target_len as usize, header.process_len as usize, header.message_len as usize);
+        if size > self.buf.len() || size_of::<DmesgRecordHeader>() + target_len + process_len + message_len > size {
+            self.buf = &[];
+            return None;
+        }
+
+        let (record, rest) = self.buf.split_at(size);
+        let (target, record) = record[size_of::<DmesgRecordHeader>()..].split_at(target_len);
+        let (process, record) = record.split_at(process_len);
+        self.buf = rest;
+        Some(DmesgRecord {
+            header,
+            target,
+            process,
+            message: &record[..message_len],
+        })
+    }
+}
+
+/// Parses the records of a buffer filled by `svcReadKernelLog`. Stops at the
+/// first invalid record.
+pub fn parse_records(buf: &[u8]) -> DmesgRecords<'_> {
+    DmesgRecords { buf }
+}
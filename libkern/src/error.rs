@@ -21,11 +21,13 @@ enum_with_val! {
         ///
         /// Generally means it is not page aligned.
         InvalidAddress = 102,
-        // SlabheapFull = 103,
+        /// A kernel resource (such as an interrupt vector) was exhausted.
+        OutOfResource = 103,
         /// The virtual address space was exhausted.
         MemoryFull = 104,
-        /// The process' handle table is full.
-        HandleTableFull = 105,
+        /// The process' handle table is full: it already has as many handles
+        /// open as its HANDLE_TABLE_SIZE kernel capability allows.
+        OutOfHandles = 105,
         /// The memory state is invalid for this action.
         InvalidMemState = 106,
         /// The memory permissions passed are wrong.
@@ -107,7 +109,7 @@ impl fmt::Display for KernelError {
             KernelError::InvalidSize => write!(f, "Invalid size."),
             KernelError::InvalidAddress => write!(f, "Invalid address."),
             KernelError::MemoryFull => write!(f, "Memory full. Try to kill some processes and try again."),
-            KernelError::HandleTableFull => write!(f, "Handle table full. You might want to bump your handle table size in the NPDM."),
+            KernelError::OutOfHandles => write!(f, "Handle table full. You might want to bump your handle table size in the NPDM."),
             KernelError::InvalidMemPerms => write!(f, "Invalid memory permissions."),
             KernelError::InvalidHandle => write!(f, "Invalid handle. Either it does not exist, or the Handle is of the wrong type."),
             KernelError::CopyFromUserFailed => write!(f, "Copy from user failed. The pointer either does not point in userspace, or points to unmapped memory."),
@@ -33,6 +33,9 @@ use static_assertions::assert_eq_size;
 use core::mem::size_of;
 
 pub mod process;
+pub mod dmesg;
+pub mod trace;
+pub mod npdm;
 
 bitflags! {
     /// Represents the current state of a memory region: why is it allocated, and
@@ -294,6 +297,36 @@ impl MemoryPermissions {
     }
 }
 
+enum_with_val! {
+    /// Operation to apply on a CodeMemory with `svcControlCodeMemory`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct CodeMemoryOperation(pub u32) {
+        /// Map the CodeMemory RW- in its owner process, so code can be
+        /// written into it.
+        MapOwner = 0,
+        /// Map the CodeMemory R-X (or R--) in the current process, so the code
+        /// can be executed.
+        MapSlave = 1,
+        /// Unmap a mapping created with MapOwner.
+        UnmapOwner = 2,
+        /// Unmap a mapping created with MapSlave.
+        UnmapSlave = 3,
+    }
+}
+
+enum_with_val! {
+    /// Triggering mode of an interrupt event, passed to
+    /// `svcCreateInterruptEvent`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct InterruptType(pub u32) {
+        /// Level-triggered. The IRQ line is masked each time the IRQ fires,
+        /// until the driver acknowledges it with `svcAcknowledgeInterrupt`.
+        Level = 0,
+        /// Rising-edge triggered. Every IRQ signals the event once.
+        Edge = 1,
+    }
+}
+
 /// The structure returned by the `query_memory` syscall.
 #[repr(C)]
 #[derive(Debug, Default)]
@@ -529,8 +562,24 @@ syscalls! {
     StartProcessEntrypoint = 0x81,
     MapMmioRegion = 0x82,
     SetThreadArea = 0x83,
+    GetProcessMemoryUsage = 0x84,
+    AcknowledgeInterrupt = 0x85,
+    CreateMsiEvent = 0x86,
+    SetExceptionHandler = 0x87,
+    GetCrashReport = 0x88,
+    SetProcessSymbols = 0x89,
+    GetDebugProcessSymbol = 0x8A,
+    ReadKernelLog = 0x8B,
+    CreateKernelLogEvent = 0x8C,
+    SetLogFilter = 0x8D,
+    ReadTraceBuffer = 0x8E,
+    CreateTimer = 0x8F,
+    SetTimer = 0x90,
+    CancelTimer = 0x91,
+    CreatePowerButtonEvent = 0x92,
+    GetProcessHandles = 0x93,
 
     ---
     // Add SVCs before this line.
-    MaxSvc = 0x83
+    MaxSvc = 0x93
 }
\ No newline at end of file
@@ -0,0 +1,322 @@
+//! NPDM, the access controls of a title.
+//!
+//! A title may come with a `main.npdm` next to its binary, granting it its
+//! kernel capabilities and the services it may use or host, without them being
+//! part of the binary. It follows the format of
+//! Horizon/NX, see [switchbrew]:
+//!
+//! - A META header, with the name of the title and its main thread parameters.
+//! - An ACID, with the access controls the title may be granted at most. On
+//!   Horizon/NX it is signed, we don't check the signature.
+//! - An ACI0, with the access controls the title is actually granted. They must
+//!   be a subset of the ACID ones, see [Npdm::check_aci0].
+//!
+//! Both the ACID and the ACI0 hold filesystem permissions, a service access
+//! control list (SAC) and kernel capabilities (KAC), in the format of the
+//! `.kernel_caps` section of binaries. fs does not check the permissions of its
+//! clients, so the filesystem permissions are ignored.
+//!
+//! [switchbrew]: https://switchbrew.org/wiki/NPDM
+
+use core::convert::TryInto;
+use core::fmt;
+
+/// Size of the META header.
+pub const META_SIZE: usize = 0x80;
+/// Size of the ACI0 header.
+pub const ACI0_HEADER_SIZE: usize = 0x40;
+/// Offset of the ACID header in the ACID, after its signature and public key.
+pub const ACID_HEADER_OFFSET: usize = 0x200;
+/// Size of the ACID, signature and header included.
+pub const ACID_HEADER_SIZE: usize = ACID_HEADER_OFFSET + 0x40;
+
+/// Type of a kernel capability allowing a set of syscalls.
+const KAC_SYSCALL_MASK: u32 = 4;
+
+/// Why an NPDM was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NpdmError {
+    /// A META, ACI0 or ACID magic is wrong.
+    InvalidMagic,
+    /// A section goes past the end of its container.
+    OutOfBounds,
+    /// A service access control list is malformed.
+    InvalidSac,
+    /// The kernel capabilities are not made of u32s.
+    InvalidKac,
+    /// The program id of the ACI0 is not in the range the ACID allows.
+    ProgramIdNotAllowed,
+    /// The ACI0 grants kernel capabilities the ACID doesn't.
+    KacNotAllowed,
+    /// The ACI0 grants access to services the ACID doesn't.
+    SacNotAllowed,
+}
+
+impl fmt::Display for NpdmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NpdmError::InvalidMagic => write!(f, "Invalid META, ACI0 or ACID magic."),
+            NpdmError::OutOfBounds => write!(f, "Section out of bounds."),
+            NpdmError::InvalidSac => write!(f, "Malformed service access control list."),
+            NpdmError::InvalidKac => write!(f, "Malformed kernel capabilities."),
+            NpdmError::ProgramIdNotAllowed => write!(f, "ACI0 program id not allowed by the ACID."),
+            NpdmError::KacNotAllowed => write!(f, "ACI0 kernel capabilities not allowed by the ACID."),
+            NpdmError::SacNotAllowed => write!(f, "ACI0 services not allowed by the ACID."),
+        }
+    }
+}
+
+/// Reads a little-endian u32 at `offset`.
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, NpdmError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(NpdmError::OutOfBounds)
+}
+
+/// Reads a little-endian u64 at `offset`.
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, NpdmError> {
+    data.get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(NpdmError::OutOfBounds)
+}
+
+/// Gets the section of `data` whose offset and size are at `header_offset`.
+fn section(data: &[u8], header_offset: usize) -> Result<&[u8], NpdmError> {
+    let offset = read_u32(data, header_offset)? as usize;
+    let size = read_u32(data, header_offset + 4)? as usize;
+    offset.checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(NpdmError::OutOfBounds)
+}
+
+/// An entry of a [ServiceAccessControl].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SacEntry<'a> {
+    /// Name of the service, up to 8 bytes. A name ending with `*` matches all
+    /// the services starting with what's before it.
+    pub name: &'a [u8],
+    /// Whether the title may host the service, or only use it.
+    pub is_server: bool,
+}
+
+impl<'a> SacEntry<'a> {
+    /// Checks whether this entry grants access to the service `name`.
+    pub fn matches(&self, name: &[u8], is_server: bool) -> bool {
+        if self.is_server != is_server {
+            return false;
+        }
+        match self.name.split_last() {
+            Some((b'*', prefix)) => name.starts_with(prefix),
+            _ => self.name == name
+        }
+    }
+}
+
+/// A service access control list, the services a title may use or host.
+///
+/// Each entry is a control byte, whose bit 7 is set for services the title
+/// hosts, and bits 0..3 hold the length of the name minus one, followed by the
+/// name.
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceAccessControl<'a>(&'a [u8]);
+
+impl<'a> ServiceAccessControl<'a> {
+    /// Wraps a raw service access control list.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidSac`
+    ///    - An entry goes past the end of the list.
+    pub fn new(data: &'a [u8]) -> Result<ServiceAccessControl<'a>, NpdmError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            offset += 1 + (data[offset] & 7) as usize + 1;
+        }
+        if offset != data.len() {
+            return Err(NpdmError::InvalidSac);
+        }
+        Ok(ServiceAccessControl(data))
+    }
+
+    /// Gets the raw service access control list.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// Iterates over the entries of the list.
+    pub fn entries(&self) -> impl Iterator<Item = SacEntry<'a>> {
+        let mut data = self.0;
+        core::iter::from_fn(move || {
+            let (control, rest) = data.split_first()?;
+            let (name, rest) = rest.split_at((control & 7) as usize + 1);
+            data = rest;
+            Some(SacEntry { name, is_server: control & 0x80 != 0 })
+        })
+    }
+
+    /// Checks whether the list grants access to the service `name`, to host
+    /// it if `is_server`, or to use it otherwise.
+    pub fn allows(&self, name: &[u8], is_server: bool) -> bool {
+        self.entries().any(|entry| entry.matches(name, is_server))
+    }
+
+    /// Checks whether every entry of this list is matched by an entry of
+    /// `other`, that is, whether it grants nothing `other` doesn't.
+    pub fn is_subset_of(&self, other: &ServiceAccessControl<'_>) -> bool {
+        self.entries().all(|entry| other.allows(entry.name, entry.is_server))
+    }
+}
+
+/// The access controls of an ACID or an ACI0.
+#[derive(Debug, Clone, Copy)]
+pub struct AccessControl<'a> {
+    /// The services the title may use or host.
+    pub sac: ServiceAccessControl<'a>,
+    /// The kernel capabilities, in the format of the `.kernel_caps` section.
+    pub kac: &'a [u8],
+}
+
+impl<'a> AccessControl<'a> {
+    /// Parses the access controls, from the SAC and KAC sections.
+    fn parse(sac: &'a [u8], kac: &'a [u8]) -> Result<AccessControl<'a>, NpdmError> {
+        if kac.len() % 4 != 0 {
+            return Err(NpdmError::InvalidKac);
+        }
+        Ok(AccessControl {
+            sac: ServiceAccessControl::new(sac)?,
+            kac,
+        })
+    }
+
+    /// Iterates over the kernel capabilities.
+    pub fn kacs(&self) -> impl Iterator<Item = u32> + 'a {
+        self.kac.chunks(4).map(|kac| u32::from_le_bytes(kac.try_into().unwrap()))
+    }
+}
+
+/// A parsed NPDM. See the [module documentation](self).
+#[derive(Debug, Clone, Copy)]
+pub struct Npdm<'a> {
+    /// Name of the title, padded with 0s.
+    pub name: [u8; 0x10],
+    /// Whether the title is a 64-bit binary.
+    pub is_64bit: bool,
+    /// Address space type of the title, as in `ProcInfoAddrSpace`.
+    pub address_space_type: u8,
+    /// Priority of the main thread.
+    pub main_thread_priority: u8,
+    /// Core the main thread runs on.
+    pub main_thread_core: u8,
+    /// Size of the stack of the main thread, in bytes.
+    pub main_thread_stack_size: u32,
+    /// Program id of the title, from the ACI0.
+    pub program_id: u64,
+    /// Smallest program id the ACID allows.
+    pub program_id_min: u64,
+    /// Biggest program id the ACID allows.
+    pub program_id_max: u64,
+    /// The access controls the title may be granted at most.
+    pub acid: AccessControl<'a>,
+    /// The access controls the title is granted.
+    pub aci0: AccessControl<'a>,
+}
+
+impl<'a> Npdm<'a> {
+    /// Parses an NPDM.
+    ///
+    /// Does not check the ACI0 against the ACID, see [Npdm::check_aci0].
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidMagic`
+    ///    - A META, ACI0 or ACID magic is wrong.
+    /// - `OutOfBounds`
+    ///    - A header or section goes past the end of its container.
+    /// - `InvalidSac`
+    ///    - A service access control list is malformed.
+    /// - `InvalidKac`
+    ///    - The size of kernel capabilities is not a multiple of 4.
+    pub fn parse(data: &'a [u8]) -> Result<Npdm<'a>, NpdmError> {
+        let meta = data.get(..META_SIZE).ok_or(NpdmError::OutOfBounds)?;
+        if &meta[0..4] != b"META" {
+            return Err(NpdmError::InvalidMagic);
+        }
+
+        let aci0 = section(data, 0x70)?;
+        if aci0.len() < ACI0_HEADER_SIZE {
+            return Err(NpdmError::OutOfBounds);
+        }
+        if &aci0[0..4] != b"ACI0" {
+            return Err(NpdmError::InvalidMagic);
+        }
+
+        let acid = section(data, 0x78)?;
+        if acid.len() < ACID_HEADER_SIZE {
+            return Err(NpdmError::OutOfBounds);
+        }
+        let acid_header = &acid[ACID_HEADER_OFFSET..];
+        if &acid_header[0..4] != b"ACID" {
+            return Err(NpdmError::InvalidMagic);
+        }
+
+        let mut name = [0; 0x10];
+        name.copy_from_slice(&meta[0x20..0x30]);
+
+        Ok(Npdm {
+            name,
+            is_64bit: meta[0xC] & 1 != 0,
+            address_space_type: (meta[0xC] >> 1) & 7,
+            main_thread_priority: meta[0xE],
+            main_thread_core: meta[0xF],
+            main_thread_stack_size: read_u32(meta, 0x1C)?,
+            program_id: read_u64(aci0, 0x10)?,
+            program_id_min: read_u64(acid_header, 0x10)?,
+            program_id_max: read_u64(acid_header, 0x18)?,
+            // The offsets in the ACID are relative to its signature. The first
+            // section holds the filesystem permissions, which we ignore.
+            acid: AccessControl::parse(section(acid, ACID_HEADER_OFFSET + 0x28)?,
+                section(acid, ACID_HEADER_OFFSET + 0x30)?)?,
+            aci0: AccessControl::parse(section(aci0, 0x28)?, section(aci0, 0x30)?)?,
+        })
+    }
+
+    /// Checks that the ACI0 grants nothing the ACID doesn't:
+    ///
+    /// - Its program id is in the range of the ACID.
+    /// - Its syscall masks only allow syscalls the ACID ones allow, and its
+    ///   other kernel capabilities are all in the ACID.
+    /// - Its services are all matched by an ACID entry.
+    ///
+    /// # Errors
+    ///
+    /// - `ProgramIdNotAllowed`, `KacNotAllowed`, `SacNotAllowed`
+    ///    - The ACI0 grants something the ACID doesn't.
+    pub fn check_aci0(&self) -> Result<(), NpdmError> {
+        if self.program_id < self.program_id_min || self.program_id > self.program_id_max {
+            return Err(NpdmError::ProgramIdNotAllowed);
+        }
+
+        for kac in self.aci0.kacs() {
+            let allowed = if (!kac).trailing_zeros() == KAC_SYSCALL_MASK {
+                // Syscall masks may be split differently, compare the
+                // syscalls they allow at each index.
+                let index = kac >> 29;
+                let acid_mask = self.acid.kacs()
+                    .filter(|&acid_kac| (!acid_kac).trailing_zeros() == KAC_SYSCALL_MASK && acid_kac >> 29 == index)
+                    .fold(0, |mask, acid_kac| mask | acid_kac);
+                kac & !acid_mask == 0
+            } else {
+                self.acid.kacs().any(|acid_kac| acid_kac == kac)
+            };
+            if !allowed {
+                return Err(NpdmError::KacNotAllowed);
+            }
+        }
+
+        if !self.aci0.sac.is_subset_of(&self.acid.sac) {
+            return Err(NpdmError::SacNotAllowed);
+        }
+
+        Ok(())
+    }
+}
@@ -220,4 +220,269 @@ enum_with_val! {
         /// Get the state the process is currently in.
         ProcessState = 0,
     }
-}
\ No newline at end of file
+}
+
+/// Memory and handle usage of a process, as returned by
+/// `get_process_memory_usage`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessMemoryUsage {
+    /// Size of the heap, in bytes.
+    pub heap_size: usize,
+    /// Physical memory mapped by the process, in bytes. Memory shared between
+    /// multiple processes is counted in each of them.
+    pub resident_size: usize,
+    /// Memory mapped through shared memory or IPC buffers, in bytes.
+    pub shared_size: usize,
+    /// Number of handles in the process' handle table.
+    pub handle_count: usize,
+}
+
+enum_with_val! {
+    /// Kind of kernel object a handle points to, as returned by
+    /// `get_process_handles`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct HandleKind(pub u32) {
+        /// An event triggered by an IRQ.
+        InterruptEvent = 0,
+        /// An event on which we can wait, triggered by a WritableEvent.
+        ReadableEvent = 1,
+        /// Trigger for an associated ReadableEvent.
+        WritableEvent = 2,
+        /// The server side of an IPC port.
+        ServerPort = 3,
+        /// The client side of an IPC port.
+        ClientPort = 4,
+        /// The server side of an IPC session.
+        ServerSession = 5,
+        /// The client side of an IPC session.
+        ClientSession = 6,
+        /// A thread.
+        Thread = 7,
+        /// A process.
+        Process = 8,
+        /// A shared memory region.
+        SharedMemory = 9,
+        /// A memory region that can be aliased as both RW- and R-X.
+        CodeMemory = 10,
+        /// A debug session on a process.
+        Debug = 11,
+        /// An event signaled when records are added to the kernel log.
+        KernelLogEvent = 12,
+        /// A timer.
+        Timer = 13,
+        /// An event signaled when the power button is pressed.
+        PowerButtonEvent = 14,
+    }
+}
+
+/// An open handle of a process, as returned by `get_process_handles`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HandleInfo {
+    /// The handle number, in the handle table of the process.
+    pub handle: u32,
+    /// Kind of kernel object the handle points to.
+    pub kind: HandleKind,
+    /// Pid of the process on the other side of the handle, or
+    /// [HandleInfo::NO_PEER].
+    ///
+    /// For sessions and ports, it is the process holding the other side,
+    /// found by looking through the handle tables of every process. For
+    /// processes and debug handles, it is the process itself, and for threads
+    /// the process they belong to.
+    pub peer_pid: u64,
+    /// Time since the handle was added to the table, in nanoseconds.
+    pub age_ns: u64,
+}
+
+impl HandleInfo {
+    /// [HandleInfo::peer_pid] of handles without a peer, or whose peer could
+    /// not be found.
+    pub const NO_PEER: u64 = u64::max_value();
+}
+
+enum_with_val! {
+    /// Kind of information to extract from the system with `get_system_info`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct SystemInfoType(pub u32) {
+        /// Total amount of physical memory, in bytes.
+        TotalPhysicalMemorySize = 0,
+        /// Amount of physical memory currently allocated, in bytes.
+        UsedPhysicalMemorySize = 1,
+        /// Smallest PID of the kernel built-ins with sub_id 0, biggest with
+        /// sub_id 1.
+        InitialProcessIdRange = 2,
+        /// Title id of the kernel built-in whose PID is sub_id.
+        InitialProcessTitleId = 3,
+    }
+}
+
+enum_with_val! {
+    /// Activity to set on a thread with `set_thread_activity`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ThreadActivity(pub u32) {
+        /// The thread is allowed to run.
+        Runnable = 0,
+        /// The thread is suspended, and will not return to userspace until
+        /// it is set back to Runnable.
+        Paused = 1,
+    }
+}
+
+enum_with_val! {
+    /// Activity to set on every thread of a process with
+    /// `set_process_activity`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ProcessActivity(pub u32) {
+        /// The threads of the process are allowed to run.
+        Runnable = 0,
+        /// All the threads of the process are suspended.
+        Paused = 1,
+    }
+}
+
+enum_with_val! {
+    /// Scheduling state of a thread, as returned by `get_debug_thread_param`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ThreadState(pub u32) {
+        /// Not in the schedule queue, waiting for an event.
+        Waiting = 1,
+        /// Currently on the CPU.
+        Running = 2,
+        /// Dying, will be unscheduled at the next syscall boundary.
+        TerminationPending = 3,
+        /// In the schedule queue, waiting for its turn to run.
+        Scheduled = 4,
+    }
+}
+
+enum_with_val! {
+    /// Kind of information to extract from a debugged thread with
+    /// `get_debug_thread_param`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct DebugThreadParam(pub u32) {
+        /// Get the [ThreadState] of the thread in the 32-bit output, and
+        /// whether it is suspended in the 64-bit output.
+        State = 1,
+    }
+}
+
+/// Userspace registers of a thread, as returned by `get_thread_context3` and
+/// `get_debug_thread_context`.
+///
+/// Registers are saved every time the thread enters the kernel. For a thread
+/// that is not suspended, this is a snapshot of its last kernel entry.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+#[allow(missing_docs)]
+pub struct ThreadContext {
+    pub eip: usize,
+    pub esp: usize,
+    pub ebp: usize,
+    pub eax: usize,
+    pub ebx: usize,
+    pub ecx: usize,
+    pub edx: usize,
+    pub esi: usize,
+    pub edi: usize,
+    pub eflags: usize,
+}
+
+enum_with_val! {
+    /// Cause of an exception, as reported in [ExceptionInfo].
+    ///
+    /// Hardware exceptions use their x86 vector number.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ExceptionType(pub u32) {
+        /// Integer division by zero, or quotient too large (#DE).
+        DivideError = 0,
+        /// INTO executed with the overflow flag set (#OF).
+        Overflow = 4,
+        /// BOUND executed with an out of range index (#BR).
+        BoundRangeExceeded = 5,
+        /// Undefined or privileged instruction (#UD).
+        InvalidOpcode = 6,
+        /// FPU instruction with no FPU available (#NM).
+        DeviceNotAvailable = 7,
+        /// Loaded a segment that is not present (#NP).
+        SegmentNotPresent = 11,
+        /// Stack segment fault (#SS).
+        StackFault = 12,
+        /// General protection fault (#GP).
+        GeneralProtectionFault = 13,
+        /// Access to unmapped memory, or memory with the wrong permissions
+        /// (#PF). The faulting address is in `fault_address`.
+        PageFault = 14,
+        /// x87 floating-point error (#MF).
+        X87FloatingPoint = 16,
+        /// Unaligned memory access with alignment checking enabled (#AC).
+        AlignmentCheck = 17,
+        /// SIMD floating-point error (#XM).
+        SimdFloatingPoint = 19,
+        /// Virtualization exception (#VE).
+        Virtualization = 20,
+        /// The process called `svcBreak`. Never delivered to the exception
+        /// handler.
+        UserBreak = 0x100,
+        /// The process used an unknown SVC, or one its capabilities don't
+        /// allow. The SVC number is in `error_code`.
+        InvalidSvc = 0x101,
+        /// The process accessed an IO port its capabilities don't allow. The
+        /// port is in `error_code`.
+        IoPortNotAllowed = 0x102,
+        /// Page fault in the guard page below a stack: the thread overflowed
+        /// its stack. The faulting address is in `fault_address`, and the page
+        /// fault error code in `error_code`.
+        StackOverflow = 0x103,
+    }
+}
+
+enum_with_val! {
+    /// Reason given to `svcBreak`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct BreakReason(pub u32) {
+        /// The program panicked. The break info contains the panic message.
+        Panic = 0,
+        /// An assertion failed.
+        Assert = 1,
+        /// Any other fatal error detected by the program.
+        User = 2,
+    }
+}
+
+/// Description of an exception, given to the handler registered with
+/// `set_exception_handler`.
+///
+/// The handler may modify `context` before calling `return_from_exception`,
+/// the thread will then resume with those registers.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExceptionInfo {
+    /// What caused the exception.
+    pub exception_type: ExceptionType,
+    /// Error code pushed by the CPU, or 0 for exceptions that don't have one.
+    pub error_code: usize,
+    /// Address that caused a page fault. 0 for other exceptions.
+    pub fault_address: usize,
+    /// Registers of the thread when the exception happened.
+    pub context: ThreadContext,
+}
+
+/// Why a process died, as returned by `get_crash_report`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CrashReport {
+    /// Id of the thread that crashed.
+    pub tid: usize,
+    /// The unhandled exception. For a `svcBreak`, the type is
+    /// [ExceptionType::UserBreak], and the context is the one of the `svcBreak`
+    /// call.
+    pub exception: ExceptionInfo,
+    /// Reason given to `svcBreak`.
+    pub break_reason: BreakReason,
+    /// Size of the data in `break_info`.
+    pub break_info_size: usize,
+    /// Start of the info buffer given to `svcBreak`, truncated.
+    pub break_info: [u8; 32],
+}
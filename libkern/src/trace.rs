@@ -0,0 +1,61 @@
+//! Kernel tracing events
+//!
+//! When built with the `tracing` feature, the kernel records timestamped
+//! events in a ring buffer for each CPU: syscalls, context switches, IRQs, IPC
+//! requests and replies, and thread exits. Userspace reads them with `svcReadTraceBuffer`,
+//! as an array of [TraceEvent].
+
+use plain::Plain;
+use static_assertions::assert_eq_size;
+
+enum_with_val! {
+    /// What a [TraceEvent] records.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct TraceEventKind(pub u32) {
+        /// A thread called a syscall. `arg0` is the syscall number.
+        SyscallEnter = 0,
+        /// A syscall returned. `arg0` is the syscall number, `arg1` the error
+        /// code it returned, 0 on success.
+        SyscallExit = 1,
+        /// The scheduler switched to another thread. `arg0` and `arg1` are the
+        /// pid and tid of the thread switched to.
+        ContextSwitch = 2,
+        /// An IRQ was triggered. `arg0` is the IRQ number.
+        IrqEnter = 3,
+        /// The kernel finished handling an IRQ. `arg0` is the IRQ number.
+        IrqExit = 4,
+        /// A thread sent an IPC request. `arg0` identifies the session.
+        IpcRequest = 5,
+        /// A server replied to an IPC request. `arg0` identifies the session,
+        /// `arg1` is the tid of the thread that sent the request.
+        IpcReply = 6,
+        /// A thread exited, e.g. after ExitThread or ExitProcess, or because
+        /// another thread killed it. This is the last event of the thread.
+        ThreadExit = 7,
+    }
+}
+
+/// A kernel tracing event.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TraceEvent {
+    /// Time the event happened at, in nanoseconds since boot.
+    pub timestamp_ns: u64,
+    /// What the event records.
+    pub kind: TraceEventKind,
+    /// Pid of the process the event happened in. 0 if it happened outside of
+    /// any process.
+    pub pid: u32,
+    /// Tid of the thread the event happened in.
+    pub tid: u32,
+    /// First argument of the event, see [TraceEventKind].
+    pub arg0: u32,
+    /// Second argument of the event, see [TraceEventKind].
+    pub arg1: u32,
+    /// Reserved, always 0.
+    pub reserved: u32,
+}
+
+assert_eq_size!(TraceEvent, [u8; 32]);
+
+unsafe impl Plain for TraceEvent {}
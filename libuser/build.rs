@@ -24,6 +24,7 @@ const MODULES_ARRAY: &[(&str, &str)] =
         ("keyboard", "../../ipcdefs/keyboard.id"),
         ("ldr", "../../ipcdefs/loader.id"),
         ("twili", "../../ipcdefs/twili.id"),
+        ("uart", "../../ipcdefs/uart.id"),
         ("example", "../../ipcdefs/example.id"),
     ];
 
@@ -16,8 +16,9 @@
 //!    |    ProgramArguments    |
 //!    |   u32 allocated_size   |
 //!    |   u32 arguments_size   |
+//!    |  u32 environment_size  |
 //!    +------------------------+
-//!    |   0x18 Reserved bytes  |
+//!    |   0x14 Reserved bytes  |
 //!    +------------------------+
 //!    |      Raw CmdLine       |
 //!    |  arguments_size bytes  |
@@ -32,7 +33,13 @@
 //!    |  Array of pointers to  |
 //!    |    Argument Storage    |
 //!    +------------------------+ < allocated_size
+//!    |      Environment       |
+//!    | environment_size bytes |
+//!    +------------------------+
 //! ```
+//!
+//! The environment is a list of `KEY=VALUE` strings, each terminated by a \0.
+//! See `env`.
 
 #[cfg(not(feature = "build-for-std-app"))]
 use core::mem::{size_of, align_of};
@@ -282,4 +289,90 @@ fn __libuser_get_args() -> (usize, isize) {
         #[allow(clippy::cast_possible_wrap)]
         (__system_argv.as_ptr() as usize, __system_argc as isize)
     })
-}
\ No newline at end of file
+}
+
+/// Iterates over the arguments of the process, starting with its name.
+/// Arguments that are not valid UTF-8 are skipped.
+#[cfg(not(feature = "build-for-std-app"))]
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let argv = argv();
+    (0..argc() as usize).filter_map(move |i| unsafe {
+        // Safety: argv has argc elements, each pointing to a \0-terminated
+        // string in the argdata memory, which is never unmapped.
+        let arg = *argv.add(i);
+        let len = (0..).take_while(|&j| *arg.add(j) != 0).count();
+        core::str::from_utf8(core::slice::from_raw_parts(arg, len)).ok()
+    })
+}
+
+/// Get the environment the loader passed to the process, as a list of
+/// `KEY=VALUE` strings each terminated by a \0. Empty if there is none.
+#[cfg(not(feature = "build-for-std-app"))]
+fn __libuser_get_env() -> &'static [u8] {
+    use sunrise_libkern::MemoryPermissions;
+
+    /// Once the environment is found, this static contains its address and
+    /// size.
+    static ENV: Once<(usize, usize)> = Once::new();
+
+    extern {
+        /// Location where the loader will put the argument data. This symbol is
+        /// provided by the linker script.
+        static __argdata__: u32;
+    }
+
+    let (addr, size) = *ENV.call_once(|| {
+        let argdata = unsafe {
+            &__argdata__ as *const u32 as usize
+        };
+
+        let meminfo = match query_memory(argdata) {
+            Ok((meminfo, _)) if meminfo.perms.contains(MemoryPermissions::READABLE) => meminfo,
+            _ => return (0, 0)
+        };
+
+        let (argdata_allocsize, env_size) = unsafe {
+            // Safety: Argdata is mapped and starts at the start of a page, so
+            // we've got 0x1000 bytes available at least.
+            let data = argdata as *const u32;
+            (*data as usize, *data.offset(2) as usize)
+        };
+
+        if (argdata - meminfo.baseaddr) + argdata_allocsize + env_size > meminfo.size {
+            debug!("Weird env. We claim to have {:x} bytes of env, but it doesn't fit in mem.", env_size);
+            return (0, 0);
+        }
+        (argdata + argdata_allocsize, env_size)
+    });
+
+    if size == 0 {
+        return &[];
+    }
+    unsafe {
+        // Safety: We checked above that the environment is in the argdata
+        // memory, which is never unmapped. Nothing writes to it after the
+        // loader.
+        core::slice::from_raw_parts(addr as *const u8, size)
+    }
+}
+
+/// Iterates over the environment variables the loader passed to the process,
+/// as `(key, value)` pairs.
+///
+/// Only available to the processes providing the crt0. Std apps get it through
+/// `std::env`.
+#[cfg(not(feature = "build-for-std-app"))]
+pub fn env() -> impl Iterator<Item = (&'static str, &'static str)> {
+    __libuser_get_env().split(|b| *b == 0)
+        .filter_map(|var| {
+            let mut split = core::str::from_utf8(var).ok()?.splitn(2, '=');
+            Some((split.next()?, split.next()?))
+        })
+}
+
+/// Gets the value of the environment variable `key` the loader passed to the
+/// process.
+#[cfg(not(feature = "build-for-std-app"))]
+pub fn var(key: &str) -> Option<&'static str> {
+    env().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
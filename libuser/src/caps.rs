@@ -199,6 +199,19 @@ pub const fn ioport(ioport: u16) -> u32 {
    0b1111111111 | ((ioport as u32) << 11)
 }
 
+/// Allows the process to use every IO port from `start` up to the port given
+/// to the [ioport_range_end] that must immediately follow it. Sunrise
+/// extension.
+pub const fn ioport_range_start(start: u16) -> u32 {
+    ioport(start) | 1 << 27
+}
+
+/// Last IO port, inclusive, of the range started by [ioport_range_start].
+/// Sunrise extension.
+pub const fn ioport_range_end(end: u16) -> u32 {
+    ioport(end) | 1 << 28
+}
+
 /// Allows the process to create an IRQEvent for those IRQs. Each IRQ should be
 /// under or equal to 0xFF, or equal to 0x3FF, in which case the IRQ will be
 /// ignored.
@@ -219,9 +232,10 @@ pub const fn kernel_release_version(version: u32) -> u32 {
 }
 
 /// Declare the maximum number of live handles this process is allowed to have
-/// open.
+/// open, up to 1023. Processes that don't declare it, or declare 0, can have
+/// 1024 handles open.
 pub const fn handle_table_size(size: u32) -> u32 {
-    0b111111111111111 | ((size & 0x1FF) << 16)
+    0b111111111111111 | ((size & 0x3FF) << 16)
 }
 
 /// Declares whether this application can be debugged (e.g. it allows the use
@@ -230,3 +244,15 @@ pub const fn handle_table_size(size: u32) -> u32 {
 pub const fn debug_flags(can_be_debugged: bool, can_debug_others: bool) -> u32 {
     0b1111111111111111 | ((can_be_debugged as u32) << 17) | ((can_debug_others as u32) << 18)
 }
+
+/// Allows the process to create a CodeMemory, in order to generate code at
+/// runtime (e.g. for a JIT). Sunrise extension.
+pub const fn code_memory_allowed() -> u32 {
+    0b11111111111111111
+}
+
+/// Allows the process to allocate Message Signaled Interrupt vectors for the
+/// PCI devices it drives. Sunrise extension.
+pub const fn msi_allowed() -> u32 {
+    0b111111111111111111
+}
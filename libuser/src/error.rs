@@ -17,7 +17,7 @@
 //! fn ret_err() -> Result<(), Error> {
 //!    // Will automatically be converted to Error, the backtrace filled
 //!    let _ = Err(KernelError::PortRemoteDead)?;
-//!    let _ = Err(SmError::PermissionDenied)?;
+//!    let _ = Err(SmError::NotAllowed)?;
 //!    Ok(())
 //! }
 //! ```
@@ -262,9 +262,11 @@ enum_with_val! {
         /// Attempted to unregister a service that was not previously registered.
         ServiceNotRegistered = 7,
         /// Process SACs do not allow accessing or hosting this service.
-        PermissionDenied = 8,
+        NotAllowed = 8,
         /// The provided SACs are too big.
         ServiceAccessControlTooBig = 9,
+        /// Attempted to unregister a process that was not previously registered.
+        ProcessNotRegistered = 10,
     }
 }
 
@@ -329,6 +331,12 @@ enum_with_val! {
         ProgramNotFound = 8,
         /// The ELF is corrupted.
         InvalidElf = 9,
+        /// The title is not listed in the title manifest, or its hash does not
+        /// match it.
+        UntrustedTitle = 10,
+        /// The main.npdm of the title is malformed, or its ACI0 grants more
+        /// than its ACID allows.
+        InvalidNpdm = 11,
     }
 }
 
@@ -344,6 +352,10 @@ enum_with_val! {
     pub struct PmError(u32) {
         /// Pid not found
         PidNotFound = 1,
+        /// The service is already running.
+        AlreadyStarted = 2,
+        /// The title is not a service listed in the boot configuration.
+        ServiceNotFound = 7,
     }
 }
 
@@ -0,0 +1,76 @@
+//! Userspace exception handling
+//!
+//! A process can register a handler that gets called when one of its threads
+//! causes an exception, such as a page fault or a division by zero. The
+//! handler can inspect and fix the registers of the thread and let it resume,
+//! or let the process crash.
+//!
+//! The handler runs on a stack dedicated to it, on the thread that caused the
+//! exception. Only one thread can run it at a time: an exception in another
+//! thread meanwhile crashes the process.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::syscalls;
+use crate::error::KernelError;
+pub use sunrise_libkern::process::{ExceptionInfo, ExceptionType, BreakReason};
+
+/// Size of the stack the exception handler runs on.
+const EXCEPTION_STACK_SIZE: usize = 0x4000;
+
+/// The stack the exception handler runs on.
+#[repr(align(16))]
+struct ExceptionStack([u8; EXCEPTION_STACK_SIZE]);
+
+/// The stack the exception handler runs on. Only ever used by the kernel and
+/// the exception handler.
+static mut EXCEPTION_STACK: ExceptionStack = ExceptionStack([0; EXCEPTION_STACK_SIZE]);
+
+/// The handler given to [set_handler], as a `fn(&mut ExceptionInfo) -> bool`.
+/// 0 if none.
+static HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers `handler` as the exception handler of the process.
+///
+/// `handler` returns true if it handled the exception, in which case the
+/// thread resumes with the registers in `info.context`, which the handler may
+/// have modified. Returning false crashes the process.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///   - A thread is currently running the exception handler.
+pub fn set_handler(handler: fn(&mut ExceptionInfo) -> bool) -> Result<(), KernelError> {
+    HANDLER.store(handler as usize, Ordering::SeqCst);
+    unsafe {
+        // Safety: EXCEPTION_STACK is only used by the exception handler.
+        let stack_top = EXCEPTION_STACK.0.as_ptr() as usize + EXCEPTION_STACK_SIZE;
+        syscalls::set_exception_handler(Some(exception_entry), stack_top)
+    }
+}
+
+/// Unregisters the exception handler. Exceptions crash the process again.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///   - A thread is currently running the exception handler.
+pub fn remove_handler() -> Result<(), KernelError> {
+    unsafe {
+        // Safety: Unregistering the handler doesn't use any stack.
+        syscalls::set_exception_handler(None, 0)
+    }
+}
+
+/// Entrypoint of the exception handler. Calls the handler given to
+/// [set_handler], and returns from the exception.
+extern "C" fn exception_entry(info: &mut ExceptionInfo) -> ! {
+    let handler = HANDLER.load(Ordering::SeqCst);
+    let handled = handler != 0 && {
+        // Safety: HANDLER only ever contains 0 or a fn(&mut ExceptionInfo) -> bool.
+        let handler = unsafe { core::mem::transmute::<usize, fn(&mut ExceptionInfo) -> bool>(handler) };
+        handler(info)
+    };
+    let err = syscalls::return_from_exception(handled);
+    let _ = syscalls::output_debug_string(&format!("Failed to return from exception: {}", err), 10, "sunrise_libuser::exception");
+    syscalls::break_(BreakReason::User, b"ReturnFromException failed")
+}
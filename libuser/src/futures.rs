@@ -26,11 +26,11 @@ use alloc::sync::Arc;
 use alloc::collections::VecDeque;
 use alloc::vec::Vec;
 use futures::task::ArcWake;
-use futures::future::{FutureObj, LocalFutureObj};
+use futures::future::{self, Either, FutureObj, LocalFutureObj};
 use spin::Mutex;
 
-use crate::error::KernelError;
-use crate::types::HandleRef;
+use crate::error::{Error, KernelError};
+use crate::types::{HandleRef, Timer};
 use crate::syscalls;
 
 /// A Task represents a future spawned on the [WaitableManager].
@@ -297,4 +297,36 @@ impl<'a> WaitableManager<'a> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Returns a future that completes after `ns` nanoseconds, backed by a
+/// one-shot [Timer].
+///
+/// # Panics
+///
+/// Panics if polled from outside the context of a Future spawned on a libuser
+/// future executor.
+pub fn sleep(queue: WorkQueue<'_>, ns: u64) -> impl Future<Output = Result<(), Error>> + Unpin {
+    #[allow(missing_docs, clippy::missing_docs_in_private_items)]
+    struct Sleep<F> {
+        // Stop waiting on the timer before closing it.
+        wait: F,
+        _timer: Timer,
+    }
+
+    impl<F: Future + Unpin> Future for Sleep<F> {
+        type Output = F::Output;
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+            Pin::new(&mut self.wait).poll(cx)
+        }
+    }
+
+    let timer = syscalls::create_timer().and_then(|timer| timer.set(ns, 0).map(|()| timer));
+    match timer {
+        Ok(timer) => Either::Left(Sleep {
+            wait: timer.0.as_ref_static().wait_async(queue),
+            _timer: timer,
+        }),
+        Err(err) => Either::Right(future::ready(Err(err.into())))
+    }
+}
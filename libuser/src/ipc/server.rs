@@ -337,7 +337,9 @@ where
     use crate::sm::IUserInterfaceProxy;
     // We use `new()` and not `raw_new()` in order to avoid deadlocking when closing the
     // IUserInterfaceProxy handle. See implementation note in sm/src/main.rs
-    let port = IUserInterfaceProxy::new()?.register_service(encode_bytes(server_name), false, 0)?;
+    let sm = IUserInterfaceProxy::new()?;
+    sm.initialize()?;
+    let port = sm.register_service(encode_bytes(server_name), false, 0)?;
     Ok(common_port_handler(work_queue, port, dispatch))
 }
 
@@ -43,6 +43,7 @@ pub mod ipc;
 pub mod threads;
 pub mod thread_local_storage;
 pub mod futures;
+pub mod exception;
 
 //#[gen_ipc(path = "../../ipcdefs/sm.id", prefix = "sunrise_libuser")]
 //pub mod sm {}
@@ -60,6 +61,8 @@ pub mod futures;
 //pub mod ldr {}
 //#[gen_ipc(path = "../../ipcdefs/twili.id", prefix = "sunrise_libuser")]
 //pub mod twili {}
+//#[gen_ipc(path = "../../ipcdefs/uart.id", prefix = "sunrise_libuser")]
+//pub mod uart {}
 //#[gen_ipc(path = "../../ipcdefs/example.id", prefix = "sunrise_libuser")]
 //pub mod example {}
 include!(concat!(env!("OUT_DIR"), "/ipc_code.rs"));
@@ -70,6 +73,7 @@ pub mod terminal;
 pub mod ps2;
 pub mod window;
 pub mod zero_box;
+pub mod test;
 
 #[cfg(all(target_os = "sunrise", not(feature = "build-for-std-app")))]
 mod crt0;
@@ -101,12 +105,17 @@ pub static ALLOCATOR: allocator::Allocator = allocator::Allocator::new();
 #[lang = "eh_personality"] #[no_mangle] pub extern fn eh_personality() {}
 
 /// Function called on `panic!` invocation. Prints the panic information to the
-/// kernel debug logger, and exits the process.
+/// kernel debug logger, and crashes the process with `svcBreak`, keeping the
+/// start of the message in its crash report.
+///
+/// If an in-OS test was running, its failure is reported first. See [test].
 #[cfg(all(target_os = "sunrise", not(test), feature = "lang-items", not(rustdoc)))]
 #[panic_handler] #[no_mangle]
 pub extern fn panic_fmt(p: &core::panic::PanicInfo<'_>) -> ! {
-    let _ = syscalls::output_debug_string(&format!("{}", p), 10, "sunrise_libuser::panic_fmt");
-    syscalls::exit_process();
+    let message = format!("{}", p);
+    let _ = syscalls::output_debug_string(&message, 10, "sunrise_libuser::panic_fmt");
+    test::report_panic(&message);
+    syscalls::break_(syscalls::BreakReason::Panic, message.as_bytes());
 }
 
 // TODO: Don't panic in the oom handler, exit instead.
@@ -1,7 +1,10 @@
 //! Implementation for the log crate
 //!
 //! Redirects all logs to the kernel logger (output_debug_string syscall). No
-//! filtering is done, so everything will be sent.
+//! filtering is done here, so everything will be sent. The kernel filters the
+//! records of each process, see [set_log_filter].
+//!
+//! [set_log_filter]: crate::syscalls::set_log_filter
 
 use log::{self, Log, LevelFilter, Metadata, Record};
 use crate::syscalls::output_debug_string;
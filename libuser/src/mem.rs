@@ -5,6 +5,9 @@
 use sunrise_libutils::{align_down, align_up};
 use crate::syscalls;
 use crate::error::{KernelError, LibuserError, Error};
+use crate::types::{CodeMemory as CodeMemoryHandle};
+use sunrise_libkern::{MemoryPermissions, CodeMemoryOperation};
+use alloc::alloc::{alloc, dealloc, Layout};
 
 /// The size of page. Used to interface with the kernel.
 pub const PAGE_SIZE: usize = 4096;
@@ -100,3 +103,132 @@ pub fn virt_to_phys<T>(virtual_address: *const T) -> usize {
     let offset = virtual_address as usize - base_addr;
     phys_region_start + offset
 }
+
+/// Memory that can be written to through one mapping, and executed through
+/// another. This is the only way to generate code at runtime, as no page is
+/// ever allowed to be writable and executable at the same time.
+///
+/// The memory is allocated from the heap, and mapped twice:
+///
+/// - [as_mut_ptr](CodeMemory::as_mut_ptr) points to a RW- alias, where the code
+///   should be written.
+/// - [as_ptr](CodeMemory::as_ptr) points to a R-X alias, where the code can be
+///   executed from.
+///
+/// The process needs the [code_memory_allowed] capability.
+///
+/// [code_memory_allowed]: crate::caps::code_memory_allowed
+#[derive(Debug)]
+pub struct CodeMemory {
+    /// The kernel CodeMemory. Only None while dropping.
+    handle: Option<CodeMemoryHandle>,
+    /// The heap allocation backing the CodeMemory.
+    backing: *mut u8,
+    /// Layout of the backing allocation.
+    layout: Layout,
+    /// Address of the RW- alias.
+    rw_addr: usize,
+    /// Address of the R-X alias.
+    rx_addr: usize,
+}
+
+impl CodeMemory {
+    /// Allocates a new CodeMemory of the given size, rounded up to PAGE_SIZE,
+    /// and maps its RW- and R-X aliases.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidSize`
+    ///   - The size is 0.
+    /// - `LibuserError::AddressSpaceExhausted`
+    ///   - No space left in the address space to map the aliases.
+    /// - `InvalidKernelCaps`
+    ///   - The process does not have the code_memory_allowed capability.
+    pub fn new(size: usize) -> Result<CodeMemory, Error> {
+        if size == 0 {
+            return Err(KernelError::InvalidSize.into());
+        }
+        let size = align_up(size, PAGE_SIZE);
+        let layout = Layout::from_size_align(size, PAGE_SIZE)
+            .or(Err(KernelError::InvalidSize))?;
+        let backing = unsafe {
+            // Safety: size is not 0.
+            alloc(layout)
+        };
+        if backing.is_null() {
+            return Err(KernelError::MemoryFull.into());
+        }
+
+        let mut code_memory = CodeMemory {
+            handle: None,
+            backing,
+            layout,
+            rw_addr: 0,
+            rx_addr: 0,
+        };
+
+        // If anything fails from here on, the drop impl cleans up for us.
+        code_memory.handle = Some(syscalls::create_code_memory(backing as usize, size)?);
+        let handle = code_memory.handle.as_ref().unwrap();
+
+        let rw_addr = find_free_address(size, PAGE_SIZE)?;
+        unsafe {
+            // Safety: Mapping is always safe.
+            syscalls::control_code_memory(handle, CodeMemoryOperation::MapOwner, rw_addr, size, MemoryPermissions::RW)?;
+        }
+        code_memory.rw_addr = rw_addr;
+
+        let rx_addr = find_free_address(size, PAGE_SIZE)?;
+        unsafe {
+            // Safety: Mapping is always safe.
+            syscalls::control_code_memory(handle, CodeMemoryOperation::MapSlave, rx_addr, size, MemoryPermissions::RX)?;
+        }
+        code_memory.rx_addr = rx_addr;
+
+        Ok(code_memory)
+    }
+
+    /// Gets a pointer to the executable alias of the CodeMemory.
+    ///
+    /// The pointer is valid until the CodeMemory gets dropped.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.rx_addr as *const u8
+    }
+
+    /// Gets a pointer to the writable alias of the CodeMemory.
+    ///
+    /// The pointer is valid until the CodeMemory gets dropped.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.rw_addr as *mut u8
+    }
+
+    /// Gets the byte length of the CodeMemory.
+    #[allow(clippy::len_without_is_empty)] // len cannot be zero.
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+impl Drop for CodeMemory {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            unsafe {
+                // Safety: If this is dropped, then all references given out to
+                // the aliases should have been dropped as well.
+                if self.rx_addr != 0 {
+                    let _ = syscalls::control_code_memory(&handle, CodeMemoryOperation::UnmapSlave, self.rx_addr, self.len(), MemoryPermissions::empty());
+                }
+                if self.rw_addr != 0 {
+                    let _ = syscalls::control_code_memory(&handle, CodeMemoryOperation::UnmapOwner, self.rw_addr, self.len(), MemoryPermissions::empty());
+                }
+            }
+            // Closing the handle unlocks the backing memory.
+            drop(handle);
+        }
+        unsafe {
+            // Safety: The backing memory was allocated with this layout on
+            // construction, and is not locked by the kernel anymore.
+            dealloc(self.backing, self.layout);
+        }
+    }
+}
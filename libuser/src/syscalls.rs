@@ -2,9 +2,11 @@
 
 use core::slice;
 use crate::types::*;
-pub use sunrise_libkern::nr;
-pub use sunrise_libkern::{MemoryInfo, MemoryPermissions};
+pub use sunrise_libkern::{nr, SYSCALL_NAMES};
+pub use sunrise_libkern::{MemoryInfo, MemoryPermissions, MemoryState, MemoryType, CodeMemoryOperation, InterruptType};
 pub use sunrise_libkern::process::*;
+pub use sunrise_libkern::dmesg;
+pub use sunrise_libkern::trace;
 use crate::error::KernelError;
 
 // Assembly blob can't get documented, but clippy requires it.
@@ -127,6 +129,39 @@ pub unsafe fn set_heap_size(new_size: usize) -> Result<usize, KernelError> {
     Ok(heap_address_base)
 }
 
+/// Maps the given src memory range to the dst memory range as Stack memory.
+///
+/// The src range gets locked until it is unmapped with [unmap_memory].
+///
+/// # Errors
+///
+/// - src and dst addresses and size must be page-aligned.
+/// - The src must be RW- heap memory.
+/// - The dst must be unmapped.
+pub fn map_memory(dstaddr: usize, srcaddr: usize, size: usize) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::MapMemory, dstaddr, srcaddr, size, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Unmaps a memory range mapped with [map_memory].
+///
+/// # Safety
+///
+/// This function unmaps the memory, invalidating any pointer to the given
+/// region. The user must take care that no pointers point to this region before
+/// calling this function.
+///
+/// # Errors
+///
+/// - The given dst range must map the given src range, as created by
+///   [map_memory].
+pub unsafe fn unmap_memory(dstaddr: usize, srcaddr: usize, size: usize) -> Result<(), KernelError> {
+    syscall(nr::UnmapMemory, dstaddr, srcaddr, size, 0, 0, 0)?;
+    Ok(())
+}
+
 /// Query information about an address. Will fetch the page-aligned mapping `addr` falls in.
 /// mapping that contains the provided address.
 ///
@@ -208,7 +243,7 @@ pub fn signal_event(event: &WritableEvent) -> Result<(), KernelError> {
 /// event, [wait_synchronization()] on this handle will wait until
 /// [signal_event()] is called once again.
 ///
-/// Takes either a [ReadableEvent] or a [WritableEvent].
+/// Takes either a [ReadableEvent], a [WritableEvent], or a [Timer].
 ///
 /// # Errors
 ///
@@ -417,13 +452,56 @@ pub fn create_event() -> Result<(WritableEvent, ReadableEvent), KernelError> {
 /// Create a waitable object for the given IRQ number.
 ///
 /// Note that the process needs to be authorized to listen for the given IRQ.
-pub fn create_interrupt_event(irqnum: usize, flag: u32) -> Result<ReadableEvent, KernelError> {
+///
+/// A [InterruptType::Level] event masks the IRQ each time it is triggered. The
+/// driver must call [acknowledge_interrupt] once it handled the interrupt to
+/// receive the next one.
+pub fn create_interrupt_event(irqnum: usize, flag: InterruptType) -> Result<ReadableEvent, KernelError> {
     unsafe {
-        let (out_handle, ..) = syscall(nr::CreateInterruptEvent, irqnum, flag as usize, 0, 0, 0, 0)?;
+        let (out_handle, ..) = syscall(nr::CreateInterruptEvent, irqnum, flag.0 as usize, 0, 0, 0, 0)?;
         Ok(ReadableEvent(Handle::new(out_handle as _)))
     }
 }
 
+/// Acknowledges a level-triggered interrupt event, unmasking its IRQ. Should
+/// be called once the interrupt condition was cleared on the device.
+///
+/// This is a Sunrise extension.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The handle is not an interrupt event.
+/// - `InvalidState`
+///   - The interrupt event is edge-triggered.
+pub fn acknowledge_interrupt(event: &ReadableEvent) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::AcknowledgeInterrupt, (event.0).0.get() as _, 0, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Allocates an interrupt vector for a Message Signaled Interrupt, and creates
+/// an event signaled each time the interrupt is triggered.
+///
+/// Returns the event, along with the address and data to program in the
+/// device's MSI capability.
+///
+/// This is a Sunrise extension. The process needs the msi_allowed capability.
+///
+/// # Errors
+///
+/// - `InvalidKernelCaps`
+///   - The process does not have the msi_allowed capability.
+/// - `OutOfResource`
+///   - All the MSI vectors are in use.
+pub fn create_msi_event() -> Result<(ReadableEvent, u32, u32), KernelError> {
+    unsafe {
+        let (out_handle, address, data, ..) = syscall(nr::CreateMsiEvent, 0, 0, 0, 0, 0, 0)?;
+        Ok((ReadableEvent(Handle::new(out_handle as _)), address as u32, data as u32))
+    }
+}
+
 /// Gets the physical region a given virtual address maps.
 ///
 /// This syscall is mostly used for DMAs, where the physical address of a buffer needs to be known
@@ -632,6 +710,160 @@ pub unsafe fn unmap_process_memory(dstaddr: usize, proc_handle: &Process, srcadd
     Ok(())
 }
 
+/// Creates a CodeMemory from the given region of the current process' heap.
+/// The region gets locked until the CodeMemory is closed. Use
+/// [control_code_memory()] to map the CodeMemory as RW- and R-X.
+///
+/// The process needs the [code_memory_allowed] capability to use this syscall.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `addr + size` overflows
+///    - The region is outside of the UserLand address space.
+///    - The region does not have the CODE_MEMORY_ALLOWED state, or is not RW-.
+/// - `InvalidKernelCaps`
+///    - The process does not have the code_memory_allowed capability.
+///
+/// [code_memory_allowed]: crate::caps::code_memory_allowed
+pub fn create_code_memory(addr: usize, size: usize) -> Result<CodeMemory, KernelError> {
+    unsafe {
+        let (out_handle, ..) = syscall(nr::CreateCodeMemory, addr, size, 0, 0, 0, 0)?;
+        Ok(CodeMemory(Handle::new(out_handle as _)))
+    }
+}
+
+/// Maps or unmaps an alias of a CodeMemory.
+///
+/// - `MapOwner` maps the CodeMemory at `addr` as RW- in the process that
+///   created it. `perm` must be RW-.
+/// - `MapSlave` maps the CodeMemory at `addr` in the current process. `perm`
+///   must be R-- or R-X.
+/// - `UnmapOwner` and `UnmapSlave` unmap those aliases.
+///
+/// The size must be equal to the size of the CodeMemory.
+///
+/// # Safety
+///
+/// Unmapping invalidates any pointer to the given region. The user must take
+/// care that no pointers point to this region, and that no thread executes
+/// code from it, before unmapping it.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is not the size of the CodeMemory.
+/// - `InvalidMemState`
+///    - `addr + size` overflows
+///    - The region is outside of the UserLand address space.
+///    - When mapping, the region is not fully Unmapped.
+///    - When unmapping, the region is not of the expected type.
+/// - `InvalidMemRange`
+///    - When unmapping, the region does not map the CodeMemory.
+/// - `InvalidMemPerms`
+///    - `perm` is not allowed for this operation.
+/// - `InvalidEnum`
+///    - `op` is not a valid operation.
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a CodeMemory
+///      handle.
+///    - The owner of the CodeMemory is dead.
+pub unsafe fn control_code_memory(handle: &CodeMemory, op: CodeMemoryOperation, addr: usize, size: usize, perm: MemoryPermissions) -> Result<(), KernelError> {
+    syscall(nr::ControlCodeMemory, (handle.0).0.get() as _, op.0 as _, addr, size, perm.bits() as _, 0)?;
+    Ok(())
+}
+
+/// Maps the given src memory range of a process as code in the same process.
+/// The src region gets locked until it is unmapped with
+/// [unmap_process_code_memory()]. The dst region gets the ModuleCodeStatic
+/// state with no permissions, use [set_process_memory_permission()] to make it
+/// usable.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - `dst_addr + size` overflows
+///    - The src region is outside of the UserLand address space.
+///    - The src memory pages does not have the MAP_ALLOWED state, or are not
+///      RW-.
+///    - The dst memory pages are not all Unmapped.
+/// - `InvalidMemRange`
+///    - The dst region is outside of the UserLand address space.
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a Process
+///      handle.
+pub fn map_process_code_memory(proc_handle: &Process, dstaddr: usize, srcaddr: usize, size: usize) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::MapProcessCodeMemory, (proc_handle.0).0.get() as _, dstaddr, srcaddr, size, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Unmaps a memory range mapped with [map_process_code_memory()], and gives
+/// its original permissions back to the src memory range.
+///
+/// # Safety
+///
+/// This function unmaps the memory, invalidating any pointer to the given
+/// region. The user must take care that no pointers point to this region, and
+/// that no thread executes code from it, before calling this function.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - `dst_addr + size` overflows
+///    - The src region is outside of the UserLand address space.
+///    - The src memory pages does not have the MAP_ALLOWED state, or are not
+///      locked.
+///    - The dst memory pages does not have the UNMAP_PROCESS_CODE_MEMORY_ALLOWED
+///      state.
+/// - `InvalidMemRange`
+///    - The dst region is outside of the UserLand address space.
+///    - The given source range does not map the same pages as the given dst
+///      range.
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a Process
+///      handle.
+pub unsafe fn unmap_process_code_memory(proc_handle: &Process, dstaddr: usize, srcaddr: usize, size: usize) -> Result<(), KernelError> {
+    syscall(nr::UnmapProcessCodeMemory, (proc_handle.0).0.get() as _, dstaddr, srcaddr, size, 0, 0)?;
+    Ok(())
+}
+
+/// Query information about an address in the given process' address space.
+/// Behaves like [query_memory()] otherwise.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a Process
+///      handle.
+pub fn query_process_memory(proc_handle: &Process, addr: usize) -> Result<(MemoryInfo, usize), KernelError> {
+    let mut meminfo = MemoryInfo::default();
+    let (pageinfo, ..) = unsafe {
+        syscall(nr::QueryProcessMemory, &mut meminfo as *mut _ as usize, 0, (proc_handle.0).0.get() as _, addr, 0, 0)?
+    };
+    Ok((meminfo, pageinfo))
+}
+
 /// Creates a new process with the given parameters.
 ///
 /// Note that you probably don't want to use this! Look instead for
@@ -756,4 +988,436 @@ pub fn get_process_list(list: &mut [u64]) -> Result<usize, KernelError> {
         let (read, ..) = syscall(nr::GetProcessList, list.as_ptr() as usize, list.len(), 0, 0, 0, 0)?;
         Ok(read)
     }
-}
\ No newline at end of file
+}
+
+/// Gets the memory and handle usage of the process with the given pid.
+///
+/// This is a Sunrise extension.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has this pid.
+pub fn get_process_memory_usage(pid: u64) -> Result<ProcessMemoryUsage, KernelError> {
+    let mut usage = ProcessMemoryUsage::default();
+    unsafe {
+        syscall(nr::GetProcessMemoryUsage, &mut usage as *mut _ as usize, pid as usize, 0, 0, 0, 0)?;
+    }
+    Ok(usage)
+}
+
+/// Gets information about the system. `sub_id` selects the memory pool the
+/// information is about, the bound of the PID range, or the built-in whose
+/// title id to get.
+///
+/// # Errors
+///
+/// - `InvalidCombination`
+///   - sub_id is not a valid pool, or not 0 or 1 for InitialProcessIdRange.
+/// - `InvalidState`
+///   - The kernel started no built-in.
+/// - `NoSuchEntry`
+///   - sub_id is not the PID of a living built-in, for InitialProcessTitleId.
+/// - `InvalidEnum`
+///   - The passed info type is unknown.
+pub fn get_system_info(ty: SystemInfoType, sub_id: usize) -> Result<u64, KernelError> {
+    unsafe {
+        let (info_low, info_high, ..) = syscall(nr::GetSystemInfo, ty.0 as usize, 0, sub_id, 0, 0, 0)?;
+        Ok(info_low as u64 | (info_high as u64) << 32)
+    }
+}
+
+/// Pauses or resumes a thread of the current process. A paused thread stops
+/// the next time it would return to userspace.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The thread is the current thread, or belongs to another process.
+/// - `InvalidState`
+///   - The thread is already in the requested activity.
+pub fn set_thread_activity(thread: &Thread, activity: ThreadActivity) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetThreadActivity, (thread.0).0.get() as _, activity.0 as usize, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Pauses or resumes every thread of a process.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The process is the current process.
+/// - `InvalidState`
+///   - A thread of the process was already in the requested activity.
+pub fn set_process_activity(process: &Process, activity: ProcessActivity) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetProcessActivity, (process.0).0.get() as _, activity.0 as usize, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Gets the id of the given thread. Thread ids are unique across the whole
+/// system.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+pub fn get_thread_id(thread: &Thread) -> Result<u64, KernelError> {
+    unsafe {
+        let (tid, ..) = syscall(nr::GetThreadId, (thread.0).0.get() as _, 0, 0, 0, 0, 0)?;
+        Ok(tid as _)
+    }
+}
+
+/// Gets the userspace registers of a paused thread of the current process.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The thread is the current thread, or belongs to another process.
+/// - `InvalidState`
+///   - The thread is not paused.
+pub fn get_thread_context3(thread: &Thread) -> Result<ThreadContext, KernelError> {
+    let mut context = ThreadContext::default();
+    unsafe {
+        syscall(nr::GetThreadContext3, &mut context as *mut _ as usize, (thread.0).0.get() as _, 0, 0, 0, 0)?;
+    }
+    Ok(context)
+}
+
+/// Fills the provided array with the ids of the living threads of the debugged
+/// process, or of the current process if `debug` is None.
+///
+/// It returns the total number of living threads. If this number is bigger
+/// than the size of the array, the user won't have all the ids.
+pub fn get_thread_list(list: &mut [u64], debug: Option<&DebugSession>) -> Result<usize, KernelError> {
+    let debug = debug.map_or(0, |debug| (debug.0).0.get() as usize);
+    unsafe {
+        let (read, ..) = syscall(nr::GetThreadList, list.as_mut_ptr() as usize, list.len(), debug, 0, 0, 0)?;
+        Ok(read)
+    }
+}
+
+/// Creates a debug session on the process with the given pid. The process
+/// must allow being debugged, or the current process must have the right to
+/// debug others, see [debug_flags].
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has this pid.
+/// - `InvalidState`
+///   - Neither capability allows debugging the process.
+///
+/// [debug_flags]: crate::caps::debug_flags
+pub fn debug_active_process(pid: u64) -> Result<DebugSession, KernelError> {
+    unsafe {
+        let (hnd, ..) = syscall(nr::DebugActiveProcess, pid as usize, 0, 0, 0, 0, 0)?;
+        Ok(DebugSession(Handle::new(hnd as _)))
+    }
+}
+
+/// Gets the userspace registers of a thread of a debugged process. If the
+/// thread is not paused, this is a snapshot of its last kernel entry.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - The process has no living thread with this id.
+pub fn get_debug_thread_context(debug: &DebugSession, tid: u64) -> Result<ThreadContext, KernelError> {
+    let mut context = ThreadContext::default();
+    unsafe {
+        syscall(nr::GetDebugThreadContext, &mut context as *mut _ as usize, (debug.0).0.get() as _, tid as usize, 0, 0, 0)?;
+    }
+    Ok(context)
+}
+
+/// Gets the scheduling state of a thread of a debugged process, and whether
+/// it is paused.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - The process has no living thread with this id.
+pub fn get_debug_thread_state(debug: &DebugSession, tid: u64) -> Result<(ThreadState, bool), KernelError> {
+    unsafe {
+        let (paused, state, ..) = syscall(nr::GetDebugThreadParam, (debug.0).0.get() as _, tid as usize, DebugThreadParam::State.0 as usize, 0, 0, 0)?;
+        Ok((ThreadState(state as u32), paused != 0))
+    }
+}
+
+/// Query information about an address in the address space of a debugged
+/// process. Behaves like [query_memory()] otherwise.
+pub fn query_debug_process_memory(debug: &DebugSession, addr: usize) -> Result<(MemoryInfo, usize), KernelError> {
+    let mut meminfo = MemoryInfo::default();
+    let (pageinfo, ..) = unsafe {
+        syscall(nr::QueryDebugProcessMemory, &mut meminfo as *mut _ as usize, 0, (debug.0).0.get() as _, addr, 0, 0)?
+    };
+    Ok((meminfo, pageinfo))
+}
+
+/// Copies memory of a debugged process, starting at `addr`, into `buffer`.
+///
+/// # Errors
+///
+/// - `InvalidMemState`
+///   - Part of the range is not readable, or is not regular memory.
+pub fn read_debug_process_memory(debug: &DebugSession, addr: usize, buffer: &mut [u8]) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::ReadDebugProcessMemory, buffer.as_mut_ptr() as usize, (debug.0).0.get() as _, addr, buffer.len(), 0, 0)?;
+    }
+    Ok(())
+}
+
+/// Reports a fatal error, and crashes the process. The reason and the start
+/// of `info` are kept in the crash report of the process.
+pub fn break_(reason: BreakReason, info: &[u8]) -> ! {
+    unsafe {
+        match syscall(nr::Break, reason.0 as usize, info.as_ptr() as usize, info.len(), 0, 0, 0) {
+            Ok(_) => (),
+            Err(err) => { let _ = output_debug_string(&format!("Failed to break: {}", err), 10, "sunrise_libuser::syscalls::break_"); },
+        }
+    }
+    exit_process()
+}
+
+/// Registers the exception handler of the process. `entrypoint` will be
+/// called with the description of the exception, on the stack ending at
+/// `stack_top`, and must finish with [return_from_exception]. Passing `None`
+/// unregisters the handler. See also [crate::exception], which manages this
+/// for you.
+///
+/// # Unsafety
+///
+/// `stack_top` must be the end of a stack that is uniquely owned by the
+/// exception handler, as the kernel will write to it.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///   - The page below `stack_top` is outside of the userspace address space.
+/// - `InvalidState`
+///   - A thread is currently running the exception handler.
+pub unsafe fn set_exception_handler(entrypoint: Option<extern "C" fn(&mut ExceptionInfo) -> !>, stack_top: usize) -> Result<(), KernelError> {
+    syscall(nr::SetExceptionHandler, entrypoint.map_or(0, |entrypoint| entrypoint as usize), stack_top, 0, 0, 0, 0)?;
+    Ok(())
+}
+
+/// Finishes handling an exception. If `handled` is true, the thread that
+/// caused the exception resumes with the registers in the [ExceptionInfo]
+/// given to the handler. Otherwise, the process crashes.
+///
+/// Only returns on error.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///   - The current thread is not running the exception handler.
+pub fn return_from_exception(handled: bool) -> KernelError {
+    unsafe {
+        match syscall(nr::ReturnFromException, if handled { 0 } else { 1 }, 0, 0, 0, 0, 0) {
+            Err(err) => err,
+            Ok(_) => unreachable!("ReturnFromException returned successfully"),
+        }
+    }
+}
+
+/// Gets why a debugged process crashed.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - The process did not crash. It is still running, or exited normally.
+pub fn get_crash_report(debug: &DebugSession) -> Result<CrashReport, KernelError> {
+    let mut report = CrashReport::default();
+    unsafe {
+        syscall(nr::GetCrashReport, &mut report as *mut _ as usize, (debug.0).0.get() as _, 0, 0, 0, 0)?;
+    }
+    Ok(report)
+}
+
+/// Sets the functions of a process that was not started yet, so the kernel
+/// can symbolize its backtrace if it crashes.
+///
+/// `symtab` and `strtab` are the raw `.symtab` and `.strtab` sections of its
+/// ELF binary, loaded at `base`.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///   - The process was already started.
+pub fn set_process_symbols(process: &Process, symtab: &[u8], strtab: &[u8], base: usize) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetProcessSymbols, (process.0).0.get() as _, symtab.as_ptr() as _, symtab.len(), strtab.as_ptr() as _, strtab.len(), base)?;
+    }
+    Ok(())
+}
+
+/// Finds the function containing `addr` in a debugged process.
+///
+/// Copies as much of its name as fits in `name`, and returns the offset of
+/// `addr` in the function, and the length of its whole name.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - The process has no symbols, or `addr` is not in any of its functions.
+pub fn get_debug_process_symbol(debug: &DebugSession, addr: usize, name: &mut [u8]) -> Result<(usize, usize), KernelError> {
+    unsafe {
+        let (offset, name_len, ..) = syscall(nr::GetDebugProcessSymbol, name.as_mut_ptr() as _, name.len(), (debug.0).0.get() as _, addr, 0, 0)?;
+        Ok((offset, name_len))
+    }
+}
+
+/// Reads the kernel log, starting after `cursor`.
+///
+/// Fills `buf` with as many whole records as fit, to be parsed with
+/// [dmesg::parse_records]. `buf` should be at least [dmesg::MAX_RECORD_SIZE]
+/// bytes. Pass 0 as `cursor` to start from the oldest record still in the
+/// log, and the returned cursor to get the following records.
+///
+/// Returns the cursor to pass to the next read, and the number of bytes
+/// written to `buf`.
+///
+/// This is a Sunrise extension.
+pub fn read_kernel_log(cursor: usize, buf: &mut [u8]) -> Result<(usize, usize), KernelError> {
+    unsafe {
+        let (cursor, written, ..) = syscall(nr::ReadKernelLog, buf.as_mut_ptr() as _, buf.len(), cursor, 0, 0, 0)?;
+        Ok((cursor, written))
+    }
+}
+
+/// Creates an event signaled when records are added to the kernel log. It
+/// does not need to be cleared.
+///
+/// This is a Sunrise extension.
+pub fn create_kernel_log_event() -> Result<ReadableEvent, KernelError> {
+    unsafe {
+        let (out_handle, ..) = syscall(nr::CreateKernelLogEvent, 0, 0, 0, 0, 0, 0)?;
+        Ok(ReadableEvent(Handle::new(out_handle as _)))
+    }
+}
+
+/// Replaces the kernel log filter directives of the processes named
+/// `process_name`, or the global filter if it is None.
+///
+/// The directives use the same syntax as the kernel cmdline, e.g.
+/// `info,sunrise_libuser::ipc=trace`. The filter of a process applies to the
+/// records it logs, and to the ones the kernel logs while handling its
+/// syscalls. Empty directives remove the filter of the processes, making them
+/// use the global filter again.
+///
+/// This is a Sunrise extension.
+pub fn set_log_filter(process_name: Option<&str>, directives: &str) -> Result<(), KernelError> {
+    let process_name = process_name.unwrap_or("");
+    unsafe {
+        syscall(nr::SetLogFilter, directives.as_ptr() as _, directives.len(), process_name.as_ptr() as _, process_name.len(), 0, 0)?;
+    }
+    Ok(())
+}
+
+/// Reads the kernel trace buffer of CPU `cpu`, starting after `cursor`.
+///
+/// Fills `events` with as many events as fit. Pass 0 as `cursor` to start
+/// from the oldest event still in the buffer, and the returned cursor to get
+/// the following events.
+///
+/// Returns the cursor to pass to the next read, and the number of events
+/// written to `events`.
+///
+/// This is a Sunrise extension.
+///
+/// # Errors
+///
+/// - `InvalidProcessorId`
+///   - `cpu` does not exist.
+/// - `NotImplemented`
+///   - The kernel was built without the `tracing` feature.
+pub fn read_trace_buffer(cpu: usize, cursor: usize, events: &mut [trace::TraceEvent]) -> Result<(usize, usize), KernelError> {
+    unsafe {
+        let (cursor, count, ..) = syscall(nr::ReadTraceBuffer, cpu, events.as_mut_ptr() as _, events.len() * core::mem::size_of::<trace::TraceEvent>(), cursor, 0, 0)?;
+        Ok((cursor, count))
+    }
+}
+
+/// Creates a disarmed timer. Arm it with [set_timer].
+///
+/// This is a Sunrise extension.
+pub fn create_timer() -> Result<Timer, KernelError> {
+    unsafe {
+        let (out_handle, ..) = syscall(nr::CreateTimer, 0, 0, 0, 0, 0, 0)?;
+        Ok(Timer(Handle::new(out_handle as _)))
+    }
+}
+
+/// Arms a timer to get signaled in `delay_ns` nanoseconds, and then every
+/// `period_ns` nanoseconds if it is not 0. Clears its signaled state.
+///
+/// A timer stays signaled until it is cleared with [Timer::clear]. A periodic
+/// timer then gets signaled again at its next period.
+///
+/// This is a Sunrise extension.
+pub fn set_timer(timer: &Timer, delay_ns: u64, period_ns: u64) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetTimer, (timer.0).0.get() as _, delay_ns as usize, (delay_ns >> 32) as usize, period_ns as usize, (period_ns >> 32) as usize, 0)?;
+    }
+    Ok(())
+}
+
+/// Disarms a timer, and clears its signaled state.
+///
+/// This is a Sunrise extension.
+pub fn cancel_timer(timer: &Timer) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::CancelTimer, (timer.0).0.get() as _, 0, 0, 0, 0, 0)?;
+    }
+    Ok(())
+}
+
+/// Turns the system off, or resets it if `reboot` is true. Ask the services to
+/// save their state first, the filesystem in particular.
+///
+/// Only returns if the system could not be turned off.
+///
+/// Unlike Horizon's, which puts the console to sleep, Sunrise's SleepSystem
+/// takes whether to reboot.
+pub fn sleep_system(reboot: bool) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SleepSystem, reboot as usize, 0, 0, 0, 0, 0)?;
+    }
+    Ok(())
+}
+
+/// Creates an event signaled when the power button is pressed. It stays
+/// signaled until it is cleared.
+///
+/// This is a Sunrise extension.
+pub fn create_power_button_event() -> Result<ReadableEvent, KernelError> {
+    unsafe {
+        let (out_handle, ..) = syscall(nr::CreatePowerButtonEvent, 0, 0, 0, 0, 0, 0)?;
+        Ok(ReadableEvent(Handle::new(out_handle as _)))
+    }
+}
+
+/// Lists the open handles of the process with the given pid, sorted by handle
+/// number, to chase handle leaks.
+///
+/// Fills `handles` with as many handles as fit, and returns the number of
+/// handles the process has open. If this number is bigger than the size of the
+/// array, the user won't have all the handles.
+///
+/// This is a Sunrise extension.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has this pid.
+pub fn get_process_handles(pid: u64, handles: &mut [HandleInfo]) -> Result<usize, KernelError> {
+    unsafe {
+        let (count, ..) = syscall(nr::GetProcessHandles, handles.as_mut_ptr() as usize, handles.len(), pid as usize, 0, 0, 0)?;
+        Ok(count)
+    }
+}
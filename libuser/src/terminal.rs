@@ -38,7 +38,7 @@ pub enum WindowSize {
 pub struct Terminal {
     /// Internal write buffer.
     buffer: ArrayVec<[u8; 256]>,
-    /// The vi pipe backing this terminal.
+    /// The pipe backing this terminal, usually created by vi.
     pipe: crate::twili::IPipeProxy
 }
 
@@ -87,6 +87,15 @@ impl Terminal {
         })
     }
 
+    /// Creates a Terminal writing to and reading from an existing pipe, e.g. a
+    /// serial console.
+    pub fn from_pipe(pipe: crate::twili::IPipeProxy) -> Terminal {
+        Terminal {
+            pipe,
+            buffer: ArrayVec::new()
+        }
+    }
+
     /// Flush the write buffer and draw the text.
     pub fn draw(&mut self) -> Result<(), Error> {
         if !self.buffer.is_empty() {
@@ -0,0 +1,177 @@
+//! In-OS test harness
+//!
+//! Runs tests on the running system, against the live kernel and services,
+//! and reports their results over the serial port in the [TAP] format:
+//!
+//! ```text
+//! 1..3
+//! ok 1 - sm::get_service
+//! not ok 2 - fs::read_back # Fs(FileNotFound)
+//! not ok 3 - loader::wait # panicked at 'oops', test/src/main.rs:42:5
+//! Bail out! test panicked
+//! ```
+//!
+//! When running under QEMU with an `isa-debug-exit` device on
+//! [QEMU_EXIT_PORT], the run ends by exiting QEMU with a status telling
+//! whether every test passed. See [QemuExitCode].
+//!
+//! A panic aborts the whole process, so the remaining tests are not run: the
+//! failure is reported, and QEMU exits right away.
+//!
+//! The harness is a custom test framework. Tests are `#[test_case]` functions
+//! returning a `Result<(), Error>`, see [TestCase], and the crate is built with
+//! `--test`:
+//!
+//! ```ignore
+//! #![feature(custom_test_frameworks)]
+//! #![test_runner(sunrise_libuser::test::runner)]
+//!
+//! mod kernel {
+//!     #[test_case]
+//!     fn sleep_thread() -> Result<(), Error> {
+//!         sunrise_libuser::syscalls::sleep_thread(0)?;
+//!         Ok(())
+//!     }
+//! }
+//! ```
+//!
+//! The process needs the ConnectToNamedPort and SendSyncRequestWithUserBuffer
+//! SVCs to talk to the uart service, and access to [QEMU_EXIT_PORT].
+//!
+//! [TAP]: https://testanything.org/tap-specification.html
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::string::String;
+use spin::Mutex;
+
+use crate::error::Error;
+use crate::io::{Io, Pio};
+use crate::twili::IPipeProxy;
+use crate::uart::IUartServiceProxy;
+
+/// IO port of QEMU's `isa-debug-exit` device, as configured with
+/// `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+pub const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// Value written to the `isa-debug-exit` device at the end of the run.
+///
+/// QEMU exits with `(value << 1) | 1`, so 33 means success and 35 failure.
+/// Other statuses come from QEMU itself, e.g. 1 for a triple fault without
+/// `-no-reboot`.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QemuExitCode {
+    /// Every test passed.
+    Success = 0x10,
+    /// At least one test failed.
+    Failure = 0x11,
+}
+
+/// A test to run on the live system. Fails by returning an error, or
+/// panicking.
+///
+/// Implemented by the functions returning a `Result<(), Error>`, which are
+/// named after their path without the crate, e.g. `kernel::sleep_thread` in the
+/// example of the module documentation.
+pub trait TestCase {
+    /// Name of the test, as reported.
+    fn name(&self) -> &'static str;
+    /// Runs the test.
+    fn run(&self) -> Result<(), Error>;
+}
+
+impl<T: Fn() -> Result<(), Error>> TestCase for T {
+    fn name(&self) -> &'static str {
+        let path = core::any::type_name::<T>();
+        path.splitn(2, "::").nth(1).unwrap_or(path)
+    }
+
+    fn run(&self) -> Result<(), Error> {
+        self()
+    }
+}
+
+/// The pipe the results are written to, on the serial port.
+static OUTPUT: Mutex<Option<IPipeProxy>> = Mutex::new(None);
+
+/// The name of the test currently running, used to report panics.
+static CURRENT_TEST: Mutex<Option<&'static str>> = Mutex::new(None);
+/// The number of the test currently running.
+static CURRENT_TEST_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes a line of the report.
+///
+/// Uses the uart service when available, so the report is not affected by
+/// the log filters. Falls back to the logger otherwise.
+fn report(args: fmt::Arguments) {
+    let mut line = String::new();
+    let _ = line.write_fmt(args);
+    line.push('\n');
+
+    // Don't deadlock if we panicked while writing.
+    if let Some(mut output) = OUTPUT.try_lock() {
+        if output.is_none() {
+            *output = IUartServiceProxy::raw_new().and_then(|uart| uart.open_pipe(false)).ok();
+        }
+        if let Some(pipe) = &*output {
+            if pipe.write(line.as_bytes()).is_ok() {
+                return;
+            }
+        }
+    }
+    info!("{}", line.trim_end());
+}
+
+/// Exits QEMU through its `isa-debug-exit` device.
+///
+/// Returns if there is no such device, e.g. when not running under QEMU.
+pub fn exit_qemu(code: QemuExitCode) {
+    Pio::<u8>::new(QEMU_EXIT_PORT).write(code as u8);
+}
+
+/// Runs the tests, reports their results, and exits QEMU. This is the
+/// `test_runner` of the crates using the harness.
+///
+/// Returns if QEMU could not be exited.
+pub fn runner(tests: &[&dyn TestCase]) {
+    report(format_args!("1..{}", tests.len()));
+
+    let mut failed = 0;
+    for (idx, test) in tests.iter().enumerate() {
+        CURRENT_TEST_NUMBER.store(idx + 1, Ordering::SeqCst);
+        *CURRENT_TEST.lock() = Some(test.name());
+
+        match test.run() {
+            Ok(()) => report(format_args!("ok {} - {}", idx + 1, test.name())),
+            Err(err) => {
+                failed += 1;
+                report(format_args!("not ok {} - {} # {:?}", idx + 1, test.name(), err));
+            }
+        }
+    }
+    *CURRENT_TEST.lock() = None;
+
+    if failed == 0 {
+        info!("All {} tests passed", tests.len());
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        error!("{} of {} tests failed", failed, tests.len());
+        exit_qemu(QemuExitCode::Failure);
+    }
+}
+
+/// Reports a panic as the failure of the running test, and exits QEMU.
+///
+/// Called by the panic handler. Does nothing if no test is running.
+pub(crate) fn report_panic(message: &str) {
+    // Don't deadlock if we panicked while setting the test.
+    let name = match CURRENT_TEST.try_lock().and_then(|mut test| test.take()) {
+        Some(name) => name,
+        None => return
+    };
+
+    report(format_args!("not ok {} - {} # {}", CURRENT_TEST_NUMBER.load(Ordering::SeqCst), name, message));
+    report(format_args!("Bail out! test panicked"));
+    exit_qemu(QemuExitCode::Failure);
+}
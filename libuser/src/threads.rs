@@ -76,11 +76,24 @@ use spin::Once;
 pub const DEFAULT_STACK_SIZE: usize = 0x8000;
 
 /// Stack allocation informations
+///
+/// The stack memory is allocated on the heap, and then remapped elsewhere with
+/// `svcMapMemory`, leaving the page right below it unmapped as a guard page.
+/// Overflowing the stack thus faults instead of overwriting the heap, and the
+/// kernel reports it as a stack overflow.
+///
+/// Nothing stops another mapping from being put in the guard page later on, as
+/// [find_free_address] only considers mapped memory.
+///
+/// [find_free_address]: crate::mem::find_free_address
 #[derive(Debug)]
 struct StackContext {
-    /// The addresss of the allocated stack
+    /// The addresss of the heap memory backing the stack.
     stack_address: *const u8,
 
+    /// The address the stack is mapped at, or 0 if it isn't mapped yet.
+    stack_alias: usize,
+
     /// The stack layout.
     stack_layout: Layout
 }
@@ -93,32 +106,60 @@ impl StackContext {
     /// - `InvalidSize`
     ///   - The size passed was 0
     ///   - The size overflows when rounded up to the nearest multiple of PAGE_SIZE.
+    /// - `MemoryFull`
+    ///   - The stack could not be allocated.
+    /// - `LibuserError::AddressSpaceExhausted`
+    ///   - No space left in the address space to map the stack.
     pub fn new(stack_size: usize) -> Result<Self, Error> {
         if stack_size == 0 {
             return Err(KernelError::InvalidSize.into());
         }
 
+        let stack_size = sunrise_libutils::align_up_checked(stack_size, crate::mem::PAGE_SIZE)
+            .ok_or(KernelError::InvalidSize)?;
         let stack_layout = Layout::from_size_align(stack_size, crate::mem::PAGE_SIZE)
             .or(Err(KernelError::InvalidSize))?;
 
-        Ok(StackContext {
-            stack_address: unsafe {
-                // Safety: We error from the function early if stack_size is 0. We don't care much about whether the block is initialized.
-                alloc(stack_layout) as *const u8
-            },
+        let stack_address = unsafe {
+            // Safety: We error from the function early if stack_size is 0. We don't care much about whether the block is initialized.
+            alloc(stack_layout) as *const u8
+        };
+        if stack_address.is_null() {
+            return Err(KernelError::MemoryFull.into());
+        }
+
+        let mut stack = StackContext {
+            stack_address,
+            stack_alias: 0,
             stack_layout
-        })
+        };
+
+        // If anything fails from here on, the drop impl cleans up for us.
+        // Look for room for the stack and its guard page, and leave the guard
+        // page unmapped.
+        let guard_size = stack_size.checked_add(crate::mem::PAGE_SIZE)
+            .ok_or(KernelError::InvalidSize)?;
+        let guard_address = crate::mem::find_free_address(guard_size, crate::mem::PAGE_SIZE)?;
+        let stack_alias = guard_address + crate::mem::PAGE_SIZE;
+        syscalls::map_memory(stack_alias, stack_address as usize, stack_size)?;
+        stack.stack_alias = stack_alias;
+
+        Ok(stack)
     }
 
     /// Get the address of the stack top.
     pub fn get_stack_top(&self) -> *const u8 {
-        self.stack_address.wrapping_add(self.stack_layout.size())
+        (self.stack_alias + self.stack_layout.size()) as *const u8
     }
 }
 
 impl Drop for StackContext {
     fn drop(&mut self) {
         unsafe {
+            // Safety: The stack is only dropped once its thread is dead, so nothing points to it anymore.
+            if self.stack_alias != 0 {
+                let _ = syscalls::unmap_memory(self.stack_alias, self.stack_address as usize, self.stack_layout.size());
+            }
             // Safety: The stack_address is guaranteed to be valid (it was allocated on construction). We also keep the layout around to ensure it stays the same between alloc and dealloc.
             dealloc(self.stack_address as *mut u8, self.stack_layout);
         }
@@ -273,14 +314,6 @@ impl Thread {
     /// Allocates the stack, sets up the context and TLS, and calls `svcCreateThread`.
     ///
     /// [`start`]: Thread::start
-    // todo: Libuser Thread stack guard
-    // body: Currently the stack of every non-main thread is allocated in the heap, and no page
-    // body: guard protects from stack-overflowing and rewriting all the heap.
-    // body:
-    // body: This is of course terrible for security, as with this stack overflowing is U.B.
-    // body:
-    // body: The simpler way to fix this would be to continue allocating the stack on the heap,
-    // body: but remap the last page with no permissions with the yet unimplemented svcMapMemory syscall.
     pub fn create(entry: fn (usize) -> (), arg: usize, stack_size: usize) -> Result<Self, Error> {
 
         let tls_elf = Once::new();
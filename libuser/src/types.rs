@@ -248,6 +248,45 @@ impl WritableEvent {
     }
 }
 
+/// A timer, signaled at a deadline, and optionally periodically after it.
+///
+/// A timer stays signaled until it is cleared. See [crate::futures::sleep] to
+/// wait for some time from a future.
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct Timer(pub Handle);
+
+impl Timer {
+    /// Arms the timer to get signaled in `delay_ns` nanoseconds, and then
+    /// every `period_ns` nanoseconds if it is not 0. Clears its signaled
+    /// state.
+    pub fn set(&self, delay_ns: u64, period_ns: u64) -> Result<(), KernelError> {
+        syscalls::set_timer(self, delay_ns, period_ns)
+    }
+
+    /// Disarms the timer, and clears its signaled state.
+    pub fn cancel(&self) -> Result<(), KernelError> {
+        syscalls::cancel_timer(self)
+    }
+
+    /// Clears the signaled state. A periodic timer gets signaled again at its
+    /// next period.
+    pub fn clear(&self) -> Result<(), KernelError> {
+        syscalls::clear_event(self.0.as_ref())
+    }
+
+    /// Waits for the timer to get signaled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if used from outside the context of a Future spawned on a libuser
+    /// future executor. Please make sure you only call this function from a
+    /// future spawned on a WaitableManager.
+    pub fn wait_async(&self, queue: WorkQueue<'_>) -> impl core::future::Future<Output = Result<(), Error>> + Unpin {
+        self.0.as_ref().wait_async(queue)
+    }
+}
+
 /// The client side of an IPC session.
 ///
 /// Usually obtained by connecting to a service through the sm: service manager.
@@ -389,6 +428,19 @@ impl ClientPort {
         syscalls::connect_to_port(self)
             .map_err(|v| v.into())
     }
+
+    /// Waits for the server side of the port to die, that is, for all the
+    /// [ServerPort]s to be closed. This usually happens when the process
+    /// hosting the port exits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if used from outside the context of a Future spawned on a libuser
+    /// future executor. Please make sure you only call this function from a
+    /// future spawned on a WaitableManager.
+    pub fn wait_async(&self, queue: crate::futures::WorkQueue<'_>) -> impl core::future::Future<Output = Result<(), Error>> + Unpin {
+        self.0.as_ref().wait_async(queue)
+    }
 }
 
 /// The server side of an IPC Port. Allows listening for connections, providing
@@ -463,7 +515,7 @@ pub struct Process(pub Handle);
 impl Process {
     /// Gets the current process handle. Uses the 0xFFFF8001 meta-handle, which
     /// may not be valid in all contexts!
-    fn current() -> Process {
+    pub fn current() -> Process {
         Process(Handle::new(0xFFFF8001))
     }
 
@@ -581,6 +633,25 @@ impl SharedMemory {
     }
 }
 
+/// A memory region that can be mapped both as RW- and R-X, allowing to generate
+/// code at runtime. Created with the [create_code_memory] syscall.
+///
+/// This is the raw handle. See [crate::mem::CodeMemory] for a more convenient
+/// wrapper.
+///
+/// [create_code_memory]: crate::syscalls::create_code_memory
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct CodeMemory(pub Handle);
+
+/// A debug session on a process, allowing to inspect its threads. Created with
+/// the [debug_active_process] syscall.
+///
+/// [debug_active_process]: crate::syscalls::debug_active_process
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct DebugSession(pub Handle);
+
 /// A mapping to a shared memory region.
 ///
 /// When dropped, the memory region will be unmapped, and the SharedMemory handle
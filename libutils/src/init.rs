@@ -0,0 +1,370 @@
+//! Boot configuration, describing the titles the loader starts and supervises.
+//!
+//! The configuration is a small subset of TOML: one `[<titlename>]` section per
+//! title, followed by `key = value` lines. Values are integers, strings in
+//! double quotes, or arrays of strings on a single line. Strings have no escape
+//! sequences. Empty lines and lines starting with `#` are ignored.
+//!
+//! ```toml
+//! # Started once vi: is up, and restarted whenever it exits.
+//! [wall-clock]
+//! depends = ["vi:"]
+//! restart = "always"
+//! backoff_ms = 500
+//! args = ["--utc"]
+//! env = ["RUST_LOG=info"]
+//! ```
+//!
+//! The keys are:
+//!
+//! - `depends`: names of the services registered in `sm` that must be up
+//!   before the title is started.
+//! - `restart`: [RestartPolicy] of the title, `"never"` if omitted.
+//! - `backoff_ms`: delay before the first restart, doubled on every
+//!   consecutive one. [DEFAULT_BACKOFF_MS] if omitted.
+//! - `args`: arguments passed to the title, after its name.
+//! - `env`: environment passed to the title, as `KEY=VALUE` strings.
+//!
+//! The loader reads it from `/etc/init.toml`.
+
+use core::fmt;
+use core::iter::{Enumerate, Peekable};
+use core::str::Lines;
+
+/// Delay before the first restart of a title, if its configuration doesn't
+/// specify one.
+pub const DEFAULT_BACKOFF_MS: u64 = 1000;
+
+/// When a title is restarted after it exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// The title is never restarted.
+    Never,
+    /// The title is restarted if it crashed, or failed to start.
+    OnFailure,
+    /// The title is restarted whenever it exits, unless it was stopped.
+    Always,
+}
+
+/// Why a configuration was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitErrorKind {
+    /// A `key = value` line appears before any `[<titlename>]` section.
+    NoSection,
+    /// A section header is not a `[<titlename>]`.
+    InvalidSection,
+    /// A line is neither a section header nor a `key = value`.
+    InvalidLine,
+    /// The key is not one of the keys documented in the [module
+    /// documentation](self).
+    UnknownKey,
+    /// The value does not have the type the key expects, or is not one of the
+    /// values it accepts.
+    InvalidValue,
+}
+
+/// An error in a configuration, and the line it was found on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitError {
+    /// Number of the offending line, starting at 1.
+    pub line: usize,
+    /// What is wrong with it.
+    pub kind: InitErrorKind,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.kind {
+            InitErrorKind::NoSection => "key outside of a [title] section",
+            InitErrorKind::InvalidSection => "invalid section header",
+            InitErrorKind::InvalidLine => "expected a key = value",
+            InitErrorKind::UnknownKey => "unknown key",
+            InitErrorKind::InvalidValue => "invalid value",
+        };
+        write!(f, "line {}: {}", self.line, reason)
+    }
+}
+
+/// An array of strings, as written in the configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StringList<'a>(&'a str);
+
+impl<'a> StringList<'a> {
+    /// Parses an array of strings, e.g. `["a", "b"]`.
+    fn parse(value: &'a str) -> Option<StringList<'a>> {
+        if !value.starts_with('[') || !value.ends_with(']') {
+            return None;
+        }
+        let list = StringList(value[1..value.len() - 1].trim());
+        // Check the array is well-formed now, so iter() doesn't have to.
+        let mut remaining = list.0;
+        while !remaining.is_empty() {
+            let (_, rest) = parse_string_prefix(remaining)?;
+            let rest = rest.trim_start();
+            remaining = if rest.starts_with(',') {
+                rest[1..].trim_start()
+            } else if rest.is_empty() {
+                rest
+            } else {
+                return None;
+            };
+        }
+        Some(list)
+    }
+
+    /// Iterates over the strings of the array.
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> {
+        let mut remaining = self.0;
+        core::iter::from_fn(move || {
+            let (string, rest) = parse_string_prefix(remaining)?;
+            remaining = rest.trim_start().trim_start_matches(',').trim_start();
+            Some(string)
+        })
+    }
+
+    /// Whether the array is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Configuration of a title.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TitleConfig<'a> {
+    /// Name of the title, in `/bin`.
+    pub name: &'a str,
+    /// Services that must be registered in `sm` before the title is started.
+    pub depends: StringList<'a>,
+    /// When the title is restarted.
+    pub restart: RestartPolicy,
+    /// Delay before the first restart, in milliseconds.
+    pub backoff_ms: u64,
+    /// Arguments passed to the title, after its name.
+    pub args: StringList<'a>,
+    /// Environment passed to the title, as `KEY=VALUE` strings.
+    pub env: StringList<'a>,
+}
+
+impl<'a> TitleConfig<'a> {
+    /// Creates the default configuration of the title `name`.
+    fn new(name: &'a str) -> TitleConfig<'a> {
+        TitleConfig {
+            name,
+            depends: StringList::default(),
+            restart: RestartPolicy::Never,
+            backoff_ms: DEFAULT_BACKOFF_MS,
+            args: StringList::default(),
+            env: StringList::default(),
+        }
+    }
+
+    /// Sets `key` to `value`.
+    fn set(&mut self, key: &str, value: &'a str) -> Result<(), InitErrorKind> {
+        match key {
+            "depends" => self.depends = StringList::parse(value).ok_or(InitErrorKind::InvalidValue)?,
+            "args" => self.args = StringList::parse(value).ok_or(InitErrorKind::InvalidValue)?,
+            "env" => {
+                let env = StringList::parse(value).ok_or(InitErrorKind::InvalidValue)?;
+                if env.iter().any(|var| !var.contains('=') || var.starts_with('=')) {
+                    return Err(InitErrorKind::InvalidValue);
+                }
+                self.env = env;
+            },
+            "restart" => {
+                self.restart = match parse_string(value) {
+                    Some("never") => RestartPolicy::Never,
+                    Some("on-failure") => RestartPolicy::OnFailure,
+                    Some("always") => RestartPolicy::Always,
+                    _ => return Err(InitErrorKind::InvalidValue)
+                };
+            },
+            "backoff_ms" => self.backoff_ms = value.parse().or(Err(InitErrorKind::InvalidValue))?,
+            _ => return Err(InitErrorKind::UnknownKey)
+        }
+        Ok(())
+    }
+}
+
+/// A boot configuration. See the [module documentation](self).
+#[derive(Debug, Clone, Copy)]
+pub struct InitConfig<'a>(&'a str);
+
+impl<'a> InitConfig<'a> {
+    /// Wraps the text of a configuration.
+    pub fn new(text: &'a str) -> InitConfig<'a> {
+        InitConfig(text)
+    }
+
+    /// Iterates over the titles of the configuration, in order.
+    ///
+    /// A malformed section yields an error, and iteration continues with the
+    /// next section.
+    pub fn titles(&self) -> Titles<'a> {
+        Titles { lines: self.0.lines().enumerate().peekable() }
+    }
+}
+
+/// Iterator over the titles of an [InitConfig].
+#[derive(Debug, Clone)]
+pub struct Titles<'a> {
+    /// Remaining lines of the configuration, with their index.
+    lines: Peekable<Enumerate<Lines<'a>>>,
+}
+
+impl<'a> Titles<'a> {
+    /// Gets the next line that isn't empty or a comment, with its number.
+    fn next_line(&mut self) -> Option<(usize, &'a str)> {
+        while let Some((idx, line)) = self.lines.next() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                return Some((idx + 1, line));
+            }
+        }
+        None
+    }
+
+    /// Whether the next line that isn't empty or a comment is a section
+    /// header, or the end of the file.
+    fn at_section_end(&mut self) -> bool {
+        while let Some((_, line)) = self.lines.peek() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                return line.starts_with('[');
+            }
+            self.lines.next();
+        }
+        true
+    }
+
+    /// Skips the lines up to the next section header.
+    fn skip_section(&mut self) {
+        while !self.at_section_end() {
+            self.lines.next();
+        }
+    }
+}
+
+impl<'a> Iterator for Titles<'a> {
+    type Item = Result<TitleConfig<'a>, InitError>;
+
+    fn next(&mut self) -> Option<Result<TitleConfig<'a>, InitError>> {
+        let (line_nbr, header) = self.next_line()?;
+        if !header.starts_with('[') {
+            self.skip_section();
+            return Some(Err(InitError { line: line_nbr, kind: InitErrorKind::NoSection }));
+        }
+        let name = if header.ends_with(']') { header[1..header.len() - 1].trim() } else { "" };
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '/' || c == '[' || c == ']') {
+            self.skip_section();
+            return Some(Err(InitError { line: line_nbr, kind: InitErrorKind::InvalidSection }));
+        }
+
+        let mut title = TitleConfig::new(name);
+        while !self.at_section_end() {
+            let (line_nbr, line) = match self.next_line() {
+                Some(line) => line,
+                None => break
+            };
+            let mut split = line.splitn(2, '=');
+            let res = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => title.set(key.trim(), value.trim()),
+                _ => Err(InitErrorKind::InvalidLine)
+            };
+            if let Err(kind) = res {
+                self.skip_section();
+                return Some(Err(InitError { line: line_nbr, kind }));
+            }
+        }
+        Some(Ok(title))
+    }
+}
+
+/// Parses a string in double quotes at the start of `value`, returning it and
+/// the rest of `value`.
+fn parse_string_prefix(value: &str) -> Option<(&str, &str)> {
+    if !value.starts_with('"') {
+        return None;
+    }
+    let end = value[1..].find('"')? + 1;
+    Some((&value[1..end], &value[end + 1..]))
+}
+
+/// Parses a string in double quotes.
+fn parse_string(value: &str) -> Option<&str> {
+    match parse_string_prefix(value)? {
+        (string, "") => Some(string),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_init_config() {
+        let config = InitConfig::new("# comment
+[twili]
+restart = \"on-failure\"
+
+[wall-clock]
+depends = [\"vi:\", \"twili\"]
+restart = \"always\"
+backoff_ms = 500
+args = [\"--utc\", \"two words\"]
+env = [\"RUST_LOG=info\"]
+
+[hello]
+");
+        let mut titles = config.titles();
+        let twili = titles.next().unwrap().unwrap();
+        let clock = titles.next().unwrap().unwrap();
+        let hello = titles.next().unwrap().unwrap();
+        assert_eq!(titles.next(), None);
+
+        assert_eq!(twili.name, "twili");
+        assert_eq!(twili.restart, RestartPolicy::OnFailure);
+        assert_eq!(twili.backoff_ms, DEFAULT_BACKOFF_MS);
+        assert!(twili.depends.is_empty());
+
+        assert_eq!(clock.name, "wall-clock");
+        assert_eq!(clock.restart, RestartPolicy::Always);
+        assert_eq!(clock.backoff_ms, 500);
+        assert!(clock.depends.iter().eq(["vi:", "twili"].iter().cloned()));
+        assert!(clock.args.iter().eq(["--utc", "two words"].iter().cloned()));
+        assert!(clock.env.iter().eq(["RUST_LOG=info"].iter().cloned()));
+
+        assert_eq!(hello.name, "hello");
+        assert_eq!(hello.restart, RestartPolicy::Never);
+        assert!(hello.args.is_empty());
+    }
+
+    #[test]
+    fn test_init_config_errors() {
+        let config = InitConfig::new("restart = \"always\"
+[bad
+[policy]
+restart = \"sometimes\"
+[list]
+args = [\"unterminated]
+[key]
+colour = \"blue\"
+[env]
+env = [\"NOVALUE\"]
+[fine]
+backoff_ms = 10
+");
+        let mut titles = config.titles();
+        assert_eq!(titles.next(), Some(Err(InitError { line: 1, kind: InitErrorKind::NoSection })));
+        assert_eq!(titles.next(), Some(Err(InitError { line: 2, kind: InitErrorKind::InvalidSection })));
+        assert_eq!(titles.next(), Some(Err(InitError { line: 4, kind: InitErrorKind::InvalidValue })));
+        assert_eq!(titles.next(), Some(Err(InitError { line: 6, kind: InitErrorKind::InvalidValue })));
+        assert_eq!(titles.next(), Some(Err(InitError { line: 8, kind: InitErrorKind::UnknownKey })));
+        assert_eq!(titles.next(), Some(Err(InitError { line: 10, kind: InitErrorKind::InvalidValue })));
+        match titles.next() {
+            Some(Ok(title)) => assert_eq!((title.name, title.backoff_ms), ("fine", 10)),
+            res => panic!("Expected the fine title, got {:?}", res)
+        }
+        assert_eq!(titles.next(), None);
+    }
+}
@@ -28,6 +28,8 @@ pub mod io;
 mod cursor;
 pub use crate::cursor::*;
 pub mod loop_future;
+pub mod manifest;
+pub mod init;
 
 /// Align the address to the next alignment.
 ///
@@ -0,0 +1,178 @@
+//! Hash manifests, pinning the binaries the system is allowed to run.
+//!
+//! A manifest lists the expected SHA-256 of binaries, in the format of
+//! `sha256sum`: one `<hex hash>  <name>` per line. Empty lines and lines
+//! starting with `#` are ignored, and so is the `*` `sha256sum` puts in front of
+//! names in binary mode.
+//!
+//! ```text
+//! # Builtin modules.
+//! 3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b8552  shell
+//! e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 *sm
+//! ```
+//!
+//! The kernel checks the builtin modules against a manifest compiled in, and
+//! the loader checks titles against a manifest on the system partition.
+
+use core::fmt;
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 hash.
+pub type Sha256Hash = [u8; 32];
+
+/// Computes the SHA-256 hash of `data`.
+pub fn sha256(data: &[u8]) -> Sha256Hash {
+    let mut hasher = Sha256::default();
+    hasher.input(data);
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+/// Why a binary was refused by a [Manifest].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestError {
+    /// The binary is not listed in the manifest.
+    NotPinned,
+    /// The hash of the binary is not the one in the manifest.
+    HashMismatch {
+        /// The hash in the manifest.
+        expected: Sha256Hash,
+        /// The hash of the binary.
+        actual: Sha256Hash,
+    },
+}
+
+/// Prints a hash in hexadecimal.
+struct HexHash<'a>(&'a Sha256Hash);
+
+impl<'a> fmt::Display for HexHash<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::NotPinned => write!(f, "not listed in the manifest"),
+            ManifestError::HashMismatch { expected, actual } =>
+                write!(f, "hash {} does not match the manifest, expected {}", HexHash(actual), HexHash(expected)),
+        }
+    }
+}
+
+/// A hash manifest, in the format of `sha256sum`. See the [module
+/// documentation](self).
+#[derive(Debug, Clone, Copy)]
+pub struct Manifest<'a>(&'a str);
+
+impl<'a> Manifest<'a> {
+    /// Wraps the text of a manifest. Malformed lines are ignored, so the
+    /// binaries they list are not pinned.
+    pub fn new(text: &'a str) -> Manifest<'a> {
+        Manifest(text)
+    }
+
+    /// Iterates over the entries of the manifest, yielding their name and hash.
+    pub fn entries(&self) -> impl Iterator<Item = (&'a str, Sha256Hash)> {
+        self.0.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut split = line.splitn(2, char::is_whitespace);
+                let hash = parse_hash(split.next()?)?;
+                let name = split.next()?.trim_start();
+                let name = name.trim_start_matches('*');
+                Some((name, hash))
+            })
+    }
+
+    /// Whether the manifest has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries().next().is_none()
+    }
+
+    /// Gets the hash the manifest pins for `name`.
+    pub fn get(&self, name: &str) -> Option<Sha256Hash> {
+        self.entries()
+            .find(|(entry, _)| *entry == name)
+            .map(|(_, hash)| hash)
+    }
+
+    /// Checks that `data` is the binary the manifest pins for `name`.
+    ///
+    /// # Errors
+    ///
+    /// - `NotPinned`
+    ///    - The manifest has no entry for `name`.
+    /// - `HashMismatch`
+    ///    - The hash of `data` is not the one in the manifest.
+    pub fn verify(&self, name: &str, data: &[u8]) -> Result<(), ManifestError> {
+        let expected = self.get(name).ok_or(ManifestError::NotPinned)?;
+        let actual = sha256(data);
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(ManifestError::HashMismatch { expected, actual })
+        }
+    }
+}
+
+/// Parses a SHA-256 hash written in hexadecimal.
+fn parse_hash(hex: &str) -> Option<Sha256Hash> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// SHA-256 of the empty string.
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(Some(sha256(b"")), parse_hash(EMPTY));
+    }
+
+    #[test]
+    fn test_manifest_verify() {
+        let manifest = Manifest::new("# comment
+
+e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  empty
+e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 *binary
+not a hash  broken
+");
+        assert!(!manifest.is_empty());
+        assert_eq!(manifest.entries().count(), 2);
+        assert_eq!(manifest.verify("empty", b""), Ok(()));
+        assert_eq!(manifest.verify("binary", b""), Ok(()));
+        assert_eq!(manifest.verify("broken", b""), Err(ManifestError::NotPinned));
+        assert_eq!(manifest.verify("missing", b""), Err(ManifestError::NotPinned));
+        match manifest.verify("empty", b"tampered") {
+            Err(ManifestError::HashMismatch { expected, actual }) => {
+                assert_eq!(expected, sha256(b""));
+                assert_eq!(actual, sha256(b"tampered"));
+            },
+            res => panic!("Tampered binary verified: {:?}", res)
+        }
+    }
+
+    #[test]
+    fn test_manifest_empty() {
+        assert!(Manifest::new("").is_empty());
+        assert!(Manifest::new("# nothing pinned\n").is_empty());
+    }
+}
@@ -0,0 +1,28 @@
+//! Pins the hash of the title manifest in the loader.
+//!
+//! The manifest is read from the file `SUNRISE_TITLES_MANIFEST` points to,
+//! generated by the `disk-template` task of the Makefile. When it is not set,
+//! no hash is pinned, and the loader refuses to boot any title unless it is
+//! built with the `insecure` feature.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SUNRISE_TITLES_MANIFEST");
+
+    let hash = match env::var_os("SUNRISE_TITLES_MANIFEST") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+            let manifest = fs::read(&path)
+                .unwrap_or_else(|err| panic!("Failed to read the titles manifest {:?}: {}", path, err));
+            let hash = sunrise_libutils::manifest::sha256(&manifest);
+            format!("Some({:?})", hash)
+        },
+        None => String::from("None")
+    };
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("titles_manifest_hash.rs"), hash).unwrap();
+}
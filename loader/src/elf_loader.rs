@@ -4,6 +4,7 @@
 
 use core::slice;
 use xmas_elf::ElfFile;
+use xmas_elf::header::Class;
 use xmas_elf::program::{ProgramHeader, Type::Load, SegmentData};
 use sunrise_libuser::syscalls::{self, map_process_memory};
 use sunrise_libuser::types::Process;
@@ -18,11 +19,17 @@ use sunrise_libuser::error::{Error, LoaderError};
 ///
 /// - `LoaderError::InvalidElf`
 ///   - The provided ELF file is invalid.
+///   - The provided ELF file is 64-bit. Sunrise only runs 32-bit code.
 pub fn from_data(data: &[u8]) -> Result<ElfFile, Error> {
-    ElfFile::new(&data[..]).or_else(|err| {
+    let elf = ElfFile::new(&data[..]).or_else(|err| {
         error!("Invalid ELF: {}", err);
         Err(LoaderError::InvalidElf.into())
-    })
+    })?;
+    if elf.header.pt1.class() != Class::ThirtyTwo {
+        error!("Invalid ELF: only 32-bit titles are supported");
+        return Err(LoaderError::InvalidElf.into());
+    }
+    Ok(elf)
 }
 
 /// Gets the size of the allocation necessary to load all the segments.
@@ -69,6 +76,14 @@ pub fn get_kacs<'a>(elf: &'a ElfFile<'_>) -> Option<&'a [u8]> {
         .map(|section| section.raw_data(&elf))
 }
 
+/// Gets the symbol table of an executable, as the raw content of its .symtab
+/// and .strtab sections.
+pub fn get_symbols<'a>(elf: &'a ElfFile<'_>) -> Option<(&'a [u8], &'a [u8])> {
+    let symtab = elf.find_section_by_name(".symtab")?.raw_data(&elf);
+    let strtab = elf.find_section_by_name(".strtab")?.raw_data(&elf);
+    Some((symtab, strtab))
+}
+
 /// Loads the given executable into the given process/address space.
 ///
 /// # Errors
@@ -20,6 +20,26 @@
 //!   - main.npdm
 //!   - flags/
 //!     - boot.flag
+//!
+//! Titles are only booted if they are pinned by the manifest at
+//! [TITLES_MANIFEST], listing the SHA-256 of their `main` as
+//! `bin/<titlename>/main`. See [sunrise_libutils::manifest]. It is generated by
+//! the `disk-template` task of the Makefile, and its own hash is pinned in the
+//! loader at build time, see [TITLES_MANIFEST_HASH].
+//!
+//! The capabilities of a title come from its `main.npdm` if it has one, see
+//! [sunrise_libkern::npdm], and from the `.kernel_caps` section of its `main`
+//! otherwise. The NPDM must be pinned by the manifest too, as
+//! `bin/<titlename>/main.npdm`. `npdmtool` generates it from the binary.
+//!
+//! The services a title may access or host come from its NPDM too, and are
+//! registered with `sm:m` before it starts. Titles without an NPDM get
+//! [DEFAULT_SAC].
+//!
+//! Titles listed in the boot configuration at [supervisor::INIT_CONFIG] are
+//! booted once the services they depend on are up, and booted again when they
+//! exit according to their restart policy, with the arguments and environment
+//! it specifies. Their `boot.flag` is ignored. See the [supervisor] module.
 
 #![feature(async_await)]
 #![no_std]
@@ -34,6 +54,7 @@ use core::slice;
 use core::mem::size_of;
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use alloc::collections::BTreeMap;
 
 use sunrise_libuser::fs::{DirectoryEntry, DirectoryEntryType, FileSystemPath, IFileSystemProxy, IFileSystemServiceProxy};
@@ -41,13 +62,16 @@ use sunrise_libuser::{kip_header, capabilities};
 use sunrise_libuser::ipc::server::{port_handler};
 use sunrise_libuser::futures::{WaitableManager, WorkQueue};
 use sunrise_libuser::error::{Error, LoaderError, PmError, KernelError};
-use sunrise_libuser::ldr::ILoaderInterfaceAsync;
+use sunrise_libuser::ldr::{ILoaderInterfaceAsync, ServiceStatus};
+use sunrise_libuser::sm::IManagerInterfaceProxy;
 use sunrise_libuser::syscalls::{self, map_process_memory};
 use sunrise_libuser::types::{Pid, Process};
 use sunrise_libkern::process::*;
+use sunrise_libkern::npdm::Npdm;
 use sunrise_libkern::MemoryPermissions;
 use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
 use sunrise_libutils::{align_up, div_ceil};
+use sunrise_libutils::manifest::{Manifest, ManifestError, Sha256Hash};
 
 use sunrise_libuser::futures_rs::future::FutureObj;
 use lazy_static::lazy_static;
@@ -55,18 +79,128 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 
 mod elf_loader;
+mod supervisor;
 
 /// Max size of an ELF before we issue a warning. Loader needs to keep its
 /// memory usage fairly low to avoid trouble, so we bail upon trying to load a
 /// file bigger than 128MiB.
 const MAX_ELF_SIZE: u64 = 128 * 1024 * 1024;
 
+/// Path of the manifest pinning the hashes of the titles.
+const TITLES_MANIFEST: &str = "/etc/titles.sha256";
+
+/// SHA-256 of the [TITLES_MANIFEST], pinned at build time from the file
+/// `SUNRISE_TITLES_MANIFEST` points to. The system partition is writable, so
+/// the manifest it holds is only trusted if it matches.
+///
+/// Without it, no title is booted, unless the loader is built with the
+/// `insecure` feature.
+static TITLES_MANIFEST_HASH: Option<Sha256Hash> = include!(concat!(env!("OUT_DIR"), "/titles_manifest_hash.rs"));
+
+/// Service access control list of the titles without an NPDM: they may access
+/// any service, but may not host any.
+const DEFAULT_SAC: &[u8] = &[0x00, b'*'];
+
 lazy_static! {
     static ref PROCESSES: Mutex<BTreeMap<u64, (Process, String)>> = Mutex::new(BTreeMap::new());
 }
 
+/// Reads the whole file at `path`.
+fn read_file(fs: &IFileSystemProxy, path: &str) -> Result<Vec<u8>, Error> {
+    let mut raw_path: FileSystemPath = [0; 0x300];
+    (&mut raw_path[0..path.len()]).copy_from_slice(path.as_bytes());
+    let file = fs.open_file(1, &raw_path)?;
+
+    let size = file.get_size()?;
+    let mut data = vec![0; size as usize];
+    let mut cur_offset = 0;
+    while cur_offset < size {
+        let read_count = file.read(0, cur_offset, size - cur_offset, &mut data[cur_offset as usize..])?;
+        if read_count == 0 {
+            break;
+        }
+        cur_offset += read_count;
+    }
+    data.truncate(cur_offset as usize);
+    Ok(data)
+}
+
+/// Reads the manifest pinning the hashes of the titles, at [TITLES_MANIFEST].
+///
+/// # Errors
+///
+/// - `UntrustedTitle`
+///    - The manifest does not exist, or is not valid UTF-8.
+///    - The manifest does not match [TITLES_MANIFEST_HASH].
+///    - No hash was pinned, and the loader was not built with the `insecure`
+///      feature.
+fn read_titles_manifest(fs: &IFileSystemProxy) -> Result<String, Error> {
+    let manifest = match read_file(fs, TITLES_MANIFEST) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            error!("Failed to read the title manifest {}: {:?}", TITLES_MANIFEST, err);
+            return Err(LoaderError::UntrustedTitle.into());
+        }
+    };
+
+    match TITLES_MANIFEST_HASH {
+        Some(expected) => {
+            let actual = sunrise_libutils::manifest::sha256(&manifest);
+            if actual != expected {
+                error!("The title manifest {} was tampered with: {}", TITLES_MANIFEST, ManifestError::HashMismatch { expected, actual });
+                return Err(LoaderError::UntrustedTitle.into());
+            }
+        },
+        None if cfg!(feature = "insecure") => {
+            warn!("No hash of the title manifest {} was pinned, trusting it as-is", TITLES_MANIFEST);
+        },
+        None => {
+            error!("No hash of the title manifest {} was pinned, refusing to boot titles", TITLES_MANIFEST);
+            return Err(LoaderError::UntrustedTitle.into());
+        }
+    }
+
+    String::from_utf8(manifest).or_else(|_| {
+        error!("The title manifest {} is not valid UTF-8", TITLES_MANIFEST);
+        Err(LoaderError::UntrustedTitle.into())
+    })
+}
+
+/// Reads the `main.npdm` of the given title, if it has one.
+///
+/// A title whose NPDM is pinned by the manifest must have it, so deleting it
+/// can't make the loader fall back to the capabilities of the binary.
+///
+/// # Errors
+///
+/// - `UntrustedTitle`
+///    - The NPDM is not pinned by the manifest, or its hash does not match it.
+///    - The NPDM is pinned by the manifest, but is missing.
+fn read_npdm(fs: &IFileSystemProxy, titlename: &str, manifest: &Manifest) -> Result<Option<Vec<u8>>, Error> {
+    let path = format!("/bin/{}/main.npdm", titlename);
+    let mut raw_path: FileSystemPath = [0; 0x300];
+    (&mut raw_path[0..path.len()]).copy_from_slice(path.as_bytes());
+    if fs.get_entry_type(&raw_path).is_err() {
+        if manifest.get(&path[1..]).is_some() {
+            error!("Refusing to boot titleid {}: its NPDM is pinned by the manifest, but missing", titlename);
+            return Err(LoaderError::UntrustedTitle.into());
+        }
+        return Ok(None);
+    }
+
+    let npdm = read_file(fs, &path)?;
+    if let Err(err) = manifest.verify(&path[1..], &npdm) {
+        error!("Refusing the NPDM of titleid {}: {}", titlename, err);
+        return Err(LoaderError::UntrustedTitle.into());
+    }
+    Ok(Some(npdm))
+}
+
 /// Start the given titleid by loading its content from the provided filesystem.
-fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], start: bool) -> Result<Pid, Error> {
+///
+/// `env` is passed after the arguments, as `KEY=VALUE` strings each terminated
+/// by a \0. See [sunrise_libuser::argv].
+fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], env: &[u8], start: bool) -> Result<Pid, Error> {
     info!("Booting titleid {}", titlename);
 
     let val = format!("/bin/{}/main", titlename);
@@ -101,6 +235,29 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], start: bool) -> Res
         cur_offset += read_count;
     }
 
+    // Don't honor the KACs of a binary we don't trust.
+    let manifest = read_titles_manifest(fs)?;
+    let manifest = Manifest::new(&manifest);
+    if let Err(err) = manifest.verify(&val[1..], elf_data) {
+        error!("Refusing to boot titleid {}: {}", titlename, err);
+        return Err(LoaderError::UntrustedTitle.into());
+    }
+
+    let npdm_data = read_npdm(fs, titlename, &manifest)?;
+    let npdm = match npdm_data {
+        Some(ref npdm_data) => {
+            let npdm = Npdm::parse(npdm_data).and_then(|npdm| npdm.check_aci0().map(|()| npdm));
+            match npdm {
+                Ok(npdm) => Some(npdm),
+                Err(err) => {
+                    error!("Invalid NPDM for titleid {}: {}", titlename, err);
+                    return Err(LoaderError::InvalidNpdm.into());
+                }
+            }
+        },
+        None => None
+    };
+
     let elf = elf_loader::from_data(&elf_data)?;
 
     let mut flags = ProcInfoFlags(0);
@@ -112,10 +269,13 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], start: bool) -> Res
 
     let aslr_base = 0x400000;
 
-    let kacs = match elf_loader::get_kacs(&elf) {
-        Some(kacs) => kacs,
-        None => {
-            error!("TitleID {} did not have a KAC section. Bailing.", titlename);
+    // The NPDM takes precedence over the capabilities the binary embeds, so
+    // they can be restricted without rebuilding it.
+    let kacs = match (npdm, elf_loader::get_kacs(&elf)) {
+        (Some(npdm), _) => npdm.aci0.kac,
+        (None, Some(kacs)) => kacs,
+        (None, None) => {
+            error!("TitleID {} did not have a KAC section nor an NPDM. Bailing.", titlename);
             return Err(LoaderError::InvalidKacs.into());
         }
     };
@@ -137,13 +297,15 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], start: bool) -> Res
     // Add a whole page for the vector of ptrs.
     let args_size = args_size + 0x1000 / size_of::<usize>();
     let args_size = align_up(args_size, PAGE_SIZE);
+    // The environment comes right after the args.
+    let args_env_size = align_up(args_size + env.len(), PAGE_SIZE);
 
-    let total_size = elf_size + align_up(args_size, PAGE_SIZE);
+    let total_size = elf_size + args_env_size;
 
     let process = sunrise_libuser::syscalls::create_process(&ProcInfo {
         name: titlename_bytes,
         process_category: ProcessCategory::RegularTitle,
-        title_id: 0,
+        title_id: npdm.map(|npdm| npdm.program_id).unwrap_or(0),
         code_addr: aslr_base as _,
         code_num_pages: div_ceil(total_size, PAGE_SIZE) as u32,
         flags,
@@ -154,47 +316,136 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], start: bool) -> Res
     debug!("Loading ELF");
     elf_loader::load_file(&process, &elf, aslr_base)?;
 
+    // Let the kernel symbolize the backtrace of the title if it crashes.
+    match elf_loader::get_symbols(&elf) {
+        Some((symtab, strtab)) => syscalls::set_process_symbols(&process, symtab, strtab, aslr_base)?,
+        None => warn!("TitleID {} has no symbol table, its backtraces won't be symbolized.", titlename)
+    }
+
     debug!("Handling args");
-    let addr = find_free_address(args_size, 0x1000)?;
-    map_process_memory(addr, &process, aslr_base + elf_size, args_size)?;
+    let addr = find_free_address(args_env_size, 0x1000)?;
+    map_process_memory(addr, &process, aslr_base + elf_size, args_env_size)?;
 
     {
         // Copy the ELF data in the remote process.
         let dest_ptr = addr as *mut u8;
         let dest = unsafe {
             // Safety: Guaranteed to be OK if the syscall returns successfully.
-            slice::from_raw_parts_mut(dest_ptr, args_size)
+            slice::from_raw_parts_mut(dest_ptr, args_env_size)
         };
         // Copy header
         dest[0..4].copy_from_slice(&args_size.to_le_bytes());
         dest[4..8].copy_from_slice(&args.len().to_le_bytes());
+        dest[8..12].copy_from_slice(&env.len().to_le_bytes());
         // Copy raw cmdline.
         dest[0x20..0x20 + args.len()].copy_from_slice(args);
+        // Copy environment.
+        dest[args_size..args_size + env.len()].copy_from_slice(env);
     }
 
     // Maybe I should panic if this fails, cuz that'd be really bad.
     unsafe {
         // Safety: this memory was previously mapped and all pointers to it
         // should have been dropped already.
-        syscalls::unmap_process_memory(addr, &process, aslr_base + elf_size, args_size)?;
+        syscalls::unmap_process_memory(addr, &process, aslr_base + elf_size, args_env_size)?;
     }
 
-    syscalls::set_process_memory_permission(&process, aslr_base + elf_size, args_size, MemoryPermissions::RW)?;
+    syscalls::set_process_memory_permission(&process, aslr_base + elf_size, args_env_size, MemoryPermissions::RW)?;
+
+    let pid = process.pid()?;
+
+    // Tell sm which services the title may access and host, before it gets a
+    // chance to talk to it.
+    let (acid_sac, aci0_sac) = match npdm {
+        Some(npdm) => (npdm.acid.sac.as_bytes(), npdm.aci0.sac.as_bytes()),
+        None => (DEFAULT_SAC, DEFAULT_SAC)
+    };
+    IManagerInterfaceProxy::new()?.register_process(pid.0, acid_sac, aci0_sac)?;
 
     if start {
         debug!("Starting process.");
         if let Err(err) = process.start(0, 0, PAGE_SIZE as u32 * 32) {
             error!("Failed to start titleid {}: {}", titlename, err);
+            unregister_process(pid.0);
             return Err(err)
         }
     }
 
-    let pid = process.pid()?;
     PROCESSES.lock().insert(pid.0, (process, titlename.to_string()));
 
     Ok(pid)
 }
 
+/// Tells sm to forget about the service access control list of an exited
+/// process.
+fn unregister_process(pid: u64) {
+    if let Err(err) = IManagerInterfaceProxy::new().and_then(|sm| sm.unregister_process(pid)) {
+        warn!("Failed to unregister pid {} from sm: {:?}", pid, err);
+    }
+}
+
+/// Waits for the process with the given pid to exit.
+///
+/// Processes are only removed from [PROCESSES] once they exited, so a pid that
+/// isn't there (anymore) is considered exited.
+async fn wait_exited(workqueue: WorkQueue<'static>, pid: u64) -> Result<(), Error> {
+    // See the comment in `LoaderIface::wait` for why we do this.
+    let process_wait = match PROCESSES.lock().get(&pid) {
+        Some(process) => ((process.0).0).as_ref_static(),
+        None => return Ok(())
+    };
+    loop {
+        let res = process_wait.wait_async(workqueue.clone()).await;
+        let lock = PROCESSES.lock();
+        let process = match lock.get(&pid) {
+            Some(process) => &process.0,
+            // The handle was closed from under us.
+            None => return Ok(())
+        };
+        res?;
+        match process.reset_signal() {
+            Ok(()) | Err(Error::Kernel(KernelError::InvalidState, _)) => (),
+            Err(err) => return Err(err)
+        };
+
+        if process.state()? == ProcessState::Exited {
+            return Ok(());
+        }
+    }
+}
+
+/// Waits for the process with the given pid to exit, and starts creport to
+/// write a crash report if it crashed. Returns whether it crashed.
+///
+/// A debug session is held on the process until creport is done with it, so
+/// the dead process stays around even if it gets waited on and removed from
+/// [PROCESSES] meanwhile.
+async fn report_crash(workqueue: WorkQueue<'static>, pid: u64, titlename: String) -> Result<bool, Error> {
+    let debug = syscalls::debug_active_process(pid)?;
+    wait_exited(workqueue.clone(), pid).await?;
+    if syscalls::get_crash_report(&debug).is_err() {
+        return Ok(false);
+    }
+
+    info!("{} (pid {}) crashed, starting creport", titlename, pid);
+    let args = format!("creport {} {}", pid, titlename);
+    let Pid(creport_pid) = boot(&*BOOT_FROM_FS, "creport", args.as_bytes(), &[], true)?;
+    wait_exited(workqueue, creport_pid).await?;
+    PROCESSES.lock().remove(&creport_pid);
+    unregister_process(creport_pid);
+    Ok(true)
+}
+
+/// Spawns a [report_crash] future for the given process.
+fn spawn_crash_reporter(workqueue: &WorkQueue<'static>, pid: u64, titlename: &str) {
+    let reporter = report_crash(workqueue.clone(), pid, titlename.to_string());
+    workqueue.spawn(FutureObj::new(Box::new(async move {
+        if let Err(err) = reporter.await {
+            error!("Failed to report crashes of pid {}: {:?}", pid, err);
+        }
+    })));
+}
+
 lazy_static! {
     /// The filesystem to boot titles from.
     static ref BOOT_FROM_FS: IFileSystemProxy = {
@@ -208,10 +459,11 @@ lazy_static! {
 struct LoaderIface;
 
 impl ILoaderInterfaceAsync for LoaderIface {
-    fn create_title(&mut self, _workqueue: WorkQueue<'static>, title_name: &[u8], args: &[u8]) -> FutureObj<'_, Result<u64, Error>> {
+    fn create_title(&mut self, workqueue: WorkQueue<'static>, title_name: &[u8], args: &[u8]) -> FutureObj<'_, Result<u64, Error>> {
         let res = (|| -> Result<u64, Error> {
             let title_name = str::from_utf8(title_name).or(Err(LoaderError::ProgramNotFound))?;
-            let Pid(pid) = boot(&*BOOT_FROM_FS, title_name, args, false)?;
+            let Pid(pid) = boot(&*BOOT_FROM_FS, title_name, args, &[], false)?;
+            spawn_crash_reporter(&workqueue, pid, title_name);
             Ok(pid)
         })();
         FutureObj::new(Box::new(async move {
@@ -267,6 +519,8 @@ impl ILoaderInterfaceAsync for LoaderIface {
 
                 if process.state()? == ProcessState::Exited {
                     lock.remove(&pid);
+                    drop(lock);
+                    unregister_process(pid);
                     // TODO: Return exit state.
                     return Ok(0);
                 }
@@ -294,10 +548,35 @@ impl ILoaderInterfaceAsync for LoaderIface {
             Ok(copied_len as u64)
         }))
     }
+
+    fn list_services<'a>(&mut self, _workqueue: WorkQueue<'static>, services: &'a mut [ServiceStatus]) -> FutureObj<'a, Result<u64, Error>> {
+        FutureObj::new(Box::new(async move {
+            Ok(supervisor::list(services) as u64)
+        }))
+    }
+
+    fn start_service(&mut self, workqueue: WorkQueue<'static>, title_name: &[u8]) -> FutureObj<'_, Result<(), Error>> {
+        let res = str::from_utf8(title_name).or(Err(PmError::ServiceNotFound.into()))
+            .and_then(|title_name| supervisor::start(&workqueue, title_name));
+        FutureObj::new(Box::new(async move {
+            res
+        }))
+    }
+
+    fn stop_service(&mut self, _workqueue: WorkQueue<'static>, title_name: &[u8]) -> FutureObj<'_, Result<(), Error>> {
+        let res = str::from_utf8(title_name).or(Err(PmError::ServiceNotFound.into()))
+            .and_then(supervisor::stop);
+        FutureObj::new(Box::new(async move {
+            res
+        }))
+    }
 }
 
 fn main() {
     let fs = &*BOOT_FROM_FS;
+    let mut man = WaitableManager::new();
+
+    supervisor::load_config(fs);
 
     let mut raw_path: FileSystemPath = [0; 0x300];
     (&mut raw_path[0..4]).copy_from_slice(b"/bin");
@@ -333,7 +612,12 @@ fn main() {
                         .find(|(_, v)| **v == b'/' || **v == b'\0')
                         .map(|(idx, _)| idx).unwrap_or_else(|| entry.path.len());
                     if let Ok(titleid) = str::from_utf8(&entry.path[5..endpos]) {
-                        let _ = boot(&fs, titleid, &[], true);
+                        if supervisor::is_service(titleid) {
+                            continue;
+                        }
+                        if let Ok(Pid(pid)) = boot(&fs, titleid, &[], &[], true) {
+                            spawn_crash_reporter(&man.work_queue(), pid, titleid);
+                        }
                     } else {
                         error!("Non-ASCII titleid found in /boot.");
                         continue;
@@ -345,7 +629,7 @@ fn main() {
         warn!("No /bin folder on filesystem!");
     }
 
-    let mut man = WaitableManager::new();
+    supervisor::start_all(&man.work_queue());
 
     let handler = port_handler(man.work_queue(), "ldr:shel", LoaderIface::dispatch).unwrap();
     man.work_queue().spawn(FutureObj::new(Box::new(handler)));
@@ -369,6 +653,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
@@ -392,6 +677,11 @@ capabilities!(CAPABILITIES = Capabilities {
         sunrise_libuser::syscalls::nr::GetProcessId,
         sunrise_libuser::syscalls::nr::ResetSignal,
         sunrise_libuser::syscalls::nr::TerminateProcess,
+        sunrise_libuser::syscalls::nr::DebugActiveProcess,
+        sunrise_libuser::syscalls::nr::GetCrashReport,
+        sunrise_libuser::syscalls::nr::SetProcessSymbols,
+        sunrise_libuser::syscalls::nr::CreateTimer,
+        sunrise_libuser::syscalls::nr::SetTimer,
     ],
-    raw_caps: [sunrise_libuser::caps::ioport(0x60), sunrise_libuser::caps::ioport(0x64), sunrise_libuser::caps::irq_pair(1, 0x3FF)]
+    raw_caps: [sunrise_libuser::caps::ioport(0x60), sunrise_libuser::caps::ioport(0x64), sunrise_libuser::caps::irq_pair(1, 0x3FF), sunrise_libuser::caps::debug_flags(false, true)]
 });
\ No newline at end of file
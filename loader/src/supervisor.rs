@@ -0,0 +1,441 @@
+//! Supervision of the services
+//!
+//! Services are the titles listed in the boot configuration at [INIT_CONFIG],
+//! see [sunrise_libutils::init] for its format. Unlike the titles with a
+//! `boot.flag`, they are booted in the background once the services they
+//! depend on are registered in sm, and they are booted again when they exit,
+//! according to their [RestartPolicy].
+//!
+//! Each service is driven by a [supervise] future, which waits on the title
+//! and boots it again after a delay. The delay starts at the `backoff_ms` of
+//! the service, and doubles on every consecutive restart, up to
+//! [MAX_BACKOFF_MS]. It goes back to `backoff_ms` once the title stayed up for
+//! [STABLE_NS]. Stopping or starting the service during the delay takes effect
+//! right away.
+//!
+//! The `ldr:shel` `list_services`, `start_service` and `stop_service` commands
+//! expose them to the shell's `svc`.
+
+use core::mem;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use sunrise_libuser::error::{Error, PmError};
+use sunrise_libuser::fs::IFileSystemProxy;
+use sunrise_libuser::futures::WorkQueue;
+use sunrise_libuser::futures_rs::future::{self, Either, FutureObj};
+use sunrise_libuser::ldr::{ServiceState, ServiceStatus};
+use sunrise_libuser::sm::{IUserInterfaceProxy, ServiceInfo};
+use sunrise_libuser::syscalls;
+use sunrise_libuser::types::{Pid, ReadableEvent, WritableEvent};
+use sunrise_libutils::init::{InitConfig, RestartPolicy, TitleConfig};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{boot, read_file, report_crash, unregister_process, wait_exited, BOOT_FROM_FS, PROCESSES};
+
+/// Path of the boot configuration, listing the services.
+pub const INIT_CONFIG: &str = "/etc/init.toml";
+
+/// Upper bound of the delay before restarting a service.
+const MAX_BACKOFF_MS: u64 = 60_000;
+
+/// How long a title must stay up for the delay before restarting it to go back
+/// to its `backoff_ms`.
+const STABLE_NS: u64 = 10_000_000_000;
+
+/// How often sm is polled while waiting for the dependencies of a service.
+const DEPENDENCY_POLL_NS: u64 = 100_000_000;
+
+/// Maximum number of services fetched from sm while waiting for dependencies.
+const MAX_SM_SERVICES: usize = 64;
+
+/// A service, and its current state.
+#[derive(Debug)]
+struct Service {
+    /// Name of the title.
+    name: String,
+    /// Services that must be registered in sm before the title is booted,
+    /// encoded like the names `get_service` takes.
+    depends: Vec<u64>,
+    /// When the title is booted again.
+    restart: RestartPolicy,
+    /// Delay before the first restart, in milliseconds.
+    backoff_ms: u64,
+    /// Cmdline the title is booted with.
+    cmdline: Vec<u8>,
+    /// Environment the title is booted with, as `KEY=VALUE` strings each
+    /// terminated by a \0.
+    env: Vec<u8>,
+    /// Current state.
+    state: ServiceState,
+    /// Pid of the running title, or 0.
+    pid: u64,
+    /// Number of times the title was booted again.
+    restarts: u32,
+    /// Whether the service should be running. Cleared by [stop], and by the
+    /// [supervise] future when it gives up on the service.
+    enabled: bool,
+    /// Whether a [supervise] future is driving the service.
+    supervised: bool,
+    /// Whether [start] was called while the [supervise] future was still
+    /// driving the service after a [stop], e.g. waiting for the title to die or
+    /// backing off. The title is then booted again right away, whatever the
+    /// restart policy.
+    restart_requested: bool,
+}
+
+/// Encodes a service name like `get_service` takes it.
+fn encode_service_name(name: &str) -> Option<u64> {
+    let mut bytes = [0; 8];
+    if name.is_empty() || name.len() > bytes.len() {
+        return None;
+    }
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    Some(u64::from_le_bytes(bytes))
+}
+
+impl Service {
+    /// Creates a stopped service from its configuration. Returns None if the
+    /// configuration can't be honored.
+    fn from_config(config: &TitleConfig) -> Option<Service> {
+        let mut depends = Vec::new();
+        for name in config.depends.iter() {
+            match encode_service_name(name) {
+                Some(name) => depends.push(name),
+                None => {
+                    error!("Service {} depends on {}, which is not a valid service name", config.name, name);
+                    return None;
+                }
+            }
+        }
+
+        // The args are split on whitespace, unless they are quoted. There's no
+        // way to escape a quote.
+        let mut cmdline = config.name.to_string();
+        for arg in config.args.iter() {
+            if arg.contains('"') {
+                error!("Argument {} of service {} contains a quote", arg, config.name);
+                return None;
+            }
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                cmdline += &format!(" \"{}\"", arg);
+            } else {
+                cmdline += " ";
+                cmdline += arg;
+            }
+        }
+
+        let mut env = Vec::new();
+        for var in config.env.iter() {
+            env.extend_from_slice(var.as_bytes());
+            env.push(0);
+        }
+
+        Some(Service {
+            name: config.name.to_string(),
+            depends,
+            restart: config.restart,
+            backoff_ms: core::cmp::min(config.backoff_ms, MAX_BACKOFF_MS),
+            cmdline: cmdline.into_bytes(),
+            env,
+            state: ServiceState::Stopped,
+            pid: 0,
+            restarts: 0,
+            enabled: false,
+            supervised: false,
+            restart_requested: false,
+        })
+    }
+}
+
+lazy_static! {
+    /// The services, in the order of the boot configuration.
+    static ref SERVICES: Mutex<Vec<Service>> = Mutex::new(Vec::new());
+    /// Event signaled whenever a service is started or stopped, so a
+    /// [supervise] future backing off notices it right away.
+    static ref SERVICES_EVENT: (WritableEvent, ReadableEvent) = {
+        syscalls::create_event().unwrap()
+    };
+}
+
+/// Calls `f` on the service `name`, returning None if there is no such service.
+fn with_service<R>(name: &str, f: impl FnOnce(&mut Service) -> R) -> Option<R> {
+    SERVICES.lock().iter_mut().find(|service| service.name == name).map(f)
+}
+
+/// Reads the services from the boot configuration at [INIT_CONFIG]. Malformed
+/// entries are logged and ignored, and so is a missing configuration.
+pub fn load_config(fs: &IFileSystemProxy) {
+    let config = match read_file(fs, INIT_CONFIG) {
+        Ok(config) => config,
+        Err(err) => {
+            info!("No boot configuration at {}: {:?}", INIT_CONFIG, err);
+            return;
+        }
+    };
+    let config = match core::str::from_utf8(&config) {
+        Ok(config) => config,
+        Err(_) => return error!("The boot configuration {} is not valid UTF-8", INIT_CONFIG)
+    };
+
+    let mut services = SERVICES.lock();
+    for title in InitConfig::new(config).titles() {
+        match title {
+            Ok(title) => if services.iter().any(|service| service.name == title.name) {
+                error!("{}: service {} is listed twice", INIT_CONFIG, title.name);
+            } else if let Some(service) = Service::from_config(&title) {
+                services.push(service);
+            },
+            Err(err) => error!("{}: {}", INIT_CONFIG, err)
+        }
+    }
+}
+
+/// Whether the title `name` is a service.
+pub fn is_service(name: &str) -> bool {
+    with_service(name, |_| ()).is_some()
+}
+
+/// Starts all the services.
+pub fn start_all(workqueue: &WorkQueue<'static>) {
+    let names: Vec<String> = SERVICES.lock().iter().map(|service| service.name.clone()).collect();
+    for name in names {
+        if let Err(err) = start(workqueue, &name) {
+            error!("Failed to start service {}: {:?}", name, err);
+        }
+    }
+}
+
+/// Starts the service `name`, spawning a [supervise] future for it.
+///
+/// # Errors
+///
+/// - `ServiceNotFound`
+///    - `name` is not a service.
+/// - `AlreadyStarted`
+///    - The service is already enabled.
+pub fn start(workqueue: &WorkQueue<'static>, name: &str) -> Result<(), Error> {
+    let spawn = with_service(name, |service| {
+        if service.enabled {
+            return Err(PmError::AlreadyStarted);
+        }
+        service.enabled = true;
+        // A future still drives a service that was stopped while it was
+        // waiting or backing off, or whose title is still dying. It will pick
+        // up the change.
+        if service.supervised {
+            service.restart_requested = true;
+        }
+        Ok(!mem::replace(&mut service.supervised, true))
+    }).ok_or(PmError::ServiceNotFound)??;
+    SERVICES_EVENT.0.signal()?;
+
+    if spawn {
+        let name = name.to_string();
+        workqueue.spawn(FutureObj::new(Box::new(supervise(workqueue.clone(), name))));
+    }
+    Ok(())
+}
+
+/// Stops the service `name`, killing its title if it is running.
+///
+/// # Errors
+///
+/// - `ServiceNotFound`
+///    - `name` is not a service.
+pub fn stop(name: &str) -> Result<(), Error> {
+    let pid = with_service(name, |service| {
+        service.enabled = false;
+        service.pid
+    }).ok_or(PmError::ServiceNotFound)?;
+    SERVICES_EVENT.0.signal()?;
+
+    if pid != 0 {
+        if let Some((process, _)) = PROCESSES.lock().get(&pid) {
+            syscalls::terminate_process(process)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the status of the services to `statuses`, returning how many were
+/// copied.
+pub fn list(statuses: &mut [ServiceStatus]) -> usize {
+    let services = SERVICES.lock();
+    for (status, service) in statuses.iter_mut().zip(services.iter()) {
+        let mut name = [0; 0xc];
+        let len = core::cmp::min(name.len(), service.name.len());
+        name[..len].copy_from_slice(&service.name.as_bytes()[..len]);
+        *status = ServiceStatus {
+            name,
+            pid: service.pid,
+            state: service.state,
+            restarts: service.restarts,
+        };
+    }
+    core::cmp::min(statuses.len(), services.len())
+}
+
+/// Waits for the services in `depends` to be registered in sm.
+///
+/// Returns false if the service `name` got stopped meanwhile.
+async fn wait_for_dependencies(workqueue: WorkQueue<'static>, name: String, depends: Vec<u64>) -> Result<bool, Error> {
+    if depends.is_empty() {
+        return Ok(true);
+    }
+    let sm = IUserInterfaceProxy::raw_new()?;
+    let mut services = [ServiceInfo { name: 0, pid: 0 }; MAX_SM_SERVICES];
+    loop {
+        if !with_service(&name, |service| service.enabled).unwrap_or(false) {
+            return Ok(false);
+        }
+        let (count, total) = sm.list_services(&mut services)?;
+        if total > count {
+            warn!("Only got {} of the {} services registered in sm", count, total);
+        }
+        let registered = &services[..count as usize];
+        if depends.iter().all(|dep| registered.iter().any(|service| service.name == *dep)) {
+            return Ok(true);
+        }
+        sunrise_libuser::futures::sleep(workqueue.clone(), DEPENDENCY_POLL_NS).await?;
+    }
+}
+
+/// Waits for the title `pid` of the service `name` to exit, returning whether
+/// it crashed, and whether it stayed up for [STABLE_NS].
+async fn wait_service(workqueue: WorkQueue<'static>, name: String, pid: u64) -> (bool, bool) {
+    let exited = Box::pin(report_crash(workqueue.clone(), pid, name.clone()));
+    let stable = sunrise_libuser::futures::sleep(workqueue.clone(), STABLE_NS);
+    let (res, was_stable) = match future::select(exited, stable).await {
+        Either::Left((res, _)) => (res, false),
+        Either::Right((_, exited)) => (exited.await, true)
+    };
+
+    let crashed = match res {
+        Ok(crashed) => crashed,
+        Err(err) => {
+            // The title may still be running, don't restart it while it is.
+            error!("Failed to watch service {} (pid {}) for crashes: {:?}", name, pid, err);
+            if let Err(err) = wait_exited(workqueue, pid).await {
+                error!("Failed to wait for service {} (pid {}): {:?}", name, pid, err);
+            }
+            true
+        }
+    };
+    (crashed, was_stable)
+}
+
+/// Drives the service `name`: boots it once its dependencies are up, waits for
+/// it to exit, and boots it again according to its restart policy, until it
+/// is stopped or shouldn't be restarted.
+async fn supervise(workqueue: WorkQueue<'static>, name: String) {
+    let mut backoff_ms = None;
+    loop {
+        let config = with_service(&name, |service| {
+            if service.enabled {
+                service.restart_requested = false;
+                service.state = ServiceState::WaitingForDependencies;
+                Some((service.depends.clone(), service.cmdline.clone(), service.env.clone(), service.restart, service.backoff_ms))
+            } else {
+                service.state = ServiceState::Stopped;
+                service.supervised = false;
+                None
+            }
+        });
+        let (depends, cmdline, env, restart, initial_backoff_ms) = match config {
+            Some(Some(config)) => config,
+            _ => return
+        };
+        let mut cur_backoff_ms = backoff_ms.unwrap_or(initial_backoff_ms);
+
+        let failed = match wait_for_dependencies(workqueue.clone(), name.clone(), depends).await {
+            // Stopped while waiting, the next iteration takes care of it.
+            Ok(false) => continue,
+            Ok(true) => match boot(&*BOOT_FROM_FS, &name, &cmdline, &env, true) {
+                Ok(Pid(pid)) => {
+                    info!("Service {} is up, pid {}", name, pid);
+                    with_service(&name, |service| {
+                        service.state = ServiceState::Running;
+                        service.pid = pid;
+                    });
+                    let (crashed, was_stable) = wait_service(workqueue.clone(), name.clone(), pid).await;
+                    if was_stable {
+                        cur_backoff_ms = initial_backoff_ms;
+                    }
+                    if PROCESSES.lock().remove(&pid).is_some() {
+                        unregister_process(pid);
+                    }
+                    crashed
+                },
+                Err(err) => {
+                    error!("Failed to boot service {}: {:?}", name, err);
+                    true
+                }
+            },
+            Err(err) => {
+                error!("Failed to wait for the dependencies of service {}: {:?}", name, err);
+                true
+            }
+        };
+
+        // Started again while the title was dying after being stopped: boot
+        // it right away, this is not a restart.
+        let requested = with_service(&name, |service| {
+            service.pid = 0;
+            service.enabled && mem::replace(&mut service.restart_requested, false)
+        }).unwrap_or(false);
+        if requested {
+            info!("Starting service {} again", name);
+            backoff_ms = None;
+            continue;
+        }
+
+        let restarting = with_service(&name, |service| {
+            let restart = service.enabled && match restart {
+                RestartPolicy::Never => false,
+                RestartPolicy::OnFailure => failed,
+                RestartPolicy::Always => true,
+            };
+            service.state = if restart {
+                service.restarts += 1;
+                ServiceState::BackingOff
+            } else {
+                service.supervised = false;
+                // Giving up, start must spawn a new future.
+                if !mem::replace(&mut service.enabled, false) {
+                    ServiceState::Stopped
+                } else if failed {
+                    ServiceState::Failed
+                } else {
+                    ServiceState::Exited
+                }
+            };
+            restart
+        }).unwrap_or(false);
+        if !restarting {
+            info!("Service {} is down", name);
+            return;
+        }
+
+        info!("Restarting service {} in {}ms", name, cur_backoff_ms);
+        let delay = sunrise_libuser::futures::sleep(workqueue.clone(), cur_backoff_ms.saturating_mul(1_000_000));
+        // Stopping or starting the service cuts the delay short, the next
+        // iteration takes care of it.
+        let changed = SERVICES_EVENT.1.wait_async_cb(workqueue.clone(), || {
+            let changed = with_service(&name, |service| !service.enabled || service.restart_requested)
+                .unwrap_or(true);
+            if changed { Some(()) } else { None }
+        });
+        backoff_ms = match future::select(delay, changed).await {
+            Either::Left((res, _)) => {
+                if let Err(err) = res {
+                    error!("Failed to wait before restarting service {}: {:?}", name, err);
+                }
+                Some(core::cmp::min(cur_backoff_ms.saturating_mul(2), MAX_BACKOFF_MS))
+            },
+            Either::Right(((), _)) => None
+        };
+    }
+}
@@ -0,0 +1,311 @@
+//! NPDM generator
+//!
+//! Generates the `main.npdm` of a title from its binary, so the capabilities it
+//! is granted can be restricted without rebuilding it. See
+//! [sunrise_libkern::npdm] for the format.
+//!
+//! The ACID gets the kernel capabilities the binary embeds in its
+//! `.kernel_caps` section, the ACI0 gets them too, minus the denied syscalls.
+//! Both get the given services. They grant no filesystem permissions, fs does
+//! not check them.
+//!
+//! Usage: <title ELF> <output npdm> [--name <name>] [--program-id <hex id>]
+//! [--deny-svc <hex syscall number>]... [--service <name>]... [--server <name>]...
+//!
+//! The NPDM must then be pinned by the titles manifest, like the binary.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use xmas_elf::ElfFile;
+use xmas_elf::header::Class;
+
+/// Type of a kernel capability allowing a set of syscalls.
+const KAC_SYSCALL_MASK: u32 = 4;
+/// Number of syscalls a syscall mask capability holds.
+const SYSCALLS_PER_MASK: u32 = 24;
+
+/// The access controls of an ACID or an ACI0.
+#[derive(Debug, Default, Clone)]
+struct AccessControl {
+    /// The services the title may use or host, with whether it hosts them.
+    services: Vec<(String, bool)>,
+    /// The kernel capabilities.
+    kacs: Vec<u32>,
+}
+
+impl AccessControl {
+    /// Removes the given syscall from the syscall masks.
+    fn deny_svc(&mut self, svc: u32) {
+        let index = svc / SYSCALLS_PER_MASK;
+        let bit = 1 << (5 + svc % SYSCALLS_PER_MASK);
+        for kac in self.kacs.iter_mut() {
+            if (!*kac).trailing_zeros() == KAC_SYSCALL_MASK && *kac >> 29 == index {
+                *kac &= !bit;
+            }
+        }
+    }
+
+    /// Serializes the service access control list.
+    fn sac(&self) -> Vec<u8> {
+        let mut sac = Vec::new();
+        for (name, is_server) in &self.services {
+            assert!(!name.is_empty() && name.len() <= 8, "Service name {} is not 1 to 8 bytes long", name);
+            sac.push((name.len() - 1) as u8 | if *is_server { 0x80 } else { 0 });
+            sac.extend_from_slice(name.as_bytes());
+        }
+        sac
+    }
+
+    /// Serializes the kernel capabilities.
+    fn kac(&self) -> Vec<u8> {
+        self.kacs.iter().flat_map(|kac| kac.to_le_bytes().to_vec()).collect()
+    }
+}
+
+/// Builds an NPDM.
+#[derive(Debug, Default, Clone)]
+struct NpdmBuilder {
+    /// Name of the title, up to 16 bytes.
+    name: String,
+    /// Program id of the title.
+    program_id: u64,
+    /// Whether the title is a 64-bit binary.
+    is_64bit: bool,
+    /// Priority of the main thread.
+    main_thread_priority: u8,
+    /// Size of the stack of the main thread, in bytes.
+    main_thread_stack_size: u32,
+    /// The access controls the title may be granted at most.
+    acid: AccessControl,
+    /// The access controls the title is granted.
+    aci0: AccessControl,
+}
+
+/// Appends `data` to `out`, aligned on 0x10, and returns its offset and size.
+fn append_section(out: &mut Vec<u8>, data: &[u8]) -> (u32, u32) {
+    while out.len() % 0x10 != 0 {
+        out.push(0);
+    }
+    let offset = out.len();
+    out.extend_from_slice(data);
+    (offset as u32, data.len() as u32)
+}
+
+/// Writes a little-endian u32 at `offset`.
+fn write_u32(out: &mut [u8], offset: usize, value: u32) {
+    out[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Writes a little-endian u64 at `offset`.
+fn write_u64(out: &mut [u8], offset: usize, value: u64) {
+    out[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+impl NpdmBuilder {
+    /// Serializes the ACID, with an empty signature.
+    fn build_acid(&self) -> Vec<u8> {
+        let header_offset = sunrise_libkern::npdm::ACID_HEADER_OFFSET;
+        let mut acid = vec![0; sunrise_libkern::npdm::ACID_HEADER_SIZE];
+        acid[header_offset..header_offset + 4].copy_from_slice(b"ACID");
+        write_u64(&mut acid, header_offset + 0x10, self.program_id);
+        write_u64(&mut acid, header_offset + 0x18, self.program_id);
+
+        // No filesystem permissions.
+        let mut fac = vec![0; 0x2C];
+        fac[0] = 1;
+
+        let sections = [fac, self.acid.sac(), self.acid.kac()];
+        for (i, section) in sections.iter().enumerate() {
+            let (offset, size) = append_section(&mut acid, section);
+            write_u32(&mut acid, header_offset + 0x20 + i * 8, offset);
+            write_u32(&mut acid, header_offset + 0x24 + i * 8, size);
+        }
+        // The signed region starts after the signature.
+        let size = acid.len() as u32 - 0x100;
+        write_u32(&mut acid, header_offset + 4, size);
+        acid
+    }
+
+    /// Serializes the ACI0.
+    fn build_aci0(&self) -> Vec<u8> {
+        let mut aci0 = vec![0; sunrise_libkern::npdm::ACI0_HEADER_SIZE];
+        aci0[0..4].copy_from_slice(b"ACI0");
+        write_u64(&mut aci0, 0x10, self.program_id);
+
+        // No filesystem permissions, content or save data owners.
+        let mut fah = vec![0; 0x1C];
+        fah[0] = 1;
+        write_u32(&mut fah, 0xC, 0x1C);
+        write_u32(&mut fah, 0x14, 0x1C);
+
+        let sections = [fah, self.aci0.sac(), self.aci0.kac()];
+        for (i, section) in sections.iter().enumerate() {
+            let (offset, size) = append_section(&mut aci0, section);
+            write_u32(&mut aci0, 0x20 + i * 8, offset);
+            write_u32(&mut aci0, 0x24 + i * 8, size);
+        }
+        aci0
+    }
+
+    /// Serializes the NPDM.
+    fn build(&self) -> Vec<u8> {
+        assert!(self.name.len() <= 0x10, "Title name {} is longer than 16 bytes", self.name);
+
+        let mut npdm = vec![0; sunrise_libkern::npdm::META_SIZE];
+        npdm[0..4].copy_from_slice(b"META");
+        // 64-bit titles get a 39-bit address space, 32-bit ones a 32-bit one.
+        npdm[0xC] = if self.is_64bit { 1 | 3 << 1 } else { 0 };
+        npdm[0xE] = self.main_thread_priority;
+        write_u32(&mut npdm, 0x1C, self.main_thread_stack_size);
+        npdm[0x20..0x20 + self.name.len()].copy_from_slice(self.name.as_bytes());
+
+        let (offset, size) = append_section(&mut npdm, &self.build_aci0());
+        write_u32(&mut npdm, 0x70, offset);
+        write_u32(&mut npdm, 0x74, size);
+        let (offset, size) = append_section(&mut npdm, &self.build_acid());
+        write_u32(&mut npdm, 0x78, offset);
+        write_u32(&mut npdm, 0x7C, size);
+        npdm
+    }
+}
+
+/// Prints the usage and exits.
+fn usage() -> ! {
+    eprintln!("Usage: npdmtool <title ELF> <output npdm> [--name <name>] [--program-id <hex id>] \
+               [--deny-svc <hex syscall number>]... [--service <name>]... [--server <name>]...");
+    process::exit(1)
+}
+
+/// Parses a hexadecimal argument, with or without a 0x prefix.
+fn parse_hex(arg: Option<String>) -> u64 {
+    let arg = arg.unwrap_or_else(|| usage());
+    u64::from_str_radix(arg.trim_start_matches("0x"), 16).unwrap_or_else(|_| {
+        eprintln!("Invalid hexadecimal number {}", arg);
+        usage()
+    })
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let (elf_path, npdm_path) = match (args.next(), args.next()) {
+        (Some(elf_path), Some(npdm_path)) => (elf_path, npdm_path),
+        _ => usage()
+    };
+
+    let elf_data = fs::read(&elf_path).expect("Cannot read the title ELF");
+    let elf = ElfFile::new(&elf_data).expect("Invalid title ELF");
+    let kac = elf.find_section_by_name(".kernel_caps")
+        .expect("The title ELF has no .kernel_caps section")
+        .raw_data(&elf);
+    assert!(kac.len() % 4 == 0, "Malformed .kernel_caps section");
+    let kacs = kac.chunks(4)
+        .map(|kac| u32::from_le_bytes([kac[0], kac[1], kac[2], kac[3]]))
+        .collect();
+
+    let mut builder = NpdmBuilder {
+        name: elf_path.rsplit('/').nth(1).unwrap_or("").to_string(),
+        is_64bit: elf.header.pt1.class() == Class::SixtyFour,
+        main_thread_priority: 0,
+        main_thread_stack_size: 0x20000,
+        acid: AccessControl { kacs, ..AccessControl::default() },
+        ..NpdmBuilder::default()
+    };
+
+    let mut denied_svcs = Vec::new();
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--name" => builder.name = args.next().unwrap_or_else(|| usage()),
+            "--program-id" => builder.program_id = parse_hex(args.next()),
+            "--deny-svc" => denied_svcs.push(parse_hex(args.next()) as u32),
+            "--service" => builder.acid.services.push((args.next().unwrap_or_else(|| usage()), false)),
+            "--server" => builder.acid.services.push((args.next().unwrap_or_else(|| usage()), true)),
+            _ => usage()
+        }
+    }
+
+    builder.aci0 = builder.acid.clone();
+    for svc in denied_svcs {
+        builder.aci0.deny_svc(svc);
+    }
+
+    fs::write(&npdm_path, builder.build()).expect("Cannot write the NPDM");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sunrise_libkern::npdm::{Npdm, NpdmError};
+
+    /// A builder allowing syscalls 0x01 and 0x26, an irq pair, and using sm.
+    fn builder() -> NpdmBuilder {
+        let acid = AccessControl {
+            services: vec![("sm:".to_string(), false), ("fsp-*".to_string(), false), ("vi:m".to_string(), true)],
+            kacs: vec![(1 << 1) << 5 | 0b1111, 1 << 29 | (1 << 14) << 5 | 0b1111, 0x3FF << 12 | 1 << 11 | 0b11111111111],
+        };
+        NpdmBuilder {
+            name: "test".to_string(),
+            program_id: 0x0100000000000042,
+            aci0: acid.clone(),
+            acid,
+            ..NpdmBuilder::default()
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = builder().build();
+        let npdm = Npdm::parse(&data).unwrap();
+        assert_eq!(npdm.check_aci0(), Ok(()));
+        assert_eq!(&npdm.name[..4], b"test");
+        assert_eq!(npdm.program_id, 0x0100000000000042);
+        assert_eq!(npdm.aci0.kacs().collect::<Vec<_>>(), builder().aci0.kacs);
+        assert!(npdm.aci0.sac.allows(b"sm:", false));
+        assert!(npdm.aci0.sac.allows(b"fsp-srv", false));
+        assert!(!npdm.aci0.sac.allows(b"fsp-srv", true));
+        assert!(npdm.aci0.sac.allows(b"vi:m", true));
+        assert!(!npdm.aci0.sac.allows(b"vi:u", false));
+    }
+
+    #[test]
+    fn test_deny_svc() {
+        let mut builder = builder();
+        builder.aci0.deny_svc(0x26);
+        let data = builder.build();
+        let npdm = Npdm::parse(&data).unwrap();
+        assert_eq!(npdm.check_aci0(), Ok(()));
+        assert_eq!(npdm.aci0.kacs().nth(1), Some(1 << 29 | 0b1111));
+    }
+
+    #[test]
+    fn test_aci0_exceeds_acid() {
+        let mut builder = builder();
+        builder.aci0.kacs[0] |= (1 << 2) << 5;
+        assert_eq!(Npdm::parse(&builder.build()).unwrap().check_aci0(), Err(NpdmError::KacNotAllowed));
+
+        let mut builder = self::builder();
+        builder.aci0.kacs.push(0x10 << 12 | 1 << 11 | 0b11111111111);
+        assert_eq!(Npdm::parse(&builder.build()).unwrap().check_aci0(), Err(NpdmError::KacNotAllowed));
+
+        let mut builder = self::builder();
+        builder.aci0.services.push(("fsp-srv".to_string(), true));
+        assert_eq!(Npdm::parse(&builder.build()).unwrap().check_aci0(), Err(NpdmError::SacNotAllowed));
+
+        let mut builder = self::builder();
+        builder.program_id = 0;
+        let mut data = builder.build();
+        // Make the ACI0 program id fall out of the ACID range.
+        let aci0_offset = u32::from_le_bytes([data[0x70], data[0x71], data[0x72], data[0x73]]) as usize;
+        data[aci0_offset + 0x10] = 1;
+        assert_eq!(Npdm::parse(&data).unwrap().check_aci0(), Err(NpdmError::ProgramIdNotAllowed));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut data = builder().build();
+        assert_eq!(Npdm::parse(&data[..0x40]).unwrap_err(), NpdmError::OutOfBounds);
+        data[0] = b'X';
+        assert_eq!(Npdm::parse(&data).unwrap_err(), NpdmError::InvalidMagic);
+    }
+}
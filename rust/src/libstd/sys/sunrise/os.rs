@@ -92,8 +92,11 @@ pub fn current_exe() -> io::Result<PathBuf> {
 }
 
 lazy_static! {
-    /// Storage of all events of the current process.
-    static ref ENVIRONMENT_STORAGE: Mutex<HashMap<OsString, OsString>> = Mutex::new(HashMap::new());
+    /// Storage of all environment variables of the current process, seeded
+    /// with the ones the loader passed to it.
+    static ref ENVIRONMENT_STORAGE: Mutex<HashMap<OsString, OsString>> = Mutex::new(
+        sunrise_libuser::argv::env().map(|(k, v)| (OsString::from(k), OsString::from(v))).collect()
+    );
 }
 
 pub struct Env(Vec<(OsString, OsString)>, usize);
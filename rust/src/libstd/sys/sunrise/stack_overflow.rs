@@ -1,3 +1,9 @@
+//! Stack overflows on sunrise don't need any userspace handling: the kernel
+//! puts a guard page below the main thread's stack, and libuser maps every
+//! other thread's stack with `svcMapMemory`, leaving the page below it
+//! unmapped. When a thread hits its guard page, the kernel reports the
+//! overflow and kills the process.
+
 pub struct Handler;
 
 impl Handler {
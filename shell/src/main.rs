@@ -2,6 +2,11 @@
 //!
 //! Creates an interactive terminal window, providing a few functions useful to
 //! test Sunrise. Type help followed by enter to get a list of allowed commands.
+//!
+//! When started with the `--serial` argument, e.g. `module2
+//! /boot/sunrise-shell shell --serial` in grub.cfg, the shell runs on the COM1
+//! serial port, through the uart service, instead of a vi window. This allows
+//! driving Sunrise headless, e.g. with `qemu -nographic`.
 
 #![feature(asm, naked_functions)]
 #![no_std]
@@ -28,16 +33,19 @@ extern crate sunrise_libuser as libuser;
 use crate::libuser::sm;
 use crate::libuser::fs::{IFileSystemServiceProxy, IFileSystemProxy, IFileProxy};
 use crate::libuser::window::{Window, Color};
-use crate::libuser::terminal::{Terminal, WindowSize};
-use crate::libuser::ldr::{ILoaderInterfaceProxy};
+use crate::libuser::terminal::Terminal;
+use crate::libuser::ldr::{ILoaderInterfaceProxy, ServiceState, ServiceStatus};
 use crate::libuser::threads::{self, Thread};
 use crate::libuser::error::{Error, LoaderError, FileSystemError};
-use crate::libuser::syscalls;
+use crate::libuser::syscalls::{self, SystemInfoType, ThreadState, HandleInfo};
 use crate::libuser::ps2::Keyboard;
 use crate::libuser::twili::ITwiliManagerServiceProxy;
+use crate::libuser::uart::IUartServiceProxy;
+use crate::libuser::argv;
 
 use core::fmt::Write;
 use alloc::string::String;
+use alloc::format;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use bstr::ByteSlice;
@@ -55,7 +63,7 @@ lazy_static! {
 
 /// Asks the user to login repeatedly. Returns with an error if the /etc/passwd
 /// file is invalid or doesn't exist.
-fn login(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &IFileSystemProxy) -> Result<(), Error> {
+fn login(mut terminal: &mut Terminal, keyboard: &mut Keyboard, secret: &mut Option<Terminal>, filesystem: &IFileSystemProxy) -> Result<(), Error> {
     let mut ipc_path = [0x0; 0x300];
     ipc_path[..b"/etc/passwd".len()].copy_from_slice(b"/etc/passwd");
 
@@ -80,7 +88,7 @@ fn login(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &IFil
         let username = username.trim_end_matches('\n');
 
         let _ = writeln!(&mut terminal, "Password: ");
-        let password = get_next_line_no_echo(keyboard);
+        let password = get_next_line_no_echo(keyboard, secret);
         let password = password.trim_end_matches('\n');
 
         let hash = sha1::Sha1::from(&password).digest().bytes();
@@ -107,9 +115,9 @@ fn login(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &IFil
 /// The function takes care of prompting for the password in no-echo mode. If
 /// an error is returned, then it should be assumed that the user was not added
 /// to /etc/passwd.
-fn user_add(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &IFileSystemProxy, username: &str) -> Result<(), Error> {
+fn user_add(mut terminal: &mut Terminal, keyboard: &mut Keyboard, secret: &mut Option<Terminal>, filesystem: &IFileSystemProxy, username: &str) -> Result<(), Error> {
     let _ = writeln!(&mut terminal, "Password: ");
-    let password = get_next_line_no_echo(keyboard);
+    let password = get_next_line_no_echo(keyboard, secret);
     let password = password.trim_end_matches('\n');
 
     let hash = sha1::Sha1::from(&password).digest().bytes();
@@ -133,7 +141,16 @@ fn user_add(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &I
 
 /// Read key presses until a \n is detected, and return the string
 /// (excluding \n). Don't print the key presses on stdout.
-pub fn get_next_line_no_echo(keyboard: &mut Keyboard) -> String {
+///
+/// Reads from `secret` if there is one, a terminal that doesn't echo what it
+/// receives, and from the keyboard otherwise.
+pub fn get_next_line_no_echo(keyboard: &mut Keyboard, secret: &mut Option<Terminal>) -> String {
+    if let Some(secret) = secret {
+        let mut line = get_next_line(secret);
+        line.pop();
+        return line;
+    }
+
     let mut ret = String::from("");
     loop {
         let key = keyboard.read_key();
@@ -165,8 +182,26 @@ pub fn get_next_line(logger: &mut Terminal) -> String {
     }
 }
 
+/// Opens the terminal the shell runs on, and the terminal to read passwords
+/// from if the keyboard shouldn't be used for this.
+///
+/// With the `--serial` argument, this is the COM1 serial port. Passwords are
+/// read from it too, through a pipe that doesn't echo them. Otherwise, this
+/// is a vi window taking the whole screen but the last line, and the keyboard
+/// is used for passwords.
+fn open_console() -> Result<(Terminal, Option<Terminal>), Error> {
+    if argv::args().skip(1).any(|arg| arg == "--serial") {
+        let uart = IUartServiceProxy::raw_new()?;
+        let terminal = Terminal::from_pipe(uart.open_pipe(true)?);
+        let secret = Terminal::from_pipe(uart.open_pipe(false)?);
+        Ok((terminal, Some(secret)))
+    } else {
+        Ok((Terminal::new(crate::libuser::terminal::WindowSize::FontLines(-1, false))?, None))
+    }
+}
+
 fn main() {
-    let mut terminal = Terminal::new(WindowSize::FontLines(-1, false)).unwrap();
+    let (mut terminal, mut secret) = open_console().unwrap();
     let mut keyboard = Keyboard::new().unwrap();
     let twili = ITwiliManagerServiceProxy::new().unwrap();
     let loader = ILoaderInterfaceProxy::raw_new().unwrap();
@@ -174,9 +209,16 @@ fn main() {
     let fs_proxy = IFileSystemServiceProxy::raw_new().unwrap();
     let filesystem = fs_proxy.open_disk_partition(0, 0).unwrap();
 
+    match Thread::create(power_button_thread, 0, threads::DEFAULT_STACK_SIZE) {
+        Ok(thread) => if let Err(err) = thread.start() {
+            warn!("Failed to start the power button thread: {:?}", err);
+        },
+        Err(err) => warn!("Failed to create the power button thread: {:?}", err)
+    }
+
     cat(&mut terminal, &filesystem, "/etc/motd").unwrap();
 
-    if let Err(err) = login(&mut terminal, &mut keyboard, &filesystem) {
+    if let Err(err) = login(&mut terminal, &mut keyboard, &mut secret, &filesystem) {
         error!("Error while setting up login: {:?}", err);
     }
 
@@ -195,7 +237,7 @@ fn main() {
                     None => {
                         let _ = writeln!(&mut terminal, "usage: useradd <username>");
                     }
-                    Some(username) => match user_add(&mut terminal, &mut keyboard, &filesystem, username) {
+                    Some(username) => match user_add(&mut terminal, &mut keyboard, &mut secret, &filesystem, username) {
                         Ok(_) => (),
                         Err(err) => {
                             let _ = writeln!(&mut terminal, "Failed to add user: {:?}", err);
@@ -245,13 +287,41 @@ fn main() {
             "test_divide_by_zero" => test_divide_by_zero(),
             "test_page_fault" => test_page_fault(),
             "connect" => {
-                let handle = sm::IUserInterfaceProxy::raw_new().unwrap().get_service(u64::from_le_bytes(*b"vi:\0\0\0\0\0"));
+                let sm = sm::IUserInterfaceProxy::raw_new().unwrap();
+                sm.initialize().unwrap();
+                let handle = sm.get_service(u64::from_le_bytes(*b"vi:\0\0\0\0\0"));
                 let _ = writeln!(&mut terminal, "Got handle {:?}", handle);
             },
             "exit" => return,
             "ps" => if let Err(err) = ps(&mut terminal, &loader) {
                 let _ = writeln!(&mut terminal, "ps: {}", err);
             },
+            "free" => if let Err(err) = free(&mut terminal) {
+                let _ = writeln!(&mut terminal, "free: {}", err);
+            },
+            "top" => if let Err(err) = top(&mut terminal, &mut keyboard, &loader) {
+                let _ = writeln!(&mut terminal, "top: {}", err);
+            },
+            "threads" => {
+                match arguments.nth(0).map(str::parse) {
+                    Some(Ok(pid)) => if let Err(err) = list_threads(&mut terminal, &loader, pid) {
+                        let _ = writeln!(&mut terminal, "threads: {}", err);
+                    },
+                    _ => {
+                        let _ = writeln!(&mut terminal, "usage: threads <pid>");
+                    }
+                }
+            },
+            "handles" => {
+                match arguments.nth(0).map(str::parse) {
+                    Some(Ok(pid)) => if let Err(err) = list_handles(&mut terminal, &loader, pid) {
+                        let _ = writeln!(&mut terminal, "handles: {}", err);
+                    },
+                    _ => {
+                        let _ = writeln!(&mut terminal, "usage: handles <pid>");
+                    }
+                }
+            },
             "kill" => {
                 match arguments.nth(0) {
                     None => {
@@ -269,6 +339,47 @@ fn main() {
                     }
                 }
             }
+            "loglevel" => {
+                let arguments: Vec<&str> = arguments.collect();
+                let (process_name, directives) = match arguments[..] {
+                    [directives] => (None, Some(directives)),
+                    ["-p", process_name] => (Some(process_name), Some("")),
+                    ["-p", process_name, directives] => (Some(process_name), Some(directives)),
+                    _ => (None, None)
+                };
+                match directives {
+                    Some(directives) => if let Err(err) = syscalls::set_log_filter(process_name, directives) {
+                        let _ = writeln!(&mut terminal, "loglevel: {}", err);
+                    },
+                    None => {
+                        let _ = writeln!(&mut terminal, "usage: loglevel [-p <process>] <directives>");
+                    }
+                }
+            },
+            "svc" => {
+                let arguments: Vec<&str> = arguments.collect();
+                let res = match arguments[..] {
+                    ["status"] => Some(list_services(&mut terminal, &loader)),
+                    ["start", name] => Some(loader.start_service(name.as_bytes())),
+                    ["stop", name] => Some(loader.stop_service(name.as_bytes())),
+                    _ => None
+                };
+                match res {
+                    Some(Ok(())) => (),
+                    Some(Err(err)) => {
+                        let _ = writeln!(&mut terminal, "svc: {}", err);
+                    },
+                    None => {
+                        let _ = writeln!(&mut terminal, "usage: svc status|start <title>|stop <title>");
+                    }
+                }
+            },
+            "shutdown" => if let Err(err) = shutdown(&fs_proxy, false) {
+                let _ = writeln!(&mut terminal, "shutdown: {:?}", err);
+            },
+            "reboot" => if let Err(err) = shutdown(&fs_proxy, true) {
+                let _ = writeln!(&mut terminal, "reboot: {:?}", err);
+            },
             //"stackdump" => unsafe { stack::KernelStack::dump_current_stack() },
             "help" => {
                 let _ = writeln!(&mut terminal, "COMMANDS:");
@@ -280,6 +391,17 @@ fn main() {
                 let _ = writeln!(&mut terminal, "pwd: Print name of the current/working directory");
                 let _ = writeln!(&mut terminal, "kill <pid>: Kill the given process");
                 let _ = writeln!(&mut terminal, "ps: List running processes");
+                let _ = writeln!(&mut terminal, "free: Display the amount of used and free memory");
+                let _ = writeln!(&mut terminal, "top: Display the memory usage of every process, until a key is pressed");
+                let _ = writeln!(&mut terminal, "threads <pid>: List the threads of the given process");
+                let _ = writeln!(&mut terminal, "handles <pid>: List the open handles of the given process, with their peer process and age");
+                let _ = writeln!(&mut terminal, "loglevel <directives>: Replace the kernel log filter, e.g. info,sunrise_kernel::ipc=trace");
+                let _ = writeln!(&mut terminal, "loglevel -p <process> [directives]: Set the log filter of a process. Without directives, reset it to the kernel one");
+                let _ = writeln!(&mut terminal, "svc status: List the services of /etc/init.toml and their state");
+                let _ = writeln!(&mut terminal, "svc start <title>: Start a service, and restart it according to its restart policy");
+                let _ = writeln!(&mut terminal, "svc stop <title>: Stop a service, killing it if it is running");
+                let _ = writeln!(&mut terminal, "shutdown: Save the filesystem and turn the system off");
+                let _ = writeln!(&mut terminal, "reboot: Save the filesystem and restart the system");
                 let _ = writeln!(&mut terminal, "meme1: Display the KFS-1 meme");
                 let _ = writeln!(&mut terminal, "meme2: Display the KFS-2 meme");
                 let _ = writeln!(&mut terminal, "meme3: Display the KFS-3 meme");
@@ -317,6 +439,35 @@ fn main() {
     }
 }
 
+/// Asks the services to save their state, and turns the system off, or resets
+/// it if `reboot` is true.
+///
+/// Does not go down if the filesystem could not be flushed.
+fn shutdown(fs_proxy: &IFileSystemServiceProxy, reboot: bool) -> Result<(), Error> {
+    fs_proxy.flush_all()?;
+    syscalls::sleep_system(reboot)?;
+    Ok(())
+}
+
+/// Turns the system off when the power button is pressed.
+///
+/// Runs in its own thread, as the main thread is busy reading the commands.
+fn power_button_thread(_: usize) {
+    let event = match syscalls::create_power_button_event() {
+        Ok(event) => event,
+        Err(err) => return warn!("The power button is not available: {:?}", err)
+    };
+    let fs_proxy = IFileSystemServiceProxy::raw_new().unwrap();
+
+    loop {
+        let _ = syscalls::wait_synchronization(&[event.0.as_ref()], None);
+        let _ = event.clear();
+        if let Err(err) = shutdown(&fs_proxy, false) {
+            error!("Failed to power off: {:?}", err);
+        }
+    }
+}
+
 /// Splits a path at the first `/` it encounters.
 ///
 /// Returns a tuple of the parts before and after the cut.
@@ -517,20 +668,138 @@ fn kill(loader: &ILoaderInterfaceProxy, pid: u64) -> Result<(), Error> {
     Ok(())
 }
 
+/// Get the name of the process with the given pid, or `<Unknown>` if the
+/// loader doesn't know about it.
+fn process_name(loader: &ILoaderInterfaceProxy, pid: u64) -> String {
+    let mut name = [0; 32];
+    match loader.get_name(pid, &mut name) {
+        Ok(copied_len) => String::from_utf8_lossy(&name[..copied_len as usize]).into_owned(),
+        Err(err) => {
+            log::debug!("Error: {:?}", err);
+            String::from("<Unknown>")
+        }
+    }
+}
+
 /// Get the pid and names of processes currently running.
 fn ps(terminal: &mut Terminal, loader: &ILoaderInterfaceProxy) -> Result<(), Error> {
     let mut pids = [0; 256];
     let pid_read = syscalls::get_process_list(&mut pids)?;
     for pid in &pids[..pid_read] {
-        let mut name = [0; 32];
-        let name = match loader.get_name(*pid, &mut name) {
-            Ok(copied_len) => String::from_utf8_lossy(&name[..copied_len as usize]).into_owned(),
-            Err(err) => {
-                log::debug!("Error: {:?}", err);
-                String::from("<Unknown>")
+        let _ = writeln!(terminal, "{}: {}", pid, process_name(loader, *pid));
+    }
+    Ok(())
+}
+
+/// Print the amount of total, used and free physical memory.
+fn free(terminal: &mut Terminal) -> Result<(), Error> {
+    let total = syscalls::get_system_info(SystemInfoType::TotalPhysicalMemorySize, 0)?;
+    let used = syscalls::get_system_info(SystemInfoType::UsedPhysicalMemorySize, 0)?;
+    let _ = writeln!(terminal, "     {:>10} {:>10} {:>10}", "total", "used", "free");
+    let _ = writeln!(terminal, "Mem: {:>9}K {:>9}K {:>9}K", total / 1024, used / 1024, (total - used) / 1024);
+    Ok(())
+}
+
+/// Print the memory usage of every process every second, until a key is
+/// pressed.
+fn top(terminal: &mut Terminal, keyboard: &mut Keyboard, loader: &ILoaderInterfaceProxy) -> Result<(), Error> {
+    loop {
+        free(terminal)?;
+        let _ = writeln!(terminal, "{:>5} {:<12} {:>9} {:>9} {:>9} {:>7}", "PID", "NAME", "RES", "HEAP", "SHR", "HANDLES");
+
+        let mut pids = [0; 256];
+        let pid_read = syscalls::get_process_list(&mut pids)?;
+        for pid in &pids[..pid_read] {
+            let usage = match syscalls::get_process_memory_usage(*pid) {
+                Ok(usage) => usage,
+                // The process died since we listed it.
+                Err(_) => continue
+            };
+            let _ = writeln!(terminal, "{:>5} {:<12} {:>8}K {:>8}K {:>8}K {:>7}",
+                pid, process_name(loader, *pid),
+                usage.resident_size / 1024, usage.heap_size / 1024,
+                usage.shared_size / 1024, usage.handle_count);
+        }
+        let _ = writeln!(terminal);
+
+        // Refresh every second, checking for a key press every 100ms.
+        for _ in 0..10 {
+            if keyboard.try_read_key().is_some() {
+                return Ok(())
             }
+            let _ = syscalls::sleep_thread(100 * 1000 * 1000);
+        }
+    }
+}
+
+/// Print the threads of the given process, with their state and instruction
+/// pointer.
+fn list_threads(terminal: &mut Terminal, loader: &ILoaderInterfaceProxy, pid: u64) -> Result<(), Error> {
+    let debug = syscalls::debug_active_process(pid)?;
+    let mut tids = [0; 64];
+    let tid_read = syscalls::get_thread_list(&mut tids, Some(&debug))?;
+
+    let _ = writeln!(terminal, "{}: {}", pid, process_name(loader, pid));
+    let _ = writeln!(terminal, "{:>5} {:<10} {:>10}", "TID", "STATE", "EIP");
+    for tid in &tids[..core::cmp::min(tid_read, tids.len())] {
+        let (state, paused) = match syscalls::get_debug_thread_state(&debug, *tid) {
+            Ok(state) => state,
+            // The thread died since we listed it.
+            Err(_) => continue
+        };
+        let context = syscalls::get_debug_thread_context(&debug, *tid)?;
+        let state = match (state, paused) {
+            (ThreadState::TerminationPending, _) => "dying",
+            (_, true) => "paused",
+            (ThreadState::Waiting, _) => "waiting",
+            (ThreadState::Running, _) => "running",
+            (ThreadState::Scheduled, _) => "scheduled",
+            _ => "unknown",
+        };
+        let _ = writeln!(terminal, "{:>5} {:<10} {:#010x}", tid, state, context.eip);
+    }
+    Ok(())
+}
+
+/// Print the open handles of the given process, with the process on their
+/// other side and how long they have been open.
+fn list_handles(terminal: &mut Terminal, loader: &ILoaderInterfaceProxy, pid: u64) -> Result<(), Error> {
+    let mut handles = [HandleInfo::default(); 256];
+    let handle_count = syscalls::get_process_handles(pid, &mut handles)?;
+
+    let _ = writeln!(terminal, "{}: {}", pid, process_name(loader, pid));
+    let _ = writeln!(terminal, "{:>10} {:<16} {:<18} {:>10}", "HANDLE", "KIND", "PEER", "AGE");
+    for handle in &handles[..core::cmp::min(handle_count, handles.len())] {
+        let kind = format!("{:?}", handle.kind);
+        let peer = match handle.peer_pid {
+            HandleInfo::NO_PEER => String::from("-"),
+            peer_pid => format!("{} {}", peer_pid, process_name(loader, peer_pid)),
+        };
+        let age_ms = handle.age_ns / 1_000_000;
+        let _ = writeln!(terminal, "{:#010x} {:<16} {:<18} {:>6}.{:03}s",
+            handle.handle, kind.trim_start_matches("HandleKind::"), peer,
+            age_ms / 1000, age_ms % 1000);
+    }
+    let _ = writeln!(terminal, "{} handles", handle_count);
+    Ok(())
+}
+
+/// Print the services supervised by the loader, with their state.
+fn list_services(terminal: &mut Terminal, loader: &ILoaderInterfaceProxy) -> Result<(), Error> {
+    let mut services = [ServiceStatus { name: [0; 0xc], pid: 0, state: ServiceState::Stopped, restarts: 0 }; 32];
+    let count = loader.list_services(&mut services)?;
+
+    let _ = writeln!(terminal, "{:<12} {:<22} {:>5} {:>8}", "SERVICE", "STATE", "PID", "RESTARTS");
+    for service in &services[..count as usize] {
+        let name_len = service.name.iter().position(|b| *b == 0).unwrap_or_else(|| service.name.len());
+        let name = String::from_utf8_lossy(&service.name[..name_len]);
+        let state = format!("{:?}", service.state);
+        let pid = match service.pid {
+            0 => String::from("-"),
+            pid => format!("{}", pid),
         };
-        let _ = writeln!(terminal, "{}: {}", pid, name);
+        let _ = writeln!(terminal, "{:<12} {:<22} {:>5} {:>8}",
+            name, state.trim_start_matches("ServiceState::"), pid, service.restarts);
     }
     Ok(())
 }
@@ -675,6 +944,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         libuser::syscalls::nr::SleepThread,
         libuser::syscalls::nr::ExitProcess,
+        libuser::syscalls::nr::Break,
         libuser::syscalls::nr::CloseHandle,
         libuser::syscalls::nr::WaitSynchronization,
         libuser::syscalls::nr::OutputDebugString,
@@ -682,6 +952,8 @@ capabilities!(CAPABILITIES = Capabilities {
         libuser::syscalls::nr::ClearEvent,
 
         libuser::syscalls::nr::SetHeapSize,
+        libuser::syscalls::nr::MapMemory,
+        libuser::syscalls::nr::UnmapMemory,
         libuser::syscalls::nr::QueryMemory,
         libuser::syscalls::nr::CreateThread,
         libuser::syscalls::nr::StartThread,
@@ -693,5 +965,16 @@ capabilities!(CAPABILITIES = Capabilities {
         libuser::syscalls::nr::CreateSharedMemory,
         libuser::syscalls::nr::CreateInterruptEvent,
         libuser::syscalls::nr::GetProcessList,
-    ]
+        libuser::syscalls::nr::GetSystemInfo,
+        libuser::syscalls::nr::GetProcessMemoryUsage,
+        libuser::syscalls::nr::DebugActiveProcess,
+        libuser::syscalls::nr::GetThreadList,
+        libuser::syscalls::nr::GetDebugThreadContext,
+        libuser::syscalls::nr::GetDebugThreadParam,
+        libuser::syscalls::nr::SetLogFilter,
+        libuser::syscalls::nr::SleepSystem,
+        libuser::syscalls::nr::CreatePowerButtonEvent,
+        libuser::syscalls::nr::GetProcessHandles,
+    ],
+    raw_caps: [libuser::caps::debug_flags(false, true)]
 });
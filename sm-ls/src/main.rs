@@ -0,0 +1,127 @@
+//! Service lister
+//!
+//! Lists the services registered in sm, with the pid of the process hosting
+//! them, or waits for a service to come up.
+//!
+//! Usage: `sm-ls [-w <service> <timeout ms>]`
+//!
+//! - `-w <service> <timeout ms>`: waits for `service` to be registered, giving
+//!   up after `timeout ms` milliseconds.
+
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+use std::env;
+use std::os::sunrise::prelude::*;
+use sunrise_libuser::error::{Error, SmError};
+use sunrise_libuser::sm::{IUserInterfaceProxy, ServiceInfo};
+
+/// Maximum number of services listed.
+const MAX_SERVICES: usize = 64;
+
+/// Decodes a service name encoded as an u64.
+fn decode_name(name: u64) -> String {
+    let bytes = name.to_le_bytes();
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or_else(|| bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Encodes a service name as an u64.
+fn encode_name(name: &str) -> Option<u64> {
+    let mut bytes = [0; 8];
+    if name.is_empty() || name.len() > bytes.len() {
+        return None;
+    }
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Prints the registered services.
+fn list(sm: &IUserInterfaceProxy) -> Result<(), Error> {
+    let mut services = [ServiceInfo { name: 0, pid: 0 }; MAX_SERVICES];
+    let (count, total) = sm.list_services(&mut services)?;
+    println!("{:<8} {:>5}", "SERVICE", "PID");
+    for service in &services[..count as usize] {
+        println!("{:<8} {:>5}", decode_name(service.name), service.pid);
+    }
+    if total > count {
+        println!("... and {} more", total - count);
+    }
+    Ok(())
+}
+
+/// Waits for `name` to be registered, for up to `timeout_ms` milliseconds.
+fn wait(sm: &IUserInterfaceProxy, name: &str, timeout_ms: u64) -> Result<(), Error> {
+    let encoded = match encode_name(name) {
+        Some(encoded) => encoded,
+        None => return Err(SmError::InvalidName.into())
+    };
+    match sm.get_service_with_timeout(encoded, timeout_ms * 1_000_000) {
+        Ok(_) => {
+            println!("{} is up", name);
+            Ok(())
+        },
+        Err(Error::Sm(SmError::ServiceNotRegistered, _)) => {
+            println!("{} did not come up in {}ms", name, timeout_ms);
+            Ok(())
+        },
+        Err(err) => Err(err)
+    }
+}
+
+/// Prints how to use sm-ls.
+fn print_usage() {
+    println!("Usage: sm-ls [-w <service> <timeout ms>]");
+}
+
+/// The entry point of the program.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let sm = IUserInterfaceProxy::raw_new().expect("Failed to connect to sm");
+    sm.initialize().expect("Failed to initialize sm");
+
+    let res = match &*args.iter().map(|arg| &**arg).collect::<Vec<_>>() {
+        [] => list(&sm),
+        ["-w", name, timeout_ms] => match timeout_ms.parse() {
+            Ok(timeout_ms) => wait(&sm, name, timeout_ms),
+            Err(_) => return print_usage()
+        },
+        _ => return print_usage()
+    };
+    if let Err(err) = res {
+        println!("sm-ls: {:?}", err);
+    }
+}
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        nr::SleepThread,
+        nr::ExitProcess,
+        nr::Break,
+        nr::CreateThread,
+        nr::StartThread,
+        nr::ExitThread,
+        nr::CloseHandle,
+        nr::WaitSynchronization,
+        nr::OutputDebugString,
+        nr::SetThreadArea,
+
+        nr::ConnectToNamedPort,
+        nr::SetHeapSize,
+        nr::MapMemory,
+        nr::UnmapMemory,
+        nr::SendSyncRequestWithUserBuffer,
+        nr::QueryMemory,
+        nr::CreateSharedMemory,
+        nr::MapSharedMemory,
+        nr::UnmapSharedMemory,
+    ]
+});
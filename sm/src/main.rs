@@ -19,6 +19,12 @@
 //! it, and it returns a ClientSession. The difference is that a Service handled
 //! by "sm:" has an additional permission check done to ensure it isn't accessed
 //! by an unprivileged process.
+//!
+//! The loader registers every process it creates through "sm:m", with the
+//! service access control list of its NPDM, see [sunrise_libkern::npdm].
+//! Builtins, started by the kernel, may access and host any service. Other
+//! processes that were not registered may not access any. Only the loader, the
+//! builtin whose title id is [LOADER_TITLE_ID], may use "sm:m".
 //! Service Manager
 
 #![feature(async_await)]
@@ -46,17 +52,20 @@ extern crate lazy_static;
 
 use log::*;
 use alloc::boxed::Box;
-use crate::libuser::syscalls;
+use alloc::vec::Vec;
+use core::future::Future;
+use crate::libuser::syscalls::{self, SystemInfoType};
 use crate::libuser::futures::{WaitableManager, WorkQueue};
 use crate::libuser::ipc::server::managed_port_handler;
 use crate::libuser::types::*;
 use crate::libuser::error::Error;
 use crate::libuser::error::SmError;
-use crate::libuser::futures_rs::future::{FutureExt, FutureObj};
-use crate::libuser::sm::IUserInterfaceAsync;
+use crate::libuser::futures_rs::future::{Either, FutureExt, FutureObj};
+use crate::libuser::sm::{IUserInterfaceAsync, IManagerInterfaceAsync, ServiceInfo};
 use crate::libuser::loop_future::{Loop, loop_fn};
 use hashbrown::hash_map::{HashMap, Entry};
 use spin::Mutex;
+use sunrise_libkern::npdm::ServiceAccessControl;
 
 use crate::libuser::futures_rs as futures;
 
@@ -66,11 +75,36 @@ use crate::libuser::futures_rs as futures;
 ///
 /// Make sure to call the `IUserInterface::initialize` method before using it.
 #[derive(Debug, Default, Clone)]
-struct UserInterface;
+struct UserInterface {
+    /// Pid of the remote process, acquired by `initialize`.
+    pid: Option<u64>,
+}
+
+/// `sm:m` service interface.
+/// Allows the loader to tell the Service Manager which services the processes
+/// it creates may access or host.
+#[derive(Debug, Default, Clone)]
+struct ManagerInterface;
+
+/// A registered service.
+#[derive(Debug)]
+struct Service {
+    /// Client side of the port of the service.
+    port: ClientPort,
+    /// Pid of the process hosting the service.
+    pid: u64,
+}
+
+/// Maximum size of a service access control list.
+const MAX_SAC_SIZE: usize = 0x200;
+
+/// Title id of the loader, the builtin managing the other processes through
+/// `sm:m`.
+const LOADER_TITLE_ID: u64 = 0x0200000000000001;
 
 lazy_static! {
-    /// Global mapping of Service Name -> ClientPort.
-    static ref SERVICES: Mutex<HashMap<ServiceName, ClientPort>> = Mutex::new(HashMap::new());
+    /// Global mapping of Service Name -> Service.
+    static ref SERVICES: Mutex<HashMap<ServiceName, Service>> = Mutex::new(HashMap::new());
     // TODO: Implement a futures-based condvar instead of using event for in-process eventing.
     // BODY: A futures-based condvar can easily be implemented entirely in userspace, without
     // BODY: the need for any kernel help. It would have a lot less overhead than using a kernel Event.
@@ -79,6 +113,94 @@ lazy_static! {
     static ref SERVICES_EVENT: (WritableEvent, ReadableEvent) = {
         crate::libuser::syscalls::create_event().unwrap()
     };
+    /// Service access control lists of the processes registered through
+    /// `sm:m`.
+    static ref PROCESS_SACS: Mutex<HashMap<u64, Vec<u8>>> = Mutex::new(HashMap::new());
+    /// Smallest and biggest pids of the builtins. Empty if the kernel won't
+    /// tell.
+    static ref BUILTIN_PIDS: (u64, u64) = {
+        let range = syscalls::get_system_info(SystemInfoType::InitialProcessIdRange, 0)
+            .and_then(|min| Ok((min, syscalls::get_system_info(SystemInfoType::InitialProcessIdRange, 1)?)));
+        match range {
+            Ok((min, max)) => (min, max),
+            Err(err) => {
+                error!("Failed to get the pids of the builtins: {:?}", err);
+                (1, 0)
+            }
+        }
+    };
+}
+
+/// Checks whether the process `pid` is a builtin, started by the kernel.
+fn is_builtin(pid: u64) -> bool {
+    BUILTIN_PIDS.0 <= pid && pid <= BUILTIN_PIDS.1
+}
+
+/// Checks that the process `pid` may access the service `servicename`, or host
+/// it if `is_server`.
+///
+/// # Errors
+///
+/// - `NotAllowed`
+///    - The service access control list of the process doesn't allow it.
+///    - The process is neither registered nor a builtin.
+fn check_access(pid: u64, servicename: ServiceName, is_server: bool) -> Result<(), Error> {
+    let allowed = match PROCESS_SACS.lock().get(&pid) {
+        Some(sac) => {
+            // Checked by register_process.
+            let sac = ServiceAccessControl::new(sac).unwrap();
+            let name = servicename.0.to_le_bytes();
+            sac.allows(&name[..get_service_length(servicename.0)], is_server)
+        },
+        None => is_builtin(pid)
+    };
+    if !allowed {
+        warn!("Process {} is not allowed to {} service {}", pid,
+            if is_server { "host" } else { "access" }, servicename);
+        return Err(SmError::NotAllowed.into());
+    }
+    Ok(())
+}
+
+/// Returns a future connecting to the service `servicename`, waiting for it to
+/// be registered if it isn't yet. See the implementation note of
+/// `IUserInterfaceAsync::get_service`.
+fn connect_to_service<'a>(work_queue: WorkQueue<'a>, servicename: ServiceName) -> impl Future<Output = Result<ClientSession, Error>> + Unpin + 'a {
+    loop_fn(work_queue, move |work_queue| {
+        if let Some(service) = SERVICES.lock().get(&servicename) {
+            debug!("Acquired service {}!", servicename);
+            // Synchronous connect. This can block.
+            let client = service.port.connect();
+            futures::future::ready(Loop::Break(client)).left_future()
+        } else {
+            debug!("Service {} not currently registered. Sleeping.", servicename);
+            SERVICES_EVENT.1.wait_async_cb(work_queue.clone(), move || {
+                if SERVICES.lock().contains_key(&servicename) { Some(()) }
+                else { None }
+            })
+                .map(|_| {
+                    Loop::Continue(work_queue)
+                }).right_future()
+        }
+    })
+}
+
+/// Unregisters the service `servicename` once all the ServerPorts of `port`
+/// are closed, usually because the process hosting it died.
+///
+/// Gives up if the service gets unregistered first, closing `port`.
+fn unregister_on_death(work_queue: WorkQueue<'static>, servicename: ServiceName, port: HandleRef<'static>) -> impl Future<Output = ()> + Unpin {
+    port.wait_async(work_queue).map(move |res| {
+        if res.is_err() {
+            return;
+        }
+        let mut services = SERVICES.lock();
+        // The service might have been unregistered and registered again meanwhile.
+        if services.get(&servicename).map(|service| service.port.0.as_ref_static() == port).unwrap_or(false) {
+            info!("Host of service {} died, unregistering it.", servicename);
+            services.remove(&servicename);
+        }
+    })
 }
 
 /// Get the length of a service encoded as an u64.
@@ -138,11 +260,28 @@ impl core::fmt::Display for ServiceName {
     }
 }
 
+impl UserInterface {
+    /// Checks that the remote process may access the service `servicename`, or
+    /// host it if `is_server`.
+    ///
+    /// # Errors
+    ///
+    /// - `NotInitialized`
+    ///    - `initialize` was not called.
+    /// - `NotAllowed`
+    ///    - The service access control list of the process doesn't allow it.
+    fn check_access(&self, servicename: ServiceName, is_server: bool) -> Result<(), Error> {
+        let pid = self.pid.ok_or(SmError::NotInitialized)?;
+        check_access(pid, servicename, is_server)
+    }
+}
+
 impl IUserInterfaceAsync for UserInterface {
     /// Initialize the UserInterface, acquiring the Pid of the remote
     /// process, which will then be used to validate the permissions of each
     /// calls.
-    fn initialize(&mut self, _manager: WorkQueue<'static>, _pid: Pid) -> FutureObj<'_, Result<(), Error>> {
+    fn initialize(&mut self, _manager: WorkQueue<'static>, pid: Pid) -> FutureObj<'_, Result<(), Error>> {
+        self.pid = Some(pid.0);
         FutureObj::new(Box::new(futures::future::ok(())))
     }
 
@@ -161,28 +300,19 @@ impl IUserInterfaceAsync for UserInterface {
     // For this reason, it is recommended for processes to use a global `sm:` handle.
     fn get_service<'a>(&mut self, work_queue: WorkQueue<'a>, servicename: u64) -> FutureObj<'a, Result<ClientSession, Error>> {
         let servicename = ServiceName(servicename);
-        FutureObj::new(Box::new(loop_fn(work_queue, move |work_queue| {
-            if let Some(port) = SERVICES.lock().get(&servicename) {
-                debug!("Acquired service {}!", servicename);
-                // Synchronous connect. This can block.
-                let client = port.connect();
-                futures::future::ready(Loop::Break(client)).left_future()
-            } else {
-                debug!("Service {} not currently registered. Sleeping.", servicename);
-                SERVICES_EVENT.1.wait_async_cb(work_queue.clone(), move || {
-                    if SERVICES.lock().contains_key(&servicename) { Some(()) }
-                    else { None }
-                })
-                    .map(|_| {
-                        Loop::Continue(work_queue)
-                    }).right_future()
-            }
-        })))
+        if let Err(err) = self.check_access(servicename, false) {
+            return FutureObj::new(Box::new(futures::future::err(err)));
+        }
+        FutureObj::new(Box::new(connect_to_service(work_queue, servicename)))
     }
     /// Register a new service, returning a ServerPort to the newly
     /// registered service.
-    fn register_service(&mut self, _work_queue: WorkQueue<'static>, servicename: u64, is_light: bool, max_handles: u32) -> FutureObj<'_, Result<ServerPort, Error>> {
+    fn register_service(&mut self, work_queue: WorkQueue<'static>, servicename: u64, is_light: bool, max_handles: u32) -> FutureObj<'_, Result<ServerPort, Error>> {
         let servicename = ServiceName(servicename);
+        let pid = match self.check_access(servicename, true) {
+            Ok(()) => self.pid.unwrap(),
+            Err(err) => return FutureObj::new(Box::new(futures::future::err(err)))
+        };
 
         let serverport = {
             let mut services_lock = SERVICES.lock();
@@ -196,7 +326,9 @@ impl IUserInterfaceAsync for UserInterface {
                 Err(err) => return FutureObj::new(Box::new(futures::future::err(err.into())))
             };
 
-            entry.insert(clientport);
+            let port = clientport.0.as_ref_static();
+            work_queue.spawn(FutureObj::new(Box::new(unregister_on_death(work_queue.clone(), servicename, port))));
+            entry.insert(Service { port: clientport, pid });
 
             serverport
         };
@@ -207,20 +339,126 @@ impl IUserInterfaceAsync for UserInterface {
         FutureObj::new(Box::new(futures::future::ok(serverport)))
     }
 
-    /// Unregister a service.
+    /// Unregister a service. Only the process hosting it, or the manager, may
+    /// unregister it.
     fn unregister_service(&mut self, _work_queue: WorkQueue<'static>, servicename: u64) -> FutureObj<'_, Result<(), Error>> {
         let servicename = ServiceName(servicename);
-        match SERVICES.lock().remove(&servicename) {
-            Some(_) => FutureObj::new(Box::new(futures::future::ok(()))),
-            None => FutureObj::new(Box::new(futures::future::err(SmError::ServiceNotRegistered.into())))
+        let pid = match self.check_access(servicename, true) {
+            Ok(()) => self.pid.unwrap(),
+            Err(err) => return FutureObj::new(Box::new(futures::future::err(err)))
+        };
+        let mut services = SERVICES.lock();
+        let res = match services.get(&servicename) {
+            Some(service) if service.pid != pid && !is_manager(pid) => {
+                warn!("Process {} is not allowed to unregister service {}, hosted by {}", pid, servicename, service.pid);
+                Err(SmError::NotAllowed.into())
+            },
+            Some(_) => {
+                services.remove(&servicename);
+                Ok(())
+            },
+            None => Err(SmError::ServiceNotRegistered.into())
+        };
+        FutureObj::new(Box::new(futures::future::ready(res)))
+    }
+
+    /// List the registered services.
+    fn list_services<'a>(&mut self, _work_queue: WorkQueue<'static>, services: &'a mut [ServiceInfo]) -> FutureObj<'a, Result<(u64, u64,), Error>> {
+        let mut infos: Vec<ServiceInfo> = SERVICES.lock().iter()
+            .map(|(name, service)| ServiceInfo { name: name.0, pid: service.pid })
+            .collect();
+        infos.sort_by_key(|info| info.name.to_le_bytes());
+        let count = core::cmp::min(infos.len(), services.len());
+        services[..count].copy_from_slice(&infos[..count]);
+        FutureObj::new(Box::new(futures::future::ok((count as u64, infos.len() as u64))))
+    }
+
+    /// Get a ClientSession to this service, giving up after a timeout.
+    fn get_service_with_timeout<'a>(&mut self, work_queue: WorkQueue<'a>, servicename: u64, timeout_ns: u64) -> FutureObj<'a, Result<ClientSession, Error>> {
+        let servicename = ServiceName(servicename);
+        if let Err(err) = self.check_access(servicename, false) {
+            return FutureObj::new(Box::new(futures::future::err(err)));
+        }
+        let timeout = crate::libuser::futures::sleep(work_queue.clone(), timeout_ns);
+        let connect = connect_to_service(work_queue, servicename);
+        FutureObj::new(Box::new(futures::future::select(connect, timeout).map(move |res| match res {
+            Either::Left((client, _)) => client,
+            Either::Right((Ok(()), _)) => {
+                warn!("Timed out waiting for service {}.", servicename);
+                Err(SmError::ServiceNotRegistered.into())
+            },
+            Either::Right((Err(err), _)) => Err(err),
+        })))
+    }
+}
+
+/// Checks whether the process `pid` is the manager, that is, the loader. The
+/// kernel tells the title id of the builtins.
+fn is_manager(pid: u64) -> bool {
+    if !is_builtin(pid) {
+        return false;
+    }
+    match syscalls::get_system_info(SystemInfoType::InitialProcessTitleId, pid as usize) {
+        Ok(title_id) => title_id == LOADER_TITLE_ID,
+        Err(err) => {
+            error!("Failed to get the title id of builtin {}: {:?}", pid, err);
+            false
         }
     }
 }
 
+/// Checks that the process `pid` may use `sm:m`, that is, that it is the
+/// manager.
+///
+/// # Errors
+///
+/// - `NotAllowed`
+///    - The process is not the manager.
+fn check_manager(pid: Pid) -> Result<(), Error> {
+    if !is_manager(pid.0) {
+        warn!("Process {} is not allowed to use sm:m", pid.0);
+        return Err(SmError::NotAllowed.into());
+    }
+    Ok(())
+}
+
+impl IManagerInterfaceAsync for ManagerInterface {
+    /// Registers the service access control lists of a process.
+    fn register_process(&mut self, _work_queue: WorkQueue<'static>, manager_pid: Pid, pid: u64, acid_sac: &[u8], aci0_sac: &[u8]) -> FutureObj<'_, Result<(), Error>> {
+        let res = (|| -> Result<(), Error> {
+            check_manager(manager_pid)?;
+            if acid_sac.len() > MAX_SAC_SIZE || aci0_sac.len() > MAX_SAC_SIZE {
+                return Err(SmError::ServiceAccessControlTooBig.into());
+            }
+            let acid = ServiceAccessControl::new(acid_sac).or(Err(SmError::NotAllowed))?;
+            let aci0 = ServiceAccessControl::new(aci0_sac).or(Err(SmError::NotAllowed))?;
+            if !aci0.is_subset_of(&acid) {
+                return Err(SmError::NotAllowed.into());
+            }
+            PROCESS_SACS.lock().insert(pid, aci0_sac.to_vec());
+            Ok(())
+        })();
+        FutureObj::new(Box::new(futures::future::ready(res)))
+    }
+
+    /// Unregisters a process.
+    fn unregister_process(&mut self, _work_queue: WorkQueue<'static>, manager_pid: Pid, pid: u64) -> FutureObj<'_, Result<(), Error>> {
+        let res = check_manager(manager_pid).and_then(|()| {
+            match PROCESS_SACS.lock().remove(&pid) {
+                Some(_) => Ok(()),
+                None => Err(SmError::ProcessNotRegistered.into())
+            }
+        });
+        FutureObj::new(Box::new(futures::future::ready(res)))
+    }
+}
+
 fn main() {
     let mut man = WaitableManager::new();
     let handler = managed_port_handler(man.work_queue(), "sm:\0", UserInterface::dispatch).unwrap();
+    man.work_queue().spawn(FutureObj::new(Box::new(handler)));
 
+    let handler = managed_port_handler(man.work_queue(), "sm:m\0", ManagerInterface::dispatch).unwrap();
     man.work_queue().spawn(FutureObj::new(Box::new(handler)));
 
     man.run();
@@ -242,6 +480,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
@@ -258,5 +497,8 @@ capabilities!(CAPABILITIES = Capabilities {
         sunrise_libuser::syscalls::nr::SignalEvent,
         sunrise_libuser::syscalls::nr::ClearEvent,
         sunrise_libuser::syscalls::nr::ResetSignal,
+        sunrise_libuser::syscalls::nr::CreateTimer,
+        sunrise_libuser::syscalls::nr::SetTimer,
+        sunrise_libuser::syscalls::nr::GetSystemInfo,
     ]
 });
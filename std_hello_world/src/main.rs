@@ -110,6 +110,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
         sunrise_libuser::syscalls::nr::CreateThread,
         sunrise_libuser::syscalls::nr::StartThread,
         sunrise_libuser::syscalls::nr::ExitThread,
@@ -120,6 +121,8 @@ capabilities!(CAPABILITIES = Capabilities {
 
         sunrise_libuser::syscalls::nr::ConnectToNamedPort,
         sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::MapMemory,
+        sunrise_libuser::syscalls::nr::UnmapMemory,
         sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
         sunrise_libuser::syscalls::nr::QueryMemory,
         sunrise_libuser::syscalls::nr::CreateSharedMemory,
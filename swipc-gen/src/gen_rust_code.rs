@@ -857,7 +857,9 @@ pub fn generate_proxy(ifacename: &str, interface: &Interface) -> String {
                 service_name += &"\\0".repeat(8 - service_name.len());
                 writeln!(s, r#"                  core::mem::transmute(*b"{}")"#, service_name).unwrap();
                 writeln!(s, "              }};").unwrap();
-                writeln!(s, "              let _ = match self::sunrise_libuser::sm::IUserInterfaceProxy::raw_new()?.get_service(svcname) {{").unwrap();
+                writeln!(s, "              let sm = self::sunrise_libuser::sm::IUserInterfaceProxy::raw_new()?;").unwrap();
+                writeln!(s, "              sm.initialize()?;").unwrap();
+                writeln!(s, "              let _ = match sm.get_service(svcname) {{").unwrap();
                 writeln!(s, "                  Ok(s) => return Ok({}(s)),", struct_name).unwrap();
                 writeln!(s, "                  Err(Error::Sm(SmError::ServiceNotRegistered, ..)) => syscalls::sleep_thread(0),").unwrap();
                 writeln!(s, "                  Err(err) => return Err(err)").unwrap();
@@ -0,0 +1,326 @@
+//! In-OS integration tests
+//!
+//! Runs tests against the live kernel and services, reports the results over
+//! the serial port, and exits QEMU with the overall status. See
+//! [sunrise_libuser::test] for the report format and the exit codes.
+//!
+//! The tests are `#[test_case]` functions, grouped in a module per component
+//! they test. They only exist when built with `--test`, which the
+//! `sunrise-test` task of the Makefile does.
+//!
+//! It is started by the loader at boot on the disk built by `cargo make
+//! test-os`, which runs it headless under QEMU.
+
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(sunrise_libuser::test::runner)]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate sunrise_libuser;
+#[cfg(test)]
+extern crate alloc;
+
+/// Tests of the kernel and its SVCs.
+#[cfg(test)]
+mod kernel {
+    use core::slice;
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+
+    use sunrise_libuser::error::{Error, KernelError};
+    use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+    use sunrise_libuser::syscalls::{self, CodeMemoryOperation, MemoryPermissions, MemoryType};
+    use sunrise_libuser::types::Process;
+
+    /// Signaling an event wakes its waiters, and clearing it makes them wait
+    /// again.
+    #[test_case]
+    fn events() -> Result<(), Error> {
+        let (writable, readable) = syscalls::create_event()?;
+        match syscalls::wait_synchronization(&[readable.0.as_ref()], Some(0)) {
+            Err(KernelError::Timeout) => (),
+            res => panic!("Unsignaled event did not time out: {:?}", res)
+        }
+        writable.signal()?;
+        syscalls::wait_synchronization(&[readable.0.as_ref()], Some(0))?;
+        readable.clear()?;
+        match syscalls::wait_synchronization(&[readable.0.as_ref()], Some(0)) {
+            Err(KernelError::Timeout) => (),
+            res => panic!("Cleared event did not time out: {:?}", res)
+        }
+        Ok(())
+    }
+
+    /// Sleeping returns.
+    #[test_case]
+    fn sleep_thread() -> Result<(), Error> {
+        syscalls::sleep_thread(10 * 1000 * 1000)?;
+        Ok(())
+    }
+
+    /// Shared memory can be mapped, written to, and unmapped.
+    #[test_case]
+    fn shared_memory() -> Result<(), Error> {
+        let sharedmem = syscalls::create_shared_memory(PAGE_SIZE, MemoryPermissions::RW, MemoryPermissions::READABLE)?;
+        let addr = find_free_address(PAGE_SIZE, PAGE_SIZE)?;
+        syscalls::map_shared_memory(&sharedmem, addr, PAGE_SIZE, MemoryPermissions::RW)?;
+        {
+            let mem = unsafe {
+                // Safety: We just mapped it.
+                slice::from_raw_parts_mut(addr as *mut u8, PAGE_SIZE)
+            };
+            assert!(mem.iter().all(|byte| *byte == 0), "New shared memory is not zeroed");
+            mem[PAGE_SIZE - 1] = 0x42;
+            assert_eq!(mem[PAGE_SIZE - 1], 0x42);
+        }
+        unsafe {
+            // Safety: The slice is gone.
+            syscalls::unmap_shared_memory(&sharedmem, addr, PAGE_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Heap memory can be aliased as code, made readable, and given back.
+    ///
+    /// Only the second page of the heap allocation is aliased, so the heap
+    /// mapping gets split.
+    #[test_case]
+    fn process_code_memory() -> Result<(), Error> {
+        let layout = Layout::from_size_align(2 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let heap = unsafe {
+            // Safety: The layout isn't zero-sized.
+            alloc_zeroed(layout)
+        };
+        assert!(!heap.is_null(), "Failed to allocate the source memory");
+        let src = heap as usize + PAGE_SIZE;
+        unsafe {
+            // Safety: In our allocation.
+            *(src as *mut u8) = 0x42;
+        }
+
+        let dst = find_free_address(PAGE_SIZE, PAGE_SIZE)?;
+        syscalls::map_process_code_memory(&Process::current(), dst, src, PAGE_SIZE)?;
+
+        let (src_info, _) = syscalls::query_memory(src)?;
+        assert_eq!((src_info.baseaddr, src_info.size), (src, PAGE_SIZE), "Source mapping was not split");
+        assert_eq!(src_info.perms, MemoryPermissions::empty(), "Source is not locked");
+        let (dst_info, _) = syscalls::query_memory(dst)?;
+        assert_eq!(dst_info.memtype.ty(), MemoryType::ModuleCodeStatic);
+        assert_eq!(dst_info.perms, MemoryPermissions::empty());
+
+        syscalls::set_process_memory_permission(&Process::current(), dst, PAGE_SIZE, MemoryPermissions::READABLE)?;
+        assert_eq!(unsafe {
+            // Safety: We just made it readable.
+            *(dst as *const u8)
+        }, 0x42);
+
+        match unsafe {
+            // Safety: Fails, the first page of the heap is not aliased.
+            syscalls::unmap_process_code_memory(&Process::current(), dst, heap as usize, PAGE_SIZE)
+        } {
+            Err(KernelError::InvalidMemState) | Err(KernelError::InvalidMemRange) => (),
+            res => panic!("Unmapped the code memory from the wrong source: {:?}", res)
+        }
+
+        unsafe {
+            // Safety: Nothing points to dst anymore.
+            syscalls::unmap_process_code_memory(&Process::current(), dst, src, PAGE_SIZE)?;
+        }
+
+        let (src_info, _) = syscalls::query_memory(src)?;
+        assert_eq!(src_info.perms, MemoryPermissions::RW, "Source was not unlocked");
+        let (dst_info, _) = syscalls::query_memory(dst)?;
+        assert_eq!(dst_info.memtype.ty(), MemoryType::Unmapped);
+
+        unsafe {
+            // Safety: The source is RW- again, and we're done with it.
+            *(src as *mut u8) = 0;
+            dealloc(heap, layout);
+        }
+        Ok(())
+    }
+
+    /// Closing a CodeMemory unmaps its aliases before unlocking its source, so
+    /// no executable alias of writable memory is left behind.
+    #[test_case]
+    fn code_memory_close_unmaps_aliases() -> Result<(), Error> {
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let src = unsafe {
+            // Safety: The layout isn't zero-sized.
+            alloc_zeroed(layout)
+        };
+        assert!(!src.is_null(), "Failed to allocate the source memory");
+
+        let code_memory = syscalls::create_code_memory(src as usize, PAGE_SIZE)?;
+        let rw_alias = find_free_address(PAGE_SIZE, PAGE_SIZE)?;
+        unsafe {
+            // Safety: Maps new memory, no pointer is invalidated.
+            syscalls::control_code_memory(&code_memory, CodeMemoryOperation::MapOwner, rw_alias, PAGE_SIZE, MemoryPermissions::RW)?;
+        }
+        let rx_alias = find_free_address(PAGE_SIZE, PAGE_SIZE)?;
+        unsafe {
+            // Safety: Maps new memory, no pointer is invalidated.
+            syscalls::control_code_memory(&code_memory, CodeMemoryOperation::MapSlave, rx_alias, PAGE_SIZE, MemoryPermissions::RX)?;
+        }
+
+        drop(code_memory);
+
+        for alias in &[rw_alias, rx_alias] {
+            let (info, _) = syscalls::query_memory(*alias)?;
+            assert_eq!(info.memtype.ty(), MemoryType::Unmapped, "Alias at {:#x} survived its CodeMemory", alias);
+        }
+        let (src_info, _) = syscalls::query_memory(src as usize)?;
+        assert_eq!(src_info.perms, MemoryPermissions::RW, "Source was not unlocked");
+
+        unsafe {
+            // Safety: The source is RW- again, and we're done with it.
+            dealloc(src, layout);
+        }
+        Ok(())
+    }
+}
+
+/// Tests of the service manager.
+#[cfg(test)]
+mod sm {
+    use sunrise_libuser::error::{Error, SmError};
+    use sunrise_libuser::sm::IUserInterfaceProxy;
+
+    /// sm gives access to registered services.
+    #[test_case]
+    fn get_service() -> Result<(), Error> {
+        let sm = IUserInterfaceProxy::raw_new()?;
+        sm.initialize()?;
+        sm.get_service(u64::from_le_bytes(*b"vi:\0\0\0\0\0"))?;
+        Ok(())
+    }
+
+    /// sm doesn't let titles host services their NPDM doesn't allow. This title
+    /// has none, so it may not host any.
+    #[test_case]
+    fn not_allowed() -> Result<(), Error> {
+        let sm = IUserInterfaceProxy::raw_new()?;
+        sm.initialize()?;
+        match sm.register_service(u64::from_le_bytes(*b"vi:\0\0\0\0\0"), false, 0) {
+            Err(Error::Sm(SmError::NotAllowed, _)) => Ok(()),
+            Err(err) => Err(err),
+            Ok(_) => panic!("Impersonated vi:")
+        }
+    }
+}
+
+/// Tests of the window manager.
+#[cfg(test)]
+mod vi {
+    use sunrise_libuser::error::Error;
+    use sunrise_libuser::vi::ViInterfaceProxy;
+
+    /// vi knows the size of the screen.
+    #[test_case]
+    fn get_screen_resolution() -> Result<(), Error> {
+        let (width, height) = ViInterfaceProxy::raw_new()?.get_screen_resolution()?;
+        assert!(width != 0 && height != 0, "Empty screen resolution {}x{}", width, height);
+        Ok(())
+    }
+}
+
+/// Tests of the filesystem service.
+#[cfg(test)]
+mod fs {
+    use sunrise_libuser::error::Error;
+    use sunrise_libuser::fs::{FileSystemPath, IFileSystemServiceProxy};
+
+    /// A file written to the disk can be read back, and deleted.
+    #[test_case]
+    fn read_back() -> Result<(), Error> {
+        const DATA: &[u8] = b"Sunrise integration tests";
+
+        let mut path: FileSystemPath = [0; 0x300];
+        path[..b"/sunrise-test.tmp".len()].copy_from_slice(b"/sunrise-test.tmp");
+
+        let filesystem = IFileSystemServiceProxy::raw_new()?.open_disk_partition(0, 0)?;
+        // Leftover of a previous run.
+        let _ = filesystem.delete_file(&path);
+
+        filesystem.create_file(0, 0, &path)?;
+        {
+            let file = filesystem.open_file(0b111, &path)?;
+            file.write(0, 0, DATA.len() as u64, DATA)?;
+            assert_eq!(file.get_size()?, DATA.len() as u64);
+
+            let mut buf = [0; 64];
+            let read = file.read(0, 0, DATA.len() as u64, &mut buf)?;
+            assert_eq!(&buf[..read as usize], DATA);
+        }
+        filesystem.delete_file(&path)?;
+        Ok(())
+    }
+}
+
+/// Tests of the loader.
+#[cfg(test)]
+mod loader {
+    use sunrise_libuser::error::{Error, LoaderError};
+    use sunrise_libuser::ldr::ILoaderInterfaceProxy;
+
+    /// The loader reports titles that don't exist.
+    #[test_case]
+    fn program_not_found() -> Result<(), Error> {
+        let loader = ILoaderInterfaceProxy::raw_new()?;
+        match loader.create_title(b"does-not-exist", b"does-not-exist") {
+            Err(Error::Loader(LoaderError::ProgramNotFound, _)) => Ok(()),
+            Err(err) => Err(err),
+            Ok(pid) => panic!("Created a title that does not exist, pid {}", pid)
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn main() {
+    log::error!("sunrise-test was built without --test, it has no tests to run.");
+}
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::CreateEvent,
+        sunrise_libuser::syscalls::nr::SignalEvent,
+        sunrise_libuser::syscalls::nr::CreateSharedMemory,
+        sunrise_libuser::syscalls::nr::MapSharedMemory,
+        sunrise_libuser::syscalls::nr::UnmapSharedMemory,
+
+        sunrise_libuser::syscalls::nr::SetProcessMemoryPermission,
+        sunrise_libuser::syscalls::nr::MapProcessCodeMemory,
+        sunrise_libuser::syscalls::nr::UnmapProcessCodeMemory,
+        sunrise_libuser::syscalls::nr::CreateCodeMemory,
+        sunrise_libuser::syscalls::nr::ControlCodeMemory,
+
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+    ],
+    raw_caps: [
+        sunrise_libuser::caps::ioport(sunrise_libuser::test::QEMU_EXIT_PORT),
+        sunrise_libuser::caps::code_memory_allowed(),
+    ]
+});
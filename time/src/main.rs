@@ -57,6 +57,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
@@ -120,7 +121,7 @@ struct Rtc {
 impl Rtc {
     /// Create a new RTC with the default IBM PC values.
     pub fn new() -> Rtc {
-        let irq = syscalls::create_interrupt_event(0x08, 0).expect("IRQ cannot be acquired");
+        let irq = syscalls::create_interrupt_event(0x08, syscalls::InterruptType::Edge).expect("IRQ cannot be acquired");
 
         let rtc = Rtc {
             registers: Mutex::new((io::Pio::new(0x70), io::Pio::new(0x71))),
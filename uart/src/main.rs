@@ -0,0 +1,269 @@
+//! UART Service
+//!
+//! Drives the COM1 serial port, and exposes it as [IPipe]s, so a shell can run
+//! on a serial console. This allows driving Sunrise headless, e.g. with
+//! `qemu -nographic`, or from scripts over stdio.
+//!
+//! The kernel keeps logging to COM1 too, so its output is interleaved with the
+//! output of the pipes. The port is expected to be already configured by the
+//! kernel logger, we only enable its receive interrupt.
+//!
+//! [IPipe]: sunrise_libuser::twili::IPipe
+
+#![feature(async_await)]
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate sunrise_libuser;
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use sunrise_libuser::error::Error;
+use sunrise_libuser::futures::{WaitableManager, WorkQueue};
+use sunrise_libuser::futures_rs::future::FutureObj;
+use sunrise_libuser::io::{Io, Pio};
+use sunrise_libuser::ipc::server::{port_handler, new_session_wrapper};
+use sunrise_libuser::syscalls::{self, InterruptType};
+use sunrise_libuser::twili::{IPipeAsync, IPipeProxy};
+use sunrise_libuser::types::ReadableEvent;
+use sunrise_libuser::uart::IUartService;
+
+/// Base IO port of COM1.
+const COM1_PORT: u16 = 0x3F8;
+/// IRQ line of COM1.
+const COM1_IRQ_LINE: usize = 4;
+
+/// Interrupt Enable Register bit raising an IRQ when data is received.
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+/// FIFO Control Register value enabling the FIFOs, clearing them, and raising
+/// the receive interrupt as soon as one byte is received.
+const FCR_ENABLE_CLEAR_TRIGGER_1: u8 = 0x07;
+/// Modem Control Register value setting DTR and RTS, and OUT2, which connects
+/// the UART interrupt to the interrupt controller.
+const MCR_DTR_RTS_OUT2: u8 = 0x0B;
+/// Line Status Register bit set while received data is available.
+const LSR_DATA_READY: u8 = 1 << 0;
+/// Line Status Register bit set when the transmit buffer is empty.
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// Ctrl-D.
+const END_OF_TRANSMISSION: u8 = 0x04;
+/// Ctrl-H, sent by some terminals for the backspace key.
+const BACKSPACE: u8 = 0x08;
+/// Sent by most terminals for the backspace key.
+const DELETE: u8 = 0x7F;
+
+/// A 16550 UART.
+#[derive(Debug)]
+struct Com {
+    /// Data register: reads the received bytes, and writes the bytes to send.
+    data: Pio<u8>,
+    /// Line status register.
+    line_status: Pio<u8>,
+    /// The bytes received, not read by any pipe yet.
+    received: VecDeque<u8>,
+    /// Whether the last byte read by a pipe was a \r, so the \n of a \r\n
+    /// doesn't end another line.
+    last_was_cr: bool,
+}
+
+impl Com {
+    /// Enables the receive interrupt of the UART at the given IO port.
+    fn new(port: u16) -> Com {
+        Pio::<u8>::new(port + 1).write(IER_RECEIVED_DATA);
+        Pio::<u8>::new(port + 2).write(FCR_ENABLE_CLEAR_TRIGGER_1);
+        Pio::<u8>::new(port + 4).write(MCR_DTR_RTS_OUT2);
+
+        Com {
+            data: Pio::new(port),
+            line_status: Pio::new(port + 5),
+            received: VecDeque::new(),
+            last_was_cr: false,
+        }
+    }
+
+    /// Moves the bytes waiting in the UART to the received queue. Reading them
+    /// acknowledges the interrupt.
+    fn receive(&mut self) {
+        while self.line_status.read() & LSR_DATA_READY != 0 {
+            let byte = self.data.read();
+            self.received.push_back(byte);
+        }
+    }
+
+    /// Sends `data`, turning \n into \r\n.
+    fn send(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte == b'\n' {
+                self.send_byte(b'\r');
+            }
+            self.send_byte(byte);
+        }
+    }
+
+    /// Sends a byte, once the transmit buffer is empty.
+    fn send_byte(&mut self, byte: u8) {
+        while self.line_status.read() & LSR_TRANSMIT_EMPTY == 0 {}
+        self.data.write(byte);
+    }
+}
+
+lazy_static! {
+    /// The COM1 UART.
+    static ref COM1: Mutex<Com> = Mutex::new(Com::new(COM1_PORT));
+    /// The IRQ event of COM1, signaled when data is received.
+    static ref COM1_IRQ: ReadableEvent = syscalls::create_interrupt_event(COM1_IRQ_LINE, InterruptType::Edge)
+        .expect("Cannot create the COM1 interrupt event");
+}
+
+/// Twili IPipe implementation on COM1.
+#[derive(Debug, Clone)]
+struct UartPipe {
+    /// Whether the received characters are sent back.
+    echo: bool,
+}
+
+impl IPipeAsync for UartPipe {
+    fn read<'a>(&'a mut self, manager: WorkQueue<'static>, buf: &'a mut [u8]) -> FutureObj<'a, Result<u64, Error>> {
+        let echo = self.echo;
+        FutureObj::new(Box::new(async move {
+            // Reads a whole line, or until buf is full.
+            let mut len = 0;
+            let len = COM1_IRQ.wait_async_cb(manager, move || {
+                let mut com = COM1.lock();
+                com.receive();
+
+                while len < buf.len() {
+                    // Wait for the next IRQ if no byte is available.
+                    let byte = com.received.pop_front()?;
+                    let last_was_cr = core::mem::replace(&mut com.last_was_cr, byte == b'\r');
+                    match byte {
+                        b'\n' if last_was_cr => (),
+                        b'\r' | b'\n' => {
+                            buf[len] = b'\n';
+                            len += 1;
+                            if echo {
+                                com.send(b"\n");
+                            }
+                            return Some(len);
+                        },
+                        END_OF_TRANSMISSION => return Some(len),
+                        BACKSPACE | DELETE => if len > 0 {
+                            // We only keep ASCII characters, so this removes
+                            // a whole character.
+                            len -= 1;
+                            if echo {
+                                com.send(b"\x08 \x08");
+                            }
+                        },
+                        b' '..=b'~' | b'\t' => {
+                            buf[len] = byte;
+                            len += 1;
+                            if echo {
+                                com.send(&[byte]);
+                            }
+                        },
+                        // Ignore the other control characters, and non-ASCII
+                        // bytes.
+                        _ => ()
+                    }
+                }
+                Some(len)
+            }).await;
+            Ok(len as u64)
+        }))
+    }
+
+    fn write<'a>(&'a mut self, _manager: WorkQueue<'static>, data: &'a [u8]) -> FutureObj<'a, Result<(), Error>> {
+        FutureObj::new(Box::new(async move {
+            COM1.lock().send(data);
+            Ok(())
+        }))
+    }
+}
+
+/// Entry point interface.
+#[derive(Default, Debug, Clone)]
+struct UartService;
+
+impl IUartService for UartService {
+    fn open_pipe(&mut self, manager: WorkQueue<'static>, echo: bool) -> Result<IPipeProxy, Error> {
+        let (server, client) = syscalls::create_session(false, 0)?;
+        let wrapper = new_session_wrapper(manager.clone(), server, UartPipe { echo }, UartPipe::dispatch);
+        manager.spawn(FutureObj::new(Box::new(wrapper)));
+        Ok(IPipeProxy::from(client))
+    }
+}
+
+fn main() {
+    // Enable the receive interrupt before anyone waits for it.
+    lazy_static::initialize(&COM1);
+    lazy_static::initialize(&COM1_IRQ);
+
+    let mut man = WaitableManager::new();
+    let handler = port_handler(man.work_queue(), "uart", UartService::dispatch).unwrap();
+    man.work_queue().spawn(FutureObj::new(Box::new(handler)));
+    man.run();
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"uart\0\0\0\0\0\0\0\0",
+    title_id: 0x0200000000001070,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::Break,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
+
+        sunrise_libuser::syscalls::nr::ReplyAndReceiveWithUserBuffer,
+        sunrise_libuser::syscalls::nr::AcceptSession,
+        sunrise_libuser::syscalls::nr::CreateSession,
+
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::CreateInterruptEvent,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+    ],
+    raw_caps: [
+        sunrise_libuser::caps::ioport(COM1_PORT),
+        sunrise_libuser::caps::ioport(COM1_PORT + 1),
+        sunrise_libuser::caps::ioport(COM1_PORT + 2),
+        sunrise_libuser::caps::ioport(COM1_PORT + 4),
+        sunrise_libuser::caps::ioport(COM1_PORT + 5),
+        sunrise_libuser::caps::irq_pair(COM1_IRQ_LINE as u16, 0x3FF)
+    ]
+});